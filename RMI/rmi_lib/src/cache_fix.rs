@@ -1,18 +1,24 @@
 use log::*;
-use crate::models::TrainingKey;
+use crate::models::{KeyType, TrainingKey};
 use crate::RMITrainingData;
+use rug::Float;
+
+// true if a comes before b in key order
+fn key_less<T: TrainingKey>(a: &T, b: &T) -> bool {
+    return a.to_model_input() < b.to_model_input();
+}
 
 #[derive(Debug)]
-pub struct Spline {
-    from_x: u64,
+pub struct Spline<T: TrainingKey> {
+    from_x: T,
     from_y: usize,
-    to_x: u64,
+    to_x: T,
     to_y: usize
 }
 
-impl Spline {
-    fn from(pt1: (u64, usize), pt2: (u64, usize)) -> Spline {
-        assert!(pt1.0 <= pt2.0,
+impl <T: TrainingKey> Spline<T> {
+    pub(crate) fn from(pt1: (T, usize), pt2: (T, usize)) -> Spline<T> {
+        assert!(!key_less(&pt2.0, &pt1.0),
                 "Cannot construct spline from {:?} to {:?}", pt1, pt2);
         assert!(pt1.1 <= pt2.1,
                 "Cannot construct spline from {:?} to {:?}", pt1, pt2);
@@ -20,37 +26,54 @@ impl Spline {
                         to_x: pt2.0, to_y: pt2.1 };
     }
 
-    fn with_new_dest(&self, dest: (u64, usize)) -> Spline {
-        assert!(dest.0 >= self.from_x,
-                "When source x is {}, cannot set dest x to {}",
+    fn with_new_dest(&self, dest: (T, usize)) -> Spline<T> {
+        assert!(!key_less(&dest.0, &self.from_x),
+                "When source x is {:?}, cannot set dest x to {:?}",
                 self.from_x, dest.0);
         assert!(dest.1 >= self.from_y);
         return Spline { from_x: self.from_x, from_y: self.from_y,
                         to_x: dest.0, to_y: dest.1 };
     }
     
-    fn end(&self) -> (u64, usize) {
+    fn end(&self) -> (T, usize) {
         return (self.to_x, self.to_y);
     }
 
-    fn predict(&self, inp: u64) -> usize {
+    pub(crate) fn predict(&self, inp: T) -> usize {
         let v0 = self.from_y as f64;
         let v1 = self.to_y as f64;
-        let t = ((inp - self.from_x) as f64) / (self.to_x - self.from_x) as f64;
+        let t = match T::key_type() {
+            KeyType::U512 | KeyType::F512 => {
+                // the differences between wide keys do not fit an f64, so
+                // interpolate in 512-bit precision
+                let dx = Float::with_val(512, inp.as_float512() - self.from_x.as_float512());
+                let width = Float::with_val(512, self.to_x.as_float512() - self.from_x.as_float512());
+                let t = Float::with_val(512, dx / width);
+                let pred = Float::with_val(512, (1 - t.clone()) * v0) + t * v1;
+                return pred.to_f64() as usize;
+            },
+            KeyType::U128 => ((inp.as_uint128() - self.from_x.as_uint128()) as f64)
+                / (self.to_x.as_uint128() - self.from_x.as_uint128()) as f64,
+            KeyType::F64 => (inp.as_float() - self.from_x.as_float())
+                / (self.to_x.as_float() - self.from_x.as_float()),
+            KeyType::U32 | KeyType::U64 | KeyType::Bytes =>
+                ((inp.as_uint() - self.from_x.as_uint()) as f64)
+                / (self.to_x.as_uint() - self.from_x.as_uint()) as f64,
+        };
 
         return (1.0 - t).mul_add(v0, t * v1) as usize;
     }
 }
 
-struct SplineFit {
-    spline: Option<Spline>,
-    curr_pts: Vec<(u64, usize)>,
+struct SplineFit<T: TrainingKey> {
+    spline: Option<Spline<T>>,
+    curr_pts: Vec<(T, usize)>,
     line_size: usize
 }
 
-impl SplineFit {
+impl <T: TrainingKey> SplineFit<T> {
 
-    pub fn new(line_size: usize) -> SplineFit {
+    pub fn new(line_size: usize) -> SplineFit<T> {
         return SplineFit {
             spline: None,
             curr_pts: Vec::new(),
@@ -58,7 +81,7 @@ impl SplineFit {
         };
     }
 
-    pub fn add_point(&mut self, point: (u64, usize)) -> Option<(u64, usize)> {
+    pub fn add_point(&mut self, point: (T, usize)) -> Option<(T, usize)> {
         if self.spline.is_none() {
             self.spline = Some(Spline::from(point, point));
             return Some(point);
@@ -76,7 +99,7 @@ impl SplineFit {
         } else {
             // reject this proposal, start a new spline.
             let prev_pt = last_spline.end();
-            assert!(point.0 > prev_pt.0,
+            assert!(key_less(&prev_pt.0, &point.0),
                     "new point: {:?} prev point: {:?}",
                     point, prev_pt);
 
@@ -88,12 +111,12 @@ impl SplineFit {
     }
 
 
-    pub fn finish(self) -> Option<(u64, usize)> {
+    pub fn finish(self) -> Option<(T, usize)> {
         return self.spline.map(|s| s.end());
     }
 
     
-    fn check_spline(&self, spline: &Spline) -> bool {
+    fn check_spline(&self, spline: &Spline<T>) -> bool {
         return self.curr_pts.iter()
             .all(|pt| {
                 let predicted_line: usize = spline.predict(pt.0) / self.line_size;
@@ -103,7 +126,81 @@ impl SplineFit {
     }
 }
 
-pub fn cache_fix(data: &RMITrainingData<u64>, line_size: usize) -> Vec<(u64, usize)> {
+// Greedy spline corridor: fits a linear spline through a subset of the
+// points such that interpolating between the spline points is within
+// max_error of every point. Unlike SplineFit, it only keeps the tightest
+// upper and lower slopes seen since the last spline point, so each point is
+// checked in constant time. Points must be sorted by x; repeated x values
+// keep their first y.
+pub fn bounded_spline<I: Iterator<Item = (u64, usize)>>(points: I, max_error: usize)
+                                                     -> Vec<(u64, usize)> {
+    // (dx, dy) of b relative to a, as signed 128-bit values
+    fn delta(a: (u64, i128), b: (u64, i128)) -> (i128, i128) {
+        return ((b.0 - a.0) as i128, b.1 - a.1);
+    }
+    // true if the slope from the origin to p1 is less than the slope to p2
+    fn slope_less(p1: (i128, i128), p2: (i128, i128)) -> bool {
+        return p1.1 * p2.0 < p2.1 * p1.0;
+    }
+
+    let err = max_error as i128;
+    let mut spline: Vec<(u64, usize)> = Vec::new();
+    let mut base: Option<(u64, usize)> = None;
+    let mut prev: Option<(u64, usize)> = None;
+    let mut upper = (0, 0);
+    let mut lower = (0, 0);
+
+    for (x, y) in points {
+        let (bx, by) = match base {
+            None => {
+                base = Some((x, y));
+                prev = Some((x, y));
+                spline.push((x, y));
+                continue;
+            },
+            Some(b) => b
+        };
+        let (px, py) = prev.unwrap();
+        if x == px { continue; }
+        assert!(x > px, "points must be sorted, {} came after {}", x, px);
+
+        let origin = (bx, by as i128);
+        let pt = delta(origin, (x, y as i128));
+        let up = delta(origin, (x, y as i128 + err));
+        let down = delta(origin, (x, y as i128 - err));
+
+        if px == bx {
+            // first point after the base point defines the corridor
+            upper = up;
+            lower = down;
+        } else if slope_less(upper, pt) || slope_less(pt, lower) {
+            // the point is outside of the corridor, the previous point
+            // becomes a spline point and the corridor starts over from it
+            spline.push((px, py));
+            base = Some((px, py));
+            let origin = (px, py as i128);
+            upper = delta(origin, (x, y as i128 + err));
+            lower = delta(origin, (x, y as i128 - err));
+        } else {
+            if slope_less(up, upper) { upper = up; }
+            if slope_less(lower, down) { lower = down; }
+        }
+        prev = Some((x, y));
+    }
+
+    if let Some(p) = prev {
+        if spline.last() != Some(&p) {
+            spline.push(p);
+        }
+    }
+    return spline;
+}
+
+// Fits a spline through the keys such that interpolating between the spline
+// points predicts the right cache line of every key, for any key type. Keys
+// that the models cannot tell apart (byte strings with the same encoding)
+// keep the position of the first one.
+pub fn cache_fix<T: TrainingKey>(data: &RMITrainingData<T>, line_size: usize) -> Vec<(T, usize)> {
     assert!(data.len() > line_size,
             "Cannot apply a cachefix with fewer items than the line size");
     info!("Fitting cachefix spline to {} datapoints", data.len());
@@ -114,17 +211,25 @@ pub fn cache_fix(data: &RMITrainingData<u64>, line_size: usize) -> Vec<(u64, usi
     // Potential speedup here by carefully building a spline over the first
     // and last element of each cache line. Requires careful handling of duplicates,
     // especially when they cross cache lines.
-    let mut last_key = 0;
+    let mut last_key = T::zero_value();
+    let mut seen_key = false;
     for (key, offset) in data.iter_unique() {
-        assert!(key.minus_epsilon() >= last_key,
-                "key: {:?} last key: {:?}, key - e: {:?}",
-                key, last_key, key.minus_epsilon());
-        
-        if key.minus_epsilon() != last_key {
-            match fit.add_point((key.minus_epsilon(), offset)) {
-                None => {},
-                Some(p) => spline.push(p)
-            };
+        if seen_key && key.same_encoding(&last_key) {
+            continue;
+        }
+
+        // nothing comes before the smallest key
+        if key != T::zero_value() {
+            assert!(!seen_key || !key_less(&key.minus_epsilon(), &last_key),
+                    "key: {:?} last key: {:?}, key - e: {:?}",
+                    key, last_key, key.minus_epsilon());
+
+            if key.minus_epsilon() != last_key {
+                match fit.add_point((key.minus_epsilon(), offset)) {
+                    None => {},
+                    Some(p) => spline.push(p)
+                };
+            }
         }
         
         match fit.add_point((key, offset)) {
@@ -133,6 +238,7 @@ pub fn cache_fix(data: &RMITrainingData<u64>, line_size: usize) -> Vec<(u64, usi
         };
         
         last_key = key;
+        seen_key = true;
     }
 
 
@@ -19,6 +19,7 @@ use std::fs::File;
 use std::io::BufWriter;
 use std::path::Path;
 use std::fmt;
+use byteorder::{WriteBytesExt, LittleEndian};
 
 
 enum LayerParams {
@@ -346,7 +347,9 @@ impl LayerParams {
 
         return Result::Ok(());
     }
-    fn with_zipped_errors(&self, lle: &[u64]) -> LayerParams {
+    // errors holds one or more values per model (e.g., the left and right
+    // errors of equal range windows), appended in order
+    fn with_zipped_errors(&self, errors: &[&[u64]]) -> LayerParams {
         
         let params = self.params();
         // integrate the errors into the model parameters of the last
@@ -356,11 +359,11 @@ impl LayerParams {
         // cache-aligned. Also a lot of unneeded copying going on here...
         let combined_lle_params: Vec<ModelParam> =
             params.chunks(self.params_per_model())
-            .zip(lle)
-            .flat_map(|(mod_params, err)| {
+            .zip(0..errors.iter().map(|e| e.len()).min().unwrap())
+            .flat_map(|(mod_params, idx)| {
                 let mut to_r: Vec<ModelParam> = Vec::new();
                 to_r.extend_from_slice(mod_params);
-                to_r.push(ModelParam::Int(*err));
+                to_r.extend(errors.iter().map(|e| ModelParam::Int(e[idx])));
                 to_r
             }).collect();
 
@@ -370,7 +373,7 @@ impl LayerParams {
             false
         };
         
-        return LayerParams::new(self.index(), is_constant, self.params_per_model() + 1,
+        return LayerParams::new(self.index(), is_constant, self.params_per_model() + errors.len(),
                                 combined_lle_params);
                                 
     }
@@ -405,21 +408,32 @@ fn params_for_layer(layer_idx: usize,
 }
 
 
-fn zip_errors( layer_idx: usize, lle: &[u64] , 
+// The errors stored with each leaf of a two-layer RMI: the left and right
+// errors of equal range or signed windows, or the symmetric error.
+fn leaf_errors(rmi: &TrainedRMI) -> Vec<&[u64]> {
+    if rmi.left_errors.is_empty() {
+        return vec![&rmi.last_layer_max_l1s];
+    }
+    return vec![&rmi.left_errors, &rmi.right_errors];
+}
+
+// Appends the errors of each model to its parameters: one value per model
+// for each slice in errors.
+fn zip_errors( layer_idx: usize, errors: &[&[u64]] , 
                models: &[Box<dyn Model>]
                 ) -> LayerParams {
         
     let params_per_model = models[0].params().len();
 
     let combined_lle_params: Vec<ModelParam> =
-        models.iter().zip(lle).flat_map(|(mod_params, err)| {
+        models.iter().zip(0..errors.iter().map(|e| e.len()).min().unwrap()).flat_map(|(mod_params, idx)| {
             let mut to_r: Vec<ModelParam> = Vec::new();
             to_r.extend_from_slice( &(mod_params.params()) );
-            to_r.push(ModelParam::Int(*err));
+            to_r.extend(errors.iter().map(|e| ModelParam::Int(e[idx])));
             to_r
         }).collect();
     
-    return LayerParams::new( layer_idx , models.len() > 1, params_per_model + 1,
+    return LayerParams::new( layer_idx , models.len() > 1, params_per_model + errors.len(),
                             combined_lle_params);
                             
 }
@@ -436,9 +450,9 @@ macro_rules! model_index_from_output {
             }
             ModelDataType::Float512 => {
                 if $needs_check {
-                    format!("FCLAMP(f512pred, {}.0 - 1.0)", $bound)
+                    format!("(f512pred > {0} - 1 ? {0} - 1 : f512pred)", $bound)
                 } else {
-                    format!("(uint64_t) f512pred")
+                    format!("f512pred")
                 }
             }
             ModelDataType::Int => {
@@ -467,6 +481,25 @@ macro_rules! model_index_from_output {
     };
 }
 
+// The parameter holding one cachefix spline key. Wide keys are stored as
+// 512-bit floats, which hold every 512-bit integer exactly, in the layout
+// the 512-bit model parameters are read with.
+fn spline_key_param(key: &ModelInput) -> ModelParam {
+    return match key {
+        ModelInput::Int(x) => ModelParam::Int(*x),
+        ModelInput::Int128(x) => ModelParam::Int128(*x),
+        ModelInput::Float(x) => ModelParam::Float(*x),
+        ModelInput::UINT512(_) | ModelInput::Float512(_) => ModelParam::Float512(key.as_float512()),
+    };
+}
+
+// The cachefix spline as layer parameters: the key and position of each point.
+fn spline_params(rmi: &TrainedRMI) -> Vec<ModelParam> {
+    return rmi.cache_fix.as_ref().unwrap().1.iter()
+        .flat_map(|(key, offset)| vec![spline_key_param(key), (*offset).into()])
+        .collect();
+}
+
 pub fn rmi_size(rmi: &TrainedRMI) -> u64 {
     // compute the RMI size (used in the header, compute here before consuming)
     let mut num_total_bytes = 0;
@@ -478,11 +511,13 @@ pub fn rmi_size(rmi: &TrainedRMI) -> u64 {
     }
 
     if !rmi.last_layer_max_l1s.is_empty() {
-        num_total_bytes += rmi.rmi.last().unwrap().len() * 8;
+        // equal range and signed windows keep a left and a right error per leaf
+        let errors_per_leaf = if rmi.left_errors.is_empty() { 1 } else { 2 };
+        num_total_bytes += rmi.rmi.last().unwrap().len() * 8 * errors_per_leaf;
     }
 
     if rmi.cache_fix.is_some() {
-        num_total_bytes += rmi.cache_fix.as_ref().unwrap().1.len() * 16;
+        num_total_bytes += spline_params(rmi).iter().map(|p| p.size()).sum::<usize>();
     }
     
     return num_total_bytes as u64;
@@ -491,27 +526,66 @@ pub fn rmi_size(rmi: &TrainedRMI) -> u64 {
 fn generate_cache_fix_code<T: Write>(
     target: &mut T,
     rmi: &TrainedRMI,
+    key_c_type: &str,
     array_name: String) -> Result<(), std::io::Error> {
 
-    let num_splines = rmi.cache_fix.as_ref().unwrap().1.len();
-    let line_size = rmi.cache_fix.as_ref().unwrap().0;
+    let (line_size, spline) = rmi.cache_fix.as_ref().unwrap();
+    let num_splines = spline.len();
     let total_keys = rmi.num_data_rows;
 
+    // Wide spline keys are read from the layout of 512-bit parameters and
+    // interpolated in 512-bit precision; other keys are stored as they are.
+    let (point_fields, point_key, search_key, interpolate) = match spline_key_param(&spline[0].0) {
+        ModelParam::Float512(_) => (
+            String::from("uint16_t mantissa[32];
+  int16_t exponent;
+  uint16_t is_minus;"),
+            String::from("inline cpp_bin_float_512 spline_key(const SplinePoint& pt) {
+  boost::multiprecision::cpp_int i;
+  import_bits(i, pt.mantissa, pt.mantissa + 32);
+  cpp_bin_float_512 g(i);
+  g.backend().exponent() = pt.exponent;
+  if (pt.is_minus) {
+    g = -g;
+  }
+  return g;
+}"),
+            "cpp_bin_float_512 search_key(key);",
+            String::from("cpp_bin_float_512 x1 = spline_key(pt1);
+  cpp_bin_float_512 t = (search_key - x1) / (spline_key(pt2) - x1);
+  cpp_bin_float_512 pred = (1 - t) * (double)pt1.value + t * (double)pt2.value;
+  return (((uint64_t) pred.convert_to<double>()) / LINE_SIZE) * LINE_SIZE;")),
+        param => (
+            format!("{} key;", param.c_type()),
+            format!("inline {} spline_key(const SplinePoint& pt) {{
+  return pt.key;
+}}", param.c_type()),
+            "auto search_key = key;",
+            String::from("auto v0 = (double)pt1.value;
+  auto v1 = (double)pt2.value;
+  auto t = ((double)(key - pt1.key)) / (double)(pt2.key - pt1.key);
+  return (((uint64_t) std::fma(1.0 - t, v0, t * v1)) / LINE_SIZE) * LINE_SIZE;"))
+    };
+
     writeln!(target,
              "
 struct __attribute__((packed)) SplinePoint {{
-  uint64_t key;
+  {point_fields}
   uint64_t value;
 }};
 
-uint64_t lookup(uint64_t key, size_t* err) {{
-  const uint64_t num_spline_pts = {};
-  const uint64_t total_keys = {};
+{point_key}
+
+uint64_t lookup({key_type} key, size_t* err) {{
+  const uint64_t num_spline_pts = {num_splines};
+  const uint64_t total_keys = {total_keys};
+  const uint64_t LINE_SIZE = {line_size};
   size_t error_on_spline_search;
 
-  struct SplinePoint* begin = (struct SplinePoint*) {};
+  struct SplinePoint* begin = (struct SplinePoint*) {array_name};
 
-  *err = {};
+  *err = {line_size};
+  {search_key}
   uint64_t start = _rmi_lookup_pre_cachefix(key, &error_on_spline_search);
 
   size_t upper = (start + error_on_spline_search > num_spline_pts
@@ -522,8 +596,8 @@ uint64_t lookup(uint64_t key, size_t* err) {{
   
   struct SplinePoint* res = std::lower_bound(begin + lower,
                                              begin + upper,
-                                             key,
-                                             [](const auto& lhs, const auto rhs) {{ return lhs.key < rhs; }});
+                                             search_key,
+                                             [](const auto& lhs, const auto& rhs) {{ return spline_key(lhs) < rhs; }});
 
   if (res == begin + num_spline_pts)
     // we've searched for something past the last point
@@ -532,16 +606,278 @@ uint64_t lookup(uint64_t key, size_t* err) {{
   auto pt1 = *(res - 1);
   auto pt2 = *res;
 
-  auto v0 = (double)pt1.value;
-  auto v1 = (double)pt2.value;
-  auto t = ((double)(key - pt1.key)) / (double)(pt2.key - pt1.key);
-  return (((uint64_t) std::fma(1.0 - t, v0, t * v1)) / {3}) * {3};
-}}", num_splines, total_keys, array_name, line_size)?;
+  {interpolate}
+}}", point_fields = point_fields, point_key = point_key, key_type = key_c_type,
+             num_splines = num_splines, total_keys = total_keys, line_size = line_size,
+             array_name = array_name, search_key = search_key, interpolate = interpolate)?;
     
 
     return Ok(());
 }
 
+// Emits the key encoding used for byte string keys, plus lookup functions
+// taking (const char*, size_t) keys. Keys that differ from the shared prefix
+// are placed before or after every indexed key without consulting the RMI.
+fn generate_bytes_key_code<T: Write>(
+    target: &mut T,
+    namespace: &str,
+    shared_prefix: &[u8],
+    num_keys: u64,
+    report_errors: bool) -> Result<(), std::io::Error> {
+
+    let prefix_bytes = if shared_prefix.is_empty() {
+        String::from("0")
+    } else {
+        shared_prefix.iter()
+            .map(|b| format!("0x{:02x}", b))
+            .collect::<Vec<String>>()
+            .join(", ")
+    };
+
+    writeln!(target, "#include <cstddef>")?;
+    writeln!(target, "#include <cstdint>")?;
+    writeln!(target, "#include <cstring>")?;
+    writeln!(target, "namespace {} {{", namespace)?;
+    writeln!(target,
+             "
+const size_t KEY_PREFIX_LENGTH = {};
+const unsigned char KEY_PREFIX[] = {{ {} }};
+
+inline uint64_t encode_key(const char* key, size_t len) {{
+  uint64_t encoded = 0;
+  for (size_t i = KEY_PREFIX_LENGTH; i < KEY_PREFIX_LENGTH + 8; i++) {{
+    encoded <<= 8;
+    if (i < len) encoded |= (unsigned char) key[i];
+  }}
+  return encoded;
+}}
+
+// -1 if the key sorts before every indexed key, 1 if after, 0 otherwise
+inline int compare_key_prefix(const char* key, size_t len) {{
+  size_t n = (len < KEY_PREFIX_LENGTH ? len : KEY_PREFIX_LENGTH);
+  int cmp = std::memcmp(key, KEY_PREFIX, n);
+  if (cmp < 0 || (cmp == 0 && len < KEY_PREFIX_LENGTH)) return -1;
+  if (cmp > 0) return 1;
+  return 0;
+}}", shared_prefix.len(), prefix_bytes)?;
+
+    if report_errors {
+        writeln!(target,
+                 "
+uint64_t lookup(uint64_t key, size_t* err);
+
+inline uint64_t lookup(const char* key, size_t len, size_t* err) {{
+  int cmp = compare_key_prefix(key, len);
+  if (cmp != 0) {{
+    *err = 0;
+    return (cmp < 0 ? 0 : {});
+  }}
+  return lookup(encode_key(key, len), err);
+}}", num_keys)?;
+    } else {
+        writeln!(target,
+                 "
+uint64_t lookup(uint64_t key);
+
+inline uint64_t lookup(const char* key, size_t len) {{
+  int cmp = compare_key_prefix(key, len);
+  if (cmp != 0) return (cmp < 0 ? 0 : {});
+  return lookup(encode_key(key, len));
+}}", num_keys)?;
+    }
+    writeln!(target, "}} // namespace")?;
+
+    return Ok(());
+}
+
+// Emits the lookup for RMIs trained with dedup semantics, whose predictions
+// are ranks among the distinct keys. The runs of duplicates are loaded from
+// the {namespace}_DUPLICATES file and turn ranks into rows of the data.
+fn generate_duplicates_code<T: Write>(
+    target: &mut T,
+    namespace: &str,
+    key_c_type: &str,
+    num_distinct_keys: u64,
+    num_rows: u64,
+    report_errors: bool) -> Result<(), std::io::Error> {
+
+    writeln!(target, "#include <algorithm>")?;
+    writeln!(target, "#include <cstddef>")?;
+    writeln!(target, "#include <cstdint>")?;
+    writeln!(target, "#include <cstdio>")?;
+    writeln!(target, "#include <vector>")?;
+    writeln!(target, "namespace {} {{", namespace)?;
+    writeln!(target,
+             "
+const uint64_t NUM_DISTINCT_KEYS = {};
+const uint64_t NUM_ROWS = {};
+
+// the rank of the key of every run of duplicates, and the number of extra
+// rows (one less than the length) of the runs before each of them
+struct Duplicates {{
+  std::vector<uint64_t> ranks;
+  std::vector<uint64_t> extra_rows;
+}};
+
+inline bool load_duplicates(const char* path, Duplicates* dups) {{
+  FILE* f = std::fopen(path, \"rb\");
+  if (!f) return false;
+  uint64_t header[2];
+  bool ok = std::fread(header, sizeof(uint64_t), 2, f) == 2 && header[0] == {};
+  uint64_t extra = 0;
+  dups->ranks.clear();
+  dups->extra_rows.assign(1, 0);
+  for (uint64_t i = 0; ok && i < header[1]; i++) {{
+    uint64_t run[2];
+    ok = std::fread(run, sizeof(uint64_t), 2, f) == 2;
+    if (!ok) break;
+    extra += run[1] - 1;
+    dups->ranks.push_back(run[0]);
+    dups->extra_rows.push_back(extra);
+  }}
+  std::fclose(f);
+  return ok;
+}}
+
+// the first row of the distinct key with the given rank
+inline uint64_t first_row(const Duplicates& dups, uint64_t rank) {{
+  if (rank > NUM_DISTINCT_KEYS) rank = NUM_DISTINCT_KEYS;
+  size_t runs_before = std::lower_bound(dups.ranks.begin(), dups.ranks.end(), rank)
+    - dups.ranks.begin();
+  return rank + dups.extra_rows[runs_before];
+}}", num_distinct_keys, num_rows, DuplicateSemantics::Dedup.code())?;
+
+    if report_errors {
+        writeln!(target,
+                 "
+uint64_t lookup({key_type} key, size_t* err);
+
+// the first row of the predicted key, and the rows [*lower, *upper) of the
+// keys in the window of the lookup
+inline uint64_t lookup_rows(const Duplicates& dups, {key_type} key,
+                            uint64_t* lower, uint64_t* upper) {{
+  size_t err;
+  uint64_t rank = lookup(key, &err);
+  *lower = first_row(dups, rank > err ? rank - err : 0);
+  *upper = first_row(dups, rank + err + 1);
+  return first_row(dups, rank);
+}}", key_type = key_c_type)?;
+    } else {
+        writeln!(target,
+                 "
+uint64_t lookup({key_type} key);
+
+// the first row of the predicted key
+inline uint64_t lookup_row(const Duplicates& dups, {key_type} key) {{
+  return first_row(dups, lookup(key));
+}}", key_type = key_c_type)?;
+    }
+    writeln!(target, "}} // namespace")?;
+
+    return Ok(());
+}
+
+fn generate_exact_leaves_code<T: Write>(
+    target: &mut T,
+    namespace: &str,
+    key_c_type: &str,
+    array_c_type: &str,
+    num_rows: u64,
+    report_errors: bool) -> Result<(), std::io::Error> {
+
+    writeln!(target, "#include <algorithm>")?;
+    writeln!(target, "#include <cstddef>")?;
+    writeln!(target, "#include <cstdint>")?;
+    writeln!(target, "#include <cstdio>")?;
+    writeln!(target, "#include <vector>")?;
+    writeln!(target, "namespace {} {{", namespace)?;
+    writeln!(target,
+             "
+const uint64_t NUM_INDEXED_ROWS = {num_rows};
+
+// the leaves searched exactly, in key order. Each holds the keys of the rows
+// [first_pos, first_pos + keys.size()): its own keys, plus the last key
+// before it and the first key after it.
+struct ExactLeaves {{
+  std::vector<uint64_t> first_pos;
+  std::vector<std::vector<{array_type}>> keys;
+}};
+
+inline bool load_exact_leaves(const char* path, ExactLeaves* leaves) {{
+  FILE* f = std::fopen(path, \"rb\");
+  if (!f) return false;
+  uint64_t num_leaves;
+  bool ok = std::fread(&num_leaves, sizeof(uint64_t), 1, f) == 1;
+  leaves->first_pos.clear();
+  leaves->keys.clear();
+  for (uint64_t i = 0; ok && i < num_leaves; i++) {{
+    // the leaf index, the position of the first key and the number of keys
+    uint64_t header[3];
+    ok = std::fread(header, sizeof(uint64_t), 3, f) == 3 && header[2] > 0;
+    if (!ok) break;
+    std::vector<{array_type}> keys(header[2]);
+    ok = std::fread(keys.data(), sizeof({array_type}), header[2], f) == header[2];
+    leaves->first_pos.push_back(header[1]);
+    leaves->keys.push_back(std::move(keys));
+  }}
+  std::fclose(f);
+  return ok;
+}}
+
+// the lower bound of a key that is routed to an exact leaf. Those are the
+// keys above the leaf's first key, up to its last one (all of the keys below
+// or above it at either end of the data).
+inline bool find_exact(const ExactLeaves& leaves, {key_type} key, uint64_t* pos) {{
+  size_t lo = 0, hi = leaves.keys.size();
+  while (lo < hi) {{
+    size_t mid = lo + (hi - lo) / 2;
+    if (leaves.keys[mid].back() < key) lo = mid + 1;
+    else hi = mid;
+  }}
+  if (lo == leaves.keys.size()) {{
+    if (lo == 0 || leaves.first_pos[lo - 1] + leaves.keys[lo - 1].size() != NUM_INDEXED_ROWS)
+      return false;
+    *pos = NUM_INDEXED_ROWS;
+    return true;
+  }}
+
+  const std::vector<{array_type}>& keys = leaves.keys[lo];
+  if (leaves.first_pos[lo] != 0 && !(keys.front() < key)) return false;
+  *pos = leaves.first_pos[lo] + (std::lower_bound(keys.begin(), keys.end(), key) - keys.begin());
+  return true;
+}}", num_rows = num_rows, array_type = array_c_type, key_type = key_c_type)?;
+
+    if report_errors {
+        writeln!(target,
+                 "
+uint64_t lookup({key_type} key, size_t* err);
+
+// the RMI lookup, with an error of zero for the keys of exact leaves
+inline uint64_t lookup_exact(const ExactLeaves& leaves, {key_type} key, size_t* err) {{
+  uint64_t pos;
+  if (find_exact(leaves, key, &pos)) {{
+    *err = 0;
+    return pos;
+  }}
+  return lookup(key, err);
+}}", key_type = key_c_type)?;
+    } else {
+        writeln!(target,
+                 "
+uint64_t lookup({key_type} key);
+
+// the RMI lookup, searched exactly for the keys of exact leaves
+inline uint64_t lookup_exact(const ExactLeaves& leaves, {key_type} key) {{
+  uint64_t pos;
+  if (find_exact(leaves, key, &pos)) return pos;
+  return lookup(key);
+}}", key_type = key_c_type)?;
+    }
+    writeln!(target, "}} // namespace")?;
+
+    return Ok(());
+}
+
 fn generate_code<T: Write>(
     code_output: &mut T,
     data_output: &mut T,
@@ -561,15 +897,17 @@ fn generate_code<T: Write>(
     let report_last_layer_errors = !rmi.last_layer_max_l1s.is_empty();
     let report_partial_layer_errors = !rmi.third_layer_max_l1s.is_empty();
 
+    // equal range and signed lookups return the window [*lo, *hi] around
+    // the position, from the left and right errors of the leaf
+    let report_range = report_last_layer_errors && !rmi.left_errors.is_empty();
+
     let mut report_lle: Vec<u8> = Vec::new();
     if report_last_layer_errors && !report_partial_layer_errors {
         let lle = &rmi.last_layer_max_l1s;
         if lle.len() > 1 {
             let old_last = layer_params.pop().unwrap();
-            let new_last = old_last.with_zipped_errors(lle);
+            let new_last = old_last.with_zipped_errors(&leaf_errors(&rmi));
             
-            write!(report_lle, "  *err = ")?;
-
             let last_layer_output = rmi.rmi[ rmi.rmi.len()-1 ][0].output_type();
 
             let var_name = match last_layer_output {
@@ -580,16 +918,29 @@ fn generate_code<T: Write>(
                     ModelDataType::Int512 => "i512pred"
             };
 
-            if var_name == "f512pred"{
-                writeln!(report_lle, "param_err[modelIndex];")?;
+            if report_range {
+                assert!(var_name != "f512pred", "Left and right errors are not emitted for 512-bit models");
+                write!(report_lle, "  *lo = ")?;
+                new_last.access_by_ref(&mut report_lle, "modelIndex",
+                                   new_last.params_per_model() - 2)?;
+                write!(report_lle, ";\n  *hi = ")?;
+                new_last.access_by_ref(&mut report_lle, "modelIndex",
+                                   new_last.params_per_model() - 1)?;
+                writeln!(report_lle, ";")?;
+            }
+            else if var_name == "f512pred"{
+                writeln!(report_lle, "  *err = param_err[modelIndex];")?;
             }
             else{
+                write!(report_lle, "  *err = ")?;
                 new_last.access_by_ref(&mut report_lle, "modelIndex",
                                    new_last.params_per_model() - 1)?;
                 writeln!(report_lle, ";")?;
             }
             layer_params.push(new_last);
             
+        } else if report_range {
+            write!(report_lle, "  *lo = {};\n  *hi = {};", rmi.left_errors[0], rmi.right_errors[0])?;
         } else {
             write!(report_lle, "  *err = {};", lle[0])?;
         }
@@ -601,8 +952,8 @@ fn generate_code<T: Write>(
         if lle.len() > 1 {
             let old_last = layer_params.pop().unwrap();
             let old_partial = layer_params.pop().unwrap();
-            let new_last = old_last.with_zipped_errors(lle);
-            let new_partial = old_partial.with_zipped_errors(ple);
+            let new_last = old_last.with_zipped_errors(&[lle]);
+            let new_partial = old_partial.with_zipped_errors(&[ple]);
             
             write!(report_lle, "  *err = ")?;
 
@@ -635,11 +986,8 @@ fn generate_code<T: Write>(
 
 
     if rmi.cache_fix.is_some() {
-        let cfv: Vec<ModelParam> = rmi.cache_fix.as_ref().unwrap().1.iter()
-            .flat_map(|(mi, offset)| vec![(*mi).into(), (*offset).into()])
-            .collect();
         let cache_fix_params = LayerParams::new(
-            layer_params.len(), true, 2, cfv
+            layer_params.len(), true, 2, spline_params(&rmi)
         );
 
         layer_params.push(cache_fix_params);
@@ -904,8 +1252,11 @@ inline size_t FCLAMP(double inp, double bound) {{
             ModelDataType::Int128 => "i128pred",
             ModelDataType::Int512 => "i512pred"
     };
+    let lookup_key_type = if var_name != "f512pred" { key_type.c_type() } else { "cpp_bin_float_512" };
 
-    let lookup_sig = if report_last_layer_errors {
+    let lookup_sig = if report_range {
+        format!("uint64_t {}({} key, size_t* lo, size_t* hi)", rmi_lookup_name, key_type.c_type())
+    } else if report_last_layer_errors {
         if  var_name != "f512pred" { 
             format!("uint64_t {}({} key, size_t* err)", rmi_lookup_name, key_type.c_type())
         }
@@ -934,7 +1285,7 @@ inline size_t FCLAMP(double inp, double bound) {{
         match layer[0].output_type() {
             ModelDataType::Int => needed_vars.insert("uint64_t ipred;"),
             ModelDataType::Float => needed_vars.insert("double fpred;"),
-            ModelDataType::Float512 => needed_vars.insert("double f512pred;"),
+            ModelDataType::Float512 => needed_vars.insert("uint64_t f512pred;"),
             ModelDataType::Int128 => needed_vars.insert("__uint128_t i128pred;"),
             ModelDataType::Int512 => needed_vars.insert("uint512_t i512pred;"),
         };
@@ -1034,15 +1385,27 @@ inline size_t FCLAMP(double inp, double bound) {{
 
     writeln!(code_output, "{}", str::from_utf8(&report_lle).unwrap())?;
 
-    writeln!(
-        code_output,
-        "  return {};",
-        model_index_from_output!(last_model_output, rmi.num_rmi_rows, true)
-    )?; // always bounds check the last level
+    if report_range {
+        writeln!(
+            code_output,
+            "  uint64_t pos = {};",
+            model_index_from_output!(last_model_output, rmi.num_rmi_rows, true)
+        )?;
+        writeln!(code_output, "  *lo = (*lo > pos ? 0 : pos - *lo);")?;
+        writeln!(code_output, "  *hi = pos + *hi;")?;
+        writeln!(code_output, "  return pos;")?;
+    } else {
+        writeln!(
+            code_output,
+            "  return {};",
+            model_index_from_output!(last_model_output, rmi.num_rmi_rows, true)
+        )?; // always bounds check the last level
+    }
     writeln!(code_output, "}}")?;
 
     if rmi.cache_fix.is_some() {
-        generate_cache_fix_code(code_output, &rmi, array_name!(layer_params.len()-1))?;
+        generate_cache_fix_code(code_output, &rmi, lookup_key_type,
+                                array_name!(layer_params.len()-1))?;
     }
     
     writeln!(code_output, "}} // namespace")?;
@@ -1074,7 +1437,7 @@ inline size_t FCLAMP(double inp, double bound) {{
     if rmi.cache_fix.is_none() {
         writeln!(header_output, "{};", lookup_sig)?;
     } else {
-        writeln!(header_output, "uint64_t lookup(uint64_t key, size_t* err);")?;
+        writeln!(header_output, "uint64_t lookup({} key, size_t* err);", lookup_key_type)?;
     }
     writeln!(header_output, "}}")?;
 
@@ -1103,24 +1466,21 @@ fn generate_model(
                 lyr[0].params()) );
             
         }else if layer_idx == 1 && !rmi.third_layer_max_l1s.is_empty() {
-            layer_params.push( zip_errors(layer_idx, &rmi.third_layer_max_l1s , lyr) );
+            layer_params.push( zip_errors(layer_idx, &[&rmi.third_layer_max_l1s] , lyr) );
             //lyr.clear();
         }else if layer_idx == 1 && rmi.third_layer_max_l1s.is_empty() {
             // empty when 2-layer. (3-layer with empty third_layer -> does not pass here)
-            layer_params.push( zip_errors(layer_idx, &rmi.last_layer_max_l1s , lyr) );
+            layer_params.push( zip_errors(layer_idx, &leaf_errors(&rmi) , lyr) );
             //lyr.clear();
         }else if layer_idx == 2  {
-            layer_params.push( zip_errors(layer_idx, &rmi.last_layer_max_l1s , lyr) );
+            layer_params.push( zip_errors(layer_idx, &[&rmi.last_layer_max_l1s] , lyr) );
         }
     }
 
 
     if rmi.cache_fix.is_some() {
-        let cfv: Vec<ModelParam> = rmi.cache_fix.as_ref().unwrap().1.iter()
-            .flat_map(|(mi, offset)| vec![(*mi).into(), (*offset).into()])
-            .collect();
         let cache_fix_params = LayerParams::new(
-            layer_params.len(), true, 2, cfv
+            layer_params.len(), true, 2, spline_params(&rmi)
         );
 
         layer_params.push(cache_fix_params);
@@ -1156,6 +1516,44 @@ fn generate_model(
             }
         }
     }
+
+    // lower bound semantics are the default, and need no metadata. Otherwise,
+    // write the semantics followed by the (rank, length) of each duplicate run.
+    if rmi.duplicates != DuplicateSemantics::LowerBound {
+        let data_path = Path::new(&data_dir)
+            .join(format!("{}_DUPLICATES", namespace));
+        let f = File::create(data_path)
+            .expect("Could not write data file to RMI directory");
+        let mut bw = BufWriter::new(f);
+        bw.write_u64::<LittleEndian>(rmi.duplicates.code())?;
+        bw.write_u64::<LittleEndian>(rmi.run_lengths.len() as u64)?;
+        for (rank, length) in rmi.run_lengths.iter() {
+            bw.write_u64::<LittleEndian>(*rank)?;
+            bw.write_u64::<LittleEndian>(*length)?;
+        }
+    }
+
+    // leaves replaced by an exact search: the leaf index, the position of the
+    // first key and the number of keys, followed by the sorted keys (see
+    // ExactLeafModel).
+    let exact_leaves: Vec<(usize, u64, &ModelParam)> = rmi.rmi.last().unwrap().iter()
+        .enumerate()
+        .filter_map(|(idx, m)| m.exact_keys().map(|(first_pos, keys)| (idx, first_pos, keys)))
+        .collect();
+    if !exact_leaves.is_empty() {
+        let data_path = Path::new(&data_dir)
+            .join(format!("{}_EXACT_LEAVES", namespace));
+        let f = File::create(data_path)
+            .expect("Could not write data file to RMI directory");
+        let mut bw = BufWriter::new(f);
+        bw.write_u64::<LittleEndian>(exact_leaves.len() as u64)?;
+        for (idx, first_pos, keys) in exact_leaves {
+            bw.write_u64::<LittleEndian>(idx as u64)?;
+            bw.write_u64::<LittleEndian>(first_pos)?;
+            bw.write_u64::<LittleEndian>(keys.len() as u64)?;
+            keys.write_to(&mut bw)?;
+        }
+    }
     
     return Result::Ok(());
 }
@@ -1176,8 +1574,37 @@ pub fn output_rmi(namespace: &str,
     // let f3 = File::create(format!("{}.h", namespace)).expect("Could not write RMI header file");
     // let mut bw3 = BufWriter::new(f3);
 
+    // deduplicated RMIs predict ranks among the distinct keys, so they come
+    // with the lookup that turns those into rows
+    if trained_model.duplicates == DuplicateSemantics::Dedup {
+        let header_path = Path::new(&data_dir)
+            .join(format!("{}_duplicates.h", namespace));
+        let f = File::create(header_path)
+            .expect("Could not write duplicates header file to RMI directory");
+        let mut bw = BufWriter::new(f);
+        generate_duplicates_code(&mut bw, namespace, key_type.c_type(),
+                                 trained_model.num_rmi_rows as u64,
+                                 trained_model.num_data_rows as u64, include_errors)?;
+    }
+
+    // leaves searched exactly come with the lookup that searches them
+    let exact_keys_type = trained_model.rmi.last()
+        .and_then(|leaves| leaves.iter().find_map(|m| m.exact_keys()))
+        .map(|(_, keys)| keys.c_type());
+    if let Some(array_c_type) = exact_keys_type {
+        let header_path = Path::new(&data_dir)
+            .join(format!("{}_exact_leaves.h", namespace));
+        let f = File::create(header_path)
+            .expect("Could not write exact leaves header file to RMI directory");
+        let mut bw = BufWriter::new(f);
+        generate_exact_leaves_code(&mut bw, namespace, key_type.c_type(), array_c_type,
+                                   trained_model.num_rmi_rows as u64, include_errors)?;
+    }
+
     if !include_errors {
         trained_model.last_layer_max_l1s.clear();
+        trained_model.left_errors.clear();
+        trained_model.right_errors.clear();
     }
 
     // return generate_code(
@@ -1199,3 +1626,27 @@ pub fn output_rmi(namespace: &str,
         
     
 }
+
+// Writes the key encoding for byte string keys: the shared prefix as a raw
+// binary file ({namespace}_KEY_PREFIX) and a header ({namespace}_key.h) that
+// encodes (const char*, size_t) keys before calling the RMI.
+pub fn output_key_encoding(namespace: &str,
+                           data_dir: &str,
+                           shared_prefix: &[u8],
+                           num_keys: u64,
+                           include_errors: bool) -> Result<(), std::io::Error> {
+    let prefix_path = Path::new(&data_dir)
+        .join(format!("{}_KEY_PREFIX", namespace));
+    let f = File::create(prefix_path)
+        .expect("Could not write key prefix file to RMI directory");
+    let mut bw = BufWriter::new(f);
+    bw.write_all(shared_prefix)?;
+
+    let header_path = Path::new(&data_dir)
+        .join(format!("{}_key.h", namespace));
+    let f = File::create(header_path)
+        .expect("Could not write key header file to RMI directory");
+    let mut bw = BufWriter::new(f);
+    return generate_bytes_key_code(&mut bw, namespace, shared_prefix,
+                                   num_keys, include_errors);
+}
@@ -2,12 +2,19 @@ mod codegen;
 mod models;
 pub mod train;
 mod cache_fix;
+mod validate;
 
 pub mod optimizer;
 pub use models::{RMITrainingData, RMITrainingDataIteratorProvider, ModelInput};
-pub use models::KeyType;
-pub use models::U512;
+pub use models::{KeyType, DuplicateSemantics, QueryWeights, MemoryBudget};
+pub use models::{U512, F512, BytesKey, shared_prefix_len};
 pub use optimizer::find_pareto_efficient_configs;
-pub use train::{train, train_for_size, train_bounded, driver_validation };
+pub use train::{train, train_with_config, train_for_size, train_bounded, driver_validation };
+pub use train::TrainingConfig;
+pub use train::{retrain, KeyDelta};
+pub use train::{verify, Verification, Violation};
+pub use train::ErrorDistribution;
+pub use validate::{check_data, validate_data, DataReport, InvalidDataPolicy};
 pub use codegen::rmi_size;
-pub use codegen::output_rmi;
\ No newline at end of file
+pub use codegen::output_rmi;
+pub use codegen::output_key_encoding;
@@ -0,0 +1,168 @@
+// < begin copyright > 
+// Copyright Ryan Marcus 2020
+// 
+// See root directory of this project for license terms.
+// 
+// < end copyright > 
+ 
+
+use crate::models::*;
+
+// A leaf that answers lookups exactly with a binary search over a sorted
+// copy of its keys. It replaces leaves whose error is too large, and keeps
+// the model it replaced so that the layer keeps a uniform parameter layout:
+// the keys themselves are written to a separate file (see
+// `codegen::generate_model`), which the generated `{namespace}_exact_leaves.h`
+// searches before falling back to the RMI. The copy also holds the last key
+// before the leaf and the first key after it, so that a search can tell
+// which keys are routed to the leaf without evaluating the upper layers.
+pub struct ExactLeafModel {
+    fallback: Box<dyn Model>,
+    first_pos: u64,
+    keys: ModelParam,
+}
+
+impl ExactLeafModel {
+    // Builds an exact leaf over the rows [start, end) of the data, or returns
+    // None if the generated code cannot compare the keys (512-bit keys).
+    pub fn new<T: TrainingKey>(data: &RMITrainingData<T>, start: usize, end: usize,
+                               fallback: Box<dyn Model>) -> Option<ExactLeafModel> {
+        // the neighbouring rows, if there are any
+        let first = start.saturating_sub(1);
+        let last = usize::min(end + 1, data.len());
+        let inputs: Vec<ModelInput> = (first..last)
+            .map(|idx| data.get_key(idx).to_model_input())
+            .collect();
+
+        let keys = match T::key_type() {
+            KeyType::U32 | KeyType::U64 | KeyType::Bytes =>
+                ModelParam::IntArray(inputs.iter().map(|x| x.as_int()).collect()),
+            KeyType::U128 =>
+                ModelParam::Int128Array(inputs.iter().map(|x| x.as_int128()).collect()),
+            KeyType::F64 =>
+                ModelParam::FloatArray(inputs.iter().map(|x| x.as_float()).collect()),
+            KeyType::U512 | KeyType::F512 => return None,
+        };
+
+        return Some(ExactLeafModel { fallback, first_pos: first as u64, keys });
+    }
+
+    // number of keys in the copy that are smaller than the input
+    fn rank(&self, inp: &ModelInput) -> usize {
+        return match &self.keys {
+            ModelParam::IntArray(keys) => {
+                let x = inp.as_int();
+                keys.partition_point(|k| *k < x)
+            },
+            ModelParam::Int128Array(keys) => {
+                let x = inp.as_int128();
+                keys.partition_point(|k| *k < x)
+            },
+            ModelParam::FloatArray(keys) => {
+                let x = inp.as_float();
+                keys.partition_point(|k| *k < x)
+            },
+            _ => panic!("Exact leaf cannot compare its keys to input {:?}", inp),
+        };
+    }
+}
+
+impl Model for ExactLeafModel {
+    fn predict_to_int(&self, inp: &ModelInput) -> u64 {
+        return self.first_pos + self.rank(inp) as u64;
+    }
+
+    fn input_type(&self) -> ModelDataType {
+        return self.fallback.input_type();
+    }
+    fn output_type(&self) -> ModelDataType {
+        return self.fallback.output_type();
+    }
+
+    fn params(&self) -> Vec<ModelParam> {
+        return self.fallback.params();
+    }
+
+    fn code(&self) -> String {
+        return self.fallback.code();
+    }
+
+    fn function_name(&self) -> String {
+        return self.fallback.function_name();
+    }
+
+    fn standard_functions(&self) -> HashSet<StdFunctions> {
+        return self.fallback.standard_functions();
+    }
+
+    fn needs_bounds_check(&self) -> bool {
+        return self.fallback.needs_bounds_check();
+    }
+
+    fn error_bound(&self) -> Option<u64> {
+        return Some(0);
+    }
+
+    fn exact_keys(&self) -> Option<(u64, &ModelParam)> {
+        return Some((self.first_pos, &self.keys));
+    }
+
+    // the fallback is what the generated code evaluates, so it moves too
+    fn shift_positions(&mut self, offset: i64) -> bool {
+        let first_pos = match (self.first_pos as i64).checked_add(offset) {
+            Some(pos) if pos >= 0 => pos as u64,
+            _ => return false,
+        };
+        if !self.fallback.shift_positions(offset) {
+            return false;
+        }
+        self.first_pos = first_pos;
+        return true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exact_lower_bound() {
+        let data: Vec<(u64, usize)> = vec![(5, 0), (9, 1), (9, 1), (20, 3), (31, 4), (40, 5), (52, 6)];
+        let md = RMITrainingData::new(Box::new(data));
+        let leaf = ExactLeafModel::new(&md, 1, 5, Box::new(LinearModel::new(&md))).unwrap();
+        // the leaf's keys and one neighbour on each side
+        assert_eq!(leaf.keys.len(), 6);
+        assert_eq!(leaf.params().len(), 2);
+
+        assert_eq!(leaf.predict_to_int(&6u64.to_model_input()), 1);
+        assert_eq!(leaf.predict_to_int(&9u64.to_model_input()), 1);
+        assert_eq!(leaf.predict_to_int(&10u64.to_model_input()), 3);
+        assert_eq!(leaf.predict_to_int(&31u64.to_model_input()), 4);
+        assert_eq!(leaf.predict_to_int(&35u64.to_model_input()), 5);
+        assert_eq!(leaf.predict_to_int(&40u64.to_model_input()), 5);
+    }
+
+    #[test]
+    fn test_exact_wide_keys() {
+        let data: Vec<(u128, usize)> = (0..10u128).map(|i| ((i * 3) << 100, i as usize)).collect();
+        let md = RMITrainingData::new(Box::new(data));
+        let leaf = ExactLeafModel::new(&md, 2, 8, Box::new(LinearModel::new(&md))).unwrap();
+        assert_eq!(leaf.predict_to_int(&(10u128 << 100).to_model_input()), 4);
+
+        let data: Vec<(U512, usize)> = (0..10u64).map(|i| (U512::from(i) << 300, i as usize)).collect();
+        let md = RMITrainingData::new(Box::new(data));
+        assert!(ExactLeafModel::new(&md, 2, 8, Box::new(LinearModel::new(&md))).is_none());
+    }
+
+    #[test]
+    fn test_exact_shift() {
+        let data: Vec<(u64, usize)> = vec![(5, 0), (9, 1), (20, 2), (31, 3)];
+        let md = RMITrainingData::new(Box::new(data));
+        let mut leaf = ExactLeafModel::new(&md, 1, 3, Box::new(LinearModel::new(&md))).unwrap();
+        assert!(leaf.shift_positions(3));
+        assert_eq!(leaf.predict_to_int(&20u64.to_model_input()), 5);
+        assert_eq!(leaf.exact_keys().unwrap().0, 3);
+        assert!(!leaf.shift_positions(-5));
+        assert_eq!(leaf.predict_to_int(&20u64.to_model_input()), 5);
+    }
+}
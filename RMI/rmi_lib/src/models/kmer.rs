@@ -0,0 +1,201 @@
+// < begin copyright > 
+// Copyright Ryan Marcus 2020
+// 
+// See root directory of this project for license terms.
+// 
+// < end copyright > 
+ 
+
+use crate::models::*;
+use log::*;
+
+// A top model for 2-bit packed DNA keys, with the first base in the most
+// significant bits. It counts the keys starting with each k-mer (the top 2k
+// bits of the key), then walks the k-mers in order and assigns them to
+// leaves so that every leaf gets about as many keys as are left divided by
+// the leaves that are left. A k-mer that is more frequent than that gets a
+// leaf of its own, but does not take the keys of the following leaves with
+// it, unlike a fixed shift (`pwl`) of the key. The table has 4^k entries.
+pub struct KmerModel {
+    key_bits: u32,
+    k: u32,
+    table: Vec<u32>,
+}
+
+const MAX_KMER_LENGTH: u32 = 14;
+
+// the top 2k bits of a key of the given width (32-bit keys are 64-bit inputs)
+fn kmer_of(inp: &ModelInput, key_bits: u32, k: u32) -> usize {
+    let bits = 2 * k;
+    return match inp {
+        ModelInput::Int(x) => (*x >> (key_bits - bits)) as usize,
+        ModelInput::Int128(x) => (*x >> (128 - bits)) as usize,
+        ModelInput::UINT512(x) => (*x >> (512 - bits as usize)).low_u64() as usize,
+        _ => panic!("kmer models require 2-bit packed integer keys"),
+    };
+}
+
+impl KmerModel {
+    pub fn new<T: TrainingKey>(data: &RMITrainingData<T>, k: u32) -> KmerModel {
+        assert!(k >= 1 && k <= MAX_KMER_LENGTH, "k-mer length must be between 1 and {}", MAX_KMER_LENGTH);
+        let key_bits = match T::key_type() {
+            KeyType::U32 => 32,
+            KeyType::U64 => 64,
+            KeyType::U128 => 128,
+            KeyType::U512 => 512,
+            _ => panic!("Model type kmer needs 2-bit packed integer keys, not {} keys", T::key_type().c_type()),
+        };
+        if data.len() == 0 {
+            return KmerModel { key_bits, k, table: Vec::new() };
+        }
+
+        // the positions were scaled to the leaf indices
+        let num_leaves = data.get(data.len() - 1).1 as u64 + 1;
+
+        // count the keys of each k-mer, then overwrite each count with the
+        // leaf of its k-mer
+        let mut table = vec![0u32; 1 << (2 * k)];
+        for (key, _) in data.iter().take(data.len()) {
+            let idx = kmer_of(&key.to_model_input(), key_bits, k);
+            table[idx] = table[idx].saturating_add(1);
+        }
+
+        let mut leaf: u64 = 0;
+        let mut filled: u64 = 0;
+        let mut remaining = data.len() as u64;
+        let mut target = remaining as f64 / num_leaves as f64;
+        for entry in table.iter_mut() {
+            let count = *entry as u64;
+            // start a new leaf if this k-mer fits the next one better
+            if filled > 0 && leaf + 1 < num_leaves
+                && (filled as f64 + count as f64 / 2.0) > target {
+                leaf += 1;
+                filled = 0;
+                target = remaining as f64 / (num_leaves - leaf) as f64;
+            }
+            *entry = leaf as u32;
+            filled += count;
+            remaining -= count;
+        }
+
+        info!("{}-mer top model mapped {} k-mers to {} of {} leaves",
+              k, table.len(), leaf + 1, num_leaves);
+        return KmerModel { key_bits, k, table };
+    }
+}
+
+impl Model for KmerModel {
+    fn predict_to_int(&self, inp: &ModelInput) -> u64 {
+        if self.table.is_empty() {
+            return 0;
+        }
+        return self.table[kmer_of(inp, self.key_bits, self.k)] as u64;
+    }
+
+    fn input_type(&self) -> ModelDataType {
+        return match self.key_bits {
+            128 => ModelDataType::Int128,
+            512 => ModelDataType::Int512,
+            _ => ModelDataType::Int,
+        };
+    }
+    fn output_type(&self) -> ModelDataType {
+        return ModelDataType::Int;
+    }
+
+    fn params(&self) -> Vec<ModelParam> {
+        return vec![ModelParam::Int(self.k as u64), ModelParam::Int32Array(self.table.clone())];
+    }
+
+    fn code(&self) -> String {
+        return format!(
+            "
+inline uint64_t kmer(uint64_t k, const uint32_t table[], {} inp) {{
+    return table[static_cast<uint64_t>(inp >> ({} - 2 * k))];
+}}",
+            self.input_type().c_type(), self.key_bits
+        );
+    }
+
+    fn function_name(&self) -> String {
+        return String::from("kmer");
+    }
+
+    fn needs_bounds_check(&self) -> bool {
+        return false;
+    }
+    fn restriction(&self) -> ModelRestriction {
+        return ModelRestriction::MustBeTop;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // keys with their positions scaled to `leaves` leaves, as the top layer
+    // is trained
+    fn top_data<T: TrainingKey>(keys: Vec<T>, leaves: usize) -> RMITrainingData<T> {
+        let n = keys.len();
+        let data: Vec<(T, usize)> = keys.into_iter().enumerate()
+            .map(|(i, k)| (k, i * leaves / n))
+            .collect();
+        return RMITrainingData::new(Box::new(data));
+    }
+
+    fn leaf_sizes<T: TrainingKey>(model: &dyn Model, md: &RMITrainingData<T>, leaves: usize) -> Vec<usize> {
+        let mut sizes = vec![0; leaves];
+        let mut last = 0;
+        for (key, _) in md.iter().take(md.len()) {
+            let leaf = model.predict_to_int(&key.to_model_input());
+            assert!(leaf >= last);
+            last = leaf;
+            sizes[leaf as usize] += 1;
+        }
+        return sizes;
+    }
+
+    #[test]
+    fn test_kmer_skewed() {
+        // a quarter of the keys start with the same 8 bases (a repeat), the
+        // rest are spread evenly
+        let mut keys: Vec<u64> = (0..30000u64).map(|i| i.wrapping_mul(0x9E3779B97F4A7C15)).collect();
+        keys.extend((0..10000u64).map(|i| (0x1234u64 << 48) | (i << 8)));
+        keys.sort();
+        let md = top_data(keys, 64);
+
+        let model = KmerModel::new(&md, 8);
+        let sizes = leaf_sizes(&model, &md, 64);
+        assert_eq!(sizes.iter().sum::<usize>(), 40000);
+        // the repeat gets a leaf of its own, all other leaves stay balanced
+        let largest = *sizes.iter().max().unwrap();
+        assert!(largest >= 10000 && largest < 10010, "leaf sizes {:?}", sizes);
+        let others: Vec<usize> = sizes.iter().cloned().filter(|&s| s < 10000).collect();
+        assert!(others.iter().all(|&s| s < 1000), "leaf sizes {:?}", sizes);
+        assert!(others.len() >= 60);
+    }
+
+    #[test]
+    fn test_kmer_wide_keys() {
+        let keys: Vec<U512> = (0..4096u64).map(|i| U512::from(i) << 500).collect();
+        let md = top_data(keys, 16);
+        let model = KmerModel::new(&md, 6);
+        let sizes = leaf_sizes(&model, &md, 16);
+        assert!(sizes.iter().all(|&s| s == 256), "leaf sizes {:?}", sizes);
+    }
+
+    #[test]
+    fn test_kmer_u32_keys() {
+        let keys: Vec<u32> = (0..4096u32).map(|i| i << 20).collect();
+        let md = top_data(keys, 16);
+        let model = KmerModel::new(&md, 6);
+        let sizes = leaf_sizes(&model, &md, 16);
+        assert!(sizes.iter().all(|&s| s == 256), "leaf sizes {:?}", sizes);
+    }
+
+    #[test]
+    fn test_kmer_empty() {
+        let model = KmerModel::new(&RMITrainingData::<u64>::empty(), 14);
+        assert_eq!(model.predict_to_int(&5u64.to_model_input()), 0);
+    }
+}
@@ -15,6 +15,12 @@ use rug::{
 };
 
 fn slr<T: Iterator<Item = (f64, f64)>>(loc_data: T) -> (f64, f64) {
+    return slr_weighted(loc_data.map(|(x, y)| (x, y, 1.0)));
+}
+
+// Weighted least squares over (x, y, weight) triples. With unit weights the
+// arithmetic is exactly that of an unweighted regression.
+fn slr_weighted<T: Iterator<Item = (f64, f64, f64)>>(loc_data: T) -> (f64, f64) {
 
     // compute the covariance of x and y as well as the variance of x in
     // a single pass.
@@ -23,21 +29,23 @@ fn slr<T: Iterator<Item = (f64, f64)>>(loc_data: T) -> (f64, f64) {
     let mut mean_y = 0.0;
     let mut c = 0.0;
     let mut n: u64 = 0;
+    let mut sum_w = 0.0;
     let mut m2 = 0.0;
 
     let mut data_size = 0;
-    for (x, y) in loc_data {    
+    for (x, y, w) in loc_data {    
         // println!("model X={} Y={}", x, y);
         n += 1;
+        sum_w += w;
         let dx = x - mean_x;
         //info!("dx={}", dx);
-        mean_x += dx / (n as f64);
+        mean_x += w * dx / sum_w;
         //info!("{} mean_x={}",n, mean_x );
-        mean_y += (y - mean_y) / (n as f64);
-        c += dx * (y - mean_y);
+        mean_y += w * (y - mean_y) / sum_w;
+        c += w * dx * (y - mean_y);
 
         let dx2 = x - mean_x;
-        m2 += dx * dx2;
+        m2 += w * dx * dx2;
         // if n == 1000000 {
         //     info!("{} mean_x={}",n, mean_x );
         //     info!("{} dx2={}",n, dx2 );
@@ -73,15 +81,15 @@ fn slr<T: Iterator<Item = (f64, f64)>>(loc_data: T) -> (f64, f64) {
 
 fn loglinear_slr<T: TrainingKey>(data: &RMITrainingData<T>) -> (f64, f64) {
     // log all of the outputs, omit any item that doesn't have a valid log
-    let transformed_data: Vec<(f64, f64)> = data
-        .iter()
-        .map(|(x, y)| (x.as_float(), (y as f64).ln()))
-        .filter(|(_, y)| y.is_finite())
+    let transformed_data: Vec<(f64, f64, f64)> = data
+        .iter_weighted()
+        .map(|(x, y, w)| (x.as_float(), (y as f64).ln(), w))
+        .filter(|(_, y, _)| y.is_finite())
         .collect();
 
     // TODO this currently creates a copy of the data and then calls
     // slr... we can probably do better by moving the log into the slr.
-    return slr(transformed_data.into_iter());
+    return slr_weighted(transformed_data.into_iter());
 }
 
 pub struct LinearModel {
@@ -90,10 +98,19 @@ pub struct LinearModel {
 
 impl LinearModel {
     pub fn new<T: TrainingKey>(data: &RMITrainingData<T>) -> LinearModel {
-        let params = slr(data.iter()
-                         .map(|(inp, offset)| (inp.as_float(), offset as f64)));
+        let params = slr_weighted(data.iter_weighted()
+                                  .map(|(inp, offset, w)| (inp.as_float(), offset as f64, w)));
         return LinearModel { params };
     }
+
+    // rebuilds a model from its params(), e.g. from a training checkpoint
+    pub fn from_params(params: &[ModelParam]) -> Option<LinearModel> {
+        return match params {
+            [ModelParam::Float(intercept), ModelParam::Float(slope)] =>
+                Some(LinearModel { params: (*intercept, *slope) }),
+            _ => None,
+        };
+    }
 }
 
 impl Model for LinearModel {
@@ -130,6 +147,11 @@ inline double linear(double alpha, double beta, double inp) {
         self.params = (constant as f64, 0.0);
         return true;
     }
+
+    fn shift_positions(&mut self, offset: i64) -> bool {
+        self.params.0 += offset as f64;
+        return true;
+    }
 }
 
 #[cfg(test)]
@@ -165,7 +187,8 @@ mod tests {
 
 
 
-fn slr_big<T: Iterator<Item = (Float, Float)>>(loc_data: T) -> (Float, Float) {
+// 512-bit version of slr_weighted, with the same exactness for unit weights.
+fn slr_big_weighted<T: Iterator<Item = (Float, Float, f64)>>(loc_data: T) -> (Float, Float) {
 
     // compute the covariance of x and y as well as the variance of x in
     // a single pass.
@@ -174,39 +197,41 @@ fn slr_big<T: Iterator<Item = (Float, Float)>>(loc_data: T) -> (Float, Float) {
     let mut mean_y = Float::with_val(512,0.0);
     let mut c = Float::with_val(512,0.0);
     let mut n: u64 = 0;
+    let mut sum_w = Float::with_val(512,0.0);
     let mut m2 = Float::with_val(512,0.0);
 
     let mut data_size: u64 = 0;
-    for (x, y) in loc_data {
+    for (x, y, w) in loc_data {
         n += 1;
+        sum_w += w;
         //let dx = x - mean_x;
         //info!("model X={} Y={}", x.to_string_radix(10, None), y.to_string_radix(10, None));
         let dx = Float::with_val(512, &x - &mean_x);
         //info!("dx={}", dx.to_string_radix(10, None));
-        let tmp_n = Float::with_val(512,n);
         let mut recip_tmp = Float::new(512);
-        recip_tmp.assign_round(tmp_n.recip_ref(), Round::Nearest);
+        recip_tmp.assign_round(sum_w.recip_ref(), Round::Nearest);
+        let weighted_dx = Float::with_val(512, &dx * w);
         //mean_x += dx / (n as f64);
-        let div = Float::with_val(512,&dx * &recip_tmp );
+        let div = Float::with_val(512,&weighted_dx * &recip_tmp );
         
 
         mean_x.add_assign_round( div, Round::Nearest);
         // info!("{} mean_x={}",n, mean_x.to_string_radix(10, None));
         //mean_y += (y - mean_y) / (n as f64);
-        let minus = Float::with_val(512, &y - &mean_y );
+        let minus = Float::with_val(512, Float::with_val(512, &y - &mean_y) * w);
         // let mut recipTmp = Float::new(512);
         let div = Float::with_val(512, &minus * &recip_tmp);
         mean_y.add_assign_round(div, Round::Nearest);
 
         //c += dx * (y - mean_y);
         let minus = Float::with_val(512, &y - &mean_y );
-        c.add_assign_round(&dx * &minus, Round::Nearest);
+        c.add_assign_round(&weighted_dx * &minus, Round::Nearest);
 
         //let dx2 = x - mean_x;
         let dx2 = Float::with_val(512,&x - &mean_x);
         
         //m2 += dx * dx2;
-        m2.add_assign_round(&dx * &dx2, Round::Nearest);
+        m2.add_assign_round(&weighted_dx * &dx2, Round::Nearest);
         // if n == 1000000 {
         //     info!("{} mean_x={}",n, mean_x.to_string_radix(10, None));
         //     info!("{} dx2={}",n, dx2.to_string_radix(10, None));
@@ -251,22 +276,251 @@ fn slr_big<T: Iterator<Item = (Float, Float)>>(loc_data: T) -> (Float, Float) {
     return (alpha, beta);
 }
 
+// A linear model over integer keys with a fixed-point slope, evaluated
+// with integer multiply-shifts so that the predictions made during training
+// are exactly the ones made by the generated code:
+//   pred = intercept + ((min((x - xmin) >> shift, 2^64 - 1) * slope) >> frac)
+// `shift` brings the key range down to 64 bits, and `frac` is the number of
+// fractional bits of the slope (64 for slopes below one).
+pub struct LinearFixedModel {
+    key_bits: u32,
+    xmin: u128,
+    shift: u32,
+    slope: u64,
+    frac: u32,
+    intercept: i64,
+}
+
+fn fixed_key(inp: &ModelInput) -> u128 {
+    return match inp {
+        ModelInput::Int(x) => *x as u128,
+        ModelInput::Int128(x) => *x,
+        _ => panic!("linear_fixed models require integer keys of at most 128 bits"),
+    };
+}
+
+impl LinearFixedModel {
+    pub fn new<T: TrainingKey>(data: &RMITrainingData<T>) -> LinearFixedModel {
+        let key_bits = match T::key_type() {
+            KeyType::U128 => 128,
+            _ => 64,
+        };
+        let mut model = LinearFixedModel { key_bits, xmin: 0, shift: 0, slope: 0, frac: 64,
+                                           intercept: 0 };
+        if data.len() == 0 {
+            return model;
+        }
+
+        model.xmin = fixed_key(&data.get(0).0.to_model_input());
+        let range = fixed_key(&data.get(data.len() - 1).0.to_model_input()) - model.xmin;
+        model.shift = (128 - range.leading_zeros()).saturating_sub(64);
+
+        let (alpha, beta) = slr_weighted(data.iter_weighted().map(|(inp, offset, w)| {
+            let dx = (fixed_key(&inp.to_model_input()) - model.xmin) >> model.shift;
+            (dx as f64, offset as f64, w)
+        }));
+
+        if beta > 0.0 && beta.is_finite() {
+            let int_bits = if beta < 1.0 { 0 } else { beta.log2().floor() as u32 + 1 };
+            model.frac = 64 - u32::min(int_bits, 64);
+            model.slope = f64::min((beta * 2f64.powi(model.frac as i32)).round(),
+                                   std::u64::MAX as f64) as u64;
+        }
+        model.intercept = alpha.round() as i64;
+        return model;
+    }
+}
+
+impl Model for LinearFixedModel {
+    fn predict_to_int(&self, inp: &ModelInput) -> u64 {
+        let dx = fixed_key(inp).saturating_sub(self.xmin) >> self.shift;
+        let dx = u128::min(dx, std::u64::MAX as u128);
+        let delta = (dx * self.slope as u128) >> self.frac;
+        let pred = if self.intercept >= 0 {
+            delta.saturating_add(self.intercept as u128)
+        } else {
+            delta.saturating_sub(self.intercept.unsigned_abs() as u128)
+        };
+        return u128::min(pred, std::u64::MAX as u128) as u64;
+    }
+
+    fn input_type(&self) -> ModelDataType {
+        return if self.key_bits == 128 { ModelDataType::Int128 } else { ModelDataType::Int };
+    }
+    fn output_type(&self) -> ModelDataType {
+        return ModelDataType::Int;
+    }
+
+    fn params(&self) -> Vec<ModelParam> {
+        let xmin = if self.key_bits == 128 {
+            ModelParam::Int128(self.xmin)
+        } else {
+            ModelParam::Int(self.xmin as u64)
+        };
+        // the intercept is stored in two's complement
+        return vec![xmin, ModelParam::Int(self.shift as u64), ModelParam::Int(self.slope),
+                    ModelParam::Int(self.frac as u64), ModelParam::Int(self.intercept as u64)];
+    }
+
+    fn code(&self) -> String {
+        return format!(
+            "
+inline uint64_t linear_fixed({kt} xmin, uint64_t shift, uint64_t slope, uint64_t frac,
+                             uint64_t intercept, {kt} inp) {{
+    __uint128_t dx = inp > xmin ? ((__uint128_t) (inp - xmin)) >> shift : 0;
+    if (dx > UINT64_MAX) dx = UINT64_MAX;
+    __uint128_t delta = (dx * slope) >> frac;
+    int64_t icpt = (int64_t) intercept;
+    __uint128_t pred;
+    if (icpt >= 0) {{
+        pred = delta + (uint64_t) icpt;
+        if (pred < delta) pred = ~((__uint128_t) 0);
+    }} else {{
+        uint64_t neg = ~((uint64_t) icpt) + 1;
+        pred = delta > neg ? delta - neg : 0;
+    }}
+    return pred > UINT64_MAX ? UINT64_MAX : (uint64_t) pred;
+}}",
+            kt = self.input_type().c_type()
+        );
+    }
+
+    fn function_name(&self) -> String {
+        return String::from("linear_fixed");
+    }
+
+    fn set_to_constant_model(&mut self, constant: u64) -> bool {
+        self.slope = 0;
+        self.intercept = u64::min(constant, std::i64::MAX as u64) as i64;
+        return true;
+    }
+
+    fn shift_positions(&mut self, offset: i64) -> bool {
+        return match self.intercept.checked_add(offset) {
+            Some(intercept) => { self.intercept = intercept; true },
+            None => false,
+        };
+    }
+}
+
+#[cfg(test)]
+mod slr_weighted_tests {
+    use super::*;
+
+    #[test]
+    fn test_slr_weighted() {
+        let pts: Vec<(f64, f64)> = (0..100).map(|i| (i as f64, (i * i) as f64)).collect();
+
+        // unit weights reproduce the unweighted fit exactly
+        let unit = slr_weighted(pts.iter().map(|&(x, y)| (x, y, 1.0)));
+        let plain = slr(pts.iter().copied());
+        assert_eq!(unit.0.to_bits(), plain.0.to_bits());
+        assert_eq!(unit.1.to_bits(), plain.1.to_bits());
+
+        // a heavily weighted range is fit more closely
+        let weighted = slr_weighted(pts.iter().map(|&(x, y)| (x, y, if x >= 80.0 { 1000.0 } else { 1.0 })));
+        let err = |(a, b): (f64, f64)| (80..100)
+            .map(|i| (b.mul_add(i as f64, a) - (i * i) as f64).abs())
+            .fold(0.0, f64::max);
+        assert!(err(weighted) < err(plain) / 4.0);
+
+        // integer weights count like repeated points
+        let repeated = slr(pts.iter().flat_map(|&(x, y)| vec![(x, y); if x < 10.0 { 3 } else { 1 }]));
+        let counted = slr_weighted(pts.iter().map(|&(x, y)| (x, y, if x < 10.0 { 3.0 } else { 1.0 })));
+        assert!((repeated.1 - counted.1).abs() < 1e-9 * repeated.1.abs());
+        assert!((repeated.0 - counted.0).abs() < 1e-6 * repeated.0.abs());
+
+        let big = slr_big_weighted(pts.iter().map(|&(x, y)| (Float::with_val(512, x), Float::with_val(512, y),
+                                                              if x >= 80.0 { 1000.0 } else { 1.0 })));
+        assert!((big.1.to_f64() - weighted.1).abs() < 1e-9 * weighted.1);
+    }
+}
+
+#[cfg(test)]
+mod linear_fixed_tests {
+    use super::*;
+
+    fn check<T: TrainingKey>(keys: Vec<T>) -> (LinearFixedModel, u64) {
+        let data: Vec<(T, usize)> = keys.into_iter().enumerate().map(|(i, k)| (k, i)).collect();
+        let md = RMITrainingData::new(Box::new(data));
+        let model = LinearFixedModel::new(&md);
+        let mut last = 0;
+        let mut max_err = 0;
+        for (key, pos) in md.iter().take(md.len()) {
+            let pred = model.predict_to_int(&key.to_model_input());
+            assert!(pred >= last);
+            last = pred;
+            max_err = u64::max(max_err, u64::max(pred, pos as u64) - u64::min(pred, pos as u64));
+        }
+        return (model, max_err);
+    }
+
+    #[test]
+    fn test_linear_fixed_u64() {
+        // near the top of the key space, where an f64 slope and intercept
+        // cannot resolve single keys
+        let (model, err) = check((0..5000u64).map(|i| (1 << 63) + 3 * i).collect());
+        assert_eq!(model.frac, 64);
+        assert!(err <= 1, "error {}", err);
+
+        // dense keys need a slope above one
+        let (model, err) = check((0..5000u64).map(|i| i / 4).collect());
+        assert!(model.frac < 64);
+        assert!(err <= 3, "error {}", err);
+    }
+
+    #[test]
+    fn test_linear_fixed_u128() {
+        let (model, err) = check((0..5000u128).map(|i| (7 << 100) + (i << 70)).collect());
+        assert!(model.shift > 0);
+        assert_eq!(model.params().len(), 5);
+        assert!(err <= 1, "error {}", err);
+    }
+
+    #[test]
+    fn test_linear_fixed_constant() {
+        let mut model = LinearFixedModel::new(&RMITrainingData::<u64>::empty());
+        assert_eq!(model.predict_to_int(&5u64.to_model_input()), 0);
+        assert!(model.set_to_constant_model(42));
+        assert_eq!(model.predict_to_int(&(1u64 << 60).to_model_input()), 42);
+    }
+}
+
 pub struct LinearModelBig {
     params: (Float, Float),
 }
 impl LinearModelBig {
     pub fn new<T: TrainingKey>(data: &RMITrainingData<T>) -> LinearModelBig {
-        let params = slr_big(data.iter()
-                         .map(|(inp, offset)| (inp.as_float512(), Float::with_val(512,offset) )));
+        let params = slr_big_weighted(data.iter_weighted()
+                         .map(|(inp, offset, w)| (inp.as_float512(), Float::with_val(512,offset), w)));
         return LinearModelBig { params };
     }
+
+    // rebuilds a model from its params(), e.g. from a training checkpoint
+    pub fn from_params(params: &[ModelParam]) -> Option<LinearModelBig> {
+        return match params {
+            [ModelParam::Float512(intercept), ModelParam::Float512(slope)] =>
+                Some(LinearModelBig { params: (intercept.clone(), slope.clone()) }),
+            _ => None,
+        };
+    }
 }
 
-impl Model for LinearModelBig {
-    fn predict_to_float(&self, inp: &ModelInput) -> f64 {
+impl ModelBig for LinearModelBig {
+    fn predict_to_float(&self, inp: &ModelInput) -> Float {
         let (intercept, slope) = &self.params;
         let result = Float::with_val(512,&(inp.as_float512()) *slope);
-        return Float::with_val(512, result + intercept).to_f64();
+        return Float::with_val(512, result + intercept);
+    }
+}
+
+impl Model for LinearModelBig {
+    fn predict_to_float(&self, inp: &ModelInput) -> f64 {
+        return ModelBig::predict_to_float(self, inp).to_f64();
+    }
+
+    fn predict_to_int(&self, inp: &ModelInput) -> u64 {
+        return ModelBig::predict_to_int(self, inp);
     }
 
     fn input_type(&self) -> ModelDataType {
@@ -283,8 +537,12 @@ impl Model for LinearModelBig {
     fn code(&self) -> String {
         return String::from(
             "
-inline double linear(cpp_bin_float_512 alpha, cpp_bin_float_512 beta, cpp_bin_float_512 inp) {
-    return boost::multiprecision::fma(beta, inp, alpha).convert_to<double>();
+inline uint64_t linear(cpp_bin_float_512 alpha, cpp_bin_float_512 beta, cpp_bin_float_512 inp) {
+    // floor and clamp in 512 bits, a double cannot tell apart positions above 2^53
+    cpp_bin_float_512 pred = boost::multiprecision::floor(boost::multiprecision::fma(beta, inp, alpha));
+    if (pred < 0) return 0;
+    if (pred > cpp_bin_float_512(UINT64_MAX)) return UINT64_MAX;
+    return pred.convert_to<uint64_t>();
 }",
         );
     }
@@ -297,6 +555,39 @@ inline double linear(cpp_bin_float_512 alpha, cpp_bin_float_512 beta, cpp_bin_fl
         self.params = (Float::with_val(512,constant), Float::with_val(512,0) );
         return true;
     }
+
+    fn shift_positions(&mut self, offset: i64) -> bool {
+        self.params.0 += offset;
+        return true;
+    }
+}
+
+#[cfg(test)]
+mod linear_big_tests {
+    use super::*;
+
+    #[test]
+    fn test_linear_big_beyond_f64() {
+        // consecutive keys above 2^60 are not distinct as f64
+        let base: u64 = (1 << 60) + 17;
+        let data: Vec<(u64, usize)> = (0..64u64).map(|i| (base + i, i as usize)).collect();
+        let md = RMITrainingData::new(Box::new(data.clone()));
+        let lin_mod = LinearModelBig::new(&md);
+
+        for (key, pos) in data {
+            let inp = key.to_model_input();
+            assert_eq!(Model::predict_to_int(&lin_mod, &inp), pos as u64);
+            let exact = ModelBig::predict_to_float(&lin_mod, &inp);
+            assert!((exact - pos as f64).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn test_linear_big_constant() {
+        let mut lin_mod = LinearModelBig::new(&RMITrainingData::<u64>::empty());
+        assert!(lin_mod.set_to_constant_model(12));
+        assert_eq!(Model::predict_to_int(&lin_mod, &5u64.to_model_input()), 12);
+    }
 }
 
 pub struct LogLinearModel {
@@ -444,4 +735,9 @@ inline double linear(double alpha, double beta, double inp) {
         self.params = (constant as f64, 0.0);
         return true;
     }
+
+    fn shift_positions(&mut self, offset: i64) -> bool {
+        self.params.0 += offset as f64;
+        return true;
+    }
 }
@@ -8,41 +8,55 @@
 
 mod balanced_radix;
 mod cubic_spline;
+mod exact;
 mod histogram;
+mod kmer;
 mod linear;
 mod piecewiselinear;
+mod polynomial;
 mod linear_spline;
+mod nn;
+mod pchip;
 mod normal;
 mod radix;
+mod radix_spline;
 mod stdlib;
 mod utils;
 
 pub use balanced_radix::BalancedRadixModel;
 pub use cubic_spline::CubicSplineModel;
+pub use exact::ExactLeafModel;
 pub use histogram::EquidepthHistogramModel;
+pub use kmer::KmerModel;
 pub use linear::LinearModel;
 pub use linear::LinearModelBig;
+pub use linear::LinearFixedModel;
 pub use linear::RobustLinearModel;
 pub use linear::LogLinearModel;
 pub use linear_spline::LinearSplineModel;
+pub use nn::NNModel;
+pub use pchip::PchipModel;
 
 pub use piecewiselinear::PiecewiselinearModel;
 pub use piecewiselinear::PiecewiselinearModel_partial;
+pub use polynomial::PolynomialModel;
 
 pub use normal::LogNormalModel;
 pub use normal::NormalModel;
 pub use radix::RadixModel;
 pub use radix::RadixTable;
+pub use radix_spline::RadixSplineModel;
 pub use stdlib::StdFunctions;
 
 use std::cmp::Ordering;
 use std::collections::HashSet;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
 use std::io::Write;
 use byteorder::{WriteBytesExt, LittleEndian};
 
 use rug::{
-    float::{self, FreeCache, Round},
+    float::{self, FreeCache},
     ops::{AddAssignRound, AssignRound, MulAssignRound},
     Float, Assign, Integer, integer::Order
 };
@@ -58,7 +72,7 @@ construct_uint! {
 
 #[derive(Clone, Copy)]
 pub enum KeyType {
-    U32, U64, F64, U128, U512, F512
+    U32, U64, F64, U128, U512, F512, Bytes
 }
 
 impl KeyType {
@@ -69,7 +83,9 @@ impl KeyType {
             KeyType::F64 => "double",
             KeyType::F512 => "cpp_bin_float_512",
             KeyType::U128 => "__uint128_t",
-            KeyType::U512 => "uint512_t"
+            KeyType::U512 => "uint512_t",
+            // byte string keys are encoded before they reach the models
+            KeyType::Bytes => "uint64_t"
         }
     }
 
@@ -80,7 +96,8 @@ impl KeyType {
             KeyType::U128 => ModelDataType::Int128,
             KeyType::U512 => ModelDataType::Int512,
             KeyType::F64 => ModelDataType::Float,
-            KeyType::F512 =>ModelDataType::Float512
+            KeyType::F512 =>ModelDataType::Float512,
+            KeyType::Bytes => ModelDataType::Int
         }
     }
 }
@@ -109,6 +126,148 @@ impl KeyType {
 #[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
 pub struct uint512(pub [u64; 8]);
 
+// A 512-bit float key. rug::Float is not Copy, so keys are stored in the
+// same layout used for Float512 parameters (see ModelParam::write_to):
+// 32 mantissa words (most significant first), the binary exponent of the
+// leading mantissa bit, and a sign flag. The value is
+// mantissa * 2^(exponent - 511), negated if sign == 1. Zero is all zeros.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct F512 {
+    mantissa: [u16; 32],
+    exponent: i16,
+    sign: u16
+}
+
+impl F512 {
+    pub fn zero() -> F512 {
+        return F512 { mantissa: [0; 32], exponent: 0, sign: 0 };
+    }
+
+    pub fn from_float(f: &Float) -> F512 {
+        let f = Float::with_val(512, f);
+        let (mut int, mut exp) = match f.to_integer_exp() {
+            None => panic!("Cannot represent {} as a 512-bit float key", f),
+            Some(v) => v
+        };
+        if int == 0 {
+            return F512::zero();
+        }
+
+        let sign = if int < 0 { 1 } else { 0 };
+        int.abs_mut();
+
+        // normalize so that the leading mantissa bit is bit 511
+        let shift = 512 - int.significant_bits() as i32;
+        int <<= shift as u32;
+        exp -= shift;
+
+        // saturate values outside of the exponent range (e.g., the next
+        // float above zero) to the smallest or largest representable key
+        let exponent = exp + 511;
+        if exponent < std::i16::MIN as i32 {
+            let mut mantissa = [0u16; 32];
+            mantissa[0] = 0x8000;
+            return F512 { mantissa, exponent: std::i16::MIN, sign };
+        }
+        if exponent > std::i16::MAX as i32 {
+            return F512 { mantissa: [std::u16::MAX; 32], exponent: std::i16::MAX, sign };
+        }
+
+        let digits = int.to_digits::<u16>(Order::Msf);
+        assert_eq!(digits.len(), 32);
+        let mut mantissa = [0u16; 32];
+        mantissa.copy_from_slice(&digits);
+
+        return F512 { mantissa, exponent: exponent as i16, sign };
+    }
+
+    pub fn to_float(&self) -> Float {
+        let int = Integer::from_digits(&self.mantissa, Order::Msf);
+        let mut result = Float::with_val(512, int);
+        if result == 0 {
+            return result;
+        }
+
+        result <<= self.exponent as i32 - 511;
+        if self.sign == 1 {
+            result = -result;
+        }
+        return result;
+    }
+
+    // reads a key in the on-disk layout: 32 little-endian u16 mantissa words,
+    // a little-endian i16 exponent and a little-endian u16 sign (68 bytes).
+    pub fn from_le_bytes(bytes: &[u8]) -> F512 {
+        assert_eq!(bytes.len(), 68);
+        let mut mantissa = [0u16; 32];
+        for (i, word) in mantissa.iter_mut().enumerate() {
+            *word = u16::from_le_bytes([bytes[2 * i], bytes[2 * i + 1]]);
+        }
+        let exponent = i16::from_le_bytes([bytes[64], bytes[65]]);
+        let sign = u16::from_le_bytes([bytes[66], bytes[67]]);
+        return F512 { mantissa, exponent, sign };
+    }
+}
+
+// A variable-length byte string key (read names, k-mer strings, ...).
+// Models only see an order-preserving encoding of the string: the 8 bytes
+// following the prefix shared by every key in the data set, read as a
+// big-endian integer and zero-padded. Strings that differ only after those
+// bytes map to the same encoding; they are told apart by length and hash,
+// and LowerBoundCorrection treats them as a run (see same_encoding).
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct BytesKey {
+    encoded: u64,
+    len: u32,
+    skip: u32,
+    hash: u64
+}
+
+impl BytesKey {
+    // `skip` is the length of the prefix shared by all keys.
+    pub fn new(bytes: &[u8], skip: usize) -> BytesKey {
+        return BytesKey {
+            encoded: BytesKey::encode(bytes, skip),
+            len: bytes.len() as u32,
+            skip: skip as u32,
+            hash: BytesKey::fnv1a(bytes)
+        };
+    }
+
+    pub fn encode(bytes: &[u8], skip: usize) -> u64 {
+        let mut encoded = 0;
+        for i in skip..skip + 8 {
+            encoded <<= 8;
+            encoded |= *bytes.get(i).unwrap_or(&0) as u64;
+        }
+        return encoded;
+    }
+
+    pub fn encoded(&self) -> u64 { self.encoded }
+
+    // probes built from an encoded value alone (used for lower bound
+    // correction) do not correspond to any real string
+    fn probe(encoded: u64) -> BytesKey {
+        return BytesKey { encoded, len: 0, skip: 0, hash: 0 };
+    }
+
+    fn fnv1a(bytes: &[u8]) -> u64 {
+        let mut hash: u64 = 0xcbf29ce484222325;
+        for b in bytes {
+            hash ^= *b as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+        return hash;
+    }
+}
+
+// length of the prefix shared by every key of a sorted set of strings,
+// which is the common prefix of the first and last key.
+pub fn shared_prefix_len(first: &[u8], last: &[u8]) -> usize {
+    return first.iter().zip(last.iter())
+        .take_while(|(a, b)| a == b)
+        .count();
+}
 
 pub trait TrainingKey: PartialEq + Copy + Send + Sync + std::fmt::Debug + 'static {
     fn minus_epsilon(&self) -> Self;
@@ -122,6 +281,21 @@ pub trait TrainingKey: PartialEq + Copy + Send + Sync + std::fmt::Debug + 'stati
     fn as_uint128(&self) -> u128;
     
     fn to_model_input(&self) -> ModelInput;
+    fn key_type() -> KeyType;
+
+    // true if the models cannot tell the two keys apart
+    fn same_encoding(&self, other: &Self) -> bool { self == other }
+
+    // the key as it is shown to the user
+    fn to_key_string(&self) -> String {
+        return match self.to_model_input() {
+            ModelInput::Int(x) => x.to_string(),
+            ModelInput::Int128(x) => x.to_string(),
+            ModelInput::Float(x) => x.to_string(),
+            ModelInput::UINT512(x) => x.to_string(),
+            ModelInput::Float512(x) => x.to_float().to_string()
+        };
+    }
 }
 
 impl TrainingKey for u64 {
@@ -136,6 +310,7 @@ impl TrainingKey for u64 {
     fn as_uint128(&self) -> u128 { *self as u128 }
     
     fn to_model_input(&self) -> ModelInput { (*self).into() }
+    fn key_type() -> KeyType { KeyType::U64 }
 }
 
 impl TrainingKey for u128 {
@@ -150,6 +325,7 @@ impl TrainingKey for u128 {
     fn as_uint128(&self) -> u128 { *self  }
     
     fn to_model_input(&self) -> ModelInput { (*self).into() }
+    fn key_type() -> KeyType { KeyType::U128 }
 }
 
 impl TrainingKey for u32 {
@@ -164,6 +340,7 @@ impl TrainingKey for u32 {
     fn as_uint128(&self) -> u128 { *self as u128 }
 
     fn to_model_input(&self) -> ModelInput { (*self).into() }
+    fn key_type() -> KeyType { KeyType::U32 }
 }
 
 impl TrainingKey for U512 {
@@ -187,6 +364,7 @@ impl TrainingKey for U512 {
     fn as_uint128(&self) -> u128 { assert!(false);(*self).low_u64() as u128 }
 
     fn to_model_input(&self) -> ModelInput { (*self).into() }
+    fn key_type() -> KeyType { KeyType::U512 }
 }
 
 // impl TrainingKey for String {
@@ -214,6 +392,64 @@ impl TrainingKey for f64 {
     fn as_uint128(&self) -> u128 { *self as u128 }
     
     fn to_model_input(&self) -> ModelInput { (*self).into() }
+    fn key_type() -> KeyType { KeyType::F64 }
+}
+
+impl TrainingKey for F512 {
+    fn minus_epsilon(&self) -> Self {
+        let mut f = self.to_float();
+        f.next_down();
+        F512::from_float(&f)
+    }
+    fn zero_value() -> Self { F512::zero() }
+    fn plus_epsilon(&self) -> Self {
+        let mut f = self.to_float();
+        f.next_up();
+        F512::from_float(&f)
+    }
+    fn max_value() -> Self {
+        F512 { mantissa: [std::u16::MAX; 32], exponent: std::i16::MAX, sign: 0 }
+    }
+
+    fn as_float(&self) -> f64 { self.to_float().to_f64() }
+    fn as_float512(&self) -> Float { self.to_float() }
+    fn as_uint(&self) -> u64 { self.as_float() as u64 }
+    fn as_uint128(&self) -> u128 { self.as_float() as u128 }
+
+    fn to_model_input(&self) -> ModelInput { (*self).into() }
+    fn key_type() -> KeyType { KeyType::F512 }
+}
+
+impl TrainingKey for BytesKey {
+    fn minus_epsilon(&self) -> Self { BytesKey::probe(self.encoded.saturating_sub(1)) }
+    fn zero_value() -> Self { BytesKey::probe(0) }
+    fn plus_epsilon(&self) -> Self { BytesKey::probe(self.encoded.saturating_add(1)) }
+    fn max_value() -> Self { BytesKey::probe(std::u64::MAX) }
+
+    fn as_float(&self) -> f64 { self.encoded as f64 }
+    fn as_float512(&self) -> Float { Float::with_val(512, self.encoded) }
+    fn as_uint(&self) -> u64 { self.encoded }
+    fn as_uint128(&self) -> u128 { self.encoded as u128 }
+
+    fn to_model_input(&self) -> ModelInput { self.encoded.into() }
+    fn key_type() -> KeyType { KeyType::Bytes }
+
+    fn same_encoding(&self, other: &Self) -> bool { self.encoded == other.encoded }
+
+    // the bytes of the string after the shared prefix, as far as the
+    // encoding holds them ("..." if the string goes on)
+    fn to_key_string(&self) -> String {
+        let shown = usize::min(8, self.len.saturating_sub(self.skip) as usize);
+        let bytes = self.encoded.to_be_bytes();
+        let mut res: String = bytes[..shown].iter()
+            .flat_map(|b| std::ascii::escape_default(*b))
+            .map(|c| c as char)
+            .collect();
+        if self.len > self.skip + 8 {
+            res.push_str("...");
+        }
+        return res;
+    }
 }
 
 pub trait RMITrainingDataIteratorProvider: Send + Sync {
@@ -240,12 +476,39 @@ impl <K: TrainingKey> RMITrainingDataIteratorProvider for Vec<(K, usize)> {
                         .map(|(key, offset)| (key.into(), offset)));
     }
 
-    fn key_type(&self) -> KeyType { return KeyType::U64; }
+    fn key_type(&self) -> KeyType { return K::key_type(); }
     fn get(&self, idx: usize) -> Option<(Self::InpType, usize)> {
         self.as_slice().get(idx).map(|(key, offset)| ((*key).into(), *offset))
     }
 }
 
+// The rows [start, end) of the iter() of some training data, read from it
+// in place instead of being copied (see RMITrainingData::rows).
+struct RowsView<K: TrainingKey> {
+    data: RMITrainingData<K>,
+    start: usize,
+    end: usize
+}
+
+impl <K: TrainingKey> RMITrainingDataIteratorProvider for RowsView<K> {
+    type InpType = K;
+    fn len(&self) -> usize {
+        return self.end - self.start;
+    }
+
+    fn cdf_iter(&self) -> Box<dyn Iterator<Item = (Self::InpType, usize)> + '_> {
+        return self.data.iter_range(self.start, self.end);
+    }
+
+    fn key_type(&self) -> KeyType { return K::key_type(); }
+    fn get(&self, idx: usize) -> Option<(Self::InpType, usize)> {
+        if idx >= self.len() {
+            return None;
+        }
+        return self.data.iter_range(self.start + idx, self.start + idx + 1).next();
+    }
+}
+
 
 struct FixDupsIter<K, T: Iterator<Item=(K, usize)>> {
     iter: T,
@@ -337,11 +600,254 @@ impl <K, T> Iterator for DedupIter<K, T> where
     }
 }
 
+// Maps each duplicate key to the last position holding that key.
+struct UpperDupsIter<K, T: Iterator<Item=(K, usize)>> {
+    iter: std::iter::Peekable<T>,
+    run: Option<(K, usize)>,
+    remaining: usize
+}
+
+impl <K, T: Iterator<Item=(K, usize)>> UpperDupsIter<K, T> {
+    fn new(iter: T) -> UpperDupsIter<K, T> {
+        return UpperDupsIter { iter: iter.peekable(), run: None, remaining: 0 };
+    }
+}
+
+impl <K, T> Iterator for UpperDupsIter<K, T> where
+    T: Iterator<Item=(K, usize)>,
+    K: TrainingKey {
+    type Item = (K, usize);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining > 0 {
+            self.remaining -= 1;
+            return self.run;
+        }
+
+        let (key, mut last) = self.iter.next()?;
+        let mut count = 1;
+        while let Some(&(nxt, idx)) = self.iter.peek() {
+            if nxt != key { break; }
+            last = idx;
+            count += 1;
+            self.iter.next();
+        }
+
+        self.run = Some((key, last));
+        self.remaining = count - 1;
+        return self.run;
+    }
+}
+
+// Which position the models should predict for a key that appears more
+// than once in the data.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DuplicateSemantics {
+    // the first position holding the key
+    LowerBound,
+    // the last position holding the key
+    UpperBound,
+    // train on the distinct keys only, and keep a side table with the length
+    // of every run of duplicates; positions are ranks among distinct keys
+    Dedup,
+    // the interval [first position, one past the last position) of the key:
+    // models predict the first position like LowerBound, and every leaf keeps
+    // a left and a right error so that its window holds both ends
+    EqualRange
+}
+
+impl DuplicateSemantics {
+    pub fn from_str(s: &str) -> Option<DuplicateSemantics> {
+        return match s {
+            "lower" => Some(DuplicateSemantics::LowerBound),
+            "upper" => Some(DuplicateSemantics::UpperBound),
+            "dedup" => Some(DuplicateSemantics::Dedup),
+            "range" => Some(DuplicateSemantics::EqualRange),
+            _ => None
+        };
+    }
+
+    pub fn name(&self) -> &'static str {
+        return match self {
+            DuplicateSemantics::LowerBound => "lower",
+            DuplicateSemantics::UpperBound => "upper",
+            DuplicateSemantics::Dedup => "dedup",
+            DuplicateSemantics::EqualRange => "range"
+        };
+    }
+
+    // identifier used in the emitted parameter files
+    pub fn code(&self) -> u64 {
+        return match self {
+            DuplicateSemantics::LowerBound => 0,
+            DuplicateSemantics::UpperBound => 1,
+            DuplicateSemantics::Dedup => 2,
+            DuplicateSemantics::EqualRange => 3
+        };
+    }
+}
+
+// How often ranges of keys are queried, relative to each other. Each range
+// starts at a key and lasts until the start of the next one, and keys
+// before the first range weigh 1. A weight per key is a range per key.
+// The weights are used by the regressions (see slr_weighted) and to average
+// the log2 error over queries instead of over keys.
+#[derive(Clone, Debug)]
+pub struct QueryWeights {
+    starts: Vec<ModelInput>,
+    weights: Vec<f64>
+}
+
+impl QueryWeights {
+    pub fn new(mut ranges: Vec<(ModelInput, f64)>) -> QueryWeights {
+        assert!(ranges.iter().all(|(_, w)| w.is_finite() && *w > 0.0),
+                "query weights must be positive");
+        ranges.sort_by(|a, b| a.0.partial_cmp(&b.0)
+                       .expect("query weight ranges must start at keys of the same type"));
+        let (starts, weights) = ranges.into_iter().unzip();
+        return QueryWeights { starts, weights };
+    }
+
+    // Parses one "<key> <weight>" range per line. Blank lines and lines
+    // starting with # are skipped.
+    pub fn parse(text: &str, key_type: KeyType) -> Result<QueryWeights, String> {
+        let mut ranges = Vec::new();
+        for (line_no, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() != 2 {
+                return Err(format!("line {}: expected a key and a weight", line_no + 1));
+            }
+            let key = match key_type {
+                KeyType::U32 | KeyType::U64 => fields[0].parse::<u64>().ok().map(ModelInput::Int),
+                KeyType::U128 => fields[0].parse::<u128>().ok().map(ModelInput::Int128),
+                KeyType::F64 => fields[0].parse::<f64>().ok().map(ModelInput::Float),
+                KeyType::U512 => U512::from_dec_str(fields[0]).ok().map(ModelInput::UINT512),
+                KeyType::F512 | KeyType::Bytes =>
+                    return Err(format!("query weights are not supported for {} keys",
+                                       key_type.c_type())),
+            }.ok_or_else(|| format!("line {}: invalid key {}", line_no + 1, fields[0]))?;
+            let weight = fields[1].parse::<f64>()
+                .ok()
+                .filter(|w| w.is_finite() && *w > 0.0)
+                .ok_or_else(|| format!("line {}: invalid weight {}", line_no + 1, fields[1]))?;
+            ranges.push((key, weight));
+        }
+        return Ok(QueryWeights::new(ranges));
+    }
+
+    pub fn len(&self) -> usize { return self.starts.len(); }
+
+    pub fn weight(&self, key: &ModelInput) -> f64 {
+        let idx = self.starts.partition_point(|start| start <= key);
+        return if idx == 0 { 1.0 } else { self.weights[idx - 1] };
+    }
+}
+
+// The memory of the rows that training copies out of the keys (leaf and
+// partition buffers, samples, distinct keys), and an optional limit on it.
+// Builders that can work in windows size them to the limit, and under a
+// limit the rows of leaves are read in place (see RMITrainingData::rows); the
+// peak is reported with the trained RMI.
+pub struct MemoryBudget {
+    limit: Option<usize>,
+    in_use: AtomicUsize,
+    peak: AtomicUsize
+}
+
+impl MemoryBudget {
+    pub fn new(limit: Option<usize>) -> MemoryBudget {
+        return MemoryBudget { limit, in_use: AtomicUsize::new(0), peak: AtomicUsize::new(0) };
+    }
+
+    // Parses a number of bytes, with an optional K, M, G or T suffix
+    // (powers of 1024).
+    pub fn parse_size(text: &str) -> Option<usize> {
+        let text = text.trim();
+        let (digits, shift) = match text.chars().last()?.to_ascii_uppercase() {
+            'K' => (&text[..text.len() - 1], 10),
+            'M' => (&text[..text.len() - 1], 20),
+            'G' => (&text[..text.len() - 1], 30),
+            'T' => (&text[..text.len() - 1], 40),
+            _ => (text, 0)
+        };
+        return digits.parse::<usize>().ok()
+            .and_then(|n| n.checked_mul(1 << shift))
+            .filter(|n| *n > 0);
+    }
+
+    pub fn limit(&self) -> Option<usize> {
+        return self.limit;
+    }
+
+    // The most memory that was in use at once.
+    pub fn peak(&self) -> usize {
+        return self.peak.load(AtomicOrdering::SeqCst);
+    }
+
+    // Counts the bytes as in use until the returned charge is dropped.
+    pub fn charge(budget: &Arc<MemoryBudget>, bytes: usize) -> MemoryCharge {
+        let in_use = budget.in_use.fetch_add(bytes, AtomicOrdering::SeqCst) + bytes;
+        budget.peak.fetch_max(in_use, AtomicOrdering::SeqCst);
+        return MemoryCharge { budget: Arc::clone(budget), bytes };
+    }
+
+    // Groups consecutive partitions, of the given numbers of rows, into
+    // windows [start, end) whose rows fit in the limit together. Without a
+    // limit, all partitions are in one window. A partition that does not fit
+    // by itself gets a window of its own (see train_partitions).
+    pub fn windows(&self, partition_rows: &[usize], row_bytes: usize) -> Vec<(usize, usize)> {
+        let limit = match self.limit {
+            None => return vec![(0, partition_rows.len())],
+            Some(limit) => limit
+        };
+
+        let mut windows = Vec::new();
+        let mut start = 0;
+        let mut bytes = 0;
+        for (idx, rows) in partition_rows.iter().enumerate() {
+            let partition_bytes = rows * row_bytes;
+            if idx > start && bytes + partition_bytes > limit {
+                windows.push((start, idx));
+                start = idx;
+                bytes = 0;
+            }
+            bytes += partition_bytes;
+        }
+        windows.push((start, partition_rows.len()));
+        return windows;
+    }
+}
+
+pub struct MemoryCharge {
+    budget: Arc<MemoryBudget>,
+    bytes: usize
+}
+
+impl Drop for MemoryCharge {
+    fn drop(&mut self) {
+        self.budget.in_use.fetch_sub(self.bytes, AtomicOrdering::SeqCst);
+    }
+}
+
 #[derive(Clone)]
 pub struct RMITrainingData<T> {
     iterable: Arc<Box<dyn RMITrainingDataIteratorProvider<InpType=T>>>,
     scale: f64,
-    offset: usize
+    offset: usize,
+    duplicates: DuplicateSemantics,
+    sample_rate: usize,
+    exact_leaf_threshold: Option<u64>,
+    signed_errors: bool,
+    key_errors: bool,
+    max_error: Option<u64>,
+    query_weights: Option<Arc<QueryWeights>>,
+    memory_budget: Arc<MemoryBudget>,
+    rows_charge: Option<Arc<MemoryCharge>>
 }
 
 macro_rules! map_scale {
@@ -362,7 +868,12 @@ macro_rules! map_scale {
 impl <T: TrainingKey> RMITrainingData<T> {
     pub fn new(iterable: Box<dyn RMITrainingDataIteratorProvider<InpType=T>>)
                -> RMITrainingData<T> {
-        return RMITrainingData { iterable: Arc::new(iterable), scale: 1.0, offset: 0 };
+        return RMITrainingData { iterable: Arc::new(iterable), scale: 1.0, offset: 0,
+                                 duplicates: DuplicateSemantics::LowerBound,
+                                 sample_rate: 1, exact_leaf_threshold: None, signed_errors: false,
+                                 key_errors: false, max_error: None, query_weights: None,
+                                 memory_budget: Arc::new(MemoryBudget::new(None)),
+                                 rows_charge: None };
     }
 
     pub fn empty() -> RMITrainingData<T> {
@@ -379,6 +890,151 @@ impl <T: TrainingKey> RMITrainingData<T> {
         self.offset = offset;
     }
 
+    pub fn set_duplicate_semantics(&mut self, duplicates: DuplicateSemantics) {
+        self.duplicates = duplicates;
+    }
+
+    pub fn duplicate_semantics(&self) -> DuplicateSemantics {
+        return self.duplicates;
+    }
+
+    // Fit models on every k-th key only. Errors are still computed on all keys.
+    pub fn set_sample_rate(&mut self, sample_rate: usize) {
+        assert!(sample_rate >= 1, "sample rate must be at least 1");
+        self.sample_rate = sample_rate;
+    }
+
+    pub fn sample_rate(&self) -> usize {
+        return self.sample_rate;
+    }
+
+    // Leaves whose error exceeds the threshold are replaced with an exact
+    // search over their keys (see ExactLeafModel).
+    pub fn set_exact_leaf_threshold(&mut self, threshold: Option<u64>) {
+        self.exact_leaf_threshold = threshold;
+    }
+
+    pub fn exact_leaf_threshold(&self) -> Option<u64> {
+        return self.exact_leaf_threshold;
+    }
+
+    // Two-layer RMIs keep how far each leaf predicts past its keys and how
+    // far short of them separately, instead of one error for both sides.
+    pub fn set_signed_errors(&mut self, signed_errors: bool) {
+        self.signed_errors = signed_errors;
+    }
+
+    pub fn signed_errors(&self) -> bool {
+        return self.signed_errors;
+    }
+
+    // After training, every key is looked up to measure the distribution of
+    // the errors (see evaluate::error_distribution), an extra pass over the
+    // keys that is left out by default.
+    pub fn set_key_errors(&mut self, key_errors: bool) {
+        self.key_errors = key_errors;
+    }
+
+    pub fn key_errors(&self) -> bool {
+        return self.key_errors;
+    }
+
+    // Leaves whose error exceeds the maximum are split with a partial third
+    // layer until they meet it, as far as a single extra layer can (see
+    // build_leaf_or_partial).
+    pub fn set_max_error(&mut self, max_error: Option<u64>) {
+        self.max_error = max_error;
+    }
+
+    pub fn max_error(&self) -> Option<u64> {
+        return self.max_error;
+    }
+
+    // Weight the regressions and the average log2 error by how often the
+    // keys are queried.
+    pub fn set_query_weights(&mut self, query_weights: Option<Arc<QueryWeights>>) {
+        self.query_weights = query_weights;
+    }
+
+    pub fn query_weights(&self) -> Option<Arc<QueryWeights>> {
+        return self.query_weights.clone();
+    }
+
+    // The query weight of a key, 1 if there are no weights.
+    pub fn weight(&self, key: &T) -> f64 {
+        return match &self.query_weights {
+            Some(weights) => weights.weight(&key.to_model_input()),
+            None => 1.0
+        };
+    }
+
+    // All training data derived from this data shares its memory budget.
+    pub fn set_memory_budget(&mut self, memory_budget: Arc<MemoryBudget>) {
+        self.memory_budget = memory_budget;
+    }
+
+    pub fn memory_budget(&self) -> Arc<MemoryBudget> {
+        return Arc::clone(&self.memory_budget);
+    }
+
+    // Takes every training setting of other (the sample rate through to the
+    // memory budget), but not its rows, scale, offset or duplicate semantics.
+    pub fn copy_settings_from(&mut self, other: &RMITrainingData<T>) {
+        self.sample_rate = other.sample_rate;
+        self.exact_leaf_threshold = other.exact_leaf_threshold;
+        self.signed_errors = other.signed_errors;
+        self.key_errors = other.key_errors;
+        self.max_error = other.max_error;
+        self.query_weights = other.query_weights.clone();
+        self.memory_budget = Arc::clone(&other.memory_budget);
+    }
+
+    // Training data over rows copied out of this data. It has the same
+    // settings, and its rows are charged to the memory budget for as long as
+    // it (or a soft copy of it) is alive.
+    pub fn materialize(&self, rows: Vec<(T, usize)>) -> RMITrainingData<T> {
+        let bytes = rows.capacity() * std::mem::size_of::<(T, usize)>();
+        let charge = MemoryBudget::charge(&self.memory_budget, bytes);
+        let mut data = RMITrainingData::new(Box::new(rows));
+        data.copy_settings_from(self);
+        data.rows_charge = Some(Arc::new(charge));
+        return data;
+    }
+
+    // The rows [start, end) of iter() as training data of their own, with the
+    // same settings. Without a memory limit they are copied (and charged to
+    // the budget); with one they are read in place, which is slower to train
+    // on but keeps even the rows of the largest leaves out of the budget.
+    pub fn rows(&self, start: usize, end: usize) -> RMITrainingData<T> {
+        if self.memory_budget.limit().is_none() {
+            return self.materialize(self.iter_range(start, end).collect());
+        }
+        let end = usize::min(end, self.iter_len());
+        let view = RowsView { data: self.soft_copy(), start, end: usize::max(start, end) };
+        let mut data = RMITrainingData::new(Box::new(view));
+        data.copy_settings_from(self);
+        return data;
+    }
+
+    // Like iter, with the query weight of each key.
+    pub fn iter_weighted(&self) -> impl Iterator<Item = (T, usize, f64)> + '_ {
+        return self.iter()
+            .map(move |(k, o)| (k, o, self.weight(&k)));
+    }
+
+    // Every k-th key and the last key, with duplicates mapped according
+    // to the duplicate semantics and the current scale applied.
+    pub fn sample(&self, every: usize) -> RMITrainingData<T> {
+        let n = self.len();
+        let pts: Vec<(T, usize)> = self.iter()
+            .take(n)
+            .enumerate()
+            .filter(|(idx, _)| idx % every == 0 || *idx == n - 1)
+            .map(|(_idx, pt)| pt)
+            .collect();
+        return self.materialize(pts);
+    }
+
     pub fn get(&self, idx: usize) -> (T, usize) {
         return map_scale!(self, self.iterable.get(idx)).unwrap();
     }
@@ -387,15 +1043,122 @@ impl <T: TrainingKey> RMITrainingData<T> {
         return map_scale!(self, self.iterable.get(idx)).unwrap().0
     }
 
-    pub fn iter(&self) -> impl Iterator<Item = (T, usize)> + '_ {
-        map_scale!(self, FixDupsIter::new(self.iterable.cdf_iter()))
+    // Iterates over the keys, with duplicates mapped to their first or last
+    // position depending on the duplicate semantics. Dedup is handled before
+    // training (see dedup_with_runs), so it iterates like LowerBound here, as
+    // does EqualRange.
+    pub fn iter(&self) -> Box<dyn Iterator<Item = (T, usize)> + '_> {
+        return match self.duplicates {
+            DuplicateSemantics::UpperBound =>
+                Box::new(map_scale!(self, UpperDupsIter::new(self.iterable.cdf_iter()))),
+            _ => Box::new(map_scale!(self, FixDupsIter::new(self.iterable.cdf_iter())))
+        };
+    }
+
+    // Number of rows iter() produces: with lower bound semantics the first
+    // row of the last run of keys is produced once more at the end.
+    pub fn iter_len(&self) -> usize {
+        let n = self.len();
+        return match self.duplicates {
+            DuplicateSemantics::UpperBound => n,
+            _ => if n == 0 { 0 } else { n + 1 }
+        };
+    }
+
+    // The rows [start, end) of iter(), found by random access rather than by
+    // walking the rows before start, so that chunks of the data can be
+    // processed in parallel. Only the run of duplicates around start is read.
+    pub fn iter_range(&self, start: usize, end: usize) -> Box<dyn Iterator<Item = (T, usize)> + '_> {
+        let n = self.len();
+        let end = usize::min(end, self.iter_len());
+        if start >= end {
+            return Box::new(std::iter::empty());
+        }
+        let raw = move |idx: usize| self.iterable.get(idx).unwrap();
+
+        return match self.duplicates {
+            DuplicateSemantics::UpperBound => {
+                // (key, position of the last duplicate, index of the last duplicate)
+                let rows = (start..end).scan(None, move |run: &mut Option<(T, usize, usize)>, idx| {
+                    match *run {
+                        Some((key, pos, last)) if idx <= last => Some((key, pos)),
+                        _ => {
+                            let (key, _) = raw(idx);
+                            let mut last = idx;
+                            while last + 1 < n && raw(last + 1).0 == key {
+                                last += 1;
+                            }
+                            *run = Some((key, raw(last).1, last));
+                            Some((key, raw(last).1))
+                        }
+                    }
+                });
+                Box::new(map_scale!(self, rows))
+            },
+            _ => {
+                // the first row of the run that the row before start is in
+                let first_run = if start == 0 {
+                    None
+                } else {
+                    let mut first = start - 1;
+                    while first > 0 && raw(first - 1).0 == raw(start - 1).0 {
+                        first -= 1;
+                    }
+                    Some(raw(first))
+                };
+                let rows = (start..end).scan(first_run, move |run: &mut Option<(T, usize)>, idx| {
+                    if idx == n {
+                        return *run;
+                    }
+                    let (key, pos) = raw(idx);
+                    match *run {
+                        Some((run_key, run_pos)) if run_key == key => Some((key, run_pos)),
+                        _ => {
+                            *run = Some((key, pos));
+                            Some((key, pos))
+                        }
+                    }
+                });
+                Box::new(map_scale!(self, rows))
+            }
+        };
     }
 
     pub fn iter_model_input(&self) -> impl Iterator<Item = (ModelInput, usize)> + '_ {
-        return map_scale!(self, FixDupsIter::new(self.iterable.cdf_iter()))
+        return self.iter()
             .map(|(k, o)| (k.to_model_input(), o));
     }
 
+    // The distinct keys, each with its rank among the distinct keys, and the
+    // (rank, run length) of every key that appears more than once. The keys
+    // are counted first so that the copy is allocated (and charged to the
+    // memory budget) at its exact size.
+    pub fn dedup_with_runs(&self) -> (RMITrainingData<T>, Vec<(u64, u64)>) {
+        let num_unique = DedupIter::new(self.iterable.cdf_iter()).count();
+        let mut unique: Vec<(T, usize)> = Vec::with_capacity(num_unique);
+        let mut runs: Vec<(u64, u64)> = Vec::new();
+        let mut run_length = 0;
+
+        for (key, _offset) in self.iterable.cdf_iter() {
+            if let Some(&(last, rank)) = unique.last() {
+                if last == key {
+                    run_length += 1;
+                    continue;
+                }
+                if run_length > 1 {
+                    runs.push((rank as u64, run_length));
+                }
+            }
+            unique.push((key, unique.len()));
+            run_length = 1;
+        }
+        if run_length > 1 {
+            runs.push(((unique.len() - 1) as u64, run_length));
+        }
+
+        return (self.materialize(unique), runs);
+    }
+
 
     pub fn iter_unique(&self) -> impl Iterator<Item = (T, usize)> + '_ {
         map_scale!(self, DedupIter::new(self.iterable.cdf_iter()))
@@ -426,7 +1189,16 @@ impl <T: TrainingKey> RMITrainingData<T> {
         return RMITrainingData {
             scale: self.scale,
             offset: self.offset,
-            iterable: Arc::clone(&self.iterable)
+            iterable: Arc::clone(&self.iterable),
+            duplicates: self.duplicates,
+            sample_rate: self.sample_rate,
+            exact_leaf_threshold: self.exact_leaf_threshold,
+            signed_errors: self.signed_errors,
+            key_errors: self.key_errors,
+            max_error: self.max_error,
+            query_weights: self.query_weights.clone(),
+            memory_budget: Arc::clone(&self.memory_budget),
+            rows_charge: self.rows_charge.clone()
         };
     }
 }
@@ -489,6 +1261,7 @@ pub enum ModelInput {
     Int128(u128),
     Float(f64),
     UINT512(U512),
+    Float512(F512),
 }
 
 impl PartialEq for ModelInput {
@@ -499,7 +1272,8 @@ impl PartialEq for ModelInput {
                     ModelInput::Int(y) => x == y,
                     ModelInput::Int128(y) => false,
                     ModelInput::Float(_) => false,
-                    ModelInput::UINT512(_) => false
+                    ModelInput::UINT512(_) => false,
+                    ModelInput::Float512(_) => false
                 }
             }
             ModelInput::Int128(x) => {
@@ -507,7 +1281,8 @@ impl PartialEq for ModelInput {
                     ModelInput::Int(y) => false,
                     ModelInput::Int128(y) => x == y,
                     ModelInput::Float(_) => false,
-                    ModelInput::UINT512(_) => false
+                    ModelInput::UINT512(_) => false,
+                    ModelInput::Float512(_) => false
                 }
             }
             ModelInput::Float(x) => {
@@ -515,7 +1290,8 @@ impl PartialEq for ModelInput {
                     ModelInput::Int(_) => false,
                     ModelInput::Int128(y) => false,
                     ModelInput::Float(y) => x == y, // exact equality is intentional
-                    ModelInput::UINT512(_) => false
+                    ModelInput::UINT512(_) => false,
+                    ModelInput::Float512(_) => false
                 }
             }
             ModelInput::UINT512(x) => {
@@ -523,7 +1299,17 @@ impl PartialEq for ModelInput {
                     ModelInput::Int(_) => false,
                     ModelInput::Int128(y) => false,
                     ModelInput::Float(_) => false, 
-                    ModelInput::UINT512(y) => x == y // exact equality is intentional
+                    ModelInput::UINT512(y) => x == y, // exact equality is intentional
+                    ModelInput::Float512(_) => false
+                }
+            }
+            ModelInput::Float512(x) => {
+                match other {
+                    ModelInput::Int(_) => false,
+                    ModelInput::Int128(_) => false,
+                    ModelInput::Float(_) => false,
+                    ModelInput::UINT512(_) => false,
+                    ModelInput::Float512(y) => x == y // exact equality is intentional
                 }
             }
         }
@@ -540,7 +1326,8 @@ impl PartialOrd for ModelInput {
                     ModelInput::Int(y) => x.partial_cmp(y),
                     ModelInput::Int128(_) => None,
                     ModelInput::Float(_) => None,
-                    ModelInput::UINT512(_) => None
+                    ModelInput::UINT512(_) => None,
+                    ModelInput::Float512(_) => None
                 }
             }
             ModelInput::Int128(x) => {
@@ -548,7 +1335,8 @@ impl PartialOrd for ModelInput {
                     ModelInput::Int(_) => None,
                     ModelInput::Int128(y) => x.partial_cmp(y),
                     ModelInput::Float(_) => None,
-                    ModelInput::UINT512(_) => None
+                    ModelInput::UINT512(_) => None,
+                    ModelInput::Float512(_) => None
                 }
             }
             ModelInput::Float(x) => {
@@ -556,7 +1344,8 @@ impl PartialOrd for ModelInput {
                     ModelInput::Int(_) => None,
                     ModelInput::Int128(_) => None,
                     ModelInput::Float(y) => x.partial_cmp(y),
-                    ModelInput::UINT512(_) => None
+                    ModelInput::UINT512(_) => None,
+                    ModelInput::Float512(_) => None
                 }
             }
             ModelInput::UINT512(x) => {
@@ -564,7 +1353,17 @@ impl PartialOrd for ModelInput {
                     ModelInput::Int(_) => None,
                     ModelInput::Int128(_) => None,
                     ModelInput::Float(_) => None,
-                    ModelInput::UINT512(y) => x.partial_cmp(y)
+                    ModelInput::UINT512(y) => x.partial_cmp(y),
+                    ModelInput::Float512(_) => None
+                }
+            }
+            ModelInput::Float512(x) => {
+                match other {
+                    ModelInput::Int(_) => None,
+                    ModelInput::Int128(_) => None,
+                    ModelInput::Float(_) => None,
+                    ModelInput::UINT512(_) => None,
+                    ModelInput::Float512(y) => x.to_float().partial_cmp(&y.to_float())
                 }
             }
         }
@@ -580,6 +1379,7 @@ impl ModelInput {
             ModelInput::Int128(x) => *x as f64,
             ModelInput::Float(x) => *x,
             ModelInput::UINT512(x) => (*x).low_u64() as f64,
+            ModelInput::Float512(x) => x.as_float(),
         };
     }
     pub fn as_float512(&self) -> Float {
@@ -592,6 +1392,7 @@ impl ModelInput {
                 (*x).to_big_endian(&mut target);
                 Float::with_val(512,Integer::from_digits( &target, Order::Msf))
             },
+            ModelInput::Float512(x) => x.to_float(),
         };
     }
     pub fn as_int(&self) -> u64 {
@@ -600,6 +1401,7 @@ impl ModelInput {
             ModelInput::Int128(x) => *x as u64,
             ModelInput::Float(x) => *x as u64,
             ModelInput::UINT512(x) => (*x).low_u64(),
+            ModelInput::Float512(x) => x.as_uint(),
         };
     }
     pub fn as_int128(&self) -> u128 {
@@ -608,6 +1410,7 @@ impl ModelInput {
             ModelInput::Int128(x) => *x as u128,
             ModelInput::Float(x) => *x as u128,
             ModelInput::UINT512(x) => (*x).low_u64() as u128,
+            ModelInput::Float512(x) => x.as_uint128(),
         };
     }
     pub fn max_value(&self) -> ModelInput {
@@ -616,6 +1419,7 @@ impl ModelInput {
             ModelInput::Int128(_) => std::u128::MAX.into(),
             ModelInput::Float(_) => std::f64::MAX.into(),
             ModelInput::UINT512(_) => U512::MAX.into(),
+            ModelInput::Float512(_) => F512::max_value().into(),
         };
     }
 
@@ -625,6 +1429,11 @@ impl ModelInput {
             ModelInput::Int128(_) => 0.into(),
             ModelInput::Float(_) => std::f64::MIN.into(),
             ModelInput::UINT512(_) => 0.into(),
+            ModelInput::Float512(_) => {
+                let mut min = F512::max_value();
+                min.sign = 1;
+                min.into()
+            },
         };
     }
 
@@ -634,6 +1443,7 @@ impl ModelInput {
             ModelInput::Int128(x) => if *x > 0 { (x - 1).into() } else { 0.into() }
             ModelInput::Float(x) => (x - std::f64::EPSILON).into(),
             ModelInput::UINT512(x) =>  if *x > U512::from(0) { (x - U512::from(1)).into() } else { U512::from(0).into() },
+            ModelInput::Float512(x) => x.minus_epsilon().into(),
         };
     }
 
@@ -655,6 +1465,7 @@ impl ModelInput {
             } else {
                 U512::MAX.into()
             }
+            ModelInput::Float512(x) => x.plus_epsilon().into(),
         };
     }
 }
@@ -697,6 +1508,12 @@ impl From<U512> for ModelInput {
     }
 }
 
+impl From<F512> for ModelInput {
+    fn from(f: F512) -> Self {
+        ModelInput::Float512(f)
+    }
+}
+
 pub enum ModelDataType {
     Int,
     Int128,
@@ -870,6 +1687,14 @@ impl ModelParam {
             ModelParam::Int128(v) => target.write_u128::<LittleEndian>(*v),
             ModelParam::Float(v) => target.write_f64::<LittleEndian>(*v),
             ModelParam::Float512(v) => {
+                if *v == 0 {
+                    // zero has no normalized mantissa, write it as all zero
+                    // words (mantissa, exponent and sign), matching F512::zero
+                    for _ in 0..34 {
+                        target.write_u16::<LittleEndian>(0)?;
+                    }
+                    return Ok(());
+                }
                 let hexstring = v.to_string_radix(2,Some(512));
                 let  tmp_string = format!("{:0>512}", hexstring);
                 // println!("Write String:\n{} \n{:?}", hexstring, v);
@@ -1148,41 +1973,33 @@ pub trait Model: Sync + Send {
     fn set_to_constant_model(&mut self, _constant: u64) -> bool {
         return false;
     }
-}
-
-pub trait ModelBig: Sync + Send {
-    fn predict_to_float(&self, inp: &ModelInput) -> Float {
-        return Float::with_val(512, self.predict_to_int(inp) );
-    }
-
-    fn predict_to_int(&self, inp: &ModelInput) -> u64 {
-        return f64::max(0.0, self.predict_to_float(inp).to_f64_round(Round::Down)) as u64;
-    }
-
-    fn input_type(&self) -> ModelDataType;
-    fn output_type(&self) -> ModelDataType;
-
-    fn params(&self) -> Vec<ModelParam>;
-
-    fn code(&self) -> String;
-    fn function_name(&self) -> String;
 
-    fn standard_functions(&self) -> HashSet<StdFunctions> {
-        return HashSet::new();
+    // moves every prediction by offset positions, e.g. when keys were
+    // inserted before the keys of a leaf. Returns false if the model cannot.
+    fn shift_positions(&mut self, _offset: i64) -> bool {
+        return false;
     }
 
-    fn needs_bounds_check(&self) -> bool {
-        return true;
-    }
-    fn restriction(&self) -> ModelRestriction {
-        return ModelRestriction::None;
-    }
-    fn error_bound(&self) -> Option<u64> {
+    // the position of the first key and the sorted keys searched by a leaf
+    // that is searched exactly instead of being predicted
+    fn exact_keys(&self) -> Option<(u64, &ModelParam)> {
         return None;
     }
+}
 
-    fn set_to_constant_model(&mut self, _constant: u64) -> bool {
-        return false;
+// Models that predict in 512-bit precision. Their `Model` implementation
+// should route through `predict_to_int` here, so that the prediction is
+// floored before it is rounded to an f64 (keys that differ beyond 2^53 would
+// otherwise land on the same position).
+pub trait ModelBig: Model {
+    fn predict_to_float(&self, inp: &ModelInput) -> Float;
+
+    fn predict_to_int(&self, inp: &ModelInput) -> u64 {
+        let pred = ModelBig::predict_to_float(self, inp).floor();
+        return match pred.to_integer() {
+            Some(v) if v > 0 => v.to_u64().unwrap_or(std::u64::MAX),
+            _ => 0,
+        };
     }
 }
 
@@ -1213,3 +2030,234 @@ mod tests {
         assert_eq!(data, iterated);
     }
 }
+
+#[cfg(test)]
+mod f512_tests {
+    use super::*;
+
+    fn f512(v: &str) -> F512 {
+        return F512::from_float(&Float::with_val(512, Float::parse(v).unwrap()));
+    }
+
+    #[test]
+    fn test_f512_roundtrip() {
+        for v in &["0", "1", "1.5", "-3.25", "0.001", "123456789", "1e-300", "-7e200"] {
+            let orig = Float::with_val(512, Float::parse(v).unwrap());
+            assert_eq!(F512::from_float(&orig).to_float(), orig, "value {}", v);
+        }
+    }
+
+    #[test]
+    fn test_f512_matches_param_layout() {
+        for v in &["0", "1", "-3.25", "0.001", "123456789"] {
+            let key = f512(v);
+            let mut buf: Vec<u8> = Vec::new();
+            ModelParam::Float512(key.to_float()).write_to(&mut buf).unwrap();
+            assert_eq!(buf.len(), 68);
+            assert_eq!(F512::from_le_bytes(&buf), key, "value {}", v);
+        }
+    }
+
+    #[test]
+    fn test_f512_epsilon() {
+        // these keys are identical as f64, but not as 512-bit floats
+        let key = f512("1");
+        let above = key.plus_epsilon();
+        let below = key.minus_epsilon();
+        assert_eq!(above.as_float(), key.as_float());
+        assert!(above.to_float() > key.to_float());
+        assert!(below.to_float() < key.to_float());
+        assert_eq!(above.minus_epsilon(), key);
+
+        let inp = key.to_model_input();
+        assert!(inp < inp.plus_epsilon());
+        assert!(inp.minus_epsilon() < inp);
+        assert!(inp.min_value() < inp && inp < inp.max_value());
+    }
+
+    #[test]
+    fn test_f512_linear_big() {
+        // keys 1 + i * 2^-200 all round to 1.0 as f64
+        let data: Vec<(F512, usize)> = (0..100)
+            .map(|i| {
+                let k = Float::with_val(512, 1) + (Float::with_val(512, i) >> 200);
+                (F512::from_float(&k), i as usize)
+            })
+            .collect();
+        let md = RMITrainingData::new(Box::new(data.clone()));
+        let lin_mod = LinearModelBig::new(&md);
+
+        for (key, pos) in data {
+            assert_eq!(ModelBig::predict_to_int(&lin_mod, &key.to_model_input()), pos as u64);
+        }
+    }
+}
+
+#[cfg(test)]
+mod bytes_key_tests {
+    use super::*;
+
+    #[test]
+    fn test_bytes_key_order() {
+        let mut keys: Vec<&[u8]> = vec![b"ACGT", b"ACGTA", b"ACGTAAAAAAAAAAAA", b"ACGTAAAAAAAAAAAC",
+                                        b"ACGTC", b"ACGTCA", b"ACGTG", b"ACGTT", b"ACGTTTTTTTTT"];
+        keys.sort();
+        let skip = shared_prefix_len(keys[0], keys[keys.len() - 1]);
+        assert_eq!(skip, 4);
+
+        let encoded: Vec<BytesKey> = keys.iter().map(|k| BytesKey::new(k, skip)).collect();
+        for w in encoded.windows(2) {
+            assert!(w[0].encoded() <= w[1].encoded());
+            assert!(w[0] != w[1]);
+        }
+    }
+
+    #[test]
+    fn test_bytes_key_shared_encoding() {
+        // these differ only after the 8 encoded bytes
+        let a = BytesKey::new(b"read_0000000001", 0);
+        let b = BytesKey::new(b"read_0000000002", 0);
+        assert_eq!(a.encoded(), b.encoded());
+        assert!(a != b);
+        assert!(a.same_encoding(&b));
+        assert!(!a.same_encoding(&a.plus_epsilon()));
+        assert_eq!(BytesKey::encode(b"read_0000000001", 5), BytesKey::encode(b"read_00000000", 5));
+        assert!(BytesKey::encode(b"read_0000000001", 7) > BytesKey::encode(b"read_00000000", 7));
+    }
+}
+
+#[cfg(test)]
+mod duplicates_tests {
+    use super::*;
+
+    fn data_of(keys: &[u64]) -> RMITrainingData<u64> {
+        let v: Vec<(u64, usize)> = keys.iter().cloned()
+            .enumerate().map(|(i, k)| (k, i)).collect();
+        return RMITrainingData::new(Box::new(v));
+    }
+
+    #[test]
+    fn test_upper_bound_iter() {
+        let mut md = data_of(&[1, 2, 2, 2, 3, 4, 4]);
+        md.set_duplicate_semantics(DuplicateSemantics::UpperBound);
+        let offsets: Vec<usize> = md.iter().map(|(_k, o)| o).collect();
+        assert_eq!(offsets, vec![0, 3, 3, 3, 4, 6, 6]);
+
+        md.set_duplicate_semantics(DuplicateSemantics::LowerBound);
+        let offsets: Vec<usize> = md.iter().take(7).map(|(_k, o)| o).collect();
+        assert_eq!(offsets, vec![0, 1, 1, 1, 4, 5, 5]);
+    }
+
+    #[test]
+    fn test_iter_range() {
+        let keys = [1, 2, 2, 2, 3, 4, 4, 5, 5, 5, 5, 6, 7, 7];
+        for semantics in &[DuplicateSemantics::LowerBound, DuplicateSemantics::UpperBound] {
+            let mut md = data_of(&keys);
+            md.set_duplicate_semantics(*semantics);
+            md.set_scale(0.5);
+            let all: Vec<(u64, usize)> = md.iter().collect();
+            assert_eq!(all.len(), md.iter_len());
+
+            for start in 0..=all.len() {
+                for end in start..=all.len() + 1 {
+                    let range: Vec<(u64, usize)> = md.iter_range(start, end).collect();
+                    let expected = &all[start..usize::min(end, all.len())];
+                    assert_eq!(range, expected, "{:?} rows {} to {}", semantics, start, end);
+                }
+            }
+        }
+        assert_eq!(data_of(&[]).iter_range(0, 5).count(), 0);
+    }
+
+    #[test]
+    fn test_dedup_with_runs() {
+        let md = data_of(&[1, 2, 2, 2, 3, 4, 4]);
+        let (unique, runs) = md.dedup_with_runs();
+        assert_eq!(unique.len(), 4);
+        assert_eq!(unique.iter().take(4).collect::<Vec<(u64, usize)>>(),
+                   vec![(1, 0), (2, 1), (3, 2), (4, 3)]);
+        assert_eq!(runs, vec![(1, 3), (3, 2)]);
+    }
+}
+
+#[cfg(test)]
+mod query_weights_tests {
+    use super::*;
+
+    #[test]
+    fn test_query_weights() {
+        let weights = QueryWeights::parse("# hot region\n30 8\n\n10 0.5\n50 1\n", KeyType::U64).unwrap();
+        assert_eq!(weights.len(), 3);
+        let w = |k: u64| weights.weight(&k.to_model_input());
+        assert_eq!(w(5), 1.0);
+        assert_eq!(w(10), 0.5);
+        assert_eq!(w(29), 0.5);
+        assert_eq!(w(30), 8.0);
+        assert_eq!(w(49), 8.0);
+        assert_eq!(w(1000), 1.0);
+
+        let keys: Vec<(u64, usize)> = vec![(1, 0), (10, 1), (20, 2), (30, 3), (40, 4), (50, 5)];
+        let mut md = RMITrainingData::new(Box::new(keys));
+        md.set_query_weights(Some(Arc::new(weights)));
+        let ws: Vec<f64> = md.iter_weighted().take(6).map(|(_k, _o, w)| w).collect();
+        assert_eq!(ws, vec![1.0, 0.5, 0.5, 8.0, 8.0, 1.0]);
+        assert_eq!(md.sample(2).weight(&40), 8.0);
+        assert_eq!(md.soft_copy().weight(&40), 8.0);
+
+        assert!(QueryWeights::parse("10 -1", KeyType::U64).is_err());
+        assert!(QueryWeights::parse("10", KeyType::U64).is_err());
+        assert!(QueryWeights::parse("x 1", KeyType::U64).is_err());
+        assert!(QueryWeights::parse("10 1", KeyType::Bytes).is_err());
+        let wide = QueryWeights::parse("340282366920938463463374607431768211456 2", KeyType::U512).unwrap();
+        assert_eq!(wide.weight(&(U512::from(1) << 128).to_model_input()), 2.0);
+    }
+}
+
+#[cfg(test)]
+mod memory_budget_tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_budget() {
+        assert_eq!(MemoryBudget::parse_size("4096"), Some(4096));
+        assert_eq!(MemoryBudget::parse_size("64k"), Some(64 << 10));
+        assert_eq!(MemoryBudget::parse_size("3G"), Some(3 << 30));
+        assert_eq!(MemoryBudget::parse_size("0"), None);
+        assert_eq!(MemoryBudget::parse_size("12X"), None);
+        assert_eq!(MemoryBudget::parse_size(""), None);
+
+        let unlimited = MemoryBudget::new(None);
+        assert_eq!(unlimited.windows(&[5, 0, 7], 16), vec![(0, 3)]);
+        let limited = MemoryBudget::new(Some(160));
+        assert_eq!(limited.windows(&[4, 6, 3, 20, 0, 1], 16), vec![(0, 2), (2, 3), (3, 4), (4, 6)]);
+
+        // rows copied out of the keys are charged until the last copy is dropped
+        let budget = Arc::new(MemoryBudget::new(Some(1 << 20)));
+        let mut md = RMITrainingData::new(Box::new((0..100u64).map(|i| (i, i as usize)).collect::<Vec<_>>()));
+        md.set_memory_budget(Arc::clone(&budget));
+        let half = md.materialize((0..50u64).map(|i| (2 * i, i as usize)).collect());
+        let copy = half.soft_copy();
+        assert_eq!(budget.in_use.load(AtomicOrdering::SeqCst), 50 * 16);
+        drop(half);
+        let sampled = copy.sample(5);
+        assert!(budget.in_use.load(AtomicOrdering::SeqCst) > 50 * 16);
+        drop(copy);
+        drop(sampled);
+        assert_eq!(budget.in_use.load(AtomicOrdering::SeqCst), 0);
+        let all = md.materialize((0..100u64).map(|i| (i, i as usize)).collect());
+        drop(all);
+        assert_eq!(budget.peak(), 100 * 16);
+
+        // the distinct keys of dedup take every setting and are charged at their exact size
+        let budget = Arc::new(MemoryBudget::new(Some(1 << 20)));
+        let mut md = RMITrainingData::new(Box::new((0..100u64).map(|i| (i / 4, i as usize)).collect::<Vec<_>>()));
+        md.set_memory_budget(Arc::clone(&budget));
+        md.set_sample_rate(3);
+        md.set_max_error(Some(8));
+        md.set_key_errors(true);
+        let (unique, runs) = md.dedup_with_runs();
+        assert_eq!((unique.len(), runs.len()), (25, 25));
+        assert_eq!((unique.sample_rate(), unique.max_error(), unique.key_errors()), (3, Some(8), true));
+        assert_eq!(budget.in_use.load(AtomicOrdering::SeqCst), 25 * 16);
+    }
+}
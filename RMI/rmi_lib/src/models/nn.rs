@@ -5,181 +5,187 @@
 // 
 // < end copyright > 
  
-extern crate anyhow;
-extern crate tch;
-use anyhow::Result;
-use tch::{nn, nn::Module, nn::OptimizerConfig, Device};
 
 use crate::models::*;
-
-fn slr<T: Iterator<Item = (f64, f64)>>(loc_data: T) -> (f64, f64) {
-
-    // compute the covariance of x and y as well as the variance of x in
-    // a single pass.
-
-    let mut mean_x = 0.0;
-    let mut mean_y = 0.0;
-    let mut c = 0.0;
-    let mut n: u64 = 0;
-    let mut m2 = 0.0;
-
-    let mut data_size = 0;
-    for (x, y) in loc_data {
-        n += 1;
-        let dx = x - mean_x;
-        mean_x += dx / (n as f64);
-        mean_y += (y - mean_y) / (n as f64);
-        c += dx * (y - mean_y);
-
-        let dx2 = x - mean_x;
-        m2 += dx * dx2;
-        data_size += 1;
-    }
-
-    // special case when we have 0 or 1 items
-    if data_size == 0 {
-        return (0.0, 0.0);
-    }
-
-    if data_size == 1 {
-        return (mean_y, 0.0);
-    }
-
-
-    let cov = c / ((n - 1) as f64);
-    let var = m2 / ((n - 1) as f64);
-    assert!(var >= 0.0, "variance of model with {} data items was negative", n);
-
-    if var == 0.0 {
-        // variance is zero. pick the mean (only) value.
-        return (mean_y, 0.0);
-    }
-
-    let beta: f64 = cov / var;
-    let alpha = mean_y - beta * mean_x;
-
-    return (alpha, beta);
-}
-
-fn loglinear_slr<T: TrainingKey>(data: &RMITrainingData<T>) -> (f64, f64) {
-    // log all of the outputs, omit any item that doesn't have a valid log
-    let transformed_data: Vec<(f64, f64)> = data
-        .iter()
-        .map(|(x, y)| (x.as_float(), (y as f64).ln()))
-        .filter(|(_, y)| y.is_finite())
-        .collect();
-
-    // TODO this currently creates a copy of the data and then calls
-    // slr... we can probably do better by moving the log into the slr.
-    return slr(transformed_data.into_iter());
+use log::*;
+
+// A small feed-forward network: one hidden layer of clipped ReLU units,
+// min(max(w*x + b, 0), 1), followed by a linear output layer. Both the
+// hidden weights and the output weights are kept non-negative, so the
+// network (and its floating point evaluation, which only uses monotone
+// operations) is non-decreasing in the key. That keeps the leaf indices
+// sorted, which `build_models_from` depends on.
+const HIDDEN_UNITS: usize = 16;
+const MAX_TRAINING_POINTS: usize = 4096;
+const EPOCHS: usize = 500;
+const LEARNING_RATE: f64 = 0.001;
+
+pub struct NNModel {
+    xmin: f64,
+    xinv: f64,
+    ymin: f64,
+    yrange: f64,
+    weights: Vec<f64>,
+    biases: Vec<f64>,
+    outputs: Vec<f64>,
 }
 
-pub struct LinearModel {
-    params: (f64, f64),
+fn clip(z: f64) -> f64 {
+    return f64::min(f64::max(z, 0.0), 1.0);
 }
 
-impl LinearModel {
-    pub fn new<T: TrainingKey>(data: &RMITrainingData<T>) -> LinearModel {
-        let params = slr(data.iter()
-                         .map(|(inp, offset)| (inp.as_float(), offset as f64)));
-        return LinearModel { params };
+fn forward(weights: &[f64], biases: &[f64], outputs: &[f64], x: f64) -> f64 {
+    let mut acc = 0.0;
+    for i in 0..weights.len() {
+        let h = clip(weights[i].mul_add(x, biases[i]));
+        acc = outputs[i].mul_add(h, acc);
     }
+    return acc;
 }
 
-impl Model for LinearModel {
-    fn predict_to_float(&self, inp: &ModelInput) -> f64 {
-        let (intercept, slope) = self.params;
-        return slope.mul_add(inp.as_float(), intercept);
-    }
-
-    fn input_type(&self) -> ModelDataType {
-        return ModelDataType::Float;
-    }
-    fn output_type(&self) -> ModelDataType {
-        return ModelDataType::Float;
-    }
-
-    fn params(&self) -> Vec<ModelParam> {
-        return vec![self.params.0.into(), self.params.1.into()];
-    }
-
-    fn code(&self) -> String {
-        return String::from(
-            "
-inline double linear(double alpha, double beta, double inp) {
-    return std::fma(beta, inp, alpha);
-}",
-        );
-    }
-
-    fn function_name(&self) -> String {
-        return String::from("linear");
-    }
+fn mse(weights: &[f64], biases: &[f64], outputs: &[f64], pts: &[(f64, f64)]) -> f64 {
+    let total: f64 = pts.iter()
+        .map(|&(x, y)| (forward(weights, biases, outputs, x) - y).powi(2))
+        .sum();
+    return total / pts.len() as f64;
+}
 
-    fn set_to_constant_model(&mut self, constant: u64) -> bool {
-        self.params = (constant as f64, 0.0);
-        return true;
+// Adam update of a single parameter vector, projected back onto w >= 0
+// when `non_negative` is set.
+fn adam_step(params: &mut [f64], grads: &[f64], m: &mut [f64], v: &mut [f64],
+             step: usize, non_negative: bool) {
+    const BETA1: f64 = 0.9;
+    const BETA2: f64 = 0.999;
+    let bias1 = 1.0 - BETA1.powi(step as i32);
+    let bias2 = 1.0 - BETA2.powi(step as i32);
+    for i in 0..params.len() {
+        m[i] = BETA1 * m[i] + (1.0 - BETA1) * grads[i];
+        v[i] = BETA2 * v[i] + (1.0 - BETA2) * grads[i] * grads[i];
+        let update = (m[i] / bias1) / ((v[i] / bias2).sqrt() + 1e-12);
+        params[i] -= LEARNING_RATE * update;
+        if non_negative && params[i] < 0.0 {
+            params[i] = 0.0;
+        }
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn test_linear1() {
-        let md = ModelData::IntKeyToIntPos(vec![(1, 2), (2, 3), (3, 4)]);
-
-        let lin_mod = LinearModel::new(&md);
-
-        assert_eq!(lin_mod.predict_to_int(1.into()), 2);
-        assert_eq!(lin_mod.predict_to_int(6.into()), 7);
+impl NNModel {
+    fn constant(value: f64) -> NNModel {
+        return NNModel {
+            xmin: 0.0, xinv: 0.0, ymin: value, yrange: 0.0,
+            weights: vec![0.0; HIDDEN_UNITS],
+            biases: vec![0.0; HIDDEN_UNITS],
+            outputs: vec![0.0; HIDDEN_UNITS],
+        };
     }
 
-    #[test]
-    fn test_linear_single() {
-        let md = ModelData::IntKeyToIntPos(vec![(1, 2)]);
-
-        let lin_mod = LinearModel::new(&md);
-
-        assert_eq!(lin_mod.predict_to_int(1.into()), 2);
-    }
+    pub fn new<T: TrainingKey>(data: &RMITrainingData<T>) -> NNModel {
+        if data.len() == 0 {
+            return NNModel::constant(0.0);
+        }
 
-    #[test]
-    fn test_empty() {
-        LinearModel::new(&ModelData::empty());
-    }
+        let (first_key, first_pos) = data.get(0);
+        let (last_key, last_pos) = data.get(data.len() - 1);
+        let (xmin, xmax) = (first_key.as_float(), last_key.as_float());
+        let (ymin, ymax) = (first_pos as f64, last_pos as f64);
 
-}
+        if xmax <= xmin || ymax <= ymin {
+            return NNModel::constant(ymin);
+        }
 
-pub struct LogLinearModel {
-    params: (f64, f64),
-}
+        let xinv = 1.0 / (xmax - xmin);
+        let yrange = ymax - ymin;
+
+        // train on evenly spaced points of the (normalized) CDF
+        let num_pts = usize::min(data.len(), MAX_TRAINING_POINTS);
+        let pts: Vec<(f64, f64)> = (0..num_pts)
+            .map(|i| if num_pts == 1 { 0 } else { i * (data.len() - 1) / (num_pts - 1) })
+            .map(|idx| data.get(idx))
+            .map(|(k, pos)| ((k.as_float() - xmin) * xinv, (pos as f64 - ymin) / yrange))
+            .collect();
+
+        // start from the linear interpolation of the CDF between equally
+        // spaced quantiles: unit i ramps from 0 to 1 between knot i and i+1.
+        let mut weights = vec![0.0; HIDDEN_UNITS];
+        let mut biases = vec![0.0; HIDDEN_UNITS];
+        let mut outputs = vec![0.0; HIDDEN_UNITS];
+        for i in 0..HIDDEN_UNITS {
+            let (x0, y0) = pts[i * (num_pts - 1) / HIDDEN_UNITS];
+            let (x1, y1) = pts[(i + 1) * (num_pts - 1) / HIDDEN_UNITS];
+            if x1 > x0 {
+                weights[i] = 1.0 / (x1 - x0);
+                biases[i] = -x0 * weights[i];
+            } else {
+                // duplicate keys: a (nearly) vertical step ending at x0
+                weights[i] = 1e9;
+                biases[i] = 1.0 - x0 * weights[i];
+            }
+            outputs[i] = y1 - y0;
+        }
 
-fn exp1(inp: f64) -> f64 {
-    let mut x = inp;
-    x = 1.0 + x / 64.0;
-    x *= x;
-    x *= x;
-    x *= x;
-    x *= x;
-    x *= x;
-    x *= x;
-    return x;
-}
+        let mut best_loss = mse(&weights, &biases, &outputs, &pts);
+        let mut best = (weights.clone(), biases.clone(), outputs.clone());
+        let initial_loss = best_loss;
+
+        let mut moments = vec![(vec![0.0; HIDDEN_UNITS], vec![0.0; HIDDEN_UNITS]); 3];
+        for epoch in 1..=EPOCHS {
+            let mut grad_w = vec![0.0; HIDDEN_UNITS];
+            let mut grad_b = vec![0.0; HIDDEN_UNITS];
+            let mut grad_a = vec![0.0; HIDDEN_UNITS];
+            let scale = 2.0 / num_pts as f64;
+
+            for &(x, y) in pts.iter() {
+                let r = forward(&weights, &biases, &outputs, x) - y;
+                for i in 0..HIDDEN_UNITS {
+                    let z = weights[i].mul_add(x, biases[i]);
+                    grad_a[i] += scale * r * clip(z);
+                    if z > 0.0 && z < 1.0 {
+                        let dz = scale * r * outputs[i];
+                        grad_w[i] += dz * x;
+                        grad_b[i] += dz;
+                    }
+                }
+            }
+
+            let (m, v) = &mut moments[0];
+            adam_step(&mut weights, &grad_w, m, v, epoch, true);
+            let (m, v) = &mut moments[1];
+            adam_step(&mut biases, &grad_b, m, v, epoch, false);
+            let (m, v) = &mut moments[2];
+            adam_step(&mut outputs, &grad_a, m, v, epoch, true);
+
+            let loss = mse(&weights, &biases, &outputs, &pts);
+            if loss < best_loss {
+                best_loss = loss;
+                best = (weights.clone(), biases.clone(), outputs.clone());
+            }
+        }
 
-impl LogLinearModel {
-    pub fn new<T: TrainingKey>(data: &RMITrainingData<T>) -> LogLinearModel {
-        return LogLinearModel {
-            params: loglinear_slr(&data),
+        info!("NN model trained on {} points, MSE {} -> {}", num_pts, initial_loss, best_loss);
+        let (weights, biases, outputs) = best;
+        return NNModel { xmin, xinv, ymin, yrange, weights, biases, outputs };
+    }
+
+    // rebuilds a model from its params(), e.g. from a training checkpoint
+    pub fn from_params(params: &[ModelParam]) -> Option<NNModel> {
+        return match params {
+            [ModelParam::Float(xmin), ModelParam::Float(xinv),
+             ModelParam::Float(ymin), ModelParam::Float(yrange),
+             ModelParam::FloatArray(weights), ModelParam::FloatArray(biases),
+             ModelParam::FloatArray(outputs)] => Some(NNModel {
+                xmin: *xmin, xinv: *xinv, ymin: *ymin, yrange: *yrange,
+                weights: weights.clone(), biases: biases.clone(), outputs: outputs.clone()
+            }),
+            _ => None,
         };
     }
 }
 
-impl Model for LogLinearModel {
+impl Model for NNModel {
     fn predict_to_float(&self, inp: &ModelInput) -> f64 {
-        let (alpha, beta) = self.params;
-        return exp1(beta.mul_add(inp.as_float(), alpha));
+        let x = (inp.as_float() - self.xmin) * self.xinv;
+        let acc = forward(&self.weights, &self.biases, &self.outputs, x);
+        return acc.mul_add(self.yrange, self.ymin);
     }
 
     fn input_type(&self) -> ModelDataType {
@@ -190,111 +196,103 @@ impl Model for LogLinearModel {
     }
 
     fn params(&self) -> Vec<ModelParam> {
-        return vec![self.params.0.into(), self.params.1.into()];
+        return vec![
+            self.xmin.into(),
+            self.xinv.into(),
+            self.ymin.into(),
+            self.yrange.into(),
+            ModelParam::FloatArray(self.weights.clone()),
+            ModelParam::FloatArray(self.biases.clone()),
+            ModelParam::FloatArray(self.outputs.clone()),
+        ];
     }
 
     fn code(&self) -> String {
-        return String::from(
+        return format!(
             "
-inline double loglinear(double alpha, double beta, double inp) {
-    return exp1(std::fma(beta, inp, alpha));
-}",
+inline double nn(double xmin, double xinv, double ymin, double yrange,
+                 const double weights[], const double biases[],
+                 const double outputs[], double inp) {{
+    double x = (inp - xmin) * xinv;
+    double acc = 0.0;
+    for (unsigned int i = 0; i < {}; i++) {{
+        double h = std::fmin(std::fmax(std::fma(weights[i], x, biases[i]), 0.0), 1.0);
+        acc = std::fma(outputs[i], h, acc);
+    }}
+    return std::fma(acc, yrange, ymin);
+}}",
+            HIDDEN_UNITS
         );
     }
 
     fn function_name(&self) -> String {
-        return String::from("loglinear");
+        return String::from("nn");
+    }
+
+    fn restriction(&self) -> ModelRestriction {
+        return ModelRestriction::MustBeTop;
     }
-    fn standard_functions(&self) -> HashSet<StdFunctions> {
-        let mut to_r = HashSet::new();
-        to_r.insert(StdFunctions::EXP1);
-        return to_r;
+
+    fn set_to_constant_model(&mut self, constant: u64) -> bool {
+        *self = NNModel::constant(constant as f64);
+        return true;
     }
 }
 
 #[cfg(test)]
-mod loglin_tests {
+mod tests {
     use super::*;
 
-    #[test]
-    fn test_loglinear1() {
-        let md = ModelData::IntKeyToIntPos(vec![(2, 2), (3, 4), (4, 16)]);
-
-        let loglin_mod = LogLinearModel::new(&md);
-
-        assert_eq!(loglin_mod.predict_to_int(2.into()), 1);
-        assert_eq!(loglin_mod.predict_to_int(4.into()), 13);
+    fn cdf(keys: Vec<u64>) -> RMITrainingData<u64> {
+        let data: Vec<(u64, usize)> = keys.into_iter().enumerate().map(|(i, k)| (k, i)).collect();
+        return RMITrainingData::new(Box::new(data));
     }
 
     #[test]
-    fn test_empty() {
-        LogLinearModel::new(&ModelData::empty());
-    }
-}
-
-
-pub struct RobustLinearModel {
-    params: (f64, f64),
-}
-
-
-impl RobustLinearModel {
-    pub fn new<T: TrainingKey>(data: &RMITrainingData<T>) -> RobustLinearModel {
-        let total_items = data.len();
-        if data.len() == 0 {
-            return RobustLinearModel {
-                params: (0.0, 0.0)
-            };
+    fn test_nn_monotone() {
+        // a skewed CDF that a single line fits poorly
+        let md = cdf((0..5000u64).map(|i| i * i * i / 1000 + i).collect());
+        let nn = NNModel::new(&md);
+
+        let mut probes: Vec<u64> = md.iter().take(md.len())
+            .flat_map(|(key, _)| vec![key, key + 1, key + 500])
+            .collect();
+        probes.sort();
+        let mut last = 0.0;
+        for probe in probes {
+            let pred = nn.predict_to_float(&probe.to_model_input());
+            assert!(pred >= last);
+            last = pred;
         }
-        
-        let bnd = usize::max(1, ((total_items as f64) * 0.0001) as usize);
-        assert!(bnd*2+1 < data.len());
-        
-        let iter = data.iter()
-            .skip(bnd)
-            .take(data.len() - 2*bnd);
-
-        let robust_params = slr(iter
-                                .map(|(inp, offset)| (inp.as_float(), offset as f64)));
-        
-        return RobustLinearModel {
-            params: robust_params
-        };
-    }
-}
-
-impl Model for RobustLinearModel {
-    fn predict_to_float(&self, inp: &ModelInput) -> f64 {
-        let (alpha, beta) = self.params;
-        return beta.mul_add(inp.as_float(), alpha);
-    }
-
-    fn input_type(&self) -> ModelDataType {
-        return ModelDataType::Float;
-    }
-    fn output_type(&self) -> ModelDataType {
-        return ModelDataType::Float;
-    }
 
-    fn params(&self) -> Vec<ModelParam> {
-        return vec![self.params.0.into(), self.params.1.into()];
+        let nn_err: f64 = md.iter().take(md.len())
+            .map(|(k, pos)| (nn.predict_to_float(&k.to_model_input()) - pos as f64).abs())
+            .fold(0.0, f64::max);
+        let lin = LinearModel::new(&md);
+        let lin_err: f64 = md.iter().take(md.len())
+            .map(|(k, pos)| (lin.predict_to_float(&k.to_model_input()) - pos as f64).abs())
+            .fold(0.0, f64::max);
+        assert!(nn_err < lin_err / 4.0, "nn error {} linear error {}", nn_err, lin_err);
     }
 
-    fn code(&self) -> String {
-        return String::from(
-            "
-inline double linear(double alpha, double beta, double inp) {
-    return std::fma(beta, inp, alpha);
-}",
-        );
-    }
-    
-    fn function_name(&self) -> String {
-        return String::from("linear");
+    #[test]
+    fn test_nn_params_layout() {
+        let nn = NNModel::new(&cdf((1..100u64).collect()));
+        let params = nn.params();
+        assert_eq!(params.len(), 7);
+        for p in params.iter().skip(4) {
+            assert_eq!(p.len(), HIDDEN_UNITS);
+        }
     }
 
-    fn set_to_constant_model(&mut self, constant: u64) -> bool {
-        self.params = (constant as f64, 0.0);
-        return true;
+    #[test]
+    fn test_nn_degenerate() {
+        let empty = NNModel::new(&cdf(Vec::new()));
+        assert_eq!(empty.predict_to_int(&5u64.to_model_input()), 0);
+
+        let mut single = NNModel::new(&cdf(vec![7]));
+        assert_eq!(single.predict_to_int(&7u64.to_model_input()), 0);
+        assert!(single.set_to_constant_model(3));
+        assert_eq!(single.predict_to_int(&100u64.to_model_input()), 3);
     }
 }
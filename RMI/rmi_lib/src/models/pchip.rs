@@ -0,0 +1,224 @@
+// < begin copyright > 
+// Copyright Ryan Marcus 2020
+// 
+// See root directory of this project for license terms.
+// 
+// < end copyright > 
+ 
+
+use crate::models::*;
+
+// Monotone piecewise cubic Hermite (PCHIP) spline with a fixed number of
+// knots, placed at evenly spaced quantiles of the leaf's keys. The slopes at
+// the knots are the weighted harmonic means of Fritsch and Butland, which
+// keep every piece monotone. Leaves with fewer distinct knots repeat their
+// last knot, so that every leaf of a layer has the same parameter layout.
+// Each piece is evaluated with the same sequence of fma operations in Rust
+// and in the generated code, so errors measured here hold at lookup time.
+pub struct PchipModel {
+    xs: Vec<f64>,
+    ys: Vec<f64>,
+    ms: Vec<f64>,
+}
+
+// slopes at the knots, assuming non-decreasing ys
+fn pchip_slopes(xs: &[f64], ys: &[f64]) -> Vec<f64> {
+    let n = xs.len();
+    let hs: Vec<f64> = (0..n - 1).map(|i| xs[i + 1] - xs[i]).collect();
+    let deltas: Vec<f64> = (0..n - 1).map(|i| (ys[i + 1] - ys[i]) / hs[i]).collect();
+
+    let mut ms = vec![0.0; n];
+    ms[0] = deltas[0];
+    ms[n - 1] = deltas[n - 2];
+    for i in 1..n - 1 {
+        if deltas[i - 1] > 0.0 && deltas[i] > 0.0 {
+            let w1 = 2.0 * hs[i] + hs[i - 1];
+            let w2 = hs[i] + 2.0 * hs[i - 1];
+            ms[i] = (w1 + w2) / (w1 / deltas[i - 1] + w2 / deltas[i]);
+        }
+    }
+    return ms;
+}
+
+impl PchipModel {
+    fn constant(num_knots: usize, value: f64) -> PchipModel {
+        return PchipModel {
+            xs: vec![0.0; num_knots],
+            ys: vec![value; num_knots],
+            ms: vec![0.0; num_knots],
+        };
+    }
+
+    pub fn new<T: TrainingKey>(data: &RMITrainingData<T>, num_knots: usize) -> PchipModel {
+        assert!(num_knots >= 2, "pchip models need at least two knots");
+        if data.len() == 0 {
+            return PchipModel::constant(num_knots, 0.0);
+        }
+
+        let mut xs: Vec<f64> = Vec::with_capacity(num_knots);
+        let mut ys: Vec<f64> = Vec::with_capacity(num_knots);
+        for j in 0..num_knots {
+            let (key, pos) = data.get(j * (data.len() - 1) / (num_knots - 1));
+            let x = key.as_float();
+            if xs.last().map(|&last| x > last).unwrap_or(true) {
+                xs.push(x);
+                ys.push(pos as f64);
+            }
+        }
+
+        if xs.len() < 2 {
+            return PchipModel::constant(num_knots, ys[0]);
+        }
+
+        let mut ms = pchip_slopes(&xs, &ys);
+        let (last_x, last_y) = (xs[xs.len() - 1], ys[ys.len() - 1]);
+        xs.resize(num_knots, last_x);
+        ys.resize(num_knots, last_y);
+        ms.resize(num_knots, 0.0);
+        return PchipModel { xs, ys, ms };
+    }
+
+    pub fn num_knots(&self) -> usize {
+        return self.xs.len();
+    }
+}
+
+impl Model for PchipModel {
+    fn predict_to_float(&self, inp: &ModelInput) -> f64 {
+        let n = self.xs.len();
+        let x = inp.as_float();
+        if x <= self.xs[0] {
+            return self.ys[0];
+        }
+        if x >= self.xs[n - 1] {
+            return self.ys[n - 1];
+        }
+
+        let i = self.xs.partition_point(|k| *k <= x) - 1;
+        let h = self.xs[i + 1] - self.xs[i];
+        let t = (x - self.xs[i]) / h;
+        let d = self.ys[i + 1] - self.ys[i];
+        let a = h * self.ms[i];
+        let b = h * self.ms[i + 1];
+        let c2 = 3.0f64.mul_add(d, (-2.0f64).mul_add(a, -b));
+        let c3 = (-2.0f64).mul_add(d, a + b);
+        return c3.mul_add(t, c2).mul_add(t, a).mul_add(t, self.ys[i]);
+    }
+
+    fn input_type(&self) -> ModelDataType {
+        return ModelDataType::Float;
+    }
+    fn output_type(&self) -> ModelDataType {
+        return ModelDataType::Float;
+    }
+
+    fn params(&self) -> Vec<ModelParam> {
+        return vec![
+            ModelParam::FloatArray(self.xs.clone()),
+            ModelParam::FloatArray(self.ys.clone()),
+            ModelParam::FloatArray(self.ms.clone()),
+        ];
+    }
+
+    fn code(&self) -> String {
+        return format!(
+            "
+inline double pchip(const double xs[], const double ys[], const double ms[], double inp) {{
+    const uint64_t n = {};
+    if (inp <= xs[0]) return ys[0];
+    if (inp >= xs[n - 1]) return ys[n - 1];
+
+    uint64_t lo = 0, hi = n;
+    while (lo < hi) {{
+        uint64_t mid = (lo + hi) / 2;
+        if (inp >= xs[mid]) lo = mid + 1; else hi = mid;
+    }}
+    uint64_t i = lo - 1;
+
+    double h = xs[i + 1] - xs[i];
+    double t = (inp - xs[i]) / h;
+    double d = ys[i + 1] - ys[i];
+    double a = h * ms[i];
+    double b = h * ms[i + 1];
+    double c2 = std::fma(3.0, d, std::fma(-2.0, a, -b));
+    double c3 = std::fma(-2.0, d, a + b);
+    return std::fma(std::fma(std::fma(c3, t, c2), t, a), t, ys[i]);
+}}",
+            self.xs.len()
+        );
+    }
+
+    fn function_name(&self) -> String {
+        return String::from("pchip");
+    }
+
+    fn set_to_constant_model(&mut self, constant: u64) -> bool {
+        *self = PchipModel::constant(self.num_knots(), constant as f64);
+        return true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cdf(keys: Vec<u64>) -> RMITrainingData<u64> {
+        let data: Vec<(u64, usize)> = keys.into_iter().enumerate().map(|(i, k)| (k, i)).collect();
+        return RMITrainingData::new(Box::new(data));
+    }
+
+    fn max_error(model: &dyn Model, md: &RMITrainingData<u64>) -> u64 {
+        return md.iter().take(md.len())
+            .map(|(k, pos)| {
+                let pred = model.predict_to_int(&k.to_model_input());
+                u64::max(pred, pos as u64) - u64::min(pred, pos as u64)
+            })
+            .max()
+            .unwrap();
+    }
+
+    #[test]
+    fn test_pchip_monotone() {
+        // a smooth CDF with a sharp bend
+        let md = cdf((0..5000u64).map(|i| if i < 2500 { i * 10 } else { 25000 + (i - 2500) * (i - 2500) }).collect());
+        let pchip = PchipModel::new(&md, 16);
+        assert_eq!(pchip.params().len(), 3);
+        assert!(pchip.params().iter().all(|p| p.len() == 16));
+
+        let mut last = 0.0;
+        for probe in 0..6_300_000u64 {
+            if probe % 97 != 0 {
+                continue;
+            }
+            let pred = pchip.predict_to_float(&probe.to_model_input());
+            assert!(pred >= last, "not monotone at {}", probe);
+            last = pred;
+        }
+
+        // knots are interpolated exactly, and more knots help
+        assert_eq!(pchip.predict_to_int(&16660u64.to_model_input()), 1666);
+        let few = PchipModel::new(&md, 4);
+        assert!(max_error(&pchip, &md) < max_error(&few, &md));
+        assert!(max_error(&pchip, &md) < max_error(&LinearModel::new(&md), &md) / 4);
+    }
+
+    #[test]
+    fn test_pchip_duplicates() {
+        // fewer distinct keys than knots: the last knot is repeated
+        let md = cdf(vec![5, 5, 5, 9, 9, 9, 9, 20]);
+        let pchip = PchipModel::new(&md, 8);
+        assert_eq!(pchip.num_knots(), 8);
+        assert_eq!(pchip.predict_to_int(&5u64.to_model_input()), 0);
+        assert_eq!(pchip.predict_to_int(&20u64.to_model_input()), 7);
+        assert_eq!(pchip.predict_to_int(&100u64.to_model_input()), 7);
+    }
+
+    #[test]
+    fn test_pchip_constant() {
+        let mut pchip = PchipModel::new(&cdf(Vec::new()), 4);
+        assert_eq!(pchip.predict_to_int(&3u64.to_model_input()), 0);
+        assert!(pchip.set_to_constant_model(11));
+        assert_eq!(pchip.predict_to_int(&3u64.to_model_input()), 11);
+        assert_eq!(pchip.predict_to_int(&0u64.to_model_input()), 11);
+    }
+}
@@ -17,6 +17,14 @@ impl PiecewiselinearModel {
     pub fn new<T: TrainingKey>(data: &RMITrainingData<T>, params: u64) -> PiecewiselinearModel {
         return PiecewiselinearModel { params };
     }
+
+    // rebuilds a model from its params(), e.g. from a training checkpoint
+    pub fn from_params(params: &[ModelParam]) -> Option<PiecewiselinearModel> {
+        return match params {
+            [ModelParam::Int(kmer)] => Some(PiecewiselinearModel { params: *kmer }),
+            _ => None,
+        };
+    }
 }
 
 impl Model for PiecewiselinearModel {
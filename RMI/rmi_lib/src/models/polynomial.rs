@@ -0,0 +1,432 @@
+// < begin copyright > 
+// Copyright Ryan Marcus 2020
+// 
+// See root directory of this project for license terms.
+// 
+// < end copyright > 
+ 
+
+use crate::models::*;
+use log::*;
+
+// Least-squares polynomial of degree 2 to 4. Keys are first normalized to
+// u = (x - xmin) / (xmax - xmin) in [0, 1], and the normal equations are
+// built and solved in 512-bit precision, since the power sums of large keys
+// are far outside of what an f64 can hold exactly. Inputs outside of the
+// fitted keys are clamped to the ends of the range.
+pub struct PolynomialModel {
+    xmin: f64,
+    xinv: f64,
+    // 128 and 512-bit keys lose their low bits as an f64, so they are
+    // normalized with a 512-bit xmin and xinv instead
+    wide: Option<(Float, Float)>,
+    coeffs: Vec<f64>,
+}
+
+fn horner(coeffs: &[f64], u: f64) -> f64 {
+    let mut v = coeffs[coeffs.len() - 1];
+    for c in coeffs.iter().rev().skip(1) {
+        v = v.mul_add(u, *c);
+    }
+    return v;
+}
+
+// the coefficients of the derivative of a polynomial
+fn derivative(coeffs: &[f64]) -> Vec<f64> {
+    if coeffs.len() < 2 {
+        return vec![0.0];
+    }
+    return coeffs.iter().enumerate().skip(1).map(|(k, c)| k as f64 * c).collect();
+}
+
+// the roots of the slope of a polynomial in [0, 1]. Between the roots of the
+// second derivative, at most a quadratic, the slope is monotone and has at
+// most one root, which is found by bisection.
+fn slope_roots(coeffs: &[f64]) -> Vec<f64> {
+    let slope = derivative(coeffs);
+    let curve = derivative(&slope);
+    let mut bounds = vec![0.0, 1.0];
+    match curve.as_slice() {
+        [c0, c1] if *c1 != 0.0 => bounds.push(-c0 / c1),
+        [c0, c1, c2] if *c2 != 0.0 => {
+            let disc = c1 * c1 - 4.0 * c2 * c0;
+            if disc >= 0.0 {
+                bounds.push((-c1 + disc.sqrt()) / (2.0 * c2));
+                bounds.push((-c1 - disc.sqrt()) / (2.0 * c2));
+            }
+        },
+        [c0, c1, _] if *c1 != 0.0 => bounds.push(-c0 / c1),
+        _ => {}
+    }
+    bounds.retain(|u| (0.0..=1.0).contains(u));
+    bounds.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+    let mut roots = Vec::new();
+    for w in bounds.windows(2) {
+        let (mut lo, mut hi) = (w[0], w[1]);
+        let negative_at_lo = horner(&slope, lo) < 0.0;
+        if negative_at_lo == (horner(&slope, hi) < 0.0) {
+            continue;
+        }
+        for _ in 0..64 {
+            let mid = (lo + hi) / 2.0;
+            if (horner(&slope, mid) < 0.0) == negative_at_lo {
+                lo = mid;
+            } else {
+                hi = mid;
+            }
+        }
+        roots.push((lo + hi) / 2.0);
+    }
+    return roots;
+}
+
+// true if no two keys in the fitted range, absent ones included, get
+// decreasing predictions. Keys are step apart in [0, 1] (0 for float keys).
+// The polynomial only decreases from a local maximum to the next local
+// minimum, so the keys around those two points are the ones to compare.
+fn nondecreasing_between_keys(coeffs: &[f64], step: f64) -> bool {
+    let slope = derivative(coeffs);
+    let mut points = vec![0.0];
+    points.extend(slope_roots(coeffs));
+    points.push(1.0);
+
+    let keys_around = |u: f64| -> Vec<f64> {
+        if step == 0.0 {
+            return vec![u];
+        }
+        return vec![(u / step).floor() * step, (u / step).ceil() * step].into_iter()
+            .filter(|k| (0.0..=1.0).contains(k))
+            .collect();
+    };
+    for w in points.windows(2) {
+        let (a, b) = (w[0], w[1]);
+        if b <= a || horner(&slope, (a + b) / 2.0) >= 0.0 {
+            continue;
+        }
+        for x1 in keys_around(a) {
+            for &x2 in keys_around(b).iter().filter(|&&x2| x2 > x1) {
+                if horner(coeffs, x1) > horner(coeffs, x2) {
+                    return false;
+                }
+            }
+        }
+    }
+    return true;
+}
+
+// solves the (degree + 1) x (degree + 1) normal equations with Gaussian
+// elimination and partial pivoting. Returns None if they are singular.
+fn solve(mut a: Vec<Vec<Float>>, mut b: Vec<Float>) -> Option<Vec<Float>> {
+    let n = b.len();
+    for col in 0..n {
+        let pivot = (col..n)
+            .max_by(|&i, &j| a[i][col].clone().abs().partial_cmp(&a[j][col].clone().abs()).unwrap())
+            .unwrap();
+        if a[pivot][col].is_zero() {
+            return None;
+        }
+        a.swap(col, pivot);
+        b.swap(col, pivot);
+
+        for row in (col + 1)..n {
+            let factor = Float::with_val(512, &a[row][col] / &a[col][col]);
+            for k in col..n {
+                let delta = Float::with_val(512, &factor * &a[col][k]);
+                a[row][k] -= delta;
+            }
+            let delta = Float::with_val(512, &factor * &b[col]);
+            b[row] -= delta;
+        }
+    }
+
+    let mut x = vec![Float::new(512); n];
+    for row in (0..n).rev() {
+        let mut acc = b[row].clone();
+        for k in (row + 1)..n {
+            acc -= Float::with_val(512, &a[row][k] * &x[k]);
+        }
+        x[row] = Float::with_val(512, acc / &a[row][row]);
+    }
+    return Some(x);
+}
+
+fn fit<T: TrainingKey>(data: &RMITrainingData<T>, xmin: &Float, xinv: &Float,
+                       degree: usize) -> Option<Vec<f64>> {
+    let n = degree + 1;
+    let mut power_sums = vec![Float::new(512); 2 * degree + 1];
+    let mut moments = vec![Float::new(512); n];
+
+    for (key, pos) in data.iter().take(data.len()) {
+        let u = Float::with_val(512, (key.as_float512() - xmin) * xinv);
+        let y = Float::with_val(512, pos);
+        let mut p = Float::with_val(512, 1);
+        for k in 0..power_sums.len() {
+            if k < n {
+                moments[k] += Float::with_val(512, &p * &y);
+            }
+            power_sums[k] += &p;
+            p *= &u;
+        }
+    }
+
+    let a: Vec<Vec<Float>> = (0..n)
+        .map(|j| (0..n).map(|k| power_sums[j + k].clone()).collect())
+        .collect();
+    return solve(a, moments)
+        .map(|c| c.iter().map(|v| v.to_f64()).collect());
+}
+
+impl PolynomialModel {
+    fn constant(degree: usize, value: u64) -> PolynomialModel {
+        let mut coeffs = vec![0.0; degree + 1];
+        coeffs[0] = value as f64;
+        return PolynomialModel { xmin: 0.0, xinv: 0.0, wide: None, coeffs };
+    }
+
+    pub fn new<T: TrainingKey>(data: &RMITrainingData<T>, degree: usize) -> PolynomialModel {
+        assert!((2..=4).contains(&degree), "polynomial degree must be between 2 and 4");
+
+        let mut model = PolynomialModel::constant(degree, 0);
+        if data.len() == 0 {
+            return model;
+        }
+
+        let first_key = data.get(0).0;
+        let last_key = data.get(data.len() - 1).0;
+        let distinct = data.iter().take(data.len())
+            .filter(|(k, _)| !k.same_encoding(&first_key))
+            .take(degree)
+            .count() + 1;
+
+        // too few distinct keys to determine the polynomial
+        if distinct <= degree {
+            model.set_to_constant_model(data.get(0).1 as u64);
+            return model;
+        }
+
+        let xmin = first_key.as_float512();
+        let xinv = Float::with_val(512, 1.0 / Float::with_val(512, last_key.as_float512() - &xmin));
+        let wide = match T::key_type() {
+            KeyType::U128 | KeyType::U512 => Some((xmin.clone(), xinv.clone())),
+            _ => None
+        };
+        // consecutive integer keys are xinv apart once normalized
+        let step = match T::key_type() {
+            KeyType::F64 | KeyType::F512 => 0.0,
+            _ => xinv.to_f64()
+        };
+
+        // if the polynomial is not monotone over the keys and between them,
+        // fall back to a straight line through the same points.
+        for deg in &[degree, 1] {
+            if let Some(mut coeffs) = fit(data, &xmin, &xinv, *deg) {
+                coeffs.resize(degree + 1, 0.0);
+                let candidate = PolynomialModel { xmin: xmin.to_f64(), xinv: xinv.to_f64(),
+                                                  wide: wide.clone(), coeffs };
+                if candidate.is_monotone(data) && nondecreasing_between_keys(&candidate.coeffs, step) {
+                    return candidate;
+                }
+                debug!("Degree {} polynomial was not monotone over {} keys", deg, data.len());
+            }
+        }
+
+        model.set_to_constant_model(data.get(0).1 as u64);
+        return model;
+    }
+
+    // rebuilds a model from its params(), e.g. from a training checkpoint
+    pub fn from_params(params: &[ModelParam]) -> Option<PolynomialModel> {
+        if let [ModelParam::Float512(xmin), ModelParam::Float512(xinv), coeffs @ ..] = params {
+            let mut model = PolynomialModel::from_params(
+                &[&[xmin.to_f64().into(), xinv.to_f64().into()], coeffs].concat())?;
+            model.wide = Some((xmin.clone(), xinv.clone()));
+            return Some(model);
+        }
+        let floats: Option<Vec<f64>> = params.iter()
+            .map(|p| match p { ModelParam::Float(v) => Some(*v), _ => None })
+            .collect();
+        return match floats?.as_slice() {
+            [xmin, xinv, coeffs @ ..] if (3..=5).contains(&coeffs.len()) =>
+                Some(PolynomialModel { xmin: *xmin, xinv: *xinv, wide: None, coeffs: coeffs.to_vec() }),
+            _ => None,
+        };
+    }
+
+    pub fn degree(&self) -> usize {
+        return self.coeffs.len() - 1;
+    }
+
+    // true if the predictions never decrease from one key to the next
+    pub fn is_monotone<T: TrainingKey>(&self, data: &RMITrainingData<T>) -> bool {
+        let mut last = f64::NEG_INFINITY;
+        for (key, _) in data.iter().take(data.len()) {
+            let pred = self.predict_to_float(&key.to_model_input());
+            if pred < last {
+                return false;
+            }
+            last = pred;
+        }
+        return true;
+    }
+}
+
+impl Model for PolynomialModel {
+    fn predict_to_float(&self, inp: &ModelInput) -> f64 {
+        let u = match &self.wide {
+            Some((xmin, xinv)) => Float::with_val(512, (inp.as_float512() - xmin) * xinv).to_f64(),
+            None => (inp.as_float() - self.xmin) * self.xinv
+        };
+        // clamp to the fitted range, the polynomial can turn around outside of it
+        let u = f64::min(f64::max(u, 0.0), 1.0);
+        return horner(&self.coeffs, u);
+    }
+
+    fn input_type(&self) -> ModelDataType {
+        return match self.wide {
+            Some(_) => ModelDataType::Float512,
+            None => ModelDataType::Float
+        };
+    }
+    fn output_type(&self) -> ModelDataType {
+        return ModelDataType::Float;
+    }
+
+    fn params(&self) -> Vec<ModelParam> {
+        let mut params: Vec<ModelParam> = match &self.wide {
+            Some((xmin, xinv)) => vec![ModelParam::Float512(xmin.clone()), ModelParam::Float512(xinv.clone())],
+            None => vec![self.xmin.into(), self.xinv.into()]
+        };
+        params.extend(self.coeffs.iter().map(|&c| ModelParam::from(c)));
+        return params;
+    }
+
+    fn code(&self) -> String {
+        let degree = self.degree();
+        let args: Vec<String> = (0..=degree).map(|i| format!("double c{}", i)).collect();
+        let steps: Vec<String> = (0..degree).rev()
+            .map(|i| format!("    v = std::fma(v, u, c{});", i))
+            .collect();
+        let (key_type, normalized) = match self.wide {
+            Some(_) => ("cpp_bin_float_512", "((inp - xmin) * xinv).convert_to<double>()"),
+            None => ("double", "(inp - xmin) * xinv")
+        };
+        return format!(
+            "
+inline double {name}({t} xmin, {t} xinv, {args}, {t} inp) {{
+    double u = std::fmin(std::fmax({normalized}, 0.0), 1.0);
+    double v = c{d};
+{steps}
+    return v;
+}}",
+            name = self.function_name(), t = key_type, normalized = normalized,
+            d = degree, args = args.join(", "), steps = steps.join("\n")
+        );
+    }
+
+    fn function_name(&self) -> String {
+        return match self.wide {
+            Some(_) => format!("poly{}_big", self.degree()),
+            None => format!("poly{}", self.degree())
+        };
+    }
+
+    fn set_to_constant_model(&mut self, constant: u64) -> bool {
+        *self = PolynomialModel::constant(self.degree(), constant);
+        return true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cdf(keys: Vec<u64>) -> RMITrainingData<u64> {
+        let data: Vec<(u64, usize)> = keys.into_iter().enumerate().map(|(i, k)| (k, i)).collect();
+        return RMITrainingData::new(Box::new(data));
+    }
+
+    fn max_error(model: &dyn Model, md: &RMITrainingData<u64>) -> f64 {
+        return md.iter().take(md.len())
+            .map(|(k, pos)| (model.predict_to_float(&k.to_model_input()) - pos as f64).abs())
+            .fold(0.0, f64::max);
+    }
+
+    #[test]
+    fn test_poly_fits_curve() {
+        // positions grow like key^(5/3), far from the origin
+        let md = cdf((0..2000u64).map(|i| (1 << 40) + ((i as f64).powf(0.6) * 1e6) as u64).collect());
+        let lin = LinearModel::new(&md);
+        for degree in 2..=4 {
+            let poly = PolynomialModel::new(&md, degree);
+            assert_eq!(poly.degree(), degree);
+            assert_eq!(poly.params().len(), degree + 3);
+            assert!(poly.is_monotone(&md));
+            assert!(max_error(&poly, &md) < max_error(&lin, &md) / 2.0);
+        }
+    }
+
+    #[test]
+    fn test_poly_exact() {
+        // an exact quadratic CDF is recovered
+        let keys: Vec<u64> = (0..100u64).collect();
+        let data: Vec<(u64, usize)> = keys.iter().map(|&k| (k, (k * k / 10) as usize)).collect();
+        let md = RMITrainingData::new(Box::new(data));
+        let poly = PolynomialModel::new(&md, 2);
+        assert!(max_error(&poly, &md) < 1.0);
+    }
+
+    #[test]
+    fn test_poly_wide_keys() {
+        // consecutive 128-bit keys are not distinct as f64
+        let base: u128 = (1 << 100) + 12345;
+        let data: Vec<(u128, usize)> = (0..1000u128).map(|i| (base + i, (i * i / 1000) as usize)).collect();
+        let md = RMITrainingData::new(Box::new(data.clone()));
+        let poly = PolynomialModel::new(&md, 2);
+        assert_eq!(poly.function_name(), "poly2_big");
+        assert!(matches!(poly.params()[0], ModelParam::Float512(_)));
+        for (key, pos) in data.iter() {
+            let pred = poly.predict_to_float(&key.to_model_input());
+            assert!((pred - *pos as f64).abs() < 2.0);
+        }
+
+        let restored = PolynomialModel::from_params(&poly.params()).unwrap();
+        assert_eq!(restored.function_name(), "poly2_big");
+        let pred = restored.predict_to_int(&(base + 500).to_model_input());
+        assert!((249..=251).contains(&pred));
+    }
+
+    #[test]
+    fn test_poly_monotone_between_keys() {
+        // rises, dips and rises again within [0, 1]
+        assert!(!nondecreasing_between_keys(&[0.0, 3.0, -9.0, 7.0], 0.0));
+        assert!(!nondecreasing_between_keys(&[0.0, 1.0, 0.0, -6.0, 5.0], 0.0));
+        assert!(nondecreasing_between_keys(&[0.0, 1.0, 2.0, 3.0], 0.0));
+        // a dip outside of [0, 1] is clamped away
+        assert!(nondecreasing_between_keys(&[0.0, 1.0, -0.4], 0.0));
+        assert!(!nondecreasing_between_keys(&[0.0, 1.0, -0.6], 0.0));
+        // and one between two consecutive integer keys cannot be seen
+        assert!(nondecreasing_between_keys(&[0.0, -0.1, 10.0], 0.1));
+        assert!(!nondecreasing_between_keys(&[0.0, -0.1, 10.0], 0.001));
+
+        // keys on both sides of a dip do not hide it
+        let keys: Vec<u64> = vec![0, 1, 2, 3, 10, 11, 12, 13];
+        let data: Vec<(u64, usize)> = keys.iter().enumerate().map(|(i, &k)| (k, i)).collect();
+        let md = RMITrainingData::new(Box::new(data));
+        let poly = PolynomialModel::new(&md, 4);
+        assert!(nondecreasing_between_keys(&poly.coeffs, 1.0 / 13.0));
+        let preds: Vec<f64> = (0..=13u64).map(|k| poly.predict_to_float(&k.to_model_input())).collect();
+        assert!(preds.windows(2).all(|w| w[0] <= w[1]));
+    }
+
+    #[test]
+    fn test_poly_tiny() {
+        let empty = PolynomialModel::new(&cdf(Vec::new()), 3);
+        assert_eq!(empty.predict_to_int(&5u64.to_model_input()), 0);
+
+        let data: Vec<(u64, usize)> = vec![(10, 4), (20, 5)];
+        let tiny = PolynomialModel::new(&RMITrainingData::new(Box::new(data)), 3);
+        assert_eq!(tiny.predict_to_int(&15u64.to_model_input()), 4);
+        assert_eq!(tiny.params().len(), 6);
+    }
+}
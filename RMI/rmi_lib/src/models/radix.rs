@@ -108,8 +108,9 @@ impl RadixTable {
             last_radix = current_radix;
         }
 
+        // radixes past the last key point past the last item
         for i in (last_radix as usize + 1)..hint_table.len() {
-            hint_table[i as usize] = hint_table.len() as u32; 
+            hint_table[i as usize] = data.len() as u32;
         }
 
         return RadixTable {
@@ -118,6 +119,32 @@ impl RadixTable {
             hint_table
         };
     }
+
+    // number of low bits dropped from a key to get its radix
+    pub fn shift(&self) -> u8 {
+        return if self.prefix_bits + self.table_bits > 64 {
+            0
+        } else {
+            64 - (self.prefix_bits + self.table_bits)
+        };
+    }
+
+    pub fn prefix_bits(&self) -> u8 { return self.prefix_bits; }
+    pub fn table(&self) -> &[u32] { return &self.hint_table; }
+
+    // The first item whose key is >= x lies in [lo, hi]: lo is the first
+    // item with the same radix as x, hi the first item with a larger radix.
+    pub fn search_bounds(&self, x: u64, num_items: usize) -> (usize, usize) {
+        let radix = ((x << self.prefix_bits) >> self.prefix_bits) >> self.shift();
+        let radix = radix as usize;
+        let lo = self.hint_table[radix] as usize;
+        let hi = if radix + 1 < self.hint_table.len() {
+            self.hint_table[radix + 1] as usize
+        } else {
+            num_items
+        };
+        return (lo, hi);
+    }
 }
 
 impl Model for RadixTable {
@@ -0,0 +1,296 @@
+// < begin copyright > 
+// Copyright Ryan Marcus 2020
+// 
+// See root directory of this project for license terms.
+// 
+// < end copyright > 
+ 
+
+use crate::cache_fix::{bounded_spline, Spline};
+use crate::models::*;
+use log::*;
+
+// A top-layer index: an error-bounded linear spline over the CDF, with a
+// radix table over the spline points to find the right segment. Keys wider
+// than 64 bits are clamped to the range of the training keys and reduced to
+// the 64 bits that follow their common prefix, which keeps them in order
+// (keys that collide are covered by the measured error bound). Without the
+// clamp, the bits of a key outside the range that differ from the prefix
+// would be cut off and the key would wrap around.
+pub struct RadixSplineModel {
+    key_bits: u32,
+    shift: u32,
+    bounds: Option<(ModelInput, ModelInput)>,
+    points: Vec<(u64, usize)>,
+    radix: Option<RadixTable>,
+    error: u64,
+}
+
+fn reduce(inp: &ModelInput, shift: u32) -> u64 {
+    return match inp {
+        ModelInput::Int(x) => *x,
+        ModelInput::Int128(x) => (*x >> shift) as u64,
+        ModelInput::UINT512(x) => (*x >> shift).low_u64(),
+        _ => panic!("radix_spline models require integer keys"),
+    };
+}
+
+// the key, clamped to the range of the training keys
+fn clamp(inp: &ModelInput, bounds: &Option<(ModelInput, ModelInput)>) -> ModelInput {
+    return match bounds {
+        Some((first, _)) if inp < first => *first,
+        Some((_, last)) if inp > last => *last,
+        _ => *inp,
+    };
+}
+
+// a key as a C++ constant of its type
+fn c_constant(inp: &ModelInput) -> String {
+    return match inp {
+        ModelInput::Int128(x) => format!("(((__uint128_t) {}UL << 64) | {}UL)", (x >> 64) as u64, *x as u64),
+        ModelInput::UINT512(x) => format!("uint512_t(\"0x{:x}\")", x),
+        _ => format!("{}UL", inp.as_int()),
+    };
+}
+
+// bits dropped so that the remaining 64 bits start after the common prefix
+fn reduction_shift(first: &ModelInput, last: &ModelInput) -> (u32, u32) {
+    return match (first, last) {
+        (ModelInput::Int(_), ModelInput::Int(_)) => (64, 0),
+        (ModelInput::Int128(a), ModelInput::Int128(b)) => {
+            let prefix = (a ^ b).leading_zeros();
+            (128, (128 - prefix).saturating_sub(64))
+        },
+        (ModelInput::UINT512(a), ModelInput::UINT512(b)) => {
+            let prefix = (*a ^ *b).leading_zeros();
+            (512, (512 - prefix).saturating_sub(64))
+        },
+        _ => panic!("radix_spline models require integer keys"),
+    };
+}
+
+impl RadixSplineModel {
+    pub fn new<T: TrainingKey>(data: &RMITrainingData<T>, max_error: usize,
+                               radix_bits: u8) -> RadixSplineModel {
+        let key_bits = match T::key_type() {
+            KeyType::U128 => 128,
+            KeyType::U512 => 512,
+            _ => 64,
+        };
+        if data.len() == 0 {
+            return RadixSplineModel { key_bits, shift: 0, bounds: None, points: Vec::new(),
+                                      radix: None, error: 0 };
+        }
+
+        let first = data.get(0).0.to_model_input();
+        let last = data.get(data.len() - 1).0.to_model_input();
+        let (_, shift) = reduction_shift(&first, &last);
+        let bounds = if key_bits == 64 { None } else { Some((first, last)) };
+
+        let points = bounded_spline(
+            data.iter().take(data.len())
+                .map(|(k, pos)| (reduce(&k.to_model_input(), shift), pos)),
+            max_error);
+
+        let radix = if points.len() > 1 {
+            let indexed: Vec<(u64, usize)> = points.iter()
+                .enumerate()
+                .map(|(idx, (x, _y))| (*x, idx))
+                .collect();
+            Some(RadixTable::new(&RMITrainingData::new(Box::new(indexed)), radix_bits))
+        } else {
+            None
+        };
+
+        let mut model = RadixSplineModel { key_bits, shift, bounds, points, radix, error: 0 };
+
+        // measure the error bound exactly, interpolation rounds
+        model.error = data.iter().take(data.len())
+            .map(|(k, pos)| {
+                let pred = model.predict_to_int(&k.to_model_input());
+                u64::max(pred, pos as u64) - u64::min(pred, pos as u64)
+            })
+            .max()
+            .unwrap();
+
+        info!("Radix spline with {} points (from {} keys), error bound {}",
+              model.points.len(), data.len(), model.error);
+        return model;
+    }
+
+    // index of the first spline point whose key is >= x
+    fn segment(&self, x: u64) -> usize {
+        let n = self.points.len();
+        if x <= self.points[0].0 {
+            return 0;
+        }
+        if x > self.points[n - 1].0 {
+            return n;
+        }
+
+        let (lo, hi) = self.radix.as_ref().unwrap().search_bounds(x, n);
+        return lo + self.points[lo..hi].partition_point(|(px, _)| *px < x);
+    }
+}
+
+impl Model for RadixSplineModel {
+    fn predict_to_int(&self, inp: &ModelInput) -> u64 {
+        if self.points.is_empty() {
+            return 0;
+        }
+
+        let x = reduce(&clamp(inp, &self.bounds), self.shift);
+        let idx = self.segment(x);
+        if idx == 0 {
+            return self.points[0].1 as u64;
+        }
+        if idx == self.points.len() {
+            return self.points[idx - 1].1 as u64;
+        }
+        return Spline::from(self.points[idx - 1], self.points[idx]).predict(x) as u64;
+    }
+
+    fn input_type(&self) -> ModelDataType {
+        return match self.key_bits {
+            128 => ModelDataType::Int128,
+            512 => ModelDataType::Int512,
+            _ => ModelDataType::Int,
+        };
+    }
+    fn output_type(&self) -> ModelDataType {
+        return ModelDataType::Int;
+    }
+
+    fn params(&self) -> Vec<ModelParam> {
+        let table = self.radix.as_ref()
+            .map(|r| r.table().to_vec())
+            .unwrap_or_default();
+        return vec![
+            ModelParam::IntArray(self.points.iter().map(|(x, _)| *x).collect()),
+            ModelParam::IntArray(self.points.iter().map(|(_, y)| *y as u64).collect()),
+            ModelParam::Int32Array(table),
+        ];
+    }
+
+    fn code(&self) -> String {
+        let (prefix, shift, table_size) = match &self.radix {
+            Some(r) => (r.prefix_bits(), r.shift(), r.table().len()),
+            None => (0, 0, 0),
+        };
+        let key_type = self.input_type().c_type();
+        let reduce = match &self.bounds {
+            None => String::from("inp"),
+            Some((first, last)) => format!(
+                "static_cast<uint64_t>(((inp < {first} ? {first} : (inp > {last} ? {last} : inp)) >> {shift}) & UINT64_MAX)",
+                first = c_constant(first), last = c_constant(last), shift = self.shift),
+        };
+
+        return format!(
+            "
+inline uint64_t radix_spline(const uint64_t xs[], const uint64_t ys[],
+                             const uint32_t table[], {key_type} inp) {{
+    const uint64_t num_points = {n};
+    uint64_t x = {reduce};
+    if (x <= xs[0]) return ys[0];
+    if (x > xs[num_points - 1]) return ys[num_points - 1];
+
+    uint64_t radix = ((x << {prefix}) >> {prefix}) >> {shift};
+    uint64_t lo = table[radix];
+    uint64_t hi = radix + 1 < {table_size} ? table[radix + 1] : num_points;
+    uint64_t idx = lo + bs_upper_bound(xs + lo, hi - lo, x - 1);
+
+    double t = (double)(x - xs[idx - 1]) / (double)(xs[idx] - xs[idx - 1]);
+    return (uint64_t) std::fma(1.0 - t, (double) ys[idx - 1], t * (double) ys[idx]);
+}}",
+            key_type = key_type, n = self.points.len(), reduce = reduce,
+            prefix = prefix, shift = shift, table_size = table_size
+        );
+    }
+
+    fn standard_functions(&self) -> HashSet<StdFunctions> {
+        let mut to_r = HashSet::new();
+        to_r.insert(StdFunctions::BinarySearch);
+        return to_r;
+    }
+
+    fn function_name(&self) -> String {
+        return String::from("radix_spline");
+    }
+
+    fn error_bound(&self) -> Option<u64> {
+        return Some(self.error);
+    }
+
+    // the radix table is sized for the whole key set, not per leaf
+    fn restriction(&self) -> ModelRestriction {
+        return ModelRestriction::MustBeTop;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check<T: TrainingKey>(keys: Vec<T>, max_error: usize) -> RadixSplineModel {
+        let data: Vec<(T, usize)> = keys.into_iter().enumerate().map(|(i, k)| (k, i)).collect();
+        let md = RMITrainingData::new(Box::new(data));
+        let rs = RadixSplineModel::new(&md, max_error, 12);
+        let bound = rs.error_bound().unwrap();
+        assert!(bound <= max_error as u64 + 1, "bound {} for max error {}", bound, max_error);
+        for (key, pos) in md.iter().take(md.len()) {
+            let pred = rs.predict_to_int(&key.to_model_input());
+            assert!(u64::max(pred, pos as u64) - u64::min(pred, pos as u64) <= bound);
+        }
+        return rs;
+    }
+
+    #[test]
+    fn test_radix_spline_u64() {
+        let keys: Vec<u64> = (0..20000u64).map(|i| 1000 + 3 * i * i + (i % 13)).collect();
+        let rs = check(keys, 16);
+        assert!(rs.points.len() > 2);
+        assert!(rs.points.len() < 2000);
+    }
+
+    #[test]
+    fn test_radix_spline_duplicates() {
+        let keys: Vec<u64> = (0..5000u64).map(|i| (i / 10) * 100).collect();
+        check(keys, 8);
+    }
+
+    #[test]
+    fn test_radix_spline_wide_keys() {
+        let base: u128 = 0xABCD << 100;
+        check((0..3000u128).map(|i| base + (i * i << 20)).collect(), 32);
+
+        let base = U512::from(0xFEED) << 400;
+        check((0..3000u64).map(|i| base + (U512::from(i * i) << 300)).collect(), 32);
+    }
+
+    #[test]
+    fn test_radix_spline_wide_keys_outside() {
+        // the keys share their top 28 bits, keys outside differ above them
+        let base: u128 = 0xABCD << 100;
+        let keys: Vec<u128> = (0..3000u128).map(|i| base + (i * i << 20)).collect();
+        let first = keys[0];
+        let last = keys[keys.len() - 1];
+        let rs = check(keys, 32);
+
+        let first_pred = rs.predict_to_int(&first.to_model_input());
+        let last_pred = rs.predict_to_int(&last.to_model_input());
+        for above in [last + 1, last.plus_epsilon() << 1, (last | (1 << 127)) + 5, u128::MAX] {
+            assert_eq!(rs.predict_to_int(&above.to_model_input()), last_pred, "{:x}", above);
+        }
+        for below in [first - 1, first >> 1, 0] {
+            assert_eq!(rs.predict_to_int(&below.to_model_input()), first_pred, "{:x}", below);
+        }
+    }
+
+    #[test]
+    fn test_radix_spline_tiny() {
+        let rs = check(vec![42u64], 4);
+        assert_eq!(rs.predict_to_int(&7u64.to_model_input()), 0);
+        assert_eq!(rs.predict_to_int(&100u64.to_model_input()), 0);
+        RadixSplineModel::new(&RMITrainingData::<u64>::empty(), 4, 12);
+    }
+}
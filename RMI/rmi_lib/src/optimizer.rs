@@ -6,6 +6,7 @@ use json::*;
 use indicatif::{ProgressBar};
 use rayon::prelude::*;
 use std::collections::BTreeSet;
+use std::sync::Arc;
 use tabular::{Table, row};
 
 // Use constants to define different model layers for the optimizer to choose from.
@@ -223,6 +224,11 @@ pub struct RMIStatistics {
     pub branching_factor: u64,
     pub average_log2_error: f64,
     pub max_log2_error: f64,
+    // percentiles of the error of the individual keys, for the tail latency,
+    // if they were measured (see RMITrainingData::set_key_errors)
+    pub p50_error: Option<u64>,
+    pub p99_error: Option<u64>,
+    pub p999_error: Option<u64>,
     pub size: u64
 }
 
@@ -231,9 +237,13 @@ impl RMIStatistics {
         // Convert a trained RMI into an RMIStatistics object.
         // トレーニングされたRMIをRMIStatisticsオブジェクトに変換します。
         // 将训练的RMI转换为RMIStatistics对象。
+        let dist = rmi.error_distribution.as_ref();
         return RMIStatistics {
             average_log2_error: rmi.model_avg_log2_error,
             max_log2_error: rmi.model_max_log2_error,
+            p50_error: dist.map(|d| d.p50),
+            p99_error: dist.map(|d| d.p99),
+            p999_error: dist.map(|d| d.p999),
             size: codegen::rmi_size(&rmi),
             models: rmi.models.clone(),
             branching_factor: rmi.branching_factor
@@ -270,14 +280,17 @@ impl RMIStatistics {
         // Display a table of RMI statistics.
         // RMI統計の表を表示します。
         // 显示RMI统计的表格。
-        let mut table = Table::new("{:<} {:>} {:>} {:>} {:>}");
+        let percentile = |err: Option<u64>| err.map(|e| e.to_string()).unwrap_or_else(|| String::from("-"));
+        let mut table = Table::new("{:<} {:>} {:>} {:>} {:>} {:>} {:>}");
         table.add_row(row!("Models", "Branch", "   AvgLg2",
-                           "   MaxLg2", "   Size (b)"));
+                           "   MaxLg2", "   P99", "   P99.9", "   Size (b)"));
         for itm in itms {
             table.add_row(row!(itm.models.clone(),
                                format!("{:10}", itm.branching_factor),
                                format!("     {:2.5}", itm.average_log2_error),
                                format!("     {:2.5}", itm.max_log2_error),
+                               format!("     {}", percentile(itm.p99_error)),
+                               format!("     {}", percentile(itm.p999_error)),
                                format!("     {}", itm.size)));
         }
 
@@ -296,6 +309,9 @@ impl RMIStatistics {
             "namespace" => namespace,
             "size" => self.size,
             "average log2 error" => self.average_log2_error,
+            "p50 error" => self.p50_error,
+            "p99 error" => self.p99_error,
+            "p99.9 error" => self.p999_error,
             "binary" => true
         );
     }
@@ -311,6 +327,8 @@ fn measure_rmis<T: TrainingKey>(data: &RMITrainingData<T>,
    configs.par_iter()
         .map(|(models, branch_factor)| {
             let mut loc_data = data.soft_copy();
+            // each configuration reports its own peak memory
+            loc_data.set_memory_budget(Arc::new(MemoryBudget::new(data.memory_budget().limit())));
             let res = train::train(&mut loc_data, models, *branch_factor); // Train each configuration.
             // 各構成をトレーニングします。
             // 训练每个配置。
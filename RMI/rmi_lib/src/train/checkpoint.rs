@@ -0,0 +1,431 @@
+// < begin copyright >
+// Copyright Ryan Marcus 2020
+//
+// See root directory of this project for license terms.
+//
+// < end copyright >
+
+
+use crate::models::*;
+use crate::train::restore_model;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use json::JsonValue;
+use rug::{Float, Integer};
+use log::*;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+// Checkpoints of a three-layer RMI, in a directory with one file per stage:
+//
+//   checkpoint.json  the input hash, model spec, branching factor and the
+//                    training settings of the data (see manifest)
+//   top.ckpt         the top model
+//   layer2-N.ckpt    the second-layer models of the N-th batch of leaves,
+//                    with their third-layer models
+//   leaves.ckpt      all of the second- and third-layer models, once empty
+//                    leaves are replaced with constants
+//
+// Stage files are written under a temporary name and then renamed, so every
+// stage file that exists is complete. A resumed run checks the manifest and
+// then reuses every stage it finds, training only the ones that are missing.
+const MANIFEST: &str = "checkpoint.json";
+const STAGE_EXTENSION: &str = "ckpt";
+const FORMAT_VERSION: u64 = 2;
+
+// Leaves per middle-layer batch, about 64 batches for 2^28 leaves.
+const DEFAULT_BATCH_LEAVES: u64 = 1 << 22;
+
+pub struct Checkpoints {
+    dir: PathBuf,
+    input_hash: u64,
+    resume: bool,
+    batch_leaves: u64
+}
+
+// The contents of a stage file: a few integers describing the stage, and
+// the params of each model of one or more layers.
+pub struct Stage {
+    pub values: Vec<u64>,
+    pub layers: Vec<Vec<Vec<ModelParam>>>
+}
+
+impl Stage {
+    // Rebuilds the models of a layer, which are all of the given type.
+    pub fn restore_layer<T: TrainingKey>(&self, layer: usize, model_type: &str) -> Vec<Box<dyn Model>> {
+        return self.layers[layer].iter()
+            .map(|params| restore_model::<T>(model_type, params)
+                 .unwrap_or_else(|| panic!("Checkpoint has invalid params for a {} model", model_type)))
+            .collect();
+    }
+}
+
+impl Checkpoints {
+    // Checkpoints in dir, of the training input with the given hash (see
+    // hash_file). When resuming, stages already in dir are reused.
+    pub fn new(dir: &Path, input_hash: u64, resume: bool) -> Checkpoints {
+        return Checkpoints { dir: dir.to_path_buf(), input_hash, resume,
+                             batch_leaves: DEFAULT_BATCH_LEAVES };
+    }
+
+    pub fn set_batch_leaves(&mut self, batch_leaves: u64) {
+        assert!(batch_leaves > 0);
+        self.batch_leaves = batch_leaves;
+    }
+
+    // Number of middle-layer batches of a layer of num_leaves leaves.
+    pub fn num_batches(&self, num_leaves: u64) -> usize {
+        return ((num_leaves + self.batch_leaves - 1) / self.batch_leaves) as usize;
+    }
+
+    // 64-bit FNV-1a hash of the contents of a file.
+    pub fn hash_file(path: &Path) -> io::Result<u64> {
+        let mut file = File::open(path)?;
+        let mut buf = vec![0u8; 1 << 20];
+        let mut hash: u64 = FNV_OFFSET;
+        loop {
+            let n = file.read(&mut buf)?;
+            if n == 0 {
+                return Ok(hash);
+            }
+            hash = fnv1a(hash, &buf[..n]);
+        }
+    }
+
+    // Everything that changes the models: the input, the RMI and the
+    // settings of the training data.
+    fn manifest<T: TrainingKey>(&self, models: &str, branching_factor: u64,
+                                data: &RMITrainingData<T>) -> JsonValue {
+        return json::object! {
+            "version" => FORMAT_VERSION,
+            "input_hash" => format!("{:016x}", self.input_hash),
+            "models" => models,
+            "branching_factor" => branching_factor.to_string(),
+            "sample_rate" => data.sample_rate(),
+            "max_error" => data.max_error().map(|e| e.to_string()),
+            "duplicates" => data.duplicate_semantics().name(),
+            "query_weights" => data.query_weights()
+                .map(|w| format!("{:016x}", fnv1a(FNV_OFFSET, format!("{:?}", w).as_bytes()))),
+            "exact_leaf_threshold" => data.exact_leaf_threshold().map(|t| t.to_string()),
+            "signed_errors" => data.signed_errors()
+        };
+    }
+
+    // Starts training with these settings. A resumed run panics if the
+    // checkpoints in the directory are of a different input, RMI or training
+    // settings; a new run removes any stages left in the directory.
+    pub fn start<T: TrainingKey>(&self, models: &str, branching_factor: u64,
+                                 data: &RMITrainingData<T>) {
+        let expected = self.manifest(models, branching_factor, data);
+        let path = self.dir.join(MANIFEST);
+
+        if self.resume {
+            match fs::read_to_string(&path) {
+                Ok(text) => {
+                    let found = json::parse(&text)
+                        .unwrap_or_else(|e| panic!("Could not parse {}: {}", path.display(), e));
+                    for (key, value) in expected.entries() {
+                        if found[key] != *value {
+                            panic!("Cannot resume from {}: the checkpoints have {} {}, \
+                                    but this run has {}", self.dir.display(), key,
+                                   found[key].dump(), value.dump());
+                        }
+                    }
+                    println!("Resuming from the checkpoints in {}", self.dir.display());
+                    return;
+                },
+                Err(_) => warn!("No checkpoints found in {}, training from the start",
+                                self.dir.display())
+            }
+        }
+
+        fs::create_dir_all(&self.dir)
+            .unwrap_or_else(|e| panic!("Could not create {}: {}", self.dir.display(), e));
+        self.remove_stages("");
+        self.write_atomic(MANIFEST, |w| w.write_all(expected.pretty(2).as_bytes()));
+    }
+
+    fn stage_path(&self, stage: &str) -> PathBuf {
+        return self.dir.join(format!("{}.{}", stage, STAGE_EXTENSION));
+    }
+
+    // Removes the stage files whose names start with prefix.
+    pub fn remove_stages(&self, prefix: &str) {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(_) => return
+        };
+        for entry in entries.filter_map(|e| e.ok()) {
+            let path = entry.path();
+            let is_stage = path.extension().map(|e| e == STAGE_EXTENSION).unwrap_or(false);
+            let matches = path.file_name()
+                .and_then(|n| n.to_str())
+                .map(|n| n.starts_with(prefix))
+                .unwrap_or(false);
+            if is_stage && matches {
+                fs::remove_file(&path)
+                    .unwrap_or_else(|e| panic!("Could not remove {}: {}", path.display(), e));
+            }
+        }
+    }
+
+    fn write_atomic<F>(&self, name: &str, write: F)
+    where F: FnOnce(&mut BufWriter<File>) -> io::Result<()> {
+        let path = self.dir.join(name);
+        let tmp = self.dir.join(format!("{}.tmp", name));
+        let result = File::create(&tmp)
+            .and_then(|f| {
+                let mut w = BufWriter::new(f);
+                write(&mut w)?;
+                w.flush()?;
+                w.get_ref().sync_all()
+            })
+            .and_then(|_| fs::rename(&tmp, &path));
+        if let Err(e) = result {
+            panic!("Could not write checkpoint {}: {}", path.display(), e);
+        }
+    }
+
+    // Writes a stage: its values and the params of the models of each layer.
+    pub fn save(&self, stage: &str, values: &[u64], layers: &[&[Box<dyn Model>]]) {
+        let name = format!("{}.{}", stage, STAGE_EXTENSION);
+        self.write_atomic(&name, |w| {
+            w.write_u64::<LittleEndian>(FORMAT_VERSION)?;
+            w.write_u64::<LittleEndian>(values.len() as u64)?;
+            for v in values {
+                w.write_u64::<LittleEndian>(*v)?;
+            }
+            w.write_u64::<LittleEndian>(layers.len() as u64)?;
+            for models in layers {
+                w.write_u64::<LittleEndian>(models.len() as u64)?;
+                for model in models.iter() {
+                    write_params(w, &model.params())?;
+                }
+            }
+            return Ok(());
+        });
+        trace!("Wrote checkpoint {}", stage);
+    }
+
+    // Reads a stage of a resumed run, if it was written.
+    pub fn load(&self, stage: &str) -> Option<Stage> {
+        if !self.resume {
+            return None;
+        }
+        let path = self.stage_path(stage);
+        let file = match File::open(&path) {
+            Ok(f) => f,
+            Err(_) => return None
+        };
+        let stage = read_stage(&mut BufReader::new(file))
+            .unwrap_or_else(|e| panic!("Could not read checkpoint {}: {}", path.display(), e));
+        return Some(stage);
+    }
+}
+
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+
+fn fnv1a(mut hash: u64, bytes: &[u8]) -> u64 {
+    for b in bytes {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    return hash;
+}
+
+fn write_str<W: Write>(w: &mut W, s: &str) -> io::Result<()> {
+    w.write_u64::<LittleEndian>(s.len() as u64)?;
+    return w.write_all(s.as_bytes());
+}
+
+fn read_str<R: Read>(r: &mut R) -> io::Result<String> {
+    let len = r.read_u64::<LittleEndian>()? as usize;
+    let mut bytes = vec![0u8; len];
+    r.read_exact(&mut bytes)?;
+    return String::from_utf8(bytes)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e));
+}
+
+fn invalid(what: &str) -> io::Error {
+    return io::Error::new(io::ErrorKind::InvalidData, what.to_string());
+}
+
+// Params are written with a tag for their type. Wide values are written as
+// hexadecimal strings, which are exact for 512-bit floats.
+fn write_params<W: Write>(w: &mut W, params: &[ModelParam]) -> io::Result<()> {
+    w.write_u64::<LittleEndian>(params.len() as u64)?;
+    for param in params {
+        match param {
+            ModelParam::Int(v) => { w.write_u8(0)?; w.write_u64::<LittleEndian>(*v)?; },
+            ModelParam::Int128(v) => { w.write_u8(1)?; w.write_u128::<LittleEndian>(*v)?; },
+            ModelParam::Float(v) => { w.write_u8(2)?; w.write_f64::<LittleEndian>(*v)?; },
+            ModelParam::Float512(v) => { w.write_u8(3)?; write_str(w, &v.to_string_radix(16, None))?; },
+            ModelParam::Int512(v) => { w.write_u8(4)?; write_str(w, &v.to_string_radix(16))?; },
+            ModelParam::ShortArray(a) => {
+                w.write_u8(5)?;
+                w.write_u64::<LittleEndian>(a.len() as u64)?;
+                for v in a { w.write_u16::<LittleEndian>(*v)?; }
+            },
+            ModelParam::IntArray(a) => {
+                w.write_u8(6)?;
+                w.write_u64::<LittleEndian>(a.len() as u64)?;
+                for v in a { w.write_u64::<LittleEndian>(*v)?; }
+            },
+            ModelParam::Int128Array(a) => {
+                w.write_u8(7)?;
+                w.write_u64::<LittleEndian>(a.len() as u64)?;
+                for v in a { w.write_u128::<LittleEndian>(*v)?; }
+            },
+            ModelParam::Int32Array(a) => {
+                w.write_u8(8)?;
+                w.write_u64::<LittleEndian>(a.len() as u64)?;
+                for v in a { w.write_u32::<LittleEndian>(*v)?; }
+            },
+            ModelParam::Int512Array(a) => {
+                w.write_u8(9)?;
+                w.write_u64::<LittleEndian>(a.len() as u64)?;
+                for v in a { write_str(w, &v.to_string_radix(16))?; }
+            },
+            ModelParam::FloatArray(a) => {
+                w.write_u8(10)?;
+                w.write_u64::<LittleEndian>(a.len() as u64)?;
+                for v in a { w.write_f64::<LittleEndian>(*v)?; }
+            },
+        }
+    }
+    return Ok(());
+}
+
+fn read_array<R: Read, V, F>(r: &mut R, read: F) -> io::Result<Vec<V>>
+where F: Fn(&mut R) -> io::Result<V> {
+    let len = r.read_u64::<LittleEndian>()?;
+    return (0..len).map(|_| read(r)).collect();
+}
+
+fn read_float512<R: Read>(r: &mut R) -> io::Result<Float> {
+    let parsed = Float::parse_radix(read_str(r)?, 16).map_err(|_| invalid("bad 512-bit float"))?;
+    return Ok(Float::with_val(512, parsed));
+}
+
+fn read_int512<R: Read>(r: &mut R) -> io::Result<Integer> {
+    return Integer::from_str_radix(&read_str(r)?, 16).map_err(|_| invalid("bad 512-bit integer"));
+}
+
+fn read_params<R: Read>(r: &mut R) -> io::Result<Vec<ModelParam>> {
+    return read_array(r, |r| {
+        return Ok(match r.read_u8()? {
+            0 => ModelParam::Int(r.read_u64::<LittleEndian>()?),
+            1 => ModelParam::Int128(r.read_u128::<LittleEndian>()?),
+            2 => ModelParam::Float(r.read_f64::<LittleEndian>()?),
+            3 => ModelParam::Float512(read_float512(r)?),
+            4 => ModelParam::Int512(read_int512(r)?),
+            5 => ModelParam::ShortArray(read_array(r, |r| r.read_u16::<LittleEndian>())?),
+            6 => ModelParam::IntArray(read_array(r, |r| r.read_u64::<LittleEndian>())?),
+            7 => ModelParam::Int128Array(read_array(r, |r| r.read_u128::<LittleEndian>())?),
+            8 => ModelParam::Int32Array(read_array(r, |r| r.read_u32::<LittleEndian>())?),
+            9 => ModelParam::Int512Array(read_array(r, read_int512)?),
+            10 => ModelParam::FloatArray(read_array(r, |r| r.read_f64::<LittleEndian>())?),
+            _ => return Err(invalid("unknown param type")),
+        });
+    });
+}
+
+fn read_stage<R: Read>(r: &mut R) -> io::Result<Stage> {
+    if r.read_u64::<LittleEndian>()? != FORMAT_VERSION {
+        return Err(invalid("unsupported checkpoint version"));
+    }
+    let values = read_array(r, |r| r.read_u64::<LittleEndian>())?;
+    let layers = read_array(r, |r| read_array(r, read_params))?;
+    return Ok(Stage { values, layers });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rmi-checkpoint-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        return dir;
+    }
+
+    #[test]
+    fn test_params_roundtrip() {
+        let params = vec![
+            ModelParam::Int(u64::MAX),
+            ModelParam::Int128(u128::MAX - 5),
+            ModelParam::Float(-0.1),
+            ModelParam::Float512(Float::with_val(512, 1) / Float::with_val(512, 3)),
+            ModelParam::Int512(Integer::from(7) << 400),
+            ModelParam::ShortArray(vec![1, 2]),
+            ModelParam::IntArray(vec![3, 4, 5]),
+            ModelParam::Int128Array(vec![1 << 100]),
+            ModelParam::Int32Array(vec![]),
+            ModelParam::Int512Array(vec![Integer::from(9) << 300, Integer::from(0)]),
+            ModelParam::FloatArray(vec![std::f64::consts::PI, 1e-300]),
+        ];
+        let mut buf: Vec<u8> = Vec::new();
+        write_params(&mut buf, &params).unwrap();
+        let read = read_params(&mut buf.as_slice()).unwrap();
+        assert_eq!(format!("{:?}", read), format!("{:?}", params));
+    }
+
+    #[test]
+    fn test_stages() {
+        let dir = temp_dir("stages");
+        let input = dir.with_extension("input");
+        fs::write(&input, b"some keys").unwrap();
+        let hash = Checkpoints::hash_file(&input).unwrap();
+        assert_ne!(hash, 0);
+
+        let md: RMITrainingData<u64> = RMITrainingData::new(Box::new(vec![(1u64, 0), (9, 1), (20, 2)]));
+        let ckpt = Checkpoints::new(&dir, hash, false);
+        ckpt.start("pwl,linear,linear", 64, &md);
+        let models: Vec<Box<dyn Model>> = vec![Box::new(LinearModel::new(&md))];
+        ckpt.save("top", &[1, 2], &[&models, &[]]);
+        // only a resumed run reads stages
+        assert!(ckpt.load("top").is_none());
+
+        let resumed = Checkpoints::new(&dir, hash, true);
+        resumed.start("pwl,linear,linear", 64, &md);
+        let stage = resumed.load("top").unwrap();
+        assert_eq!(stage.values, vec![1, 2]);
+        assert_eq!(stage.layers.len(), 2);
+        let restored = stage.restore_layer::<u64>(0, "linear");
+        assert_eq!(format!("{:?}", restored[0].params()), format!("{:?}", models[0].params()));
+        assert!(resumed.load("leaves").is_none());
+
+        // a new run starts over
+        Checkpoints::new(&dir, hash, false).start("pwl,linear,linear", 64, &md);
+        assert!(resumed.load("top").is_none());
+
+        fs::remove_dir_all(&dir).unwrap();
+        fs::remove_file(&input).unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "branching_factor")]
+    fn test_resume_mismatch() {
+        let dir = temp_dir("mismatch");
+        let md: RMITrainingData<u64> = RMITrainingData::new(Box::new(vec![(1u64, 0), (9, 1)]));
+        Checkpoints::new(&dir, 42, false).start("pwl,linear,linear", 64, &md);
+        Checkpoints::new(&dir, 42, true).start("pwl,linear,linear", 128, &md);
+    }
+
+    #[test]
+    #[should_panic(expected = "duplicates")]
+    fn test_resume_settings_mismatch() {
+        let dir = temp_dir("settings");
+        let mut md: RMITrainingData<u64> = RMITrainingData::new(Box::new(vec![(1u64, 0), (9, 1)]));
+        let ckpt = Checkpoints::new(&dir, 42, false);
+        ckpt.start("pwl,linear,linear", 64, &md);
+
+        // the same query weights resume, other duplicate semantics do not
+        let weights = QueryWeights::parse("5 2", KeyType::U64).unwrap();
+        md.set_query_weights(Some(std::sync::Arc::new(weights.clone())));
+        ckpt.start("pwl,linear,linear", 64, &md);
+        Checkpoints::new(&dir, 42, true).start("pwl,linear,linear", 64, &md);
+        md.set_duplicate_semantics(DuplicateSemantics::UpperBound);
+        Checkpoints::new(&dir, 42, true).start("pwl,linear,linear", 64, &md);
+    }
+}
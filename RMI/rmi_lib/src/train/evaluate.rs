@@ -0,0 +1,212 @@
+// < begin copyright >
+// Copyright Ryan Marcus 2020
+//
+// See root directory of this project for license terms.
+//
+// < end copyright >
+
+
+use crate::models::*;
+use crate::train::TrainedRMI;
+use crate::train::parallel::{default_num_chunks, LeafRange};
+use crate::train::verify::lookup;
+use json::JsonValue;
+use rayon::prelude::*;
+
+// errors below this are counted exactly, larger ones in buckets of 1/128 of
+// their power of two, so percentiles are within 1% of the true error
+const EXACT_ERRORS: u64 = 256;
+const SUB_BUCKET_BITS: u32 = 7;
+const NUM_BUCKETS: usize = 256 + (64 - 8) * 128;
+
+fn bucket_of(err: u64) -> usize {
+    if err < EXACT_ERRORS {
+        return err as usize;
+    }
+    let bits = 63 - err.leading_zeros();
+    let sub = (err >> (bits - SUB_BUCKET_BITS)) & ((1 << SUB_BUCKET_BITS) - 1);
+    return EXACT_ERRORS as usize + (bits as usize - 8) * 128 + sub as usize;
+}
+
+// the largest error in a bucket
+fn bucket_max(bucket: usize) -> u64 {
+    if bucket < EXACT_ERRORS as usize {
+        return bucket as u64;
+    }
+    let bits = ((bucket - EXACT_ERRORS as usize) / 128 + 8) as u32;
+    let sub = ((bucket - EXACT_ERRORS as usize) % 128) as u128;
+    let end = (128 + sub + 1) << (bits - SUB_BUCKET_BITS);
+    return u128::min(end - 1, u64::MAX as u128) as u64;
+}
+
+// The errors of every key, and the search windows they fall into.
+#[derive(Clone)]
+struct KeyErrors {
+    errors: Vec<u64>,
+    // keys whose window holds at most 2^i positions (and more than 2^(i-1))
+    windows: Vec<u64>,
+    total: u128
+}
+
+impl KeyErrors {
+    fn new() -> KeyErrors {
+        return KeyErrors { errors: vec![0; NUM_BUCKETS], windows: vec![0; 65], total: 0 };
+    }
+
+    fn add(&mut self, err: u64, window: u64) {
+        self.errors[bucket_of(err)] += 1;
+        self.windows[(64 - window.saturating_sub(1).leading_zeros()) as usize] += 1;
+        self.total += err as u128;
+    }
+
+    fn merge(&mut self, other: KeyErrors) {
+        for (all, v) in self.errors.iter_mut().zip(other.errors) {
+            *all += v;
+        }
+        for (all, v) in self.windows.iter_mut().zip(other.windows) {
+            *all += v;
+        }
+        self.total += other.total;
+    }
+
+    // the smallest error that at least a fraction p of the keys are within
+    fn percentile(&self, p: f64) -> u64 {
+        let num_keys: u64 = self.errors.iter().sum();
+        let rank = u64::max(1, (p * num_keys as f64).ceil() as u64);
+        let mut seen = 0;
+        for (bucket, count) in self.errors.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return bucket_max(bucket);
+            }
+        }
+        return 0;
+    }
+}
+
+// The distribution of the errors of the individual keys, from looking every
+// key up in the trained RMI, rather than from the error bound of its leaf.
+#[derive(Clone, Debug)]
+pub struct ErrorDistribution {
+    pub keys: usize,
+    pub mean: f64,
+    // percentiles of |predicted - position| over the keys
+    pub p50: u64,
+    pub p90: u64,
+    pub p99: u64,
+    pub p999: u64,
+    // the number of keys whose search window holds at most 2^i positions
+    // (and more than 2^(i-1)), up to the largest window
+    pub window_log2_histogram: Vec<u64>,
+    // the mean and max error of the keys routed to each last-layer model
+    pub leaf_mean_errors: Vec<f64>,
+    pub leaf_max_errors: Vec<u64>
+}
+
+impl ErrorDistribution {
+    pub fn to_json(&self) -> JsonValue {
+        return json::object! {
+            "keys" => self.keys,
+            "mean" => self.mean,
+            "p50" => self.p50,
+            "p90" => self.p90,
+            "p99" => self.p99,
+            "p99.9" => self.p999,
+            "window_log2_histogram" => self.window_log2_histogram.clone(),
+            "leaf_mean_errors" => self.leaf_mean_errors.clone(),
+            "leaf_max_errors" => JsonValue::from(self.leaf_max_errors.iter().map(|&x| x.to_string()).collect::<Vec<String>>())
+        };
+    }
+}
+
+// Looks up every key of `data`, which must hold the keys the RMI was trained
+// on, and measures the error of each one. Duplicates are looked up once per
+// row, at the position their semantics assign to them.
+pub fn error_distribution<T: TrainingKey>(data: &RMITrainingData<T>, rmi: &TrainedRMI) -> ErrorDistribution {
+    assert_eq!(rmi.num_rmi_rows, data.len());
+
+    let num_rows = data.len();
+    let num_leaves = rmi.rmi.last().unwrap().len();
+
+    let num_chunks = default_num_chunks(num_rows);
+    let chunk_size = (num_rows + num_chunks - 1) / usize::max(1, num_chunks);
+    let chunks: Vec<(KeyErrors, LeafRange<(u64, u64, u64)>)> = (0..num_chunks).into_par_iter()
+        .map(|chunk| {
+            let start = usize::min(num_rows, chunk * chunk_size);
+            let end = usize::min(num_rows, (chunk + 1) * chunk_size);
+            let mut errors = KeyErrors::new();
+            // (keys, total error, max error) of each leaf
+            let mut leaves = LeafRange::new((0, 0, 0));
+            for (key, y) in data.iter_range(start, end) {
+                let found = lookup(rmi, &key.to_model_input(), num_rows as u64);
+                let y = y as u64;
+                let err = u64::max(found.predicted, y) - u64::min(found.predicted, y);
+                errors.add(err, found.upper.saturating_sub(found.lower) + 1);
+
+                let leaf = leaves.get_mut(found.leaf);
+                *leaf = (leaf.0 + 1, leaf.1 + err, u64::max(leaf.2, err));
+            }
+            (errors, leaves)
+        })
+        .collect();
+
+    let mut errors = KeyErrors::new();
+    let mut leaves = vec![(0, 0, 0); num_leaves];
+    for (chunk_errors, chunk_leaves) in chunks {
+        errors.merge(chunk_errors);
+        chunk_leaves.merge_into(&mut leaves, |all, v| *all = (all.0 + v.0, all.1 + v.1, u64::max(all.2, v.2)));
+    }
+
+    let mut windows = errors.windows.clone();
+    while windows.last() == Some(&0) {
+        windows.pop();
+    }
+    return ErrorDistribution {
+        keys: num_rows,
+        mean: if num_rows == 0 { 0.0 } else { errors.total as f64 / num_rows as f64 },
+        p50: errors.percentile(0.5),
+        p90: errors.percentile(0.9),
+        p99: errors.percentile(0.99),
+        p999: errors.percentile(0.999),
+        window_log2_histogram: windows,
+        leaf_mean_errors: leaves.iter()
+            .map(|&(n, total, _max)| if n == 0 { 0.0 } else { total as f64 / n as f64 })
+            .collect(),
+        leaf_max_errors: leaves.iter().map(|&(_n, _total, max)| max).collect()
+    };
+}
+
+// Measures the distribution of the errors of a freshly trained RMI, if the
+// data asks for it (see RMITrainingData::set_key_errors).
+pub fn measure_key_errors<T: TrainingKey>(data: &RMITrainingData<T>, rmi: &mut TrainedRMI) {
+    if data.key_errors() {
+        rmi.error_distribution = Some(error_distribution(data, rmi));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_buckets() {
+        for err in (0..100000u64).chain((0..64).map(|b| 1u64 << b)).chain(vec![u64::MAX]) {
+            let bucket = bucket_of(err);
+            assert!(bucket < NUM_BUCKETS);
+            assert!(bucket_max(bucket) >= err);
+            // a bucket is at most 1/128 of its errors wide
+            assert!(bucket_max(bucket) - err <= err / 128);
+        }
+        assert!((1..100000u64).all(|err| bucket_of(err) >= bucket_of(err - 1)));
+
+        let mut errors = KeyErrors::new();
+        for err in 0..1000u64 {
+            errors.add(err, 2 * err + 1);
+        }
+        assert_eq!(errors.percentile(0.5), 499);
+        assert_eq!(errors.percentile(0.001), 0);
+        assert!(errors.percentile(0.999) >= 998 && errors.percentile(0.999) <= 998 + 998 / 128);
+        // windows of 1, none of 2, 3, then 5 and 7, then 9 to 15
+        assert_eq!(&errors.windows[..5], &[1, 0, 1, 2, 4]);
+    }
+}
@@ -1,4 +1,6 @@
 use crate::models::*;
+use crate::train::parallel::{default_num_chunks, leaf_aligned_chunks, LeafRange};
+use rayon::prelude::*;
 
 
 fn find_first_below<T: Copy>(data: &[Option<T>], idx: usize) -> Option<(usize, T)> {
@@ -87,6 +89,23 @@ fn compute_prev_for_leaf<T: TrainingKey>(num_leaf_models: u64,
 }
 
 
+// The first and last row of a leaf, and its longest run of keys that the
+// models cannot tell apart.
+#[derive(Clone, Copy)]
+struct LeafBounds<T> {
+    first: Option<(usize, T)>,
+    last: Option<(usize, T)>,
+    longest_run: u64
+}
+
+// What one chunk of rows contributes to the lower bound corrections.
+struct ChunkBounds<T> {
+    leaves: LeafRange<LeafBounds<T>>,
+    first_target: Option<usize>,
+    last_target: Option<usize>
+}
+
+#[derive(Debug, PartialEq)]
 pub struct LowerBoundCorrection<T> {
     first: Vec<Option<(usize, T)>>,
     last: Vec<Option<(usize, T)>>,
@@ -99,47 +118,91 @@ pub struct LowerBoundCorrection<T> {
 
 impl <T: TrainingKey> LowerBoundCorrection<T> {
     pub fn new<F>(pred_func: F, num_leaf_models: u64, data: &RMITrainingData<T>) -> LowerBoundCorrection<T>
-    where F: Fn(T) -> u64 {
-    
-        let mut first_key_for_leaf: Vec<Option<(usize, T)>>
-            = vec![None ; num_leaf_models as usize];
-        let mut last_key_for_leaf: Vec<Option<(usize, T)>>
-            = vec![None ; num_leaf_models as usize];
-        let mut max_run_length: Vec<u64> = vec![0 ; num_leaf_models as usize];
-        
-        let mut last_target = 0;
-        let mut last_nonempty_model_idx = 0;
+    where F: Fn(T) -> u64 + Sync {
+        return LowerBoundCorrection::with_chunks(pred_func, num_leaf_models, data,
+                                                 default_num_chunks(data.len()));
+    }
+
+    // Scans leaf-aligned chunks of the rows on the thread pool. The result
+    // does not depend on the number of chunks.
+    pub fn with_chunks<F>(pred_func: F, num_leaf_models: u64, data: &RMITrainingData<T>,
+                          num_chunks: usize) -> LowerBoundCorrection<T>
+    where F: Fn(T) -> u64 + Sync {
+        let target_of = |x: T| u64::min(num_leaf_models - 1, pred_func(x)) as usize;
+        let chunks = leaf_aligned_chunks(data, num_leaf_models, num_chunks, &pred_func);
+        let num_chunks = chunks.len();
+
+        let empty = LeafBounds { first: None, last: None, longest_run: 0 };
+        let chunk_bounds: Vec<ChunkBounds<T>> = chunks.par_iter().enumerate()
+            .map(|(chunk_idx, &(start, end))| {
+                let mut leaves = LeafRange::new(empty);
+                let mut first_target = None;
+                let mut last_target = None;
+                // (target, first key, length) of the current run
+                let mut run: Option<(usize, T, u64)> = None;
+
+                for (x, y) in data.iter_range(start, end) {
+                    let target = target_of(x);
+                    first_target = first_target.or(Some(target));
+
+                    // keys the models cannot tell apart (duplicates, or byte strings
+                    // sharing an encoded prefix) form a single run
+                    run = match run {
+                        Some((run_target, run_key, length))
+                            if run_target == target && x.same_encoding(&run_key) =>
+                            Some((run_target, run_key, length + 1)),
+                        _ => {
+                            if let Some((run_target, _, length)) = run {
+                                let bounds = leaves.get_mut(run_target);
+                                bounds.longest_run = u64::max(bounds.longest_run, length);
+                            }
+                            Some((target, x, 1))
+                        }
+                    };
+
+                    let bounds = leaves.get_mut(target);
+                    if bounds.first.is_none() {
+                        bounds.first = Some((y, x));
+                    }
+                    bounds.last = Some((y, x));
+                    last_target = Some(target);
+                }
+
+                // a chunk always ends a run, except for the very last run,
+                // which a single pass never records either
+                if chunk_idx + 1 < num_chunks {
+                    if let Some((run_target, _, length)) = run {
+                        let bounds = leaves.get_mut(run_target);
+                        bounds.longest_run = u64::max(bounds.longest_run, length);
+                    }
+                }
+                ChunkBounds { leaves, first_target, last_target }
+            })
+            .collect();
+
+        let mut all_bounds = vec![empty; num_leaf_models as usize];
         let mut first_nonempty_model_idx = num_leaf_models;
-        let mut current_run_length = 0;
-        let mut current_run_key = data.get_key(0);
-        
-        for (x, y) in data.iter() {
-            let leaf_idx = pred_func(x.into());
-            let target = u64::min(num_leaf_models - 1, leaf_idx) as usize;
-            if first_nonempty_model_idx ==  num_leaf_models{
-                first_nonempty_model_idx = target as u64;
-            }
-            if target == last_target && x == current_run_key {
-                current_run_length += 1;
-            } else if target != last_target || x != current_run_key {
-                max_run_length[last_target] = u64::max(
-                    max_run_length[last_target],
-                    current_run_length
-                );
-                
-                current_run_length = 1;
-                current_run_key = x;
-                last_target = target;
+        let mut last_nonempty_model_idx = 0;
+        for chunk in chunk_bounds {
+            if first_nonempty_model_idx == num_leaf_models {
+                if let Some(target) = chunk.first_target {
+                    first_nonempty_model_idx = target as u64;
+                }
             }
-            
-            if first_key_for_leaf[target].is_none() {
-                first_key_for_leaf[target] = Some((y, x));
+            if let Some(target) = chunk.last_target {
+                last_nonempty_model_idx = target as u64;
             }
-            last_key_for_leaf[target] = Some((y, x));
-
-            last_nonempty_model_idx=target as u64;
+            chunk.leaves.merge_into(&mut all_bounds, |all, bounds| {
+                all.first = all.first.or(bounds.first);
+                all.last = bounds.last.or(all.last);
+                all.longest_run = u64::max(all.longest_run, bounds.longest_run);
+            });
         }
-        
+
+        let first_key_for_leaf: Vec<Option<(usize, T)>> = all_bounds.iter().map(|b| b.first).collect();
+        let last_key_for_leaf: Vec<Option<(usize, T)>> = all_bounds.iter().map(|b| b.last).collect();
+        let max_run_length: Vec<u64> = all_bounds.iter().map(|b| b.longest_run).collect();
+
         let next_for_leaf = compute_next_for_leaf(num_leaf_models, data.len(), &first_key_for_leaf,  &last_key_for_leaf);
         let prev_for_leaf = compute_prev_for_leaf(num_leaf_models, &last_key_for_leaf);
         
@@ -182,9 +245,6 @@ impl <T: TrainingKey> LowerBoundCorrection<T> {
     pub fn prev_key(&self, leaf_idx: usize) -> T {
         return self.prev[leaf_idx].1;
     }
-    pub fn prev(&self, leaf_idx: usize) -> usize {
-        return self.prev[leaf_idx].0;
-    }
     pub fn longest_run(&self, leaf_idx: usize) -> u64 {
         return self.run_lengths[leaf_idx];
     }
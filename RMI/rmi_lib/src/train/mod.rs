@@ -17,6 +17,9 @@ use log::*; // Import logging macros.
 use std::time::SystemTime; // Import SystemTime to measure time.
 // 時間を測定するためにSystemTimeをインポートします。
 // 导入SystemTime用于测量时间。
+use std::sync::Arc; // Import Arc to share the checkpoints of a training run.
+// 学習実行のチェックポイントを共有するためにArcをインポートします。
+// 导入Arc用于共享训练运行的检查点。
 
 use rug::{ // Import the `rug` library for high-precision math operations.
     // 高精度数学操作のために `rug` ライブラリをインポートします。
@@ -40,6 +43,26 @@ mod multi_layer; // Uncomment if using multi-layer model module.
 mod lower_bound_correction; // Import the lower-bound correction module.
 // 下限補正モジュールをインポートします。
 // 导入下界修正模块。
+mod parallel; // Import the helpers that split passes over the keys across threads.
+// キーに対するパスをスレッドに分割するヘルパーをインポートします。
+// 导入将键的遍历拆分到多个线程的辅助模块。
+mod checkpoint; // Import the checkpoints that let long training runs resume.
+// 長い学習を再開できるようにするチェックポイントをインポートします。
+// 导入使长时间训练可以恢复的检查点模块。
+mod retrain; // Import the incremental retraining after keys change.
+// キーの変更後の差分再学習をインポートします。
+// 导入键变化后的增量重新训练模块。
+mod verify; // Import the exhaustive verification of the error bounds.
+// 誤差範囲の網羅的な検証をインポートします。
+// 导入误差界的穷举验证模块。
+mod evaluate; // Import the per-key error distribution of trained RMIs.
+// 学習済みRMIのキーごとの誤差分布をインポートします。
+// 导入已训练RMI的逐键误差分布模块。
+
+pub use checkpoint::Checkpoints;
+pub use retrain::{retrain, KeyDelta};
+pub use verify::{verify, Verification, Violation, Layout};
+pub use evaluate::ErrorDistribution;
 
 // Definition of the TrainedRMI structure, which stores the trained model information.
 // トレーニングされたモデル情報を格納するTrainedRMI構造体の定義。
@@ -69,27 +92,54 @@ pub struct TrainedRMI {
     pub model_max_log2_error: f64, // Maximum log2 error encountered.
     // 遭遇した最大log2誤差。
     // 遇到的最大log2误差。
+    pub model_avg_window: f64, // Average number of positions a lookup searches.
+    // 検索が探索する位置の平均数。
+    // 查找所搜索位置的平均数量。
     pub last_layer_max_l1s: Vec<u64>, // Maximum L1 errors for the last layer models.
     // 最終レイヤーモデルの最大L1誤差。
     // 最后一层模型的最大L1误差。
     pub third_layer_max_l1s: Vec<u64>, // Maximum L1 errors for the third layer models (if present).
     // 第三レイヤーモデルの最大L1誤差（存在する場合）。
     // 第三层模型的最大L1误差（如果存在）。
+    pub left_errors: Vec<u64>, // How far below the prediction each leaf's window reaches (equal range or signed errors only).
+    // 各リーフのウィンドウが予測より下に広がる幅（等値範囲または符号付き誤差の場合のみ）。
+    // 每个叶子的窗口在预测值之下延伸的距离（仅限等值范围或带符号误差）。
+    pub right_errors: Vec<u64>, // How far above the prediction each leaf's window reaches (equal range or signed errors only).
+    // 各リーフのウィンドウが予測より上に広がる幅（等値範囲または符号付き誤差の場合のみ）。
+    // 每个叶子的窗口在预测值之上延伸的距离（仅限等值范围或带符号误差）。
     pub rmi: Vec<Vec<Box<dyn Model>>>, // The RMI itself, consisting of multiple layers of models.
     // 複数のレイヤーのモデルで構成されるRMI自体。
     // RMI本身，由多个模型层组成。
+    pub layout: Layout, // How a lookup walks the layers, set by the function that trained them.
+    // 検索がレイヤーをたどる方法。レイヤーを学習した関数が設定します。
+    // 查找遍历各层的方式，由训练这些层的函数设置。
     pub models: String, // A string representing the model types used.
     // 使用されたモデルタイプを表す文字列。
     // 表示使用的模型类型的字符串。
     pub branching_factor: u64, // Branching factor for the RMI.
     // RMIの分岐係数。
     // RMI的分支因子。
-    pub cache_fix: Option<(usize, Vec<(u64, usize)>)>, // Optional cache fix applied to the model.
+    pub cache_fix: Option<(usize, Vec<(ModelInput, usize)>)>, // Optional cache fix applied to the model.
     // モデルに適用されたオプションのキャッシュ修正。
     // 应用于模型的可选缓存修复。
     pub build_time: u128, // Time taken to build the RMI.
     // RMIの構築にかかった時間。
     // 构建RMI所花费的时间。
+    pub duplicates: DuplicateSemantics, // Position predicted for duplicate keys.
+    // 重複キーに対して予測される位置。
+    // 为重复键预测的位置。
+    pub run_lengths: Vec<(u64, u64)>, // (rank, run length) of duplicate runs, for dedup.
+    // 重複の連続の（順位、長さ）。重複除去の場合に使用します。
+    // 重复键连续段的（排名，长度），用于去重。
+    pub sample_rate: usize, // Models were fit on every k-th key (1 = all keys).
+    // モデルはk番目ごとのキーで学習されました（1 = すべてのキー）。
+    // 模型在每第k个键上拟合（1 = 所有键）。
+    pub peak_memory: usize, // Peak bytes of the rows copied out of the keys for training.
+    // 学習のためにキーからコピーされた行の最大バイト数。
+    // 为训练从键中复制的行的峰值字节数。
+    pub error_distribution: Option<ErrorDistribution>, // Percentiles and histograms of the error of every key.
+    // すべてのキーの誤差のパーセンタイルとヒストグラム。
+    // 每个键误差的百分位数和直方图。
 }
 
 impl TrainedRMI {
@@ -103,49 +153,131 @@ impl TrainedRMI {
             "model_max_error" => self.model_max_error,
             "model_max_error_idx" => self.model_max_error_idx,
             "model_max_log2_error" => self.model_max_log2_error,
+            "model_avg_window" => self.model_avg_window,
+            "signed_errors" => !self.left_errors.is_empty(),
             "last_layer_max_l1s" => JsonValue::from(self.last_layer_max_l1s.iter().map(|&x| x.to_string()).collect::<Vec<String>>()),
             "third_layer_max_l1s" => JsonValue::from(self.third_layer_max_l1s.iter().map(|&x| x.to_string()).collect::<Vec<String>>()),
             "models" => self.models.clone(),
             "branching_factor" => self.branching_factor.to_string(),
-            "build_time" => self.build_time.to_string()
+            "build_time" => self.build_time.to_string(),
+            "duplicates" => self.duplicates.name(),
+            "duplicate_runs" => self.run_lengths.len(),
+            "sample_rate" => self.sample_rate,
+            "peak_memory" => self.peak_memory,
+            "exact_leaves" => self.rmi.last().map(|leaves| leaves.iter().filter(|m| m.exact_keys().is_some()).count()).unwrap_or(0),
+            "error_distribution" => self.error_distribution.as_ref().map(|d| d.to_json()).unwrap_or(JsonValue::Null)
             // Note: We're omitting 'rmi' and 'cache_fix' fields as they might be complex to serialize
         }
     }
 }
 
-// Train a "big" model based on the specified type and data.
+// Train a "big" model based on the specified type and data. All high-precision
+// models are trained here; they predict through ModelBig, in 512-bit precision.
 // 指定されたタイプとデータに基づいて「ビッグ」モデルをトレーニングします。
-// 根据指定的类型和数据训练一个“大”模型。
+// すべての高精度モデルはここでトレーニングされ、ModelBigを通じて512ビット精度で予測します。
+// 根据指定的类型和数据训练一个“大”模型。所有高精度模型都在这里训练，
+// 它们通过ModelBig以512位精度进行预测。
 fn train_model_big<T: TrainingKey>(model_type: &str, data: &RMITrainingData<T>) -> Box<dyn Model> {
     let model: Box<dyn Model> = match model_type {
-        "linear" => Box::new(LinearModelBig::new(data)), // Train a big linear model.
+        "linear" | "linear_big" => Box::new(LinearModelBig::new(data)), // Train a big linear model.
         // 大きな線形モデルをトレーニングします。
         // 训练一个大线性模型。
-        _ => panic!("Unknown model type: {}", model_type), // Handle unknown model types.
-        // 未知のモデルタイプを処理します。
-        // 处理未知的模型类型。
+        _ => panic!("Unknown high-precision model type: {}", model_type), // Handle unknown model types.
+        // 未知の高精度モデルタイプを処理します。
+        // 处理未知的高精度模型类型。
     };
 
     return model;
 }
 
+// Train a model on every sample_rate-th key of the data (see RMITrainingData::sample).
+// データのsample_rate番目ごとのキーでモデルをトレーニングします。
+// 在数据的每第sample_rate个键上训练模型。
+pub(crate) fn train_model_sampled<T: TrainingKey>(model_type: &str, data: &RMITrainingData<T>,
+                                                 sample_rate: usize) -> Box<dyn Model> {
+    if sample_rate > 1 && data.len() > 0 {
+        return train_model(model_type, &data.sample(sample_rate));
+    }
+    return train_model(model_type, data);
+}
+
 // Train a model based on the specified type and data.
 // 指定されたタイプとデータに基づいてモデルをトレーニングします。
 // 根据指定的类型和数据训练一个模型。
 fn train_model<T: TrainingKey>(model_type: &str, data: &RMITrainingData<T>) -> Box<dyn Model> {
+    if let KeyType::F512 = T::key_type() {
+        // 512-bit float keys lose precision as f64, so they are always trained with "big" models.
+        // 512ビット浮動小数点キーはf64では精度が失われるため、常に「ビッグ」モデルでトレーニングします。
+        // 512位浮点键转换为f64会丢失精度，因此始终使用“大”模型进行训练。
+        // Only the linear model has a "big" variant, other types are rejected.
+        // 「ビッグ」版があるのは線形モデルだけなので、他のタイプは拒否します。
+        // 只有线性模型有“大”版本，因此拒绝其他类型。
+        assert!(matches!(model_type, "linear" | "linear_big"),
+                "Model type {} is not available for 512-bit float keys, use linear", model_type);
+        return train_model_big(model_type, data);
+    }
+
+    // Fixed-point linear models compute on the integer key itself, so the key has to fit in 128 bits.
+    // 固定小数点の線形モデルは整数キーそのもので計算するため、キーは128ビットに収まる必要があります。
+    // 定点线性模型直接用整数键计算，因此键必须能放进128位。
+    if model_type == "linear_fixed" {
+        assert!(matches!(T::key_type(), KeyType::U32 | KeyType::U64 | KeyType::U128 | KeyType::Bytes),
+                "Model type linear_fixed needs integer keys of at most 128 bits, not {} keys",
+                T::key_type().c_type());
+    }
+
     let model: Box<dyn Model> = match model_type {
-        "linear_big" => Box::new(LinearModelBig::new(data)), // Train a large linear model.
+        "linear_big" => train_model_big(model_type, data), // Train a large linear model.
         // 大きな線形モデルをトレーニングします。
         // 训练一个大线性模型。
         "linear" => Box::new(LinearModel::new(data)), // Train a standard linear model.
         // 標準の線形モデルをトレーニングします。
         // 训练一个标准线性模型。
+        "linear_fixed" => Box::new(LinearFixedModel::new(data)), // Train a fixed-point linear model (integer keys).
+        // 固定小数点の線形モデルをトレーニングします（整数キー）。
+        // 训练一个定点线性模型（整数键）。
         "pwl" => Box::new(PiecewiselinearModel::new(data, 28)), // Train a piecewise linear model with 28 segments.
         // 28セグメントの区分線形モデルをトレーニングします。
         // 训练一个有28段的分段线性模型。
         "pwl30" => Box::new(PiecewiselinearModel::new(data, 30)), // Train a piecewise linear model with 30 segments.
         // 30セグメントの区分線形モデルをトレーニングします。
         // 训练一个有30段的分段线性模型。
+        "poly2" => Box::new(PolynomialModel::new(data, 2)), // Train a least-squares polynomial of degree 2.
+        // 2次の最小二乗多項式をトレーニングします。
+        // 训练一个2次最小二乘多项式。
+        "poly3" => Box::new(PolynomialModel::new(data, 3)), // Train a least-squares polynomial of degree 3.
+        // 3次の最小二乗多項式をトレーニングします。
+        // 训练一个3次最小二乘多项式。
+        "poly4" => Box::new(PolynomialModel::new(data, 4)), // Train a least-squares polynomial of degree 4.
+        // 4次の最小二乗多項式をトレーニングします。
+        // 训练一个4次最小二乘多项式。
+        "pchip" => Box::new(PchipModel::new(data, 8)), // Train a monotone cubic Hermite spline with 8 knots.
+        // 8ノットの単調3次エルミートスプラインをトレーニングします。
+        // 训练一个有8个节点的单调三次埃尔米特样条。
+        s if s.starts_with("pchip") && s[5..].parse::<usize>().is_ok() =>
+            Box::new(PchipModel::new(data, s[5..].parse().unwrap())), // PCHIP spline with the given knot count, e.g. pchip16.
+        // 指定したノット数のPCHIPスプライン（例: pchip16）。
+        // 指定节点数的PCHIP样条（例如pchip16）。
+        s if radix_spline_params(s).is_some() => {
+            // Error-bounded spline with a radix table (top layer only), e.g. radix_spline_32_18
+            // for a spline error of 32 and an 18-bit table; plain radix_spline uses those values.
+            // 誤差制限付きスプラインと基数テーブル（最上位レイヤーのみ）。例: radix_spline_32_18 は
+            // スプライン誤差32、18ビットのテーブルです。radix_splineだけの場合はこの値を使います。
+            // 带基数表的误差有界样条（仅限顶层），例如radix_spline_32_18表示样条误差32、
+            // 18位的表；仅写radix_spline时使用这些值。
+            let (max_error, radix_bits) = radix_spline_params(s).unwrap();
+            Box::new(RadixSplineModel::new(data, max_error, radix_bits))
+        },
+        "kmer" => Box::new(KmerModel::new(data, 12)), // Train a k-mer balanced top model for DNA keys (k = 12).
+        // DNAキー用のk-merバランス型トップモデルをトレーニングします（k = 12）。
+        // 训练用于DNA键的k-mer均衡顶层模型（k = 12）。
+        s if s.starts_with("kmer") && s[4..].parse::<u32>().is_ok() =>
+            Box::new(KmerModel::new(data, s[4..].parse().unwrap())), // k-mer top model with the given k, e.g. kmer14.
+        // 指定したkのk-merトップモデル（例: kmer14）。
+        // 指定k的k-mer顶层模型（例如kmer14）。
+        "nn" => Box::new(NNModel::new(data)), // Train a small neural network (top layer only).
+        // 小さなニューラルネットワークをトレーニングします（最上位レイヤーのみ）。
+        // 训练一个小型神经网络（仅限顶层）。
         // Additional models are defined here...
         // 他のモデルもここで定義されます。
         // 这里还定义了其他模型。
@@ -157,14 +289,70 @@ fn train_model<T: TrainingKey>(model_type: &str, data: &RMITrainingData<T>) -> B
     return model;
 }
 
+// The spline error and radix table bits of a radix_spline or radix_spline_E_B model name.
+// radix_splineまたはradix_spline_E_Bというモデル名から、スプライン誤差と基数テーブルのビット数を取得します。
+// 从radix_spline或radix_spline_E_B模型名中获取样条误差和基数表位数。
+fn radix_spline_params(model_type: &str) -> Option<(usize, u8)> {
+    if model_type == "radix_spline" {
+        return Some((32, 18));
+    }
+
+    let rest = model_type.strip_prefix("radix_spline_")?;
+    let (max_error, radix_bits) = rest.split_once('_')?;
+    let radix_bits: u8 = radix_bits.parse().ok()?;
+    if radix_bits == 0 || radix_bits > 32 {
+        return None;
+    }
+    return Some((max_error.parse().ok()?, radix_bits));
+}
+
+// Rebuild a model of the given type from its params (see Model::params), as
+// written to a training checkpoint. Returns None for model types that cannot
+// be rebuilt from their params, or params that do not fit the type.
+// 学習チェックポイントに書き込まれたパラメータ（Model::params参照）から、
+// 指定されたタイプのモデルを再構築します。パラメータから再構築できないモデルタイプや、
+// タイプに合わないパラメータの場合はNoneを返します。
+// 根据写入训练检查点的参数（见Model::params）重建指定类型的模型。
+// 对于无法从参数重建的模型类型或与类型不符的参数，返回None。
+fn restore_model<T: TrainingKey>(model_type: &str, params: &[ModelParam]) -> Option<Box<dyn Model>> {
+    if let KeyType::F512 = T::key_type() {
+        // 512-bit float keys are always trained with "big" models (see train_model).
+        // 512ビット浮動小数点キーは常に「ビッグ」モデルで学習されます（train_model参照）。
+        // 512位浮点键始终使用“大”模型训练（见train_model）。
+        return match model_type {
+            "linear" | "linear_big" => LinearModelBig::from_params(params).map(|m| Box::new(m) as Box<dyn Model>),
+            _ => None,
+        };
+    }
+
+    return match model_type {
+        "linear_big" => LinearModelBig::from_params(params).map(|m| Box::new(m) as Box<dyn Model>),
+        "linear" => LinearModel::from_params(params).map(|m| Box::new(m) as Box<dyn Model>),
+        "pwl" | "pwl30" => PiecewiselinearModel::from_params(params).map(|m| Box::new(m) as Box<dyn Model>),
+        "poly2" | "poly3" | "poly4" => PolynomialModel::from_params(params)
+            .filter(|m| m.degree() == model_type[4..].parse::<usize>().unwrap())
+            .map(|m| Box::new(m) as Box<dyn Model>),
+        "nn" => NNModel::from_params(params).map(|m| Box::new(m) as Box<dyn Model>),
+        _ => None,
+    };
+}
+
+// Whether checkpoints can rebuild models of the given type.
+// チェックポイントから指定されたタイプのモデルを再構築できるかどうか。
+// 检查点能否重建指定类型的模型。
+fn can_restore<T: TrainingKey>(model_type: &str) -> bool {
+    let model = train_model(model_type, &RMITrainingData::<T>::empty());
+    return restore_model::<T>(model_type, &model.params()).is_some();
+}
+
 // Validate the model specification to ensure the correct order of models in layers.
 // レイヤー内のモデルの順序が正しいことを確認するためにモデル仕様を検証します。
 // 验证模型规格以确保层中的模型顺序正确。
-fn validate(model_spec: &[String]) {
+fn validate<T: TrainingKey>(model_spec: &[String]) {
     let num_layers = model_spec.len(); // Number of layers specified.
     // 指定されたレイヤー数。
     // 指定的层数。
-    let empty_container: RMITrainingData<u64> = RMITrainingData::empty(); // Create an empty RMITrainingData container.
+    let empty_container: RMITrainingData<T> = RMITrainingData::empty(); // Create an empty RMITrainingData container.
     // 空のRMITrainingDataコンテナを作成します。
     // 创建一个空的RMITrainingData容器。
 
@@ -198,6 +386,16 @@ fn validate(model_spec: &[String]) {
     }
 }
 
+// Settings of a training run that are not carried by the training data.
+// 学習データが持たない、学習実行の設定。
+// 训练数据不携带的训练运行设置。
+#[derive(Clone, Default)]
+pub struct TrainingConfig {
+    pub checkpoints: Option<Arc<Checkpoints>>, // Where the training stages are checkpointed, and resumed from.
+    // 学習段階のチェックポイントの書き込み先と再開元。
+    // 训练阶段检查点的写入位置和恢复来源。
+}
+
 // Train an RMI based on the given model specification and branching factor.
 // 指定されたモデル仕様と分岐係数に基づいてRMIをトレーニングします。
 // 根据给定的模型规格和分支因子训练RMI。
@@ -205,6 +403,18 @@ pub fn train<T: TrainingKey>(
     data: &RMITrainingData<T>,
     model_spec: &str,
     branch_factor: u64
+) -> TrainedRMI {
+    return train_with_config(data, model_spec, branch_factor, &TrainingConfig::default());
+}
+
+// Like train, with the settings of the training run in config.
+// trainと同様ですが、学習実行の設定をconfigで指定します。
+// 与train相同，但训练运行的设置由config给出。
+pub fn train_with_config<T: TrainingKey>(
+    data: &RMITrainingData<T>,
+    model_spec: &str,
+    branch_factor: u64,
+    config: &TrainingConfig
 ) -> TrainedRMI {
     let start_time = SystemTime::now(); // Start timing the training process.
     // トレーニングプロセスのタイミングを開始します。
@@ -214,13 +424,129 @@ pub fn train<T: TrainingKey>(
         // モデル仕様をレイヤーに分割し、最後のモデルを抽出します。
         // 将模型规格分成层并提取最后一个模型。
         let mut all_models: Vec<String> = model_spec.split(',').map(String::from).collect();
-        validate(&all_models); // Validate the model specification.
+        validate::<T>(&all_models); // Validate the model specification.
         // モデル仕様を検証します。
         // 验证模型规格。
         let last = all_models.pop().unwrap();
         (all_models, last)
     };
 
+    // With dedup semantics, train on the distinct keys and keep the run
+    // lengths of the duplicates, so that errors are not widened by long runs.
+    // 重複除去の場合、異なるキーのみで学習し、重複の連続の長さを保持します。
+    // これにより、長い連続によって誤差が広がりません。
+    // 去重时，仅在不同的键上训练并保留重复段的长度，避免长重复段扩大误差。
+    if let DuplicateSemantics::Dedup = data.duplicate_semantics() {
+        let (unique, run_lengths) = data.dedup_with_runs();
+        info!("Training on {} distinct keys ({} duplicate runs)",
+              unique.len(), run_lengths.len());
+        let mut res = train_with_config(&unique, model_spec, branch_factor, config);
+        res.duplicates = DuplicateSemantics::Dedup;
+        res.run_lengths = run_lengths;
+        res.num_data_rows = data.len();
+        res.build_time = SystemTime::now()
+            .duration_since(start_time)
+            .map(|d| d.as_nanos())
+            .unwrap_or(std::u128::MAX);
+        return res;
+    }
+
+    // Only two-layer RMIs keep the left and right errors of equal range windows.
+    // 等値範囲ウィンドウの左右の誤差を保持するのは2層RMIだけです。
+    // 只有双层RMI保存等值范围窗口的左右误差。
+    if data.duplicate_semantics() == DuplicateSemantics::EqualRange {
+        assert!(model_list.len() == 1 && data.max_error().is_none(),
+                "Equal range bounds are only trained for two-level specs without a maximum error, not {}",
+                model_spec);
+    }
+
+    // Signed leaf errors are kept by two-layer RMIs as well; the windows of
+    // partial three-layer RMIs are signed already.
+    // 符号付きリーフ誤差も2層RMIだけが保持します。部分的な3層RMIのウィンドウは既に符号付きです。
+    // 带符号的叶子误差同样只由双层RMI保存；部分三层RMI的窗口本身已带符号。
+    if data.signed_errors() && !(model_list.len() == 1 && data.max_error().is_none()) {
+        warn!("Signed leaf errors are only kept by two-level specs, {} keeps symmetric errors",
+              model_spec);
+    }
+
+    // Exact leaves replace the leaves of two-layer RMIs only.
+    // 厳密リーフが置き換えるのは2層RMIのリーフだけです。
+    // 精确叶子只替换双层RMI的叶子。
+    if data.exact_leaf_threshold().is_some() {
+        assert!(model_list.len() == 1 && data.max_error().is_none(),
+                "Exact leaves are only built for two-level specs without a maximum error, not {}",
+                model_spec);
+    }
+
+    // Only partial three-layer RMIs are trained in checkpointed stages.
+    // 段階ごとにチェックポイントを書き込んで学習するのは部分的な3層RMIだけです。
+    // 只有部分三层RMI按阶段写入检查点进行训练。
+    if config.checkpoints.is_some() {
+        assert!(model_list.len() == 2 || (model_list.len() == 1 && data.max_error().is_some()),
+                "Checkpoints are only written for three-level specs and two-level specs with a \
+                 maximum error, not {}", model_spec);
+    }
+
+    // Only the linear regressions fit the query weights, the other model types
+    // train as if every key were queried equally.
+    // クエリ重みを使うのは線形回帰だけで、他のモデルタイプはすべてのキーが
+    // 同じ頻度でクエリされるものとして学習します。
+    // 只有线性回归使用查询权重，其他模型类型按所有键被同等查询的方式训练。
+    if data.query_weights().is_some() {
+        for model in model_spec.split(',') {
+            if !matches!(model, "linear" | "linear_fixed" | "linear_big") {
+                warn!("Model type {} ignores the query weights, it is trained on unweighted keys", model);
+            }
+        }
+    }
+
+    if model_list.is_empty() {
+        // A single model indexes all of the data by itself.
+        // 単一のモデルがすべてのデータを単独でインデックスします。
+        // 单个模型独自索引所有数据。
+        let mut res = multi_layer::train_multi_layer(
+            &mut data.soft_copy(),
+            &model_list,
+            last_model,
+            branch_factor
+        );
+        res.build_time = SystemTime::now()
+            .duration_since(start_time)
+            .map(|d| d.as_nanos())
+            .unwrap_or(std::u128::MAX);
+
+        // Measure the error of every key if asked to, outside of the build time.
+        // 指定された場合、ビルド時間の外で、すべてのキーの誤差を測定します。
+        // 如有要求，在构建时间之外测量每个键的误差。
+        evaluate::measure_key_errors(data, &mut res);
+
+        return res;
+    }
+
+    if model_list.len() == 1 && data.max_error().is_some() {
+        // With a maximum error, leaves that exceed it are split by a partial
+        // third layer of the leaf model type.
+        // 最大誤差が指定された場合、それを超えるリーフは
+        // リーフモデルタイプの部分的な第3層で分割されます。
+        // 指定最大误差时，超过它的叶子由叶子模型类型的部分第三层拆分。
+        let mut res = two_layer::train_partial_three_layer(
+            &mut data.soft_copy(),
+            &model_list[0],
+            &last_model,
+            &last_model,
+            branch_factor,
+            config.checkpoints.as_deref()
+        );
+        res.build_time = SystemTime::now()
+            .duration_since(start_time)
+            .map(|d| d.as_nanos())
+            .unwrap_or(std::u128::MAX);
+
+        evaluate::measure_key_errors(data, &mut res);
+
+        return res;
+    }
+
     if model_list.len() == 1 {
         // If the specification contains only one layer, train a two-layer RMI.
         // 仕様に1つのレイヤーしか含まれていない場合、2層RMIをトレーニングします。
@@ -239,6 +565,37 @@ pub fn train<T: TrainingKey>(
         // 计算构建时间。
         res.build_time = build_time;
 
+        evaluate::measure_key_errors(data, &mut res);
+
+        return res;
+    }
+
+    if model_list.len() == 2 && data.memory_budget().limit().is_some()
+        && data.max_error().is_none() && config.checkpoints.is_none() {
+        // Under a memory limit, a three-level spec trains a full three-layer
+        // RMI, whose third layer is trained in windows of keys that fit in the
+        // limit.
+        // メモリ制限がある場合、3層の仕様は完全な3層RMIを学習します。その第3層は
+        // 制限内に収まるキーのウィンドウごとに学習されます。
+        // 有内存限制时，三层规格训练完整的三层RMI，其第三层按能放入限制的键窗口训练。
+        let num_second = (branch_factor as f64).sqrt() as u64;
+        assert!(num_second * num_second == branch_factor,
+                "Under a memory limit, {} is trained as a full three-layer RMI, whose branching \
+                 factor must be a square, not {}", model_spec, branch_factor);
+        let mut res = two_layer::train_three_layer(
+            &mut data.soft_copy(),
+            &model_list[0],
+            &model_list[1],
+            &last_model,
+            branch_factor
+        );
+        res.build_time = SystemTime::now()
+            .duration_since(start_time)
+            .map(|d| d.as_nanos())
+            .unwrap_or(std::u128::MAX);
+
+        evaluate::measure_key_errors(data, &mut res);
+
         return res;
     }
 
@@ -251,7 +608,8 @@ pub fn train<T: TrainingKey>(
             &model_list[0],
             &model_list[1],
             &last_model,
-            branch_factor
+            branch_factor,
+            config.checkpoints.as_deref()
         );
         let build_time = SystemTime::now()
             .duration_since(start_time)
@@ -261,6 +619,8 @@ pub fn train<T: TrainingKey>(
         // 计算构建时间。
         res.build_time = build_time;
 
+        evaluate::measure_key_errors(data, &mut res);
+
         return res;
     }
 
@@ -326,8 +686,8 @@ pub fn train_for_size<T: TrainingKey>(data: &RMITrainingData<T>, max_size: usize
 // Train an error-bounded RMI using cache fixing.
 // キャッシュ修正を使用してエラーが制限されたRMIをトレーニングします。
 // 使用缓存修复训练带有误差界限的RMI。
-pub fn train_bounded(
-    data: &RMITrainingData<u64>,
+pub fn train_bounded<T: TrainingKey>(
+    data: &RMITrainingData<T>,
     model_spec: &str,
     branch_factor: u64,
     line_size: usize
@@ -347,7 +707,7 @@ pub fn train_bounded(
     // Reindex the spline points so they can be used to build an RMI.
     // スプラインポイントのインデックスを再作成して、RMIを構築できるようにします。
     // 重新索引样条点以便用于构建RMI。
-    let reindexed_splines: Vec<(u64, usize)> = spline
+    let reindexed_splines: Vec<(T, usize)> = spline
         .iter()
         .enumerate()
         .map(|(idx, (key, _old_offset))| (*key, idx))
@@ -361,7 +721,9 @@ pub fn train_bounded(
     let mut res = crate::train(&mut new_data, model_spec, branch_factor); // Train the RMI on the spline data.
     // スプラインデータに基づいてRMIをトレーニングします。
     // 在样条数据上训练RMI。
-    res.cache_fix = Some((line_size, spline)); // Store the cache fix information.
+    res.cache_fix = Some((line_size, spline.iter()
+                          .map(|(key, offset)| (key.to_model_input(), *offset))
+                          .collect())); // Store the cache fix information.
     // キャッシュ修正情報を保存します。
     // 存储缓存修复信息。
     res.num_data_rows = data.len(); // Store the number of data rows.
@@ -406,7 +768,7 @@ pub fn driver_validation<T: TrainingKey>(
 ) -> ValidationResults {
     let (model_list, last_model): (Vec<String>, String) = {
         let mut all_models: Vec<String> = model_spec.split(',').map(String::from).collect();
-        validate(&all_models);
+        validate::<T>(&all_models);
         let last = all_models.pop().unwrap();
         (all_models, last)
     };
@@ -482,7 +844,8 @@ pub fn driver_validation<T: TrainingKey>(
             &model_list[0],
             &model_list[1],
             &last_model,
-            branch_factor
+            branch_factor,
+            None
         );
         let train_partial_three_layer_build_time = SystemTime::now()
             .duration_since(start_time)
@@ -504,3 +867,1039 @@ pub fn driver_validation<T: TrainingKey>(
         partial_three_layer: train_partial_three_layer_res,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 30000 distinct keys with uneven gaps, and a run of duplicates every 97 keys
+    fn uneven_keys_with_runs() -> RMITrainingData<u64> {
+        let mut keys: Vec<u64> = (0..30000u64).map(|i| 5 + i * i / 7 + 3 * i).collect();
+        let runs: Vec<u64> = keys.iter().step_by(97).flat_map(|&k| vec![k, k]).collect();
+        keys.extend(runs);
+        keys.sort();
+        let data: Vec<(u64, usize)> = keys.iter().cloned().enumerate().map(|(i, k)| (k, i)).collect();
+        return RMITrainingData::new(Box::new(data));
+    }
+
+    #[test]
+    fn test_train_f512() {
+        // keys 1 + i * 2^-200 are indistinguishable as f64
+        let data: Vec<(F512, usize)> = (0..1000)
+            .map(|i| {
+                let k = Float::with_val(512, 1) + (Float::with_val(512, i) >> 200);
+                (F512::from_float(&k), i as usize)
+            })
+            .collect();
+        let md = RMITrainingData::new(Box::new(data));
+
+        let trained = train(&md, "linear,linear", 4);
+        assert_eq!(trained.rmi[0][0].input_type().c_type(), "cpp_bin_float_512");
+
+        // the same keys scaled to integers should give the same accuracy
+        let int_data: Vec<(u64, usize)> = (0..1000).map(|i| ((1 << 40) + i, i as usize)).collect();
+        let int_trained = train(&RMITrainingData::new(Box::new(int_data)), "linear,linear", 4);
+        assert_eq!(trained.model_max_error, int_trained.model_max_error);
+
+        let dir = std::env::temp_dir().join("rmi_test_train_f512");
+        std::fs::create_dir_all(&dir).unwrap();
+        crate::output_rmi("f512", trained, dir.to_str().unwrap(), KeyType::F512, true).unwrap();
+
+        // the root layer holds two 512-bit parameters in the key file layout
+        let root = std::fs::read(dir.join("f512_L0_PARAMETERS")).unwrap();
+        assert_eq!(root.len(), 2 * 68);
+        let slope = F512::from_le_bytes(&root[68..]).to_float();
+        assert!(slope > 1e50);
+    }
+
+    #[test]
+    #[should_panic(expected = "not available for 512-bit float keys")]
+    fn test_train_f512_nonlinear() {
+        let data: Vec<(F512, usize)> = (0..100)
+            .map(|i| (F512::from_float(&Float::with_val(512, i)), i as usize))
+            .collect();
+        train(&RMITrainingData::new(Box::new(data)), "pwl,linear", 4);
+    }
+
+    #[test]
+    fn test_train_bytes() {
+        // groups of 10 keys share the 8 encoded bytes after the common prefix
+        let strings: Vec<String> = (0..1000)
+            .map(|i| format!("ACGT{:04}NNNNNNNN{:02}", i / 10, i % 10))
+            .collect();
+        let skip = crate::shared_prefix_len(strings[0].as_bytes(),
+                                            strings[999].as_bytes());
+        let data: Vec<(BytesKey, usize)> = strings.iter().enumerate()
+            .map(|(i, s)| (BytesKey::new(s.as_bytes(), skip), i))
+            .collect();
+        let md = RMITrainingData::new(Box::new(data.clone()));
+
+        let trained = train(&md, "linear,linear", 8);
+
+        // every key must be found within the error window of its leaf,
+        // even though the models cannot tell keys within a group apart
+        for (key, idx) in data {
+            let inp = key.to_model_input();
+            let leaf = u64::min(7, trained.rmi[0][0].predict_to_int(&inp)) as usize;
+            let pred = trained.rmi[1][leaf].predict_to_int(&inp);
+            let err = trained.last_layer_max_l1s[leaf];
+            assert!(pred.max(idx as u64) - pred.min(idx as u64) <= err,
+                    "key {} predicted at {} with error {}", idx, pred, err);
+        }
+
+        let dir = std::env::temp_dir().join("rmi_test_train_bytes");
+        std::fs::create_dir_all(&dir).unwrap();
+        crate::output_rmi("bytes", trained, dir.to_str().unwrap(), KeyType::Bytes, true).unwrap();
+        crate::output_key_encoding("bytes", dir.to_str().unwrap(),
+                                   &strings[0].as_bytes()[..skip], 1000, true).unwrap();
+
+        let prefix = std::fs::read(dir.join("bytes_KEY_PREFIX")).unwrap();
+        assert_eq!(prefix, b"ACGT00");
+        let header = std::fs::read_to_string(dir.join("bytes_key.h")).unwrap();
+        assert!(header.contains("const size_t KEY_PREFIX_LENGTH = 6;"));
+        assert!(header.contains("uint64_t lookup(const char* key, size_t len, size_t* err)"));
+    }
+
+    #[test]
+    fn test_train_duplicates() {
+        // long runs of duplicates, as with truncated suffix array prefixes
+        let keys: Vec<u64> = (0..2000u64).map(|i| (i / 200) * 1000 + (i % 7) * (i / 1000)).collect();
+        let mut keys = keys;
+        keys.sort();
+        let data: Vec<(u64, usize)> = keys.iter().cloned().enumerate().map(|(i, k)| (k, i)).collect();
+
+        let lower = train(&RMITrainingData::new(Box::new(data.clone())), "linear,linear", 4);
+        assert_eq!(lower.duplicates, DuplicateSemantics::LowerBound);
+
+        // upper bound: every key is found at its last position
+        let mut md = RMITrainingData::new(Box::new(data.clone()));
+        md.set_duplicate_semantics(DuplicateSemantics::UpperBound);
+        let upper = train(&md, "linear,linear", 4);
+        assert_eq!(upper.duplicates, DuplicateSemantics::UpperBound);
+        for (key, idx) in md.iter() {
+            let inp = key.to_model_input();
+            let leaf = u64::min(3, upper.rmi[0][0].predict_to_int(&inp)) as usize;
+            let pred = upper.rmi[1][leaf].predict_to_int(&inp);
+            let err = upper.last_layer_max_l1s[leaf];
+            assert!(pred.max(idx as u64) - pred.min(idx as u64) <= err);
+            assert!(idx + 1 == keys.len() || keys[idx + 1] != key);
+        }
+        // absent keys are probed rather than covered by the longest run
+        assert!(verify(&md, &upper).passed());
+        assert!(upper.model_max_error < lower.model_max_error);
+
+        // dedup: the errors no longer include the run lengths
+        let mut md = RMITrainingData::new(Box::new(data));
+        md.set_duplicate_semantics(DuplicateSemantics::Dedup);
+        let dedup = train(&md, "linear,linear", 4);
+        let mut distinct = keys.clone();
+        distinct.dedup();
+        assert_eq!(dedup.duplicates, DuplicateSemantics::Dedup);
+        assert_eq!(dedup.num_rmi_rows, distinct.len());
+        assert_eq!(dedup.num_data_rows, 2000);
+        assert_eq!(dedup.run_lengths.iter().map(|(_r, l)| l).sum::<u64>() as usize
+                   + distinct.len() - dedup.run_lengths.len(), 2000);
+        assert!(dedup.model_max_error < lower.model_max_error);
+
+        let dir = std::env::temp_dir().join("rmi_test_train_duplicates");
+        std::fs::create_dir_all(&dir).unwrap();
+        let num_runs = dedup.run_lengths.len();
+        crate::output_rmi("dups", dedup, dir.to_str().unwrap(), KeyType::U64, true).unwrap();
+        let meta = std::fs::read(dir.join("dups_DUPLICATES")).unwrap();
+        assert_eq!(meta.len(), 16 + 16 * num_runs);
+        assert_eq!(meta[0], 2);
+        assert_eq!(meta[8] as usize, num_runs);
+        let header = std::fs::read_to_string(dir.join("dups_duplicates.h")).unwrap();
+        assert!(header.contains("const uint64_t NUM_DISTINCT_KEYS = "));
+        assert!(header.contains("const uint64_t NUM_ROWS = 2000;"));
+        assert!(header.contains("inline uint64_t lookup_rows(const Duplicates& dups, uint64_t key,"));
+    }
+
+    #[test]
+    fn test_train_equal_range() {
+        let keys: Vec<u64> = (0..2000u64).map(|i| (i / 200) * 1000 + (i % 7) * (i / 1000)).collect();
+        let mut keys = keys;
+        keys.sort();
+        let data: Vec<(u64, usize)> = keys.iter().cloned().enumerate().map(|(i, k)| (k, i)).collect();
+
+        // every window holds the first position of a key and one past its last
+        let mut md = RMITrainingData::new(Box::new(data));
+        md.set_duplicate_semantics(DuplicateSemantics::EqualRange);
+        let range = train(&md, "linear,linear", 4);
+        assert_eq!(range.duplicates, DuplicateSemantics::EqualRange);
+        assert_eq!(range.left_errors.len(), 4);
+        assert_eq!(range.right_errors.len(), 4);
+        for (key, idx) in md.iter() {
+            let inp = key.to_model_input();
+            let leaf = u64::min(3, range.rmi[0][0].predict_to_int(&inp)) as usize;
+            let pred = range.rmi[1][leaf].predict_to_int(&inp);
+            let end = keys.iter().rposition(|&k| k == key).unwrap() + 1;
+            assert!(pred.saturating_sub(range.left_errors[leaf]) <= idx as u64);
+            assert!(pred + range.right_errors[leaf] >= end as u64);
+            assert_eq!(range.last_layer_max_l1s[leaf],
+                       u64::max(range.left_errors[leaf], range.right_errors[leaf]));
+        }
+        assert!(verify(&md, &range).passed());
+
+        // the leaves store both errors after their parameters
+        let dir = std::env::temp_dir().join("rmi_test_train_equal_range");
+        std::fs::create_dir_all(&dir).unwrap();
+        crate::output_rmi("range", range, dir.to_str().unwrap(), KeyType::U64, true).unwrap();
+        let leaves = std::fs::read(dir.join("range_L1_PARAMETERS")).unwrap();
+        assert_eq!(leaves.len(), 4 * 8 * 4);
+        let meta = std::fs::read(dir.join("range_DUPLICATES")).unwrap();
+        assert_eq!(meta[0], 3);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_train_signed_errors() {
+        let md = uneven_keys_with_runs();
+
+        for semantics in &[DuplicateSemantics::LowerBound, DuplicateSemantics::UpperBound,
+                           DuplicateSemantics::Dedup] {
+            let mut md = md.soft_copy();
+            md.set_duplicate_semantics(*semantics);
+            let symmetric = train(&md, "linear,linear", 64);
+            md.set_signed_errors(true);
+            let signed = train(&md, "linear,linear", 64);
+
+            // the same models, with windows no wider on either side
+            assert!(symmetric.left_errors.is_empty());
+            assert_eq!(signed.last_layer_max_l1s, symmetric.last_layer_max_l1s);
+            for leaf in 0..64 {
+                assert!(signed.left_errors[leaf] <= signed.last_layer_max_l1s[leaf]);
+                assert!(signed.right_errors[leaf] <= signed.last_layer_max_l1s[leaf]);
+            }
+            assert!(signed.model_avg_window < symmetric.model_avg_window);
+            assert_eq!(symmetric.model_avg_window, 2.0 * symmetric.model_avg_error + 1.0);
+            assert!(verify(&md, &signed).passed());
+        }
+
+        // exact leaves find every key at the first row of its run, so only
+        // the window above the search has room for the run
+        let mut md = md.soft_copy();
+        md.set_signed_errors(true);
+        md.set_exact_leaf_threshold(Some(2));
+        let hybrid = train(&md, "linear,linear", 64);
+        let exact: Vec<usize> = (0..64).filter(|&l| hybrid.rmi[1][l].exact_keys().is_some()).collect();
+        assert!(!exact.is_empty());
+        for leaf in exact {
+            assert_eq!(hybrid.left_errors[leaf], 0);
+            assert_eq!(hybrid.right_errors[leaf], hybrid.last_layer_max_l1s[leaf]);
+        }
+        assert!(verify(&md, &hybrid).passed());
+
+        // both errors are written after the parameters of each leaf
+        md.set_exact_leaf_threshold(None);
+        let signed = train(&md, "linear,linear", 64);
+        let dir = std::env::temp_dir().join("rmi_test_train_signed_errors");
+        std::fs::create_dir_all(&dir).unwrap();
+        crate::output_rmi("signed", signed, dir.to_str().unwrap(), KeyType::U64, true).unwrap();
+        let leaves = std::fs::read(dir.join("signed_L1_PARAMETERS")).unwrap();
+        assert_eq!(leaves.len(), 64 * 8 * 4);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_error_distribution() {
+        let mut md = uneven_keys_with_runs();
+        let num_keys = md.len();
+
+        // the keys are only looked up when asked for
+        assert!(train(&md, "linear,linear", 64).error_distribution.is_none());
+        md.set_key_errors(true);
+        let rmi = train(&md, "linear,linear", 64);
+        let dist = rmi.error_distribution.as_ref().unwrap();
+
+        // the error of every key, with duplicates at their first position
+        let mut errors = Vec::new();
+        let mut leaf_errors = vec![Vec::new(); 64];
+        for (key, idx) in md.iter().take(num_keys) {
+            let inp = key.to_model_input();
+            let leaf = u64::min(63, rmi.rmi[0][0].predict_to_int(&inp)) as usize;
+            let pred = u64::min(rmi.rmi[1][leaf].predict_to_int(&inp), num_keys as u64);
+            let err = u64::max(pred, idx as u64) - u64::min(pred, idx as u64);
+            errors.push(err);
+            leaf_errors[leaf].push(err);
+        }
+        errors.sort();
+        assert_eq!(dist.keys, num_keys);
+        for (p, reported) in &[(0.5, dist.p50), (0.9, dist.p90), (0.99, dist.p99), (0.999, dist.p999)] {
+            let exact = errors[(p * errors.len() as f64).ceil() as usize - 1];
+            assert!(*reported >= exact && *reported <= exact + exact / 128,
+                    "p{} is {}, exactly {}", p * 100.0, reported, exact);
+        }
+        assert!(dist.p50 <= dist.p90 && dist.p90 <= dist.p99 && dist.p99 <= dist.p999);
+        assert!(dist.p999 <= rmi.model_max_error);
+        let mean = errors.iter().sum::<u64>() as f64 / errors.len() as f64;
+        assert!((dist.mean - mean).abs() < 1e-6);
+
+        for leaf in 0..64 {
+            let max = leaf_errors[leaf].iter().cloned().max().unwrap_or(0);
+            assert_eq!(dist.leaf_max_errors[leaf], max);
+            assert!(max <= rmi.last_layer_max_l1s[leaf]);
+            if !leaf_errors[leaf].is_empty() {
+                let mean = leaf_errors[leaf].iter().sum::<u64>() as f64 / leaf_errors[leaf].len() as f64;
+                assert!((dist.leaf_mean_errors[leaf] - mean).abs() < 1e-6);
+            }
+        }
+
+        // every key is in one window bucket, and the widest holds the largest window
+        assert_eq!(dist.window_log2_histogram.iter().sum::<u64>() as usize, num_keys);
+        let widest = 2 * rmi.model_max_error + 1;
+        assert_eq!(dist.window_log2_histogram.len() as u32, 64 - (widest - 1).leading_zeros() + 1);
+        assert!(rmi.to_json()["error_distribution"]["p99.9"].as_u64().is_some());
+
+        // partial three-layer and deduplicated RMIs are measured too
+        let mut partial = md.soft_copy();
+        partial.set_max_error(Some(4));
+        let res = train(&partial, "linear,linear", 64);
+        assert!(res.error_distribution.unwrap().p999 <= 8);
+        let mut dedup = md.soft_copy();
+        dedup.set_duplicate_semantics(DuplicateSemantics::Dedup);
+        assert_eq!(train(&dedup, "linear,linear", 64).error_distribution.unwrap().keys, 30000);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_train_equal_range_three_layer() {
+        let data: Vec<(u64, usize)> = (0..2000u64).map(|i| (i * 3, i as usize)).collect();
+        let mut md = RMITrainingData::new(Box::new(data));
+        md.set_duplicate_semantics(DuplicateSemantics::EqualRange);
+        train(&md, "linear,linear,linear", 4);
+    }
+
+    #[test]
+    fn test_train_sampled() {
+        let data: Vec<(u64, usize)> = (0..20000u64).map(|i| (i * i / 7 + 3 * i + 1, i as usize)).collect();
+        let md = RMITrainingData::new(Box::new(data));
+        let full = train(&md, "linear,linear", 64);
+        assert_eq!(full.sample_rate, 1);
+
+        let mut explicit = md.soft_copy();
+        explicit.set_sample_rate(1);
+        let same = train(&explicit, "linear,linear", 64);
+        assert_eq!(same.last_layer_max_l1s, full.last_layer_max_l1s);
+
+        // errors are still computed over every key
+        let mut sampled = md.soft_copy();
+        sampled.set_sample_rate(16);
+        let rmi = train(&sampled, "linear,linear", 64);
+        assert_eq!(rmi.sample_rate, 16);
+        assert_eq!(rmi.num_rmi_rows, 20000);
+        for (key, idx) in md.iter().take(md.len()) {
+            let inp = key.to_model_input();
+            let leaf = u64::min(63, rmi.rmi[0][0].predict_to_int(&inp)) as usize;
+            let pred = rmi.rmi[1][leaf].predict_to_int(&inp);
+            let err = rmi.last_layer_max_l1s[leaf];
+            assert!(pred.max(idx as u64) - pred.min(idx as u64) <= err);
+        }
+
+        // the other builders fit their models on the sample as well
+        let differs = |a: &Box<dyn Model>, b: &Box<dyn Model>| md.iter()
+            .any(|(key, _)| a.predict_to_int(&key.to_model_input()) != b.predict_to_int(&key.to_model_input()));
+        let single = train(&sampled, "linear", 1);
+        assert_eq!(single.sample_rate, 16);
+        assert!(differs(&single.rmi[0][0], &train(&md, "linear", 1).rmi[0][0]));
+
+        let three = two_layer::train_three_layer(&mut sampled.soft_copy(), "linear", "linear", "linear", 64);
+        let all = two_layer::train_three_layer(&mut md.soft_copy(), "linear", "linear", "linear", 64);
+        assert_eq!(three.sample_rate, 16);
+        assert!(differs(&three.rmi[0][0], &all.rmi[0][0]));
+        assert!((0..64).any(|leaf| differs(&three.rmi[2][leaf], &all.rmi[2][leaf])));
+        assert!(verify(&md, &three).passed());
+
+        let naive = two_layer::train_naive_three_layer(&mut sampled.soft_copy(), "linear", "linear", "linear", 64);
+        let all = two_layer::train_naive_three_layer(&mut md.soft_copy(), "linear", "linear", "linear", 64);
+        assert_eq!(naive.sample_rate, 16);
+        assert!(differs(&naive.rmi[0][0], &all.rmi[0][0]));
+        assert!((0..64).any(|leaf| differs(&naive.rmi[2][leaf], &all.rmi[2][leaf])));
+        assert!(verify(&md, &naive).passed());
+    }
+
+    #[test]
+    fn test_train_hybrid() {
+        // evenly spaced keys, except for a dense, exponentially spaced cluster
+        let mut keys: Vec<u64> = (0..10000u64).map(|i| 1 + i * 1000).collect();
+        keys.extend((0..40u64).map(|i| 5_000_000 + (1u64 << (i / 2)) + i));
+        keys.sort();
+        let data: Vec<(u64, usize)> = keys.iter().enumerate().map(|(i, k)| (*k, i)).collect();
+        let md = RMITrainingData::new(Box::new(data));
+        let plain = train(&md, "linear,linear", 64);
+        let worst = *plain.last_layer_max_l1s.iter().max().unwrap();
+        assert!(worst > 8);
+
+        let mut hybrid_data = md.soft_copy();
+        hybrid_data.set_exact_leaf_threshold(Some(8));
+        let hybrid = train(&hybrid_data, "linear,linear", 64);
+        let exact = hybrid.rmi[1].iter().filter(|m| m.exact_keys().is_some()).count();
+        assert!(exact > 0);
+        assert_eq!(hybrid.to_json()["exact_leaves"], exact);
+        assert!(*hybrid.last_layer_max_l1s.iter().max().unwrap() <= 8);
+
+        for (key, idx) in md.iter().take(md.len()) {
+            let inp = key.to_model_input();
+            let leaf = u64::min(63, hybrid.rmi[0][0].predict_to_int(&inp)) as usize;
+            let pred = hybrid.rmi[1][leaf].predict_to_int(&inp);
+            let err = hybrid.last_layer_max_l1s[leaf];
+            assert!(pred.max(idx as u64) - pred.min(idx as u64) <= err);
+        }
+
+        let dir = std::env::temp_dir().join("rmi_test_train_hybrid");
+        std::fs::create_dir_all(&dir).unwrap();
+        crate::output_rmi("hybrid", hybrid, dir.to_str().unwrap(), KeyType::U64, true).unwrap();
+        let side = std::fs::read(dir.join("hybrid_EXACT_LEAVES")).unwrap();
+        assert_eq!(side[0] as usize, exact);
+        let header = std::fs::read_to_string(dir.join("hybrid_exact_leaves.h")).unwrap();
+        assert!(header.contains("NUM_INDEXED_ROWS = 10040;"));
+        assert!(header.contains("inline uint64_t lookup_exact(const ExactLeaves& leaves, uint64_t key, size_t* err)"));
+    }
+
+    #[test]
+    #[should_panic(expected = "Exact leaves are only built")]
+    fn test_train_hybrid_max_error() {
+        let mut md = RMITrainingData::new(Box::new(vec![(1u64, 0usize), (2, 1)]));
+        md.set_exact_leaf_threshold(Some(8));
+        md.set_max_error(Some(4));
+        train(&md, "linear,linear", 4);
+    }
+
+    #[test]
+    fn test_train_max_error() {
+        let data: Vec<(u64, usize)> = (0..20000u64)
+            .map(|i| ((i + 5000) * (i + 5000) * (i + 5000) / 1000, i as usize))
+            .collect();
+        let md = RMITrainingData::new(Box::new(data));
+        let plain = train(&md, "linear,linear", 8);
+        assert!(plain.model_max_error > 64);
+
+        let mut bounded_data = md.soft_copy();
+        bounded_data.set_max_error(Some(64));
+        let bounded = train(&bounded_data, "linear,linear", 8);
+        assert_eq!(bounded.rmi.len(), 3);
+        assert!(bounded.rmi[1].len() > 8);
+        assert_eq!(bounded.rmi[1].len(), bounded.third_layer_max_l1s.len());
+
+        // every model meets the target, lower bound probes included; leaves
+        // with a third layer are marked by the top bit
+        let window = |err: &u64| (err & 0x7fffffff) + ((err >> 32) & 0x3fffffff);
+        for err in bounded.last_layer_max_l1s.iter().filter(|e| *e >> 63 == 0)
+            .chain(bounded.third_layer_max_l1s.iter()) {
+            assert!(window(err) <= 64, "window {}", window(err));
+        }
+    }
+
+    #[test]
+    fn test_train_big_u512() {
+        // keys that only differ below the top 53 bits
+        let base = U512::from(0xABCDu64) << 400;
+        let data: Vec<(U512, usize)> = (0..4096u64)
+            .map(|i| (base + (U512::from(i * i + i) << 100), i as usize))
+            .collect();
+        let md = RMITrainingData::new(Box::new(data));
+        let plain = train(&md, "linear,linear", 64);
+        let big = train(&md, "linear_big,linear_big", 64);
+        assert!(big.model_max_error * 10 < plain.model_max_error,
+                "max error {} (f64 models: {})", big.model_max_error, plain.model_max_error);
+
+        for (key, idx) in md.iter().take(md.len()) {
+            let inp = key.to_model_input();
+            let leaf = u64::min(63, big.rmi[0][0].predict_to_int(&inp)) as usize;
+            let pred = big.rmi[1][leaf].predict_to_int(&inp);
+            let err = big.last_layer_max_l1s[leaf];
+            assert!(pred.max(idx as u64) - pred.min(idx as u64) <= err);
+        }
+    }
+
+    #[test]
+    fn test_train_linear_fixed() {
+        let data: Vec<(u64, usize)> = (0..20000u64)
+            .map(|i| ((1 << 62) + i * i * 5 + (i % 7), i as usize))
+            .collect();
+        let md = RMITrainingData::new(Box::new(data));
+        let rmi = train(&md, "linear_fixed,linear_fixed", 64);
+        assert_eq!(rmi.rmi[1][0].function_name(), "linear_fixed");
+
+        // routing and errors use the same integer arithmetic as the generated code
+        for (key, idx) in md.iter().take(md.len()) {
+            let inp = key.to_model_input();
+            let leaf = u64::min(63, rmi.rmi[0][0].predict_to_int(&inp)) as usize;
+            let pred = rmi.rmi[1][leaf].predict_to_int(&inp);
+            let err = rmi.last_layer_max_l1s[leaf];
+            assert!(pred.max(idx as u64) - pred.min(idx as u64) <= err);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "linear_fixed needs integer keys of at most 128 bits")]
+    fn test_train_linear_fixed_wide_keys() {
+        let data: Vec<(U512, usize)> = (0..100u64).map(|i| (U512::from(i) << 200, i as usize)).collect();
+        train(&RMITrainingData::new(Box::new(data)), "linear,linear_fixed", 4);
+    }
+
+    #[test]
+    fn test_train_kmer() {
+        // random 32-mers, plus a repeat that shares its first 10 bases
+        let mut keys: Vec<u64> = (1..20000u64).map(|i| i.wrapping_mul(0x9E3779B97F4A7C15)).collect();
+        keys.extend((0..5000u64).map(|i| (0xABCDEu64 << 44) | (i * 977)));
+        keys.sort();
+        keys.dedup();
+        let data: Vec<(u64, usize)> = keys.iter().enumerate().map(|(i, k)| (*k, i)).collect();
+        let md = RMITrainingData::new(Box::new(data));
+        let rmi = train(&md, "kmer10,linear", 256);
+
+        for (key, idx) in md.iter().take(md.len()) {
+            let inp = key.to_model_input();
+            let leaf = rmi.rmi[0][0].predict_to_int(&inp) as usize;
+            assert!(leaf < 256);
+            let pred = rmi.rmi[1][leaf].predict_to_int(&inp);
+            let err = rmi.last_layer_max_l1s[leaf];
+            assert!(pred.max(idx as u64) - pred.min(idx as u64) <= err);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "kmer needs 2-bit packed integer keys, not double keys")]
+    fn test_train_kmer_float_keys() {
+        let data: Vec<(f64, usize)> = (0..100).map(|i| (i as f64, i as usize)).collect();
+        train(&RMITrainingData::new(Box::new(data)), "kmer,linear", 4);
+    }
+
+    #[test]
+    fn test_train_pchip() {
+        let data: Vec<(u64, usize)> = (0..20000u64)
+            .map(|i| (1000 + i * i + (i % 7), i as usize))
+            .collect();
+        let md = RMITrainingData::new(Box::new(data));
+        let linear = train(&md, "linear,linear", 64);
+        let rmi = train(&md, "linear,pchip16", 64);
+
+        for (key, idx) in md.iter().take(md.len()) {
+            let inp = key.to_model_input();
+            let leaf = u64::min(63, rmi.rmi[0][0].predict_to_int(&inp)) as usize;
+            let pred = rmi.rmi[1][leaf].predict_to_int(&inp);
+            let err = rmi.last_layer_max_l1s[leaf];
+            assert!(pred.max(idx as u64) - pred.min(idx as u64) <= err);
+        }
+        assert!(rmi.model_max_error < linear.model_max_error);
+    }
+
+    #[test]
+    fn test_train_radix_spline() {
+        assert_eq!(radix_spline_params("radix_spline"), Some((32, 18)));
+        assert_eq!(radix_spline_params("radix_spline_8_12"), Some((8, 12)));
+        assert_eq!(radix_spline_params("radix_spline_8"), None);
+        assert_eq!(radix_spline_params("radix_spline_8_40"), None);
+
+        let data: Vec<(u64, usize)> = (0..20000u64)
+            .map(|i| (1000 + i * i + (i % 7), i as usize))
+            .collect();
+        let md = RMITrainingData::new(Box::new(data));
+        let rmi = train(&md, "radix_spline_4_12,linear", 64);
+        for (key, idx) in md.iter().take(md.len()) {
+            let inp = key.to_model_input();
+            let leaf = u64::min(63, rmi.rmi[0][0].predict_to_int(&inp)) as usize;
+            let pred = rmi.rmi[1][leaf].predict_to_int(&inp);
+            let err = rmi.last_layer_max_l1s[leaf];
+            assert!(pred.max(idx as u64) - pred.min(idx as u64) <= err);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "must be the root model")]
+    fn test_radix_spline_leaf() {
+        let md = RMITrainingData::new(Box::new(vec![(1u64, 0usize), (2, 1)]));
+        train(&md, "linear,radix_spline", 4);
+    }
+
+    #[test]
+    fn test_train_query_weights() {
+        let data: Vec<(u64, usize)> = (0..20000u64)
+            .map(|i| (1000 + i * i + (i % 7), i as usize))
+            .collect();
+        let plain = RMITrainingData::new(Box::new(data));
+        let mut md = plain.soft_copy();
+        let hot = QueryWeights::parse("225001000 50\n289001000 1", KeyType::U64).unwrap();
+        md.set_query_weights(Some(Arc::new(hot)));
+
+        // the expected log2 error and squared error of a lookup, under the
+        // query weights
+        let query_cost = |rmi: &TrainedRMI| {
+            let (mut log2_error, mut squared_error, mut total) = (0.0, 0.0, 0.0);
+            for (key, idx) in md.iter().take(md.len()) {
+                let inp = key.to_model_input();
+                let leaf = u64::min(15, rmi.rmi[0][0].predict_to_int(&inp)) as usize;
+                let w = md.weight(&key);
+                log2_error += w * ((2 * rmi.last_layer_max_l1s[leaf] + 2) as f64).log2();
+                squared_error += w * (rmi.rmi[1][leaf].predict_to_float(&inp) - idx as f64).powi(2);
+                total += w;
+            }
+            (log2_error / total, squared_error / total)
+        };
+
+        // the pchip top model ignores the weights, so both RMIs have the same
+        // leaves and only the leaf regressions differ
+        let uniform = train(&plain, "pchip16,linear", 16);
+        let weighted = train(&md, "pchip16,linear", 16);
+        let (weighted_log2, weighted_squared) = query_cost(&weighted);
+        let (_, uniform_squared) = query_cost(&uniform);
+        assert!((weighted.model_avg_log2_error - weighted_log2).abs() < 1e-4);
+        assert!(weighted_squared < uniform_squared,
+                "weighted {} uniform {}", weighted_squared, uniform_squared);
+    }
+
+    #[test]
+    fn test_partial_query_weights() {
+        let data: Vec<(u64, usize)> = (0..20000u64)
+            .map(|i| (1000 + i * i + (i % 7), i as usize))
+            .collect();
+        let mut plain = RMITrainingData::new(Box::new(data));
+        plain.set_max_error(Some(16));
+        let unweighted = train(&plain, "linear,linear", 16);
+
+        // equal weights average over the keys, like no weights at all (up to
+        // rounding in the weighted regressions)
+        let mut md = plain.soft_copy();
+        md.set_query_weights(Some(Arc::new(QueryWeights::parse("0 3", KeyType::U64).unwrap())));
+        let equal = train(&md, "linear,linear", 16);
+        assert!((equal.model_avg_log2_error - unweighted.model_avg_log2_error).abs() < 1e-3,
+                "equal weights {} no weights {}", equal.model_avg_log2_error, unweighted.model_avg_log2_error);
+
+        // the models that serve the hot keys count for almost all of it
+        md.set_query_weights(Some(Arc::new(QueryWeights::parse("0 1\n225001000 100000", KeyType::U64).unwrap())));
+        let hot = train(&md, "linear,linear", 16);
+        assert!((hot.model_avg_log2_error - unweighted.model_avg_log2_error).abs() > 1e-2,
+                "hot weights {} no weights {}", hot.model_avg_log2_error, unweighted.model_avg_log2_error);
+        for rmi in [&unweighted, &hot] {
+            assert!(rmi.model_avg_log2_error > 0.0);
+            assert!(rmi.model_avg_log2_error <= ((rmi.model_max_error + 2) as f64).log2());
+        }
+    }
+
+    #[test]
+    fn test_parallel_last_level_errors() {
+        use lower_bound_correction::LowerBoundCorrection;
+        use two_layer::{last_level_errors, LeafErrors};
+
+        // runs of duplicates, some longer than a leaf
+        let data: Vec<(u64, usize)> = (0..30000u64)
+            .map(|i| if i % 1000 < 300 { (i / 1000) * 5000 } else { (i / 1000) * 5000 + i % 1000 })
+            .enumerate()
+            .map(|(i, k)| (k, i))
+            .collect();
+        let num_leaf_models = 64;
+
+        for semantics in &[DuplicateSemantics::LowerBound, DuplicateSemantics::UpperBound] {
+            let mut md = RMITrainingData::new(Box::new(data.clone()));
+            md.set_duplicate_semantics(*semantics);
+            md.set_scale(num_leaf_models as f64 / md.len() as f64);
+            let top_model = train_model("linear", &md);
+            md.set_scale(1.0);
+            let leaf_models: Vec<Box<dyn Model>> = (0..num_leaf_models)
+                .map(|_| train_model("linear", &md))
+                .collect();
+            let leaf_of = |x: u64| top_model.predict_to_int(&x.to_model_input());
+
+            // a single serial pass over the keys
+            let mut serial = vec![LeafErrors { count: 0, max_error: 0, left: 0, right: 0, max_pos: 0,
+                                               min_pos: u64::MAX, weight: 0.0 };
+                                  num_leaf_models as usize];
+            for (x, y) in md.iter_model_input() {
+                let leaf = u64::min(num_leaf_models - 1, top_model.predict_to_int(&x)) as usize;
+                let pred = leaf_models[leaf].predict_to_int(&x);
+                let e = &mut serial[leaf];
+                e.count += 1;
+                e.max_error = u64::max(e.max_error, u64::max(pred, y as u64) - u64::min(pred, y as u64));
+                let pred = u64::min(pred, md.len() as u64);
+                e.left = u64::max(e.left, pred.saturating_sub(y as u64));
+                e.right = u64::max(e.right, (y as u64).saturating_sub(pred));
+                e.max_pos = u64::max(e.max_pos, y as u64);
+                e.min_pos = u64::min(e.min_pos, y as u64);
+            }
+
+            let lbc = LowerBoundCorrection::with_chunks(leaf_of, num_leaf_models, &md, 1);
+            assert_eq!(last_level_errors(&md, top_model.as_ref(), &leaf_models, num_leaf_models, 1),
+                       serial);
+            for num_chunks in &[2, 7, 64, 1000] {
+                assert_eq!(LowerBoundCorrection::with_chunks(leaf_of, num_leaf_models, &md, *num_chunks),
+                           lbc);
+                assert_eq!(last_level_errors(&md, top_model.as_ref(), &leaf_models,
+                                             num_leaf_models, *num_chunks),
+                           serial);
+            }
+        }
+    }
+
+    #[test]
+    fn test_parallel_leaf_models() {
+        use two_layer::build_models_from;
+        use parallel::default_num_chunks;
+
+        // the single pass over the keys that build_models_from replaced
+        fn serial_models(data: &RMITrainingData<u64>, top_model: &Box<dyn Model>,
+                         num_models: usize, sample_rate: usize) -> Vec<Box<dyn Model>> {
+            let dummy_md = RMITrainingData::<u64>::empty();
+            let mut leaf_models: Vec<Box<dyn Model>> = Vec::with_capacity(num_models);
+            let mut second_layer_data = Vec::new();
+            let mut last_target = 0;
+            let mut leaf_count: usize = 0;
+            let mut last_seen: Option<(u64, usize)> = None;
+
+            for (x, y) in data.iter().take(data.len()) {
+                let model_pred = top_model.predict_to_int(&x.to_model_input()) as usize;
+                let target = usize::min(num_models - 1, model_pred);
+                assert!(target >= last_target);
+
+                if target > last_target {
+                    if let Some(v) = last_seen {
+                        if second_layer_data.last() != Some(&v) {
+                            second_layer_data.push(v);
+                        }
+                    }
+                    leaf_count = 0;
+
+                    let last_item = second_layer_data.last().copied();
+                    second_layer_data.push((x, y));
+                    let container = RMITrainingData::new(Box::new(second_layer_data));
+                    leaf_models.push(train_model("linear", &container));
+                    for _skipped_idx in (last_target + 1)..target {
+                        leaf_models.push(train_model("linear", &dummy_md));
+                    }
+
+                    second_layer_data = Vec::new();
+                    if let Some(v) = last_item {
+                        second_layer_data.push(v);
+                    }
+                }
+
+                if leaf_count % sample_rate == 0 {
+                    second_layer_data.push((x, y));
+                }
+                leaf_count += 1;
+                last_seen = Some((x, y));
+                last_target = target;
+            }
+
+            if let Some(v) = last_seen {
+                if second_layer_data.last() != Some(&v) {
+                    second_layer_data.push(v);
+                }
+            }
+            let container = RMITrainingData::new(Box::new(second_layer_data));
+            leaf_models.push(train_model("linear", &container));
+            for _skipped_idx in (last_target + 1)..num_models {
+                leaf_models.push(train_model("linear", &dummy_md));
+            }
+            return leaf_models;
+        }
+
+        let data: Vec<(u64, usize)> = (0..300000u64)
+            .map(|i| (i / 5000) * 70000 + if i % 5000 < 2000 { 0 } else { (i % 5000) * 13 + i % 7 })
+            .enumerate()
+            .map(|(i, k)| (k, i))
+            .collect();
+        let num_rows = data.len();
+        let num_leaf_models = 1000;
+        let mut md = RMITrainingData::new(Box::new(data));
+        md.set_scale(num_leaf_models as f64 / num_rows as f64);
+        let top_model = train_model("linear", &md);
+        md.set_scale(1.0);
+        assert!(default_num_chunks(num_rows) > 1);
+
+        // the chunks are trained exactly as a single pass over the keys
+        let params = |models: Vec<Box<dyn Model>>| -> Vec<String> {
+            models.iter().map(|m| format!("{:?}", m.params())).collect()
+        };
+        for sample_rate in &[1, 3] {
+            let parallel = build_models_from(&md, &top_model, "linear", 0, num_rows, 0,
+                                             num_leaf_models, *sample_rate);
+            let serial = serial_models(&md, &top_model, num_leaf_models, *sample_rate);
+            assert_eq!(params(parallel), params(serial));
+        }
+
+        // so are the second and third layers of the three-layer builders: a
+        // single thread trains them in one chunk
+        let keys: Vec<(u64, usize)> = md.iter().take(num_rows).collect();
+        let all_params = |rmi: TrainedRMI| -> Vec<Vec<String>> {
+            rmi.rmi.into_iter().map(params).collect()
+        };
+        let single_thread = rayon::ThreadPoolBuilder::new().num_threads(1).build().unwrap();
+        for naive in &[false, true] {
+            let build = || {
+                let mut md = RMITrainingData::new(Box::new(keys.clone()));
+                if *naive {
+                    return two_layer::train_naive_three_layer(&mut md, "linear", "linear", "linear", 10000);
+                }
+                return two_layer::train_three_layer(&mut md, "linear", "linear", "linear", 10000);
+            };
+            let parallel = build();
+            let serial = single_thread.install(build);
+            assert_eq!(all_params(parallel), all_params(serial));
+        }
+    }
+
+    #[test]
+    fn test_train_max_memory() {
+        let data: Vec<(u64, usize)> = (0..20000u64)
+            .map(|i| (1000 + i * i / 3 + 7 * i, i as usize))
+            .collect();
+        let md = RMITrainingData::new(Box::new(data));
+        let unlimited = two_layer::train_three_layer(&mut md.soft_copy(), "linear", "linear", "linear", 256);
+
+        // the third layer is trained in windows of about an eighth of the keys
+        let mut limited_md = md.soft_copy();
+        limited_md.set_memory_budget(Arc::new(MemoryBudget::new(Some(20000 * 16 / 8))));
+        let limited = two_layer::train_three_layer(&mut limited_md, "linear", "linear", "linear", 256);
+
+        let params = |rmi: &TrainedRMI| -> Vec<String> {
+            rmi.rmi.iter().flatten().map(|m| format!("{:?}", m.params())).collect()
+        };
+        assert_eq!(params(&limited), params(&unlimited));
+        assert_eq!(limited.last_layer_max_l1s, unlimited.last_layer_max_l1s);
+        assert!(limited.peak_memory > 0);
+        assert!(limited.peak_memory < unlimited.peak_memory / 2,
+                "peak {} with a limit, {} without", limited.peak_memory, unlimited.peak_memory);
+    }
+
+    #[test]
+    fn test_max_memory_specs() {
+        let data: Vec<(u64, usize)> = (0..20000u64)
+            .map(|i| (1000 + i * i / 3 + 7 * i, i as usize))
+            .collect();
+        let md = RMITrainingData::new(Box::new(data));
+        let limit = 20000 * 16 / 8;
+        let limited = |max_error: Option<u64>| -> RMITrainingData<u64> {
+            let mut limited_md = md.soft_copy();
+            limited_md.set_memory_budget(Arc::new(MemoryBudget::new(Some(limit))));
+            limited_md.set_max_error(max_error);
+            return limited_md;
+        };
+        let params = |rmi: &TrainedRMI| -> Vec<String> {
+            rmi.rmi.iter().flatten().map(|m| format!("{:?}", m.params())).collect()
+        };
+
+        // a three-level spec trains a full three-layer RMI within the limit
+        let three_level = train(&limited(None), "linear,linear,linear", 256);
+        assert_eq!(three_level.layout, Layout::ThreeLayer { num_second: 16, per_second: 16 });
+        assert!(three_level.peak_memory > 0);
+        assert!(three_level.peak_memory <= limit, "peak {} with a limit of {}", three_level.peak_memory, limit);
+
+        // the leaves of the other specs are read in place, and train as if copied
+        let mut unlimited = md.soft_copy();
+        for (spec, max_error) in &[("linear,linear", None), ("linear,linear", Some(4)),
+                                   ("linear,linear,linear", Some(4))] {
+            unlimited.set_max_error(*max_error);
+            let rmi = train(&limited(*max_error), spec, 256);
+            assert!(rmi.peak_memory <= limit, "peak {} with a limit of {}", rmi.peak_memory, limit);
+            assert_eq!(params(&rmi), params(&train(&unlimited, spec, 256)));
+        }
+    }
+
+    #[test]
+    fn test_train_resume() {
+        use two_layer::{build_partial_3layer_models_from, train_partial_three_layer};
+
+        let data: Vec<(u64, usize)> = (0..40000u64)
+            .map(|i| (1000 + i * i / 3 + 7 * i, i as usize))
+            .collect();
+        let md = RMITrainingData::new(Box::new(data));
+        let dir = std::env::temp_dir().join(format!("rmi-resume-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let summary = |rmi: &TrainedRMI| -> (Vec<String>, Vec<u64>, Vec<u64>) {
+            (rmi.rmi.iter().flatten().map(|m| format!("{:?}", m.params())).collect(),
+             rmi.last_layer_max_l1s.clone(), rmi.third_layer_max_l1s.clone())
+        };
+        let checkpoints = |resume: bool| -> Checkpoints {
+            let mut c = Checkpoints::new(&dir, 7, resume);
+            c.set_batch_leaves(16);
+            return c;
+        };
+        let train_checkpointed = |resume: bool| -> TrainedRMI {
+            return train_partial_three_layer(&mut md.soft_copy(), "linear", "linear", "linear", 64,
+                                             Some(&checkpoints(resume)));
+        };
+
+        let reference = train_partial_three_layer(&mut md.soft_copy(), "linear", "linear", "linear", 64, None);
+        assert!(!reference.third_layer_max_l1s.is_empty());
+
+        // checkpoints do not change the RMI, and a finished run resumes from its leaves
+        assert_eq!(summary(&train_checkpointed(false)), summary(&reference));
+        assert!(dir.join("top.ckpt").exists());
+        assert!(dir.join("leaves.ckpt").exists());
+        assert!(!dir.join("layer2-0.ckpt").exists());
+        assert_eq!(summary(&train_checkpointed(true)), summary(&reference));
+
+        // a run interrupted after some of the batches of second-level models
+        let mut scaled = md.soft_copy();
+        scaled.set_scale(64.0 / scaled.len() as f64);
+        let top_model = train_model("linear", &scaled);
+        build_partial_3layer_models_from(&md, &top_model, "linear", "linear", 0, md.len(), 0, 64,
+                                         Some(&checkpoints(false)));
+        for batch in 0..4 {
+            assert!(dir.join(format!("layer2-{}.ckpt", batch)).exists());
+        }
+        std::fs::remove_file(dir.join("layer2-2.ckpt")).unwrap();
+        std::fs::remove_file(dir.join("leaves.ckpt")).unwrap();
+        assert_eq!(summary(&train_checkpointed(true)), summary(&reference));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "cannot be restored from checkpoints")]
+    fn test_checkpoint_unrestorable() {
+        let data: Vec<(u64, usize)> = (0..1000u64).map(|i| (i * i, i as usize)).collect();
+        let md = RMITrainingData::new(Box::new(data));
+        let dir = std::env::temp_dir().join(format!("rmi-unrestorable-{}", std::process::id()));
+        let config = TrainingConfig { checkpoints: Some(Arc::new(Checkpoints::new(&dir, 7, false))) };
+        train_with_config(&md, "pchip,linear,linear", 16, &config);
+    }
+
+    #[test]
+    fn test_train_bounded_key_types() {
+        use crate::cache_fix::{cache_fix, Spline};
+
+        // every key must land on its own cache line when interpolating
+        // between the spline points around it
+        fn check<T: TrainingKey>(keys: Vec<T>, line_size: usize) -> TrainedRMI {
+            let data: Vec<(T, usize)> = keys.iter().cloned().enumerate().map(|(i, k)| (k, i)).collect();
+            let md = RMITrainingData::new(Box::new(data));
+            let spline = cache_fix(&md, line_size);
+            assert!(spline.len() > 2 && spline.len() < keys.len());
+
+            for (idx, key) in keys.iter().enumerate() {
+                let next = spline.iter()
+                    .position(|(x, _)| !(x.to_model_input() < key.to_model_input()))
+                    .unwrap();
+                let pred = if next == 0 {
+                    spline[0].1
+                } else {
+                    Spline::from(spline[next - 1], spline[next]).predict(*key)
+                };
+                assert_eq!(pred / line_size, idx / line_size, "key {:?} at {}", key, idx);
+            }
+
+            let trained = train_bounded(&md, "linear,linear", 16, line_size);
+            assert_eq!(trained.cache_fix.as_ref().unwrap().1.len(), spline.len());
+            assert_eq!(trained.num_data_rows, keys.len());
+            return trained;
+        }
+
+        let base: Vec<u64> = (0..5000u64).map(|i| 10 * i + (i * i) % 7 + (i / 1000) * 50000).collect();
+        check(base.iter().map(|&k| k as u32).collect(), 16);
+        check(base.iter().map(|&k| (1u128 << 90) + ((k as u128) << 40)).collect(), 16);
+
+        // 512-bit keys whose differences are far beyond what an f64 can hold
+        let trained = check(base.iter().map(|&k| U512::from(k + 1) << 300).collect(), 16);
+        let num_points = trained.cache_fix.as_ref().unwrap().1.len();
+        let dir = std::env::temp_dir().join(format!("rmi-bounded-u512-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        crate::output_rmi("bounded", trained, dir.to_str().unwrap(), KeyType::U512, true).unwrap();
+
+        // each spline point is a 512-bit key in the 68-byte parameter layout and a position
+        let points = std::fs::read(dir.join("bounded_L2_PARAMETERS")).unwrap();
+        assert_eq!(points.len(), num_points * (68 + 8));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_retrain() {
+        let keys: Vec<u64> = (0..20000u64).map(|i| 1000 + i * i / 3 + 7 * i).collect();
+        let md_of = |keys: &[u64]| {
+            let data: Vec<(u64, usize)> = keys.iter().cloned().enumerate().map(|(i, k)| (k, i)).collect();
+            RMITrainingData::new(Box::new(data))
+        };
+
+        // a patch in the middle, a removed region and keys past the end
+        let inserted: Vec<u64> = (5000..5300).map(|i| keys[i] + 1)
+            .chain((0..50).map(|i| keys[19999] + 10 + i))
+            .collect();
+        let removed: Vec<u64> = keys[12000..12400].to_vec();
+        let mut new_keys: Vec<u64> = keys.iter().cloned()
+            .filter(|k| removed.binary_search(k).is_err())
+            .chain(inserted.iter().cloned())
+            .collect();
+        new_keys.sort();
+        let new_md = md_of(&new_keys);
+
+        // every key, and every gap between keys, is found within the error
+        // window of its leaf
+        let check = |rmi: &TrainedRMI| {
+            assert_eq!(rmi.num_rmi_rows, new_keys.len());
+            let num_leaves = rmi.branching_factor;
+            for (idx, &key) in new_keys.iter().enumerate() {
+                for (probe, pos) in [(key, idx), (key + 1, idx + 1)] {
+                    if probe == key + 1 && new_keys.get(idx + 1) == Some(&probe) {
+                        continue;
+                    }
+                    let inp = probe.to_model_input();
+                    let leaf = u64::min(num_leaves - 1, rmi.rmi[0][0].predict_to_int(&inp)) as usize;
+                    let pred = u64::min(rmi.rmi[1][leaf].predict_to_int(&inp), new_keys.len() as u64);
+                    let err = rmi.last_layer_max_l1s[leaf];
+                    assert!(pred.max(pos as u64) - pred.min(pos as u64) <= err,
+                            "probe {} at {} predicted at {} with error {}", probe, pos, pred, err);
+                }
+            }
+        };
+
+        for models in ["linear,linear", "linear,pchip"] {
+            let trained = train(&md_of(&keys), models, 64);
+            let first_leaves: Vec<String> = trained.rmi[1][..8].iter()
+                .map(|m| format!("{:?}", m.params())).collect();
+
+            let retrained = retrain(trained, &new_md, &KeyDelta::new(inserted.clone(), removed.clone()));
+            check(&retrained);
+            assert_eq!(retrained.models, models);
+
+            // leaves before the first change are kept as they are
+            let kept: Vec<String> = retrained.rmi[1][..8].iter()
+                .map(|m| format!("{:?}", m.params())).collect();
+            assert_eq!(kept, first_leaves);
+
+            let rebuilt = train(&new_md, models, 64);
+            check(&rebuilt);
+        }
+    }
+
+    #[test]
+    fn test_verify() {
+        let md = uneven_keys_with_runs();
+        let num_distinct = 30000;
+
+        let passes = |md: &RMITrainingData<u64>, rmi: &TrainedRMI| {
+            let res = verify(md, rmi);
+            assert!(res.passed(), "{} {} violations, first: {:?}",
+                    rmi.models, res.num_violations, res.violations.first());
+            assert_eq!(res.keys, num_distinct);
+            assert!(res.probes > num_distinct);
+        };
+
+        for semantics in &[DuplicateSemantics::LowerBound, DuplicateSemantics::UpperBound,
+                           DuplicateSemantics::Dedup] {
+            let mut md = md.soft_copy();
+            md.set_duplicate_semantics(*semantics);
+            passes(&md, &train(&md, "linear,linear", 64));
+            passes(&md, &train(&md, "linear,pchip", 64));
+        }
+
+        passes(&md, &two_layer::train_three_layer(&mut md.soft_copy(), "linear", "linear", "linear", 64));
+        passes(&md, &two_layer::train_naive_three_layer(&mut md.soft_copy(), "linear", "linear", "linear", 64));
+
+        let mut bounded = md.soft_copy();
+        bounded.set_max_error(Some(4));
+        passes(&bounded, &train(&bounded, "linear,linear", 64));
+        passes(&md, &train(&md, "linear,linear,linear", 64));
+
+        // errors that are too small are reported at the leaf they belong to
+        let mut broken = train(&md, "linear,linear", 64);
+        let leaf = broken.model_max_error_idx;
+        broken.last_layer_max_l1s[leaf] = 0;
+        let res = verify(&md, &broken);
+        assert!(!res.passed());
+        assert!(res.violations.iter().all(|v| v.leaf == leaf && v.partial.is_none()));
+        assert!(res.violations.iter().all(|v| v.position < v.lower || v.position > v.upper));
+        assert_eq!(res.violations.len(), usize::min(res.num_violations, 1000));
+    }
+}
@@ -6,7 +6,7 @@
 // < end copyright >
 
 use crate::models::{ self, * };
-use crate::train::{ train_model, TrainedRMI };
+use crate::train::{ train_model_sampled, TrainedRMI, Layout };
 use log::*;
 
 pub fn train_multi_layer<T: TrainingKey>(
@@ -19,6 +19,8 @@ pub fn train_multi_layer<T: TrainingKey>(
     // let mut data_partitions = vec![data.clone().into_data()];
     let mut data_partitions = vec![data.clone()];
     let num_rows = data_partitions[0].len();
+    // every model is fit on a sample of the keys routed to it
+    let sample_rate = data.sample_rate();
 
     let mut current_model_count = 1;
     for (_layer_idx, model_type) in model_list.iter().enumerate() {
@@ -36,7 +38,7 @@ pub fn train_multi_layer<T: TrainingKey>(
 
             // not at the last layer -- rescale
             md_container.set_scale((next_layer_size as f64) / (num_rows as f64));
-            let model = train_model(model_type.as_str(), &md_container);
+            let model = train_model_sampled(model_type.as_str(), &md_container, sample_rate);
 
             // rescale back for next layer
             md_container.set_scale(1.0);
@@ -75,7 +77,7 @@ pub fn train_multi_layer<T: TrainingKey>(
     for (midx, model_data) in data_partitions.into_iter().enumerate() {
         // let mut md_container = RMITrainingData::<T>::new(&model_data);
         let mut md_container = model_data.clone();
-        let last_model = train_model(last_model.as_str(), &md_container);
+        let last_model = train_model_sampled(last_model.as_str(), &md_container, sample_rate);
         let mut max_error = 0;
 
         // for (idx, (x, y)) in md_container.iter_int_int().enumerate() {
@@ -125,14 +127,31 @@ pub fn train_multi_layer<T: TrainingKey>(
         model_max_error,
         model_max_error_idx,
         model_max_log2_error,
+        model_avg_window: 2.0 * model_avg_error + 1.0,
         last_layer_max_l1s,
+        layout: match rmi.len() {
+            1 => Layout::Single,
+            2 => Layout::TwoLayer,
+            _ => Layout::MultiLayer
+        },
         rmi,
-        models: format!("{},{}", model_list.join(","), last_model),
+        models: if model_list.is_empty() {
+            last_model
+        } else {
+            format!("{},{}", model_list.join(","), last_model)
+        },
         branching_factor: branch_factor,
         build_time: 0,
         cache_fix: None,
+        duplicates: data.duplicate_semantics(),
+        sample_rate: data.sample_rate(),
+        peak_memory: data.memory_budget().peak(),
+        error_distribution: None,
+        run_lengths: vec![],
         num_data_rows: data.len(),
         num_rmi_rows: data.len(),
         third_layer_max_l1s: vec![],
+        left_errors: vec![],
+        right_errors: vec![],
     };
 }
@@ -0,0 +1,89 @@
+// < begin copyright > 
+// Copyright Ryan Marcus 2020
+// 
+// See root directory of this project for license terms.
+// 
+// < end copyright > 
+ 
+
+use crate::models::*;
+
+// Chunks smaller than this are not worth handing to another thread.
+const MIN_ROWS_PER_CHUNK: usize = 1 << 16;
+
+// Number of chunks to split the rows into: a few per thread of the pool, so
+// that uneven chunks still keep every thread busy.
+pub fn default_num_chunks(num_rows: usize) -> usize {
+    return usize::max(1, usize::min(rayon::current_num_threads() * 4,
+                                    num_rows / MIN_ROWS_PER_CHUNK));
+}
+
+// Splits the rows of data.iter() into at most num_chunks contiguous
+// [start, end) ranges. Every chunk starts where the leaf index changes, so
+// all the rows of a leaf (and so every run of duplicates) are in a single
+// chunk. Folding per-leaf state over each chunk and merging the chunks in
+// order then gives exactly the result of a single serial pass.
+pub fn leaf_aligned_chunks<T: TrainingKey, F>(data: &RMITrainingData<T>, num_leaf_models: u64,
+                                              num_chunks: usize, leaf_of: F) -> Vec<(usize, usize)>
+where F: Fn(T) -> u64 {
+    return leaf_aligned_ranges(data, 0, data.iter_len(), 0, num_leaf_models, num_chunks, leaf_of);
+}
+
+// Like leaf_aligned_chunks, but only splits the rows [start, end), which are
+// routed to the num_leaf_models leaves starting at first_leaf.
+pub fn leaf_aligned_ranges<T: TrainingKey, F>(data: &RMITrainingData<T>, start: usize, end: usize,
+                                              first_leaf: u64, num_leaf_models: u64,
+                                              num_chunks: usize, leaf_of: F) -> Vec<(usize, usize)>
+where F: Fn(T) -> u64 {
+    let last_leaf = first_leaf + num_leaf_models - 1;
+    let mut bounds = vec![start];
+    for i in 1..num_chunks {
+        let leaf = first_leaf + i as u64 * num_leaf_models / num_chunks as u64;
+        let split = data.lower_bound_by(|(key, _)| {
+            u64::min(last_leaf, leaf_of(key)).cmp(&leaf)
+        });
+        if split > *bounds.last().unwrap() && split < end {
+            bounds.push(split);
+        }
+    }
+    bounds.push(end);
+    return bounds.windows(2).map(|w| (w[0], w[1])).collect();
+}
+
+// Per-leaf values for the leaves that the rows of one chunk are routed to.
+// Only the range of leaves the chunk touches is stored.
+pub struct LeafRange<V> {
+    first: usize,
+    values: Vec<V>,
+    empty: V
+}
+
+impl <V: Clone> LeafRange<V> {
+    pub fn new(empty: V) -> LeafRange<V> {
+        return LeafRange { first: 0, values: Vec::new(), empty };
+    }
+
+    pub fn get_mut(&mut self, idx: usize) -> &mut V {
+        if self.values.is_empty() {
+            self.first = idx;
+        }
+        if idx < self.first {
+            let mut grown = vec![self.empty.clone(); self.first - idx];
+            grown.append(&mut self.values);
+            self.values = grown;
+            self.first = idx;
+        }
+        if idx >= self.first + self.values.len() {
+            self.values.resize(idx - self.first + 1, self.empty.clone());
+        }
+        return &mut self.values[idx - self.first];
+    }
+
+    // Merges the values of this chunk into the values of all the leaves.
+    pub fn merge_into<F>(self, all: &mut [V], merge: F)
+    where F: Fn(&mut V, V) {
+        for (i, v) in self.values.into_iter().enumerate() {
+            merge(&mut all[self.first + i], v);
+        }
+    }
+}
@@ -0,0 +1,187 @@
+// < begin copyright >
+// Copyright Ryan Marcus 2020
+//
+// See root directory of this project for license terms.
+//
+// < end copyright >
+
+
+use crate::models::*;
+use crate::train::{train_model, TrainedRMI};
+use crate::train::two_layer::{build_chunk_models_from, finish_two_layer};
+use crate::train::evaluate::measure_key_errors;
+use rayon::prelude::*;
+use std::time::SystemTime;
+
+// The keys that changed since an RMI was trained. Every occurrence counts,
+// so inserting a key twice lists it twice.
+pub struct KeyDelta<T: TrainingKey> {
+    pub inserted: Vec<T>,
+    pub removed: Vec<T>
+}
+
+impl <T: TrainingKey> KeyDelta<T> {
+    pub fn new(inserted: Vec<T>, removed: Vec<T>) -> KeyDelta<T> {
+        return KeyDelta { inserted, removed };
+    }
+
+    fn net_change(&self) -> i64 {
+        return self.inserted.len() as i64 - self.removed.len() as i64;
+    }
+}
+
+// The rows [start, end) of the new keys that cover a run of leaves to
+// retrain, and the first and last leaf of the run.
+struct DirtyRange {
+    start: usize,
+    end: usize,
+    first_leaf: usize,
+    last_leaf: usize
+}
+
+// Updates a two-layer RMI after keys were inserted or removed, without
+// training it from scratch. `data` holds the keys after the change.
+//
+// The top model is kept, so every key is routed to the leaf it would have
+// been routed to before. Leaves that an inserted or removed key is routed to
+// are trained again on their new keys; the other leaves only move by the
+// number of keys inserted before them, if their model can be shifted (and
+// are trained again otherwise). The lower bound corrections, the constants
+// of empty leaves and all of the errors are then computed over the new keys
+// exactly as a full build computes them, so the error bounds hold for every
+// key of `data`.
+pub fn retrain<T: TrainingKey>(mut rmi: TrainedRMI, data: &RMITrainingData<T>,
+                               delta: &KeyDelta<T>) -> TrainedRMI {
+    let start_time = SystemTime::now();
+
+    assert_eq!(rmi.rmi.len(), 2,
+               "Only two-layer RMIs can be retrained, this one has {} layers", rmi.rmi.len());
+    assert!(rmi.cache_fix.is_none(), "RMIs trained with a cache fix cannot be retrained");
+    assert!(rmi.duplicates != DuplicateSemantics::Dedup,
+            "RMIs trained on deduplicated keys cannot be retrained");
+    assert!(rmi.duplicates == data.duplicate_semantics(),
+            "The RMI was trained with {} semantics, but the keys use {}",
+            rmi.duplicates.name(), data.duplicate_semantics().name());
+    assert_eq!(rmi.num_rmi_rows as i64 + delta.net_change(), data.len() as i64,
+               "The RMI was trained on {} keys and {} were inserted and {} removed, \
+                but there are {} keys",
+               rmi.num_rmi_rows, delta.inserted.len(), delta.removed.len(), data.len());
+    assert!(data.len() > 0, "Cannot retrain an RMI on no keys");
+
+    let num_leaf_models = rmi.branching_factor;
+    let num_leaves = num_leaf_models as usize;
+    let leaf_model_type = String::from(rmi.models.rsplit(',').next().unwrap());
+    let mut leaf_models = rmi.rmi.pop().unwrap();
+    let top_model = rmi.rmi.pop().unwrap().pop().unwrap();
+    assert_eq!(leaf_models.len(), num_leaves);
+
+    let leaf_of = |key: &T| u64::min(num_leaf_models - 1,
+                                     top_model.predict_to_int(&key.to_model_input())) as usize;
+
+    let mut dirty = vec![false; num_leaves];
+    let mut net = vec![0i64; num_leaves];
+    for key in delta.inserted.iter() {
+        let leaf = leaf_of(key);
+        dirty[leaf] = true;
+        net[leaf] += 1;
+    }
+    for key in delta.removed.iter() {
+        let leaf = leaf_of(key);
+        dirty[leaf] = true;
+        net[leaf] -= 1;
+    }
+
+    // the keys of an unchanged leaf keep their order, only the number of keys
+    // before them changed
+    let mut offset = 0;
+    let mut shifted = 0;
+    for leaf_idx in 0..num_leaves {
+        if !dirty[leaf_idx] && offset != 0 {
+            if leaf_models[leaf_idx].shift_positions(offset) {
+                shifted += 1;
+            } else {
+                dirty[leaf_idx] = true;
+            }
+        }
+        offset += net[leaf_idx];
+    }
+
+    let ranges = dirty_ranges(data, &dirty, leaf_of);
+    let num_dirty = dirty.iter().filter(|&&d| d).count();
+    println!("Retraining {} of {} {} leaves ({} shifted, {} runs)",
+             num_dirty, num_leaves, leaf_model_type, shifted, ranges.len());
+
+    let sample_rate = data.sample_rate();
+    let retrained: Vec<(usize, Vec<Box<dyn Model>>)> = ranges.par_iter()
+        .map(|range| {
+            // a pass over the rows trains the leaves from the one of the first
+            // row; the leaves of the run before it lost all of their keys
+            let first_trained = if range.start == range.end {
+                range.last_leaf + 1
+            } else if range.start == 0 {
+                0
+            } else {
+                usize::max(range.first_leaf, leaf_of(&data.get_key(range.start)))
+            };
+            let dummy_md = RMITrainingData::<T>::empty();
+            let mut models: Vec<Box<dyn Model>> = (range.first_leaf..first_trained)
+                .map(|_| train_model(&leaf_model_type, &dummy_md))
+                .collect();
+
+            if range.start < range.end {
+                models.append(&mut build_chunk_models_from(data, &top_model, &leaf_model_type,
+                                                           0, range.start, range.end, data.len(),
+                                                           0, num_leaves, sample_rate));
+            }
+            return (usize::min(range.first_leaf, first_trained), models);
+        })
+        .collect();
+
+    for (first_leaf, models) in retrained {
+        for (i, model) in models.into_iter().enumerate() {
+            leaf_models[first_leaf + i] = model;
+        }
+    }
+
+    let mut res = finish_two_layer(data, top_model, leaf_models, rmi.models.clone(),
+                                   num_leaf_models);
+    res.build_time = SystemTime::now()
+        .duration_since(start_time)
+        .map(|d| d.as_nanos())
+        .unwrap_or(std::u128::MAX);
+    measure_key_errors(data, &mut res);
+    return res;
+}
+
+// Groups the dirty leaves into runs and finds the rows of each run. A run
+// also covers the empty leaves that a pass over its rows trains, so runs
+// whose rows touch are merged.
+fn dirty_ranges<T: TrainingKey, F>(data: &RMITrainingData<T>, dirty: &[bool],
+                                   leaf_of: F) -> Vec<DirtyRange>
+where F: Fn(&T) -> usize {
+    let first_row_of = |leaf: usize| data.lower_bound_by(|(key, _)| leaf_of(&key).cmp(&leaf));
+
+    let mut ranges: Vec<DirtyRange> = Vec::new();
+    let mut leaf_idx = 0;
+    while leaf_idx < dirty.len() {
+        if !dirty[leaf_idx] {
+            leaf_idx += 1;
+            continue;
+        }
+        let first_leaf = leaf_idx;
+        while leaf_idx < dirty.len() && dirty[leaf_idx] {
+            leaf_idx += 1;
+        }
+        let range = DirtyRange { start: first_row_of(first_leaf), end: first_row_of(leaf_idx),
+                                 first_leaf, last_leaf: leaf_idx - 1 };
+
+        match ranges.last_mut() {
+            Some(prev) if range.start <= prev.end => {
+                prev.end = range.end;
+                prev.last_leaf = range.last_leaf;
+            },
+            _ => ranges.push(range),
+        }
+    }
+    return ranges;
+}
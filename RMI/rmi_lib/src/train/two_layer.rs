@@ -7,9 +7,12 @@
  
 use crate::models::TrainingKey;
 use crate::models::*;
-use crate::train::{validate, train_model, train_model_big, TrainedRMI};
+use crate::train::{validate, train_model, train_model_big, train_model_sampled, can_restore, TrainedRMI, Layout};
+use crate::train::checkpoint::{Checkpoints, Stage};
 use crate::train::lower_bound_correction::LowerBoundCorrection;
+use crate::train::parallel::{default_num_chunks, leaf_aligned_chunks, leaf_aligned_ranges, LeafRange};
 use log::*;
+use rayon::prelude::*;
 
 fn error_between(v1: u64, v2: u64, max_pred: u64) -> u64 {
     let pred1 = u64::min(v1, max_pred);
@@ -17,12 +20,445 @@ fn error_between(v1: u64, v2: u64, max_pred: u64) -> u64 {
     return u64::max(pred1, pred2) - u64::min(pred1, pred2);
 }
 
-fn build_models_from<T: TrainingKey>(data: &RMITrainingData<T>,
-                                    top_model: &Box<dyn Model>,
-                                    model_type: &str,
-                                    start_idx: usize, end_idx: usize,
-                                    first_model_idx: usize,
-                                    num_models: usize) -> Vec<Box<dyn Model>> {
+// How far the prediction is past the position (left) and short of it
+// (right); one of them is zero.
+fn signed_error_between(pred: u64, pos: u64, max_pred: u64) -> (u64, u64) {
+    let pred = u64::min(pred, max_pred);
+    let pos = u64::min(pos, max_pred);
+    return (pred.saturating_sub(pos), pos.saturating_sub(pred));
+}
+
+// The larger of the upper and lower errors a leaf model needs for lower
+// bound searches (see the comment in train_two_layer).
+fn lower_bound_error<T: TrainingKey>(model: &dyn Model, leaf_idx: usize,
+                                     lb_corrections: &LowerBoundCorrection<T>,
+                                     num_rows: usize) -> u64 {
+    let (left, right) = lower_bound_reach(model, leaf_idx, lb_corrections, num_rows);
+    return u64::max(left, right);
+}
+
+// The (left, right) reach a leaf model needs for lower bound searches of the
+// keys just outside of its leaf.
+fn lower_bound_reach<T: TrainingKey>(model: &dyn Model, leaf_idx: usize,
+                                     lb_corrections: &LowerBoundCorrection<T>,
+                                     num_rows: usize) -> (u64, u64) {
+    let upper_error = {
+        let (idx_of_next, key_of_next) = lb_corrections.next(leaf_idx);
+        let pred = model.predict_to_int(&key_of_next.minus_epsilon().to_model_input());
+        signed_error_between(pred, idx_of_next as u64 + 1, num_rows as u64)
+    };
+
+    let lower_error = {
+        let first_key_before = lb_corrections.prev_key(leaf_idx);
+
+        let prev_idx = if leaf_idx == 0 { 0 } else { leaf_idx - 1 };
+        let first_idx = lb_corrections.next_index(prev_idx);
+
+        let pred = model.predict_to_int(&first_key_before.plus_epsilon().to_model_input());
+        signed_error_between(pred, first_idx as u64, num_rows as u64)
+    };
+
+    return (u64::max(upper_error.0, lower_error.0), u64::max(upper_error.1, lower_error.1));
+}
+
+// The (left, right) reach a leaf model needs for the keys just outside of its
+// leaf to find the first row after them, as with equal range semantics (where
+// their intervals are empty) and upper bound semantics. Keys below the first
+// key need no check: the leaf of the first key reaches position 0.
+fn insertion_reach<T: TrainingKey>(model: &dyn Model, leaf_idx: usize,
+                                   lb_corrections: &LowerBoundCorrection<T>,
+                                   num_rows: usize) -> (u64, u64) {
+    let (idx_of_next, key_of_next) = lb_corrections.next(leaf_idx);
+    let mut probes = vec![(key_of_next.minus_epsilon(), idx_of_next)];
+    if leaf_idx > 0 {
+        probes.push((lb_corrections.prev_key(leaf_idx).plus_epsilon(),
+                     lb_corrections.next_index(leaf_idx - 1)));
+    }
+
+    let mut reach = (0, 0);
+    for (key, idx) in probes {
+        let pred = u64::min(model.predict_to_int(&key.to_model_input()), num_rows as u64);
+        let idx = u64::min(idx as u64, num_rows as u64);
+        reach = (u64::max(reach.0, pred.saturating_sub(idx)), u64::max(reach.1, idx.saturating_sub(pred)));
+    }
+    return reach;
+}
+
+// The (left, right) reach each leaf needs with upper bound semantics for the
+// absent keys around its runs of duplicates: a key just below a run belongs
+// at the run's first row and a key just above it one past its last row,
+// while the keys of the run are at its last row. Probes routed to another
+// leaf are covered by insertion_reach of that leaf, so unlike lower bound
+// searches no leaf is widened by its longest run.
+pub(crate) fn upper_bound_probes<T: TrainingKey, F, P>(data: &RMITrainingData<T>, leaf_of: F,
+                                                       predict: P, num_leaf_models: u64) -> Vec<(u64, u64)>
+where F: Fn(T) -> u64 + Sync, P: Fn(usize, &ModelInput) -> u64 + Sync {
+    let num_rows = data.len();
+    let target_of = |x: T| u64::min(num_leaf_models - 1, leaf_of(x)) as usize;
+    let key_less = |a: &T, b: &T| a.to_model_input() < b.to_model_input();
+    let chunks = leaf_aligned_chunks(data, num_leaf_models, default_num_chunks(num_rows), &leaf_of);
+
+    let chunk_reach: Vec<LeafRange<(u64, u64)>> = chunks.par_iter()
+        .map(|&(start, end)| {
+            let mut reach = LeafRange::new((0, 0));
+            let mut probe = |leaf: usize, key: T, pos: usize| {
+                if target_of(key) != leaf {
+                    return;
+                }
+                let pred = u64::min(predict(leaf, &key.to_model_input()), num_rows as u64);
+                let pos = pos as u64;
+                let r = reach.get_mut(leaf);
+                *r = (u64::max(r.0, pred.saturating_sub(pos)), u64::max(r.1, pos.saturating_sub(pred)));
+            };
+
+            // a run of duplicates never spans two chunks
+            let mut idx = start;
+            while idx < usize::min(end, num_rows) {
+                let (key, first_pos) = data.get(idx);
+                let mut run_end = idx + 1;
+                while run_end < num_rows && data.get_key(run_end) == key {
+                    run_end += 1;
+                }
+                let leaf = target_of(key);
+
+                if key != T::zero_value() {
+                    let below = key.minus_epsilon();
+                    if key_less(&below, &key) && (idx == 0 || key_less(&data.get_key(idx - 1), &below)) {
+                        probe(leaf, below, first_pos);
+                    }
+                }
+                if key != T::max_value() {
+                    let above = key.plus_epsilon();
+                    let next = if run_end < num_rows { Some(data.get(run_end)) } else { None };
+                    if key_less(&key, &above) && next.map_or(true, |(k, _)| key_less(&above, &k)) {
+                        probe(leaf, above, next.map_or(num_rows, |(_, pos)| pos));
+                    }
+                }
+                idx = run_end;
+            }
+            reach
+        })
+        .collect();
+
+    let mut all_reach = vec![(0, 0); num_leaf_models as usize];
+    for reach in chunk_reach {
+        reach.merge_into(&mut all_reach, |all, r| *all = (u64::max(all.0, r.0), u64::max(all.1, r.1)));
+    }
+    return all_reach;
+}
+
+// The (left, right) error a leaf needs with upper bound semantics for absent
+// keys, see upper_bound_probes.
+fn upper_bound_reach<T: TrainingKey>(model: &dyn Model, leaf_idx: usize,
+                                     lb_corrections: &LowerBoundCorrection<T>,
+                                     probes: &[(u64, u64)], num_rows: usize) -> (u64, u64) {
+    let (left, right) = insertion_reach(model, leaf_idx, lb_corrections, num_rows);
+    return (u64::max(left, probes[leaf_idx].0), u64::max(right, probes[leaf_idx].1));
+}
+
+// Replaces the leaves whose error is above the threshold with an exact
+// search over their keys, and recomputes the errors of those leaves (and
+// their signed errors, if there are any). Only lower bound semantics are
+// supported, since the exact search returns the first position of a key.
+fn replace_with_exact_leaves<T: TrainingKey>(md_container: &RMITrainingData<T>,
+                                             leaf_models: Vec<Box<dyn Model>>,
+                                             lb_corrections: &LowerBoundCorrection<T>,
+                                             errors: &mut [(u64, u64)],
+                                             left_errors: &mut [u64], right_errors: &mut [u64],
+                                             threshold: u64) -> Vec<Box<dyn Model>> {
+    if matches!(md_container.duplicate_semantics(), DuplicateSemantics::UpperBound | DuplicateSemantics::EqualRange)
+        || matches!(T::key_type(), KeyType::U512 | KeyType::F512) {
+        warn!("Exact leaves need lower bound semantics and keys of at most 128 bits, \
+               leaving all leaves as they are.");
+        return leaf_models;
+    }
+
+    let num_rows = md_container.len();
+    let mut replaced = 0;
+    let mut res = Vec::with_capacity(leaf_models.len());
+    for (leaf_idx, model) in leaf_models.into_iter().enumerate() {
+        // (a retrained RMI may keep exact leaves of the previous one)
+        if errors[leaf_idx].1 <= threshold || lb_corrections.last_key(leaf_idx).is_none()
+            || model.exact_keys().is_some() {
+            res.push(model);
+            continue;
+        }
+
+        let start = lb_corrections.first(leaf_idx);
+        let end = usize::min(lb_corrections.next_index(leaf_idx), num_rows);
+        let exact = ExactLeafModel::new(md_container, start, end, model).unwrap();
+
+        // every key should be found at the first position of its run, and
+        // keys between the leaves at the start of this leaf or of the next one
+        let before = lb_corrections.prev_key(leaf_idx).plus_epsilon();
+        let (next_idx, next_key) = lb_corrections.next(leaf_idx);
+        let mut run_start = start;
+        let mut targets = Vec::with_capacity(end - start + 2);
+        for idx in start..end {
+            let key = md_container.get_key(idx);
+            if idx > start && !key.same_encoding(&md_container.get_key(idx - 1)) {
+                run_start = idx;
+            }
+            targets.push((key, run_start));
+        }
+        targets.push((before, start));
+        targets.push((next_key.minus_epsilon(), next_idx));
+
+        // (how far the search lands past a key, and how far before it)
+        let (mut over, mut under) = (0, 0);
+        for (key, pos) in targets {
+            let (o, u) = signed_error_between(exact.predict_to_int(&key.to_model_input()),
+                                              pos as u64, num_rows as u64);
+            over = u64::max(over, o);
+            under = u64::max(under, u);
+        }
+
+        // keys the models cannot tell apart are all found at the first row
+        // of their run, so the run only widens the window above the search
+        let run = lb_corrections.longest_run(leaf_idx);
+        let new_err = u64::max(over, under) + run;
+        trace!("Leaf {} with error {} replaced by an exact search over {} keys (error {})",
+               leaf_idx, errors[leaf_idx].1, end - start, new_err);
+        errors[leaf_idx].1 = new_err;
+        if !left_errors.is_empty() {
+            left_errors[leaf_idx] = over;
+            right_errors[leaf_idx] = under + run;
+        }
+        res.push(Box::new(exact));
+        replaced += 1;
+    }
+
+    println!("Replaced {} of {} leaves with an exact search (error threshold {})",
+             replaced, res.len(), threshold);
+    return res;
+}
+
+// The average log2 error over queries rather than over keys: each leaf
+// counts with the total query weight of its keys.
+fn query_weighted_log2_error(leaf_weights: &[f64], errors: &[(u64, u64)]) -> f64 {
+    let total: f64 = leaf_weights.iter().sum();
+    return leaf_weights.iter().zip(errors.iter())
+        .map(|(w, (_n, err))| w * ((2 * err + 2) as f64).log2())
+        .sum::<f64>() / total;
+}
+
+// What the keys routed to one leaf tell about the leaf model: the number of
+// keys, the largest error, the range of positions and the total query weight.
+// The window around a prediction has to reach `left` positions below it and
+// `right` positions above it to hold every key (and with equal range
+// semantics, the end of every key's interval).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LeafErrors {
+    pub count: u64,
+    pub max_error: u64,
+    pub left: u64,
+    pub right: u64,
+    pub max_pos: u64,
+    pub min_pos: u64,
+    pub weight: f64
+}
+
+impl LeafErrors {
+    const EMPTY: LeafErrors = LeafErrors { count: 0, max_error: 0, left: 0, right: 0, max_pos: 0,
+                                           min_pos: u64::MAX, weight: 0.0 };
+
+    fn merge(&mut self, other: LeafErrors) {
+        self.count += other.count;
+        self.max_error = u64::max(self.max_error, other.max_error);
+        self.left = u64::max(self.left, other.left);
+        self.right = u64::max(self.right, other.right);
+        self.max_pos = u64::max(self.max_pos, other.max_pos);
+        self.min_pos = u64::min(self.min_pos, other.min_pos);
+        self.weight += other.weight;
+    }
+}
+
+// Evaluates the leaf models on every key, split into leaf-aligned chunks that
+// are processed on the thread pool. The result does not depend on the number
+// of chunks.
+pub fn last_level_errors<T: TrainingKey>(data: &RMITrainingData<T>, top_model: &dyn Model,
+                                         leaf_models: &[Box<dyn Model>], num_leaf_models: u64,
+                                         num_chunks: usize) -> Vec<LeafErrors> {
+    let leaf_of = |x: T| top_model.predict_to_int(&x.to_model_input());
+    let chunks = leaf_aligned_chunks(data, num_leaf_models, num_chunks, leaf_of);
+    let query_weights = data.query_weights();
+    let equal_range = data.duplicate_semantics() == DuplicateSemantics::EqualRange;
+    let num_rows = data.len() as u64;
+
+    let chunk_errors: Vec<LeafRange<LeafErrors>> = chunks.par_iter()
+        .map(|&(start, end)| {
+            let mut errors = LeafRange::new(LeafErrors::EMPTY);
+            for (row, (key, y)) in (start..end).zip(data.iter_range(start, end)) {
+                let x = key.to_model_input();
+                let target = u64::min(num_leaf_models - 1, top_model.predict_to_int(&x)) as usize;
+                let pred = leaf_models[target].predict_to_int(&x);
+
+                let leaf = errors.get_mut(target);
+                if let Some(weights) = &query_weights {
+                    leaf.weight += weights.weight(&x);
+                }
+                leaf.count += 1;
+                leaf.max_error = u64::max(leaf.max_error, error_between(pred, y as u64, data.len() as u64));
+                // the interval of a key ends one past its last row
+                let pred = u64::min(pred, num_rows);
+                let last = if equal_range { u64::min(row as u64 + 1, num_rows) } else { y as u64 };
+                leaf.left = u64::max(leaf.left, pred.saturating_sub(y as u64));
+                leaf.right = u64::max(leaf.right, last.saturating_sub(pred));
+                leaf.max_pos = u64::max(leaf.max_pos, y as u64);
+                leaf.min_pos = u64::min(leaf.min_pos, y as u64);
+            }
+            errors
+        })
+        .collect();
+
+    let mut all_errors = vec![LeafErrors::EMPTY; num_leaf_models as usize];
+    for errors in chunk_errors {
+        errors.merge_into(&mut all_errors, |all, leaf| all.merge(leaf));
+    }
+    return all_errors;
+}
+
+// Folds one more signed error into the (min, max) signed error of a partial
+// three-layer leaf. Both are packed into the low 63 bits: the minimum in bits
+// 32..62 with its sign in bit 62, the maximum in bits 0..31 with its sign in
+// bit 31 (a set sign bit means the model predicted past the key).
+fn track_signed_error(cur: u64, err: u64, negative: bool) -> u64 {
+    let mut min_err = (cur >> 32) & 0x000000003fffffffu64;
+    let mut min_flag = ((cur >> 32) & 0x0000000040000000u64) >> 30;
+    let mut max_err = cur & 0x000000007fffffffu64;
+    let mut max_flag = (cur & 0x0000000080000000u64) >> 31;
+
+    if negative {
+        if min_err < err || min_flag == 0 {
+            min_err = err;
+            min_flag = 1;
+        }
+        if max_err > err && max_flag == 1 {
+            max_err = err;
+            max_flag = 1;
+        }
+    } else {
+        if min_err > err && min_flag == 0 {
+            min_err = err;
+            min_flag = 0;
+        }
+        if max_err < err || max_flag == 1 {
+            max_err = err;
+            max_flag = 0;
+        }
+    }
+    return min_flag << 62 | min_err << 32 | max_flag << 31 | max_err;
+}
+
+// The width of the search window of a signed error (see track_signed_error).
+fn signed_window(err: u64) -> u64 {
+    return (err & 0x7fffffff) + ((err >> 32) & 0x3fffffff);
+}
+
+// The signed errors of the lower bound probes of a model: a key just below
+// the next key, which is at the given row, and a key just above the previous
+// key, which belongs at the given row.
+fn probe_errors<T: TrainingKey>(model: &dyn Model, next: (usize, T), prev: (T, usize),
+                                num_rows: usize) -> [(u64, bool); 2] {
+    let pred = model.predict_to_int(&next.1.minus_epsilon().to_model_input());
+    let upper = (error_between(pred, next.0 as u64 + 1, num_rows as u64), pred > next.0 as u64);
+    let pred = model.predict_to_int(&prev.0.plus_epsilon().to_model_input());
+    let lower = (error_between(pred, prev.1 as u64, num_rows as u64), pred > prev.1 as u64);
+    return [upper, lower];
+}
+
+// A key just above the given one that is absent from the data, if there is
+// one before the next key. Its lower bound is the row of the next key, which
+// can be one or more rows past the window of the given key.
+fn absent_above<T: TrainingKey>(key: T, next_key: Option<T>) -> Option<T> {
+    if key == T::max_value() {
+        return None;
+    }
+    let above = key.plus_epsilon();
+    let less = |a: &T, b: &T| a.to_model_input() < b.to_model_input();
+    if !less(&key, &above) || next_key.map_or(false, |next| !less(&above, &next)) {
+        return None;
+    }
+    return Some(above);
+}
+
+// What the lower bound probes of a partial three-layer leaf need from the
+// rest of the layer: the (row, key) of the first key after the leaf, the
+// last key before it, and the row a key just above that one belongs at.
+#[derive(Clone, Copy)]
+struct LeafEdges<T> {
+    next: (usize, T),
+    prev_key: T,
+    first_idx: usize,
+    first_leaf: bool,
+}
+
+impl <T: TrainingKey> LeafEdges<T> {
+    fn of(lb_corrections: &LowerBoundCorrection<T>, leaf_idx: usize) -> LeafEdges<T> {
+        let prev_idx = if leaf_idx == 0 { 0 } else { leaf_idx - 1 };
+        return LeafEdges { next: lb_corrections.next(leaf_idx),
+                           prev_key: lb_corrections.prev_key(leaf_idx),
+                           first_idx: lb_corrections.next_index(prev_idx),
+                           first_leaf: leaf_idx == 0 };
+    }
+
+    // The same edges, from the rows of a non-empty leaf and the neighbouring
+    // rows before and after it, if it has them (see for_each_leaf_rows).
+    fn of_rows(rows: &RMITrainingData<T>, leaf_idx: usize, has_prev: bool, has_next: bool,
+               num_rows: usize) -> LeafEdges<T> {
+        let next = if has_next {
+            let (key, row) = rows.get(rows.len() - 1);
+            (row, key)
+        } else if leaf_idx == 0 {
+            // (LowerBoundCorrection leaves the next key of a lone first leaf unset)
+            (0, T::zero_value())
+        } else {
+            (num_rows, T::max_value())
+        };
+        let prev_key = if has_prev { rows.get_key(0) } else { T::zero_value() };
+        let first_idx = if leaf_idx == 0 { next.0 } else { rows.get(has_prev as usize).1 };
+        return LeafEdges { next, prev_key, first_idx, first_leaf: leaf_idx == 0 };
+    }
+
+    // the next (row, key) and the previous (key, row) of a model of the leaf's
+    // partial layer, with the lower bound corrections of that layer
+    fn of_partial(&self, partial_lb: &LowerBoundCorrection<T>, third_idx: usize) -> ((usize, T), (T, usize)) {
+        let next = if third_idx >= partial_lb.last_non_empty_model() as usize {
+            self.next
+        } else {
+            partial_lb.next(third_idx)
+        };
+
+        let prev_idx = if third_idx == 0 { 0 } else { third_idx - 1 };
+        let prev = if third_idx <= partial_lb.first_non_empty_model() as usize {
+            let first_idx = if self.first_leaf { partial_lb.next_index(prev_idx) } else { self.first_idx };
+            (self.prev_key, first_idx)
+        } else {
+            let single = partial_lb.first_non_empty_model() == 0
+                && partial_lb.last_non_empty_model() == 0;
+            let first_idx = if single { self.first_idx } else { partial_lb.next_index(prev_idx) };
+            (partial_lb.prev_key(third_idx), first_idx)
+        };
+        return (next, prev);
+    }
+}
+
+// Leaf models are fit on every sample_rate-th key of each leaf, plus the
+// last key of the leaf (and the neighbouring keys added for lower bound
+// searches). A sample rate of 1 uses every key, which are read in place under
+// a memory limit (see for_each_leaf_rows); the samples are copied.
+//
+// The rows are split into leaf-aligned chunks that are trained on the whole
+// thread pool. Each chunk sees the same neighbouring keys as a single pass
+// over [start_idx, end_idx) would, so the models do not depend on the number
+// of chunks.
+pub fn build_models_from<T: TrainingKey>(data: &RMITrainingData<T>,
+                                        top_model: &Box<dyn Model>,
+                                        model_type: &str,
+                                        start_idx: usize, end_idx: usize,
+                                        first_model_idx: usize,
+                                        num_models: usize,
+                                        sample_rate: usize) -> Vec<Box<dyn Model>> {
 
     assert!(end_idx > start_idx,
             "start index was {} but end index was {}",
@@ -30,35 +466,91 @@ fn build_models_from<T: TrainingKey>(data: &RMITrainingData<T>,
     assert!(end_idx <= data.len());
     assert!(start_idx <= data.len());
 
-    let dummy_md = RMITrainingData::<T>::empty();
-    let mut leaf_models: Vec<Box<dyn Model>>
-                             = Vec::with_capacity(num_models as usize);
-    let mut second_layer_data = Vec::with_capacity((end_idx - start_idx) / num_models as usize);
-    let mut last_target = first_model_idx;
-           
-    let bounded_it = data.iter()
-        .skip(start_idx)
-        .take(end_idx - start_idx);
-        
-    for (x, y) in bounded_it {
+    let chunks = leaf_aligned_ranges(data, start_idx, end_idx,
+                                     first_model_idx as u64, num_models as u64,
+                                     default_num_chunks(end_idx - start_idx),
+                                     |x| top_model.predict_to_int(&x.to_model_input()));
+    let chunk_models: Vec<Vec<Box<dyn Model>>> = chunks.par_iter()
+        .map(|&(chunk_start, chunk_end)| {
+            build_chunk_models_from(data, top_model, model_type,
+                                    start_idx, chunk_start, chunk_end, end_idx,
+                                    first_model_idx, num_models, sample_rate)
+        })
+        .collect();
+
+    let leaf_models: Vec<Box<dyn Model>> = chunk_models.into_iter().flatten().collect();
+    assert_eq!(num_models as usize, leaf_models.len());
+    return leaf_models;
+}
+
+// Trains the leaves of the rows [chunk_start, chunk_end) of a pass over
+// [start_idx, end_idx): every leaf from the one of the chunk's first row (or
+// first_model_idx for the first chunk) up to the one before the next chunk's
+// first row (or the last of the num_models leaves for the last chunk).
+pub fn build_chunk_models_from<T: TrainingKey>(data: &RMITrainingData<T>,
+                                               top_model: &Box<dyn Model>,
+                                               model_type: &str,
+                                               start_idx: usize,
+                                               chunk_start: usize, chunk_end: usize,
+                                               end_idx: usize,
+                                               first_model_idx: usize,
+                                               num_models: usize,
+                                               sample_rate: usize) -> Vec<Box<dyn Model>> {
+    if sample_rate == 1 {
+        return build_chunk_leaf_models(data, top_model, model_type, start_idx, chunk_start, chunk_end,
+                                       end_idx, first_model_idx, num_models);
+    }
+
+    let target_of = |x: &T| {
         let model_pred = top_model.predict_to_int(&x.to_model_input()) as usize;
         assert!(top_model.needs_bounds_check() || model_pred < first_model_idx + num_models,
                 "Top model gave an index of {} which is out of bounds of {}. \
                 Subset range: {} to {}",
                 model_pred, start_idx + num_models, start_idx, end_idx);
-        let target = usize::min(first_model_idx + num_models - 1, model_pred);
+        return usize::min(first_model_idx + num_models - 1, model_pred);
+    };
+    let row_at = |idx: usize| data.iter_range(idx, idx + 1).next().unwrap();
+
+    let dummy_md = RMITrainingData::<T>::empty();
+    let mut leaf_models: Vec<Box<dyn Model>> = Vec::new();
+    let mut second_layer_data = Vec::with_capacity((end_idx - start_idx) / num_models as usize);
+    let mut last_target = first_model_idx;
+    let mut leaf_count: usize = 0;
+    let mut last_seen: Option<(T, usize)> = None;
+
+    // a chunk after the first starts a new leaf, which also gets the last
+    // item of the previous leaf
+    if chunk_start > start_idx {
+        let (x, _y) = row_at(chunk_start);
+        last_target = target_of(&x);
+        second_layer_data.push(row_at(chunk_start - 1));
+    }
+    let first_target = last_target;
+
+    let bounded_it = data.iter_range(chunk_start, chunk_end);
+        
+    for (x, y) in bounded_it {
+        let target = target_of(&x);
         assert!(target >= last_target);
         
         if target > last_target {
             // this is the first datapoint for the next leaf model.
             // train the previous leaf model.
+
+            // when sampling, make sure the last point of the leaf is included
+            if let Some(v) = last_seen {
+                if second_layer_data.last() != Some(&v) {
+                    second_layer_data.push(v);
+                }
+            }
+            leaf_count = 0;
             
             // include the first point of the next leaf node to 
             // support lower bound searches (not required, but reduces error)
             let last_item = second_layer_data.last().copied();
             second_layer_data.push((x, y));
             
-            let container = RMITrainingData::new(Box::new(second_layer_data));
+            let container = data.materialize(second_layer_data);
             let leaf_model = train_model(model_type, &container);
             leaf_models.push(leaf_model);
             
@@ -67,7 +559,7 @@ fn build_models_from<T: TrainingKey>(data: &RMITrainingData<T>,
             for _skipped_idx in (last_target+1)..target {
                 leaf_models.push(train_model(model_type, &dummy_md));
             }
-            assert_eq!(leaf_models.len() + first_model_idx, target);
+            assert_eq!(leaf_models.len() + first_target, target);
 
             second_layer_data = Vec::new();
 
@@ -79,22 +571,41 @@ fn build_models_from<T: TrainingKey>(data: &RMITrainingData<T>,
 
         }
         
-        second_layer_data.push((x, y));
+        if leaf_count % sample_rate == 0 {
+            second_layer_data.push((x, y));
+        }
+        leaf_count += 1;
+        last_seen = Some((x, y));
         last_target = target;
     }
 
+    if let Some(v) = last_seen {
+        if second_layer_data.last() != Some(&v) {
+            second_layer_data.push(v);
+        }
+    }
+
+    // the first row of the next chunk ends the last leaf of this chunk, as
+    // it would in a single pass
+    let end_target = if chunk_end < end_idx {
+        let (x, y) = row_at(chunk_end);
+        second_layer_data.push((x, y));
+        target_of(&x)
+    } else {
+        first_model_idx + num_models
+    };
+
     // train the last remaining model
     assert!(! second_layer_data.is_empty());
-    let container = RMITrainingData::new(Box::new(second_layer_data));
+    let container = data.materialize(second_layer_data);
     let leaf_model = train_model(model_type, &container);
     leaf_models.push(leaf_model);
-    assert!(leaf_models.len() <= num_models);
     
     // add models at the end with nothing mapped into them
-    for _skipped_idx in (last_target+1)..(first_model_idx + num_models) as usize {
+    for _skipped_idx in (last_target+1)..end_target {
         leaf_models.push(train_model(model_type, &dummy_md));
     }
-    assert_eq!(num_models as usize, leaf_models.len());
+    assert_eq!(leaf_models.len() + first_target, end_target);
     return leaf_models;
 }
 
@@ -103,8 +614,7 @@ fn build_partial_models_from<T: TrainingKey>(data: &RMITrainingData<T>,
                                     model_type: &str,
                                     start_idx: usize, end_idx: usize,
                                     first_model_idx: usize,
-                                    num_models: usize,
-                                    top_model_offset: u64 ) -> Vec<Box<dyn Model>> {
+                                    num_models: usize) -> Vec<Box<dyn Model>> {
 
     assert!(end_idx > start_idx,
             "start index was {} but end index was {}",
@@ -112,75 +622,84 @@ fn build_partial_models_from<T: TrainingKey>(data: &RMITrainingData<T>,
     assert!(end_idx <= data.len());
     assert!(start_idx <= data.len());
 
-    let dummy_md = RMITrainingData::<T>::empty();
-    let mut leaf_models: Vec<Box<dyn Model>>
-                             = Vec::with_capacity(num_models as usize);
-    let mut second_layer_data = Vec::with_capacity((end_idx - start_idx) / num_models as usize);
-    let mut last_target = first_model_idx;
-           
-    let bounded_it = data.iter()
-        .skip(start_idx)
-        .take(end_idx - start_idx);
-        
-    for (x, y) in bounded_it {
-        // let model_pred = top_model.predict_to_int(&x.to_model_input()) as usize + top_model_offset as usize;
-        let model_pred = top_model.predict_to_int(&x.to_model_input()) as usize;
-        assert!(top_model.needs_bounds_check() || model_pred < first_model_idx + num_models,
-                "Top model gave an index of {} which is out of bounds of {}. \
-                Subset range: {} to {}",
-                model_pred, start_idx + num_models, start_idx, end_idx);
-        let target = usize::min(first_model_idx + num_models - 1, model_pred);
-        assert!(target >= last_target);
-        
-        if target > last_target {
-            // println!("Model pred: {} start:{} end:{}", target, first_model_idx,first_model_idx + num_models);
-            // this is the first datapoint for the next leaf model.
-            // train the previous leaf model.
-            
-            // include the first point of the next leaf node to 
-            // support lower bound searches (not required, but reduces error)
-            let last_item = second_layer_data.last().copied();
-            second_layer_data.push((x, y));
-            
-            let container = RMITrainingData::new(Box::new(second_layer_data));
-            let leaf_model = train_model(model_type, &container);
-            leaf_models.push(leaf_model);
-            
-            
-            // leave empty models for any we skipped.
-            for _skipped_idx in (last_target+1)..target {
-                leaf_models.push(train_model(model_type, &dummy_md));
-            }
-            assert_eq!(leaf_models.len() + first_model_idx, target);
-
-            second_layer_data = Vec::new();
+    let leaf_models = build_chunk_leaf_models(data, top_model, model_type, start_idx, start_idx, end_idx,
+                                              end_idx, first_model_idx, num_models);
+    assert_eq!(num_models as usize, leaf_models.len());
+    return leaf_models;
+}
+// Trains one model per partition of the rows [start_idx, end_idx), given the
+// partition of each row; `train` gets the rows of a partition in key order
+// (none for an empty partition). The rows of a window of partitions are
+// collected in one pass over the keys. Without a memory limit all partitions
+// are collected at once, with one the partition sizes are counted first and
+// the windows are sized to the limit. A partition that does not fit in the
+// limit by itself is read in place if its rows are contiguous.
+fn train_partitions<T: TrainingKey, P, M>(data: &RMITrainingData<T>,
+                                          start_idx: usize, end_idx: usize,
+                                          num_partitions: usize,
+                                          partition_of: P,
+                                          mut train: M) -> Vec<Box<dyn Model>>
+where P: Fn(T) -> usize, M: FnMut(RMITrainingData<T>) -> Box<dyn Model> {
+    let budget = data.memory_budget();
+    let row_bytes = std::mem::size_of::<(T, usize)>();
+    // the number of rows of each partition, and its first and last row
+    let partition_rows = budget.limit().map(|_| {
+        let mut counts = vec![0; num_partitions];
+        let mut spans = vec![(usize::MAX, 0); num_partitions];
+        for (row, (x, _y)) in (start_idx..).zip(data.iter_range(start_idx, end_idx)) {
+            let idx = partition_of(x);
+            counts[idx] += 1;
+            spans[idx] = (usize::min(spans[idx].0, row), row);
+        }
+        (counts, spans)
+    });
+    let windows = match &partition_rows {
+        Some((counts, _spans)) => budget.windows(counts, row_bytes),
+        None => vec![(0, num_partitions)]
+    };
+    if windows.len() > 1 {
+        println!("Training {} partitions in {} windows to stay within {} bytes",
+                 num_partitions, windows.len(), budget.limit().unwrap());
+    }
 
-            // include the last item of this leaf in the next leaf
-            // to support lower bound searches.
-            if let Some(v) = last_item {
-                second_layer_data.push(v);
+    let mut models = Vec::with_capacity(num_partitions);
+    for (first, last) in windows {
+        if let (Some((counts, spans)), Some(limit)) = (&partition_rows, budget.limit()) {
+            let (first_row, last_row) = spans[first];
+            if counts[first] * row_bytes > limit {
+                if last_row + 1 - first_row == counts[first] {
+                    models.push(train(data.rows(first_row, last_row + 1)));
+                    continue;
+                }
+                warn!("A partition of {} rows ({} bytes) exceeds the memory limit of {} bytes",
+                      counts[first], counts[first] * row_bytes, limit);
             }
+        }
 
+        let mut window_data: Vec<Vec<(T, usize)>> = (first..last)
+            .map(|idx| match &partition_rows {
+                Some((counts, _spans)) => Vec::with_capacity(counts[idx]),
+                None => Vec::new()
+            })
+            .collect();
+        for (x, y) in data.iter_range(start_idx, end_idx) {
+            let idx = partition_of(x);
+            if idx >= first && idx < last {
+                window_data[idx - first].push((x, y));
+            }
         }
-        
-        second_layer_data.push((x, y));
-        last_target = target;
-    }
 
-    // train the last remaining model
-    assert!(! second_layer_data.is_empty());
-    let container = RMITrainingData::new(Box::new(second_layer_data));
-    let leaf_model = train_model(model_type, &container);
-    leaf_models.push(leaf_model);
-    assert!(leaf_models.len() <= num_models);
-    
-    // add models at the end with nothing mapped into them
-    for _skipped_idx in (last_target+1)..(first_model_idx + num_models) as usize {
-        leaf_models.push(train_model(model_type, &dummy_md));
+        // every partition is charged once, until its model is trained
+        let containers: Vec<RMITrainingData<T>> = window_data.into_iter()
+            .map(|rows| data.materialize(rows))
+            .collect();
+        for container in containers {
+            models.push(train(container));
+        }
     }
-    assert_eq!(num_models as usize, leaf_models.len());
-    return leaf_models;
+    return models;
 }
+
 fn build_3layer_models_from<T: TrainingKey>(data: &RMITrainingData<T>,
                                     top_model: &Box<dyn Model>,
                                     sec_models: &Vec<Box<dyn Model>>,
@@ -189,7 +708,8 @@ fn build_3layer_models_from<T: TrainingKey>(data: &RMITrainingData<T>,
                                     start_idx: usize, end_idx: usize,
                                     first_model_idx: usize,
                                     num_second_models: usize,
-                                    num_third_models: usize
+                                    num_third_models: usize,
+                                    sample_rate: usize
                                 ) -> Vec<Box<dyn Model>> {
 
     assert!(end_idx > start_idx,
@@ -200,60 +720,28 @@ fn build_3layer_models_from<T: TrainingKey>(data: &RMITrainingData<T>,
     let num_rows = data.len();
     println!("Num rows in build3layer {}", num_rows);
     let dummy_md = RMITrainingData::<T>::empty();
-    let mut second_models: Vec<Box<dyn Model>>
-                             = Vec::with_capacity(num_second_models as usize);
-    let mut third_models: Vec<Box<dyn Model>>
-                             = Vec::with_capacity(num_third_models);
-    // let mut third_layer_data = Vec::with_capacity((end_idx - start_idx) / num_third_models as usize);
-    let mut last_target = first_model_idx;
-           
-    
-
-    let batch_size = num_third_models;
-
-    for memory_batch in (0..(num_third_models)).step_by(batch_size){
-        let mut data_of_batch = vec![Vec::with_capacity((end_idx - start_idx) / num_third_models as usize);batch_size ];
-
-        let bounded_it = data.iter()
-            .skip(start_idx)
-            .take(end_idx - start_idx);
-
-        for (x, y) in bounded_it {
-            let model_pred_1 = top_model.predict_to_int(&x.to_model_input()) as usize;
-            let mut pred_sec_layer = u64::min(num_second_models as u64 - 1, model_pred_1 as u64) as usize;
-            let model_pred = sec_models[pred_sec_layer].predict_to_int(&x.to_model_input()) as usize;
-            assert!(top_model.needs_bounds_check() || model_pred < first_model_idx + num_third_models,
-                    "Top model gave an index of {} which is out of bounds of {}. \
-                    Subset range: {} to {}",
-                    model_pred, start_idx + num_second_models, start_idx, end_idx);
-            let model_pred = u64::min((pred_sec_layer  as u64 +1)*((num_third_models/num_second_models) as u64) - 1, model_pred as u64) as usize;
-            let target = u64::max((pred_sec_layer  as u64 )*((num_third_models/num_second_models) as u64), model_pred as u64) as usize;
-            // let target = usize::min(first_model_idx + num_third_models - 1, model_pred);
-            
-            if (target >= memory_batch && target < memory_batch + batch_size){
-                data_of_batch[target - memory_batch].push((x,y));
 
-            }
+    let partition_of = |x: T| {
+        let model_pred_1 = top_model.predict_to_int(&x.to_model_input()) as usize;
+        let pred_sec_layer = u64::min(num_second_models as u64 - 1, model_pred_1 as u64) as usize;
+        let model_pred = sec_models[pred_sec_layer].predict_to_int(&x.to_model_input()) as usize;
+        assert!(top_model.needs_bounds_check() || model_pred < first_model_idx + num_third_models,
+                "Top model gave an index of {} which is out of bounds of {}. \
+                Subset range: {} to {}",
+                model_pred, start_idx + num_second_models, start_idx, end_idx);
+        let model_pred = u64::min((pred_sec_layer  as u64 +1)*((num_third_models/num_second_models) as u64) - 1, model_pred as u64) as usize;
+        u64::max((pred_sec_layer  as u64 )*((num_third_models/num_second_models) as u64), model_pred as u64) as usize
+        // let target = usize::min(first_model_idx + num_third_models - 1, model_pred);
+    };
 
-            assert!(target >= last_target, "Last target was {}, Current target was {}",
-            last_target, target);
-           
+    let third_models = train_partitions(data, start_idx, end_idx, num_third_models, partition_of, |container| {
+        if container.len() != 0 {
+            train_model_sampled(third_model_type, &container, sample_rate)
         }
-        for i in 0..batch_size {
-            if memory_batch + i >= ( num_third_models) {
-                break;
-            }
-            if data_of_batch[i].len() != 0 {
-                let mut container = RMITrainingData::new(Box::new(data_of_batch.get(i).cloned().unwrap() ));
-                let leaf_model = train_model(third_model_type, &container);
-                third_models.push(leaf_model);
-            }
-            else{
-                third_models.push(train_model(third_model_type, &dummy_md));
-            }
-            
+        else{
+            train_model(third_model_type, &dummy_md)
         }
-    }
+    });
    
     assert_eq!(num_third_models as usize, third_models.len());
     return third_models;
@@ -267,7 +755,8 @@ fn build_naive_3layer_models_from<T: TrainingKey>(data: &RMITrainingData<T>,
                                     start_idx: usize, end_idx: usize,
                                     first_model_idx: usize,
                                     num_second_models: usize,
-                                    num_third_models: usize
+                                    num_third_models: usize,
+                                    sample_rate: usize
                                 ) -> Vec<Box<dyn Model>> {
 
     assert!(end_idx > start_idx,
@@ -278,274 +767,539 @@ fn build_naive_3layer_models_from<T: TrainingKey>(data: &RMITrainingData<T>,
     let num_rows = data.len();
     println!("Num rows in build3layer {}", num_rows);
     let dummy_md = RMITrainingData::<T>::empty();
-    let mut second_models: Vec<Box<dyn Model>>
-                             = Vec::with_capacity(num_second_models as usize);
-    let mut third_models: Vec<Box<dyn Model>>
-                             = Vec::with_capacity(num_third_models);
-    // let mut third_layer_data = Vec::with_capacity((end_idx - start_idx) / num_third_models as usize);
-    let mut last_target = first_model_idx;
-           
-    
 
-    let batch_size = num_third_models;
-
-    for memory_batch in (0..(num_third_models)).step_by(batch_size){
-        let mut data_of_batch = vec![Vec::with_capacity((end_idx - start_idx) / num_third_models as usize);batch_size ];
-
-        let bounded_it = data.iter()
-            .skip(start_idx)
-            .take(end_idx - start_idx);
-
-        for (x, y) in bounded_it {
-            let model_pred_1 = top_model.predict_to_int(&x.to_model_input()) as usize;
-            let mut pred_sec_layer = u64::min(num_second_models as u64 - 1, model_pred_1 as u64) as usize;
-            let model_pred = sec_models[pred_sec_layer].predict_to_int(&x.to_model_input()) as usize;
-            assert!(top_model.needs_bounds_check() || model_pred < first_model_idx + num_third_models,
-                    "Top model gave an index of {} which is out of bounds of {}. \
-                    Subset range: {} to {}",
-                    model_pred, start_idx + num_second_models, start_idx, end_idx);
-            // let model_pred = u64::min((pred_sec_layer  as u64 +1)*((num_third_models/num_second_models) as u64) - 1, model_pred as u64) as usize;
-            let target = usize::min(first_model_idx + num_third_models - 1, model_pred);
-            
-            if (target >= memory_batch && target < memory_batch + batch_size){
-                data_of_batch[target - memory_batch].push((x,y));
+    let partition_of = |x: T| {
+        let model_pred_1 = top_model.predict_to_int(&x.to_model_input()) as usize;
+        let pred_sec_layer = u64::min(num_second_models as u64 - 1, model_pred_1 as u64) as usize;
+        let model_pred = sec_models[pred_sec_layer].predict_to_int(&x.to_model_input()) as usize;
+        assert!(top_model.needs_bounds_check() || model_pred < first_model_idx + num_third_models,
+                "Top model gave an index of {} which is out of bounds of {}. \
+                Subset range: {} to {}",
+                model_pred, start_idx + num_second_models, start_idx, end_idx);
+        // let model_pred = u64::min((pred_sec_layer  as u64 +1)*((num_third_models/num_second_models) as u64) - 1, model_pred as u64) as usize;
+        usize::min(first_model_idx + num_third_models - 1, model_pred)
+    };
 
+    // empty models predict the last position of the partition before them
+    let mut last_key = 0;
+    let third_models = train_partitions(data, start_idx, end_idx, num_third_models, partition_of, |container| {
+        if container.len() != 0 {
+            last_key = container.get(container.len() - 1).1;
+            train_model_sampled(third_model_type, &container, sample_rate)
+        }
+        else{
+            let mut leaf_model = train_model(third_model_type, &dummy_md);
+            leaf_model.set_to_constant_model(last_key as u64);
+            leaf_model
+        }
+    });
+   
+    assert_eq!(num_third_models as usize, third_models.len());
+    return third_models;
+}
+
+// Leaves with more keys than this get a partial third layer, unless a
+// maximum error is set (see build_leaf_or_partial).
+const MAKE_PARTIAL_THRESHOLD: usize = 1000;
+const AVERAGE_PARTIAL_MODEL_NUM: f64 = 20.0;
+
+// Trains the model of a leaf that routes its keys to `num_partial` third
+// layer models, and the third layer models themselves. Empty third layer
+// models are replaced with constants for lower bound searches.
+fn build_partial_leaf<T: TrainingKey>(container: &mut RMITrainingData<T>,
+                                      model_type: &str, model_type_partial: &str,
+                                      num_partial: usize)
+                                      -> (Box<dyn Model>, Vec<Box<dyn Model>>, LowerBoundCorrection<T>) {
+    let start_y = container.get(0).1;
+    let end_y = container.get(container.len()-1 ).1;
+
+    container.set_offset(container.get(0).1);
+    container.set_scale( (num_partial-1) as f64 / (end_y-start_y) as f64);
+
+    let leaf_model = train_model(model_type, container);
+
+    container.set_offset(0);
+    container.set_scale(1.0);
+    // build partial 3 layer model with calculated number of models to build
+    let mut partial_models = build_partial_models_from(container, &leaf_model, model_type_partial,
+        0, container.len(), 0,
+        num_partial);
+    // Do lowerboundcorrection
+    let lb_corrections = LowerBoundCorrection::new(
+                    |x| leaf_model.predict_to_int(&x.to_model_input()) , num_partial as u64, container
+                );
+    // set empty models to constant model
+    for idx in 0..num_partial {
+        assert_eq!(lb_corrections.first_key(idx).is_none(),
+        lb_corrections.last_key(idx).is_none());
+        if lb_corrections.last_key(idx).is_none() {
+            // model is empty!
+            let mut upper_bound = lb_corrections.next_index(idx);
+            // if data is all inside single and first model of partial models, upper bound should be end_y + 1
+            // lowerbound have next set to 0 in this case
+            if lb_corrections.first_non_empty_model() == 0 && lb_corrections.first_non_empty_model() == lb_corrections.last_non_empty_model() {
+                upper_bound = end_y+1;
+            }
+            if !partial_models[idx].set_to_constant_model(upper_bound as u64) {
+                assert!(false);
             }
+        }
+    }
+    return (leaf_model, partial_models, lb_corrections);
+}
+
+// The signed errors train_partial_three_layer stores for a leaf without a
+// partial layer (one error), or for each model of its partial layer: the
+// errors of the leaf's own rows [own_start, own_end) of the container, and
+// of the lower bound probes.
+fn leaf_signed_errors<T: TrainingKey>(container: &RMITrainingData<T>, own_start: usize, own_end: usize,
+                                      edges: &LeafEdges<T>, leaf_model: &dyn Model,
+                                      partial: Option<(&[Box<dyn Model>], &LowerBoundCorrection<T>)>,
+                                      num_rows: usize) -> Vec<u64> {
+    let fold = |err: u64, probes: [(u64, bool); 2]| {
+        return probes.iter().fold(err, |err, &(e, negative)| track_signed_error(err, e, negative));
+    };
 
-            assert!(target >= last_target, "Last target was {}, Current target was {}",
-            last_target, target);
-           
+    // the key after the given row, and the row it starts at
+    let next_row = |idx: usize| -> (Option<T>, usize) {
+        if idx + 1 < container.len() {
+            let (key, row) = container.get(idx + 1);
+            return (Some(key), row);
         }
+        return (None, num_rows);
+    };
 
-        let mut last_key = 0;
-        for i in 0..batch_size {
-            if memory_batch + i >= ( num_third_models) {
-                break;
-            }
-            if data_of_batch[i].len() != 0 {
-                last_key = data_of_batch[i][data_of_batch[i].len()-1].1;
-                let mut container = RMITrainingData::new(Box::new(data_of_batch.get(i).cloned().unwrap() ));
-                let leaf_model = train_model(third_model_type, &container);
-                third_models.push(leaf_model);
+    let (partial_models, partial_lb) = match partial {
+        None => {
+            let mut err = 0;
+            for idx in own_start..own_end {
+                let (x, y) = container.get(idx);
+                let pred = leaf_model.predict_to_int(&x.to_model_input());
+                err = track_signed_error(err, error_between(pred, y as u64, num_rows as u64), pred > y as u64);
+
+                let (next_key, next_y) = next_row(idx);
+                if next_key == Some(x) {
+                    continue;
+                }
+                if let Some(above) = absent_above(x, next_key) {
+                    let pred = leaf_model.predict_to_int(&above.to_model_input());
+                    err = track_signed_error(err, error_between(pred, next_y as u64, num_rows as u64),
+                                             pred > next_y as u64);
+                }
             }
-            else{
-                let mut leaf_model = train_model(third_model_type, &dummy_md);
-                leaf_model.set_to_constant_model(last_key as u64);
-                third_models.push(leaf_model);
+            let probes = probe_errors(leaf_model, edges.next, (edges.prev_key, edges.first_idx), num_rows);
+            return vec![fold(err, probes)];
+        },
+        Some(p) => p,
+    };
+
+    let mut errors = vec![0; partial_models.len()];
+    let mut track = |key: T, y: usize| {
+        let x = key.to_model_input();
+        let third_idx = u64::min(partial_models.len() as u64 - 1, leaf_model.predict_to_int(&x)) as usize;
+        let pred = partial_models[third_idx].predict_to_int(&x);
+        errors[third_idx] = track_signed_error(errors[third_idx], error_between(pred, y as u64, num_rows as u64),
+                                               pred > y as u64);
+    };
+    for idx in own_start..own_end {
+        let (x, y) = container.get(idx);
+        track(x, y);
+
+        let (next_key, next_y) = next_row(idx);
+        if next_key == Some(x) {
+            continue;
+        }
+        if let Some(above) = absent_above(x, next_key) {
+            track(above, next_y);
+        }
+    }
+    for (third_idx, err) in errors.iter_mut().enumerate() {
+        let (next, prev) = edges.of_partial(partial_lb, third_idx);
+        *err = fold(*err, probe_errors(partial_models[third_idx].as_ref(), next, prev, num_rows));
+    }
+    return errors;
+}
+
+// Trains a leaf, adding a partial third layer below it if needed. Without a
+// maximum error, leaves with many keys get about one third layer model per
+// AVERAGE_PARTIAL_MODEL_NUM keys. With a maximum error, leaves that exceed
+// it get a third layer whose size is doubled until every third layer model
+// meets the error, or there are only two keys per model left; the third
+// layer models are not split any further, and train_partial_three_layer lists
+// the leaves that still exceed the error. The errors are the ones the layer
+// ends up storing, from the leaf's own rows [own_start, own_end) of the
+// container and its edges.
+fn build_leaf_or_partial<T: TrainingKey>(mut container: RMITrainingData<T>,
+                                         model_type: &str, model_type_partial: &str,
+                                         max_error: Option<u64>,
+                                         own_start: usize, own_end: usize,
+                                         edges: &LeafEdges<T>, num_rows: usize)
+                                         -> (Box<dyn Model>, Option<(Vec<Box<dyn Model>>, LowerBoundCorrection<T>)>) {
+    let max_error = match max_error {
+        None => {
+            if container.len() <= MAKE_PARTIAL_THRESHOLD {
+                return (train_model(model_type, &container), None);
             }
-            
+            let num_partial = (container.len() as f64 / AVERAGE_PARTIAL_MODEL_NUM).round() as usize;
+            let (leaf_model, partial_models, lb_corrections) = build_partial_leaf(
+                &mut container, model_type, model_type_partial, num_partial);
+            return (leaf_model, Some((partial_models, lb_corrections)));
+        },
+        Some(e) => e,
+    };
+
+    let leaf_model = train_model(model_type, &container);
+    let leaf_error = signed_window(leaf_signed_errors(&container, own_start, own_end, edges,
+                                                      leaf_model.as_ref(), None, num_rows)[0]);
+    let max_partial = container.len() / 2;
+    if leaf_error <= max_error || max_partial < 2
+        || container.get(0).1 == container.get(container.len() - 1).1 {
+        return (leaf_model, None);
+    }
+
+    let mut num_partial = 2;
+    loop {
+        let (leaf_model, partial_models, lb_corrections) = build_partial_leaf(
+            &mut container, model_type, model_type_partial, num_partial);
+        let error = leaf_signed_errors(&container, own_start, own_end, edges, leaf_model.as_ref(),
+                                       Some((&partial_models, &lb_corrections)), num_rows)
+            .into_iter()
+            .map(signed_window)
+            .max()
+            .unwrap();
+        if error <= max_error || num_partial * 2 > max_partial {
+            trace!("Split a leaf of {} keys with error {} into {} models (error {})",
+                   container.len(), leaf_error, num_partial, error);
+            return (leaf_model, Some((partial_models, lb_corrections)));
         }
+        num_partial *= 2;
     }
-   
-    assert_eq!(num_third_models as usize, third_models.len());
-    return third_models;
 }
 
-fn build_partial_3layer_models_from<T: TrainingKey>(data: &RMITrainingData<T>,
-                                    top_model: &Box<dyn Model>,
-                                    model_type: &str,
-                                    model_type_partial: &str,
-                                    start_idx: usize, end_idx: usize,
-                                    first_model_idx: usize,
-                                    num_models: usize) -> (Vec<Box<dyn Model>>, Vec<(usize,usize)>,Vec<LowerBoundCorrection<T>>, Vec<Box<dyn Model>>, usize) {
+// The second-layer models of a range of leaves, with the partial third
+// layers below them. partial_idx has the (first, count) of the third layer
+// models of each leaf, or (0, 0) for a leaf without any; lb_corrs has the
+// lower bound corrections of each leaf that has a third layer.
+pub(crate) struct PartialLayers<T> {
+    leaf_models: Vec<Box<dyn Model>>,
+    partial_idx: Vec<(usize, usize)>,
+    lb_corrs: Vec<LowerBoundCorrection<T>>,
+    partial_models: Vec<Box<dyn Model>>,
+    first_partial: usize
+}
 
-    assert!(end_idx > start_idx,
-            "start index was {} but end index was {}",
-            start_idx, end_idx);
-    assert!(end_idx <= data.len());
-    assert!(start_idx <= data.len());
+impl <T: TrainingKey> PartialLayers<T> {
+    fn new(first_partial: usize) -> PartialLayers<T> {
+        return PartialLayers { leaf_models: Vec::new(), partial_idx: Vec::new(), lb_corrs: Vec::new(),
+                               partial_models: Vec::new(), first_partial };
+    }
 
-    let dummy_md = RMITrainingData::<T>::empty();
-    let mut leaf_models: Vec<Box<dyn Model>>
-                             = Vec::with_capacity(num_models as usize);
-    let mut partial_3rd_models: Vec<Box<dyn Model>> =  Vec::new();                         
-    let mut partial_3rd_idx: Vec<(usize,usize)> = Vec::new();
-    let mut partial_3rd_lb_corrs: Vec<LowerBoundCorrection<T>> = Vec::new();
-    let mut second_layer_data: Vec<(T, usize)> = Vec::with_capacity((end_idx - start_idx) / num_models as usize);
-    let mut last_target = first_model_idx;
-           
-    let bounded_it = data.iter()
-        .skip(start_idx)
-        .take(end_idx - start_idx);
-    
-    let mut third_layer_num: usize = 0;
+    fn third_layer_num(&self) -> usize {
+        return self.first_partial + self.partial_models.len();
+    }
 
-    let make_partial_threshold = 1000;
-    let average_partial_model_num = 20;
+    fn push(&mut self, leaf_model: Box<dyn Model>,
+            partial: Option<(Vec<Box<dyn Model>>, LowerBoundCorrection<T>)>) {
+        match partial {
+            Some((mut curr_partial_3rd_model, lb_corrections)) => {
+                self.partial_idx.push((self.third_layer_num(), curr_partial_3rd_model.len()));
+                self.lb_corrs.push(lb_corrections);
+                self.partial_models.append(&mut curr_partial_3rd_model);
+            },
+            None => self.partial_idx.push((0, 0)),
+        }
+        self.leaf_models.push(leaf_model);
+    }
 
-    for (x, y) in bounded_it {
+    fn append(&mut self, mut other: PartialLayers<T>) {
+        assert_eq!(other.first_partial, self.third_layer_num());
+        self.leaf_models.append(&mut other.leaf_models);
+        self.partial_idx.append(&mut other.partial_idx);
+        self.lb_corrs.append(&mut other.lb_corrs);
+        self.partial_models.append(&mut other.partial_models);
+    }
+
+    fn save(&self, checkpoints: &Checkpoints, stage: &str, chunk_start: usize, chunk_end: usize) {
+        let mut values = vec![chunk_start as u64, chunk_end as u64];
+        values.extend(self.partial_idx.iter().map(|(_first, count)| *count as u64));
+        checkpoints.save(stage, &values, &[&self.leaf_models, &self.partial_models]);
+    }
+}
+
+// Calls leaf(idx, rows) with the rows of each leaf of the rows
+// [chunk_start, chunk_end) of a pass over [start_idx, end_idx), in order.
+// The rows of a leaf include the last row of the previous leaf and the first
+// row of the next one, for lower bound searches, as a single pass over
+// [start_idx, end_idx) would. They are read in place under a memory limit
+// (see RMITrainingData::rows). Returns the first leaf of the chunk, and the
+// leaf after its last one (the leaf of the next chunk's first row).
+fn for_each_leaf_rows<T: TrainingKey, F>(data: &RMITrainingData<T>,
+                                         top_model: &Box<dyn Model>,
+                                         start_idx: usize,
+                                         chunk_start: usize, chunk_end: usize,
+                                         end_idx: usize,
+                                         first_model_idx: usize,
+                                         num_models: usize,
+                                         mut leaf: F) -> (usize, usize)
+where F: FnMut(usize, RMITrainingData<T>) {
+    let target_of = |x: &T| {
         let model_pred = top_model.predict_to_int(&x.to_model_input()) as usize;
         assert!(top_model.needs_bounds_check() || model_pred < first_model_idx + num_models,
                 "Top model gave an index of {} which is out of bounds of {}. \
                 Subset range: {} to {}",
                 model_pred, start_idx + num_models, start_idx, end_idx);
-        let target = usize::min(first_model_idx + num_models - 1, model_pred);
+        return usize::min(first_model_idx + num_models - 1, model_pred);
+    };
+    let row_at = |idx: usize| data.iter_range(idx, idx + 1).next().unwrap();
+
+    // the rows of the current leaf start at leaf_start
+    let mut leaf_start = chunk_start;
+    let mut last_target = first_model_idx;
+
+    // a chunk after the first starts a new leaf, which also gets the last
+    // item of the previous leaf
+    if chunk_start > start_idx {
+        let (x, _y) = row_at(chunk_start);
+        last_target = target_of(&x);
+        leaf_start = chunk_start - 1;
+    }
+    let first_target = last_target;
+
+    for (idx, (x, _y)) in (chunk_start..).zip(data.iter_range(chunk_start, chunk_end)) {
+        let target = target_of(&x);
         assert!(target >= last_target);
         if target > last_target {
             // this is the first datapoint for the next leaf model.
-            // train the previous leaf model.
-            // include the first point of the next leaf node to 
-            // support lower bound searches (not required, but reduces error)
-            let last_item = second_layer_data.last().copied();
-            second_layer_data.push((x, y));
-            let mut container = RMITrainingData::new(Box::new(second_layer_data));
-            if container.len() > make_partial_threshold {
-                let curr_third_layer_num = (container.len() as f64 / average_partial_model_num as f64).round() as u64;
-                // let curr_third_layer_num = 65536 as u64;
-                // container.set_offset(container.get(0).1 - (third_layer_num * 100) );
-                let start_y = container.get(0).1;
-                let end_y = container.get(container.len()-1 ).1;
-
-                container.set_offset(container.get(0).1);
-                container.set_scale( (curr_third_layer_num-1) as f64 / (end_y-start_y) as f64);
-                
-                let leaf_model = train_model(model_type, &container);
-                // let leaf_model = train_model("pwl42", &container);
-
-                // check if  only single partial model is used, and exclude
-
-                partial_3rd_idx.append(&mut vec![(third_layer_num as usize, curr_third_layer_num as usize)]);
-                container.set_offset(0);
-                container.set_scale(1.0);
-            
-                let mut curr_partial_3rd_model = build_partial_models_from(&container, &leaf_model, model_type_partial,
-                                    0, container.len(), 0,
-                                    curr_third_layer_num as usize, third_layer_num as u64);
-
-                let lb_corrections = LowerBoundCorrection::new(
-                                        |x| leaf_model.predict_to_int(&x.to_model_input()) , curr_third_layer_num, &container
-                                       );
-                for idx in 0..(curr_third_layer_num as usize) {
-                    assert_eq!(lb_corrections.first_key(idx).is_none(),
-                    lb_corrections.last_key(idx).is_none());
-                    // println!("lbcorr, next index:{}", lb_corrections.next_index(idx));
-                    // println!("Partial:{} lbcorr, upper bound:{}",partial_3rd_lb_corrs.len(), lb_corrections.next_index(idx));
-                    if lb_corrections.last_key(idx).is_none() {
-                    // model is empty!
-                        let mut upper_bound = lb_corrections.next_index(idx);
-                        // next index is 0 if first and single model is used in 3rd layer
-                        // no need to use partial layer in this case, need fix
-                        if lb_corrections.first_non_empty_model() == 0 && lb_corrections.first_non_empty_model() == lb_corrections.last_non_empty_model() {
-                            upper_bound = end_y+1;
-                        }
-                        // println!("Partial:{} lbcorr, upper bound:{}",partial_3rd_lb_corrs.len(), upper_bound);
-                        if !curr_partial_3rd_model[idx].set_to_constant_model(upper_bound as u64) {
-                            assert!(false);
-                        }
-                    }
-                }
-                // println!("{} First elem y: {} Last elem y: {}, lbcor lastkey: {}",curr_third_layer_num, container.get(0).1,container.get(container.len()-1).1, lb_corrections.last_key(last_model).unwrap().as_uint()  );
-                leaf_models.push(leaf_model);
-                partial_3rd_lb_corrs.append(&mut vec![lb_corrections]);
-                partial_3rd_models.append(&mut curr_partial_3rd_model);
-                third_layer_num += curr_third_layer_num as usize;
-                assert!(partial_3rd_models.len() == third_layer_num);
+            // include it in the previous leaf to support lower bound
+            // searches (not required, but reduces error)
+            leaf(last_target, data.rows(leaf_start, idx + 1));
+
+            // include the last item of this leaf in the next leaf
+            // to support lower bound searches.
+            if idx > leaf_start {
+                leaf_start = idx - 1;
             }
-            else{
-                let leaf_model = train_model(model_type, &container);
-                partial_3rd_idx.append(&mut vec![(0,0)] );
-                leaf_models.push(leaf_model);
+        }
+        last_target = target;
+    }
+
+    // the first row of the next chunk ends the last leaf of this chunk
+    let (leaf_end, end_target) = if chunk_end < end_idx {
+        (chunk_end + 1, target_of(&row_at(chunk_end).0))
+    } else {
+        (chunk_end, first_model_idx + num_models)
+    };
+
+    assert!(leaf_end > leaf_start);
+    leaf(last_target, data.rows(leaf_start, leaf_end));
+    return (first_target, end_target);
+}
+
+// Trains a model on the rows of each leaf of the rows [chunk_start,
+// chunk_end) of a pass over [start_idx, end_idx) (see for_each_leaf_rows),
+// and an empty one for each leaf without rows.
+fn build_chunk_leaf_models<T: TrainingKey>(data: &RMITrainingData<T>,
+                                           top_model: &Box<dyn Model>,
+                                           model_type: &str,
+                                           start_idx: usize,
+                                           chunk_start: usize, chunk_end: usize,
+                                           end_idx: usize,
+                                           first_model_idx: usize,
+                                           num_models: usize) -> Vec<Box<dyn Model>> {
+    let dummy_md = RMITrainingData::<T>::empty();
+    let mut leaf_models: Vec<Box<dyn Model>> = Vec::new();
+    let mut last_leaf: Option<usize> = None;
+
+    let (first_target, end_target) = for_each_leaf_rows(
+        data, top_model, start_idx, chunk_start, chunk_end, end_idx, first_model_idx, num_models,
+        |leaf_idx, container| {
+            // leave empty models for any we skipped.
+            if let Some(last) = last_leaf {
+                for _skipped_idx in (last + 1)..leaf_idx {
+                    leaf_models.push(train_model(model_type, &dummy_md));
+                }
             }
+            leaf_models.push(train_model(model_type, &container));
+            last_leaf = Some(leaf_idx);
+        });
+
+    // add models at the end with nothing mapped into them
+    for _skipped_idx in (last_leaf.unwrap() + 1)..end_target {
+        leaf_models.push(train_model(model_type, &dummy_md));
+    }
+    assert_eq!(leaf_models.len() + first_target, end_target);
+    return leaf_models;
+}
+
+// Trains the leaves of the rows [chunk_start, chunk_end) of a pass over
+// [start_idx, end_idx) (see for_each_leaf_rows), numbering their third layer
+// models from first_partial.
+fn build_partial_3layer_chunk<T: TrainingKey>(data: &RMITrainingData<T>,
+                                              top_model: &Box<dyn Model>,
+                                              model_type: &str,
+                                              model_type_partial: &str,
+                                              start_idx: usize,
+                                              chunk_start: usize, chunk_end: usize,
+                                              end_idx: usize,
+                                              first_model_idx: usize,
+                                              num_models: usize,
+                                              first_partial: usize) -> PartialLayers<T> {
+    let dummy_md = RMITrainingData::<T>::empty();
+    let max_error = data.max_error();
+    let mut layers = PartialLayers::new(first_partial);
+    let mut last_leaf: Option<usize> = None;
+    let target_of = |x: &T| {
+        let model_pred = top_model.predict_to_int(&x.to_model_input()) as usize;
+        return usize::min(first_model_idx + num_models - 1, model_pred);
+    };
+
+    let (first_target, end_target) = for_each_leaf_rows(
+        data, top_model, start_idx, chunk_start, chunk_end, end_idx, first_model_idx, num_models,
+        |leaf_idx, container| {
             // leave empty models for any we skipped.
-            for _skipped_idx in (last_target+1)..target {
-                leaf_models.push(train_model(model_type, &dummy_md));
-                partial_3rd_idx.append(&mut vec![(0,0)] );
+            if let Some(last) = last_leaf {
+                for _skipped_idx in (last + 1)..leaf_idx {
+                    layers.push(train_model(model_type, &dummy_md), None);
+                }
             }
-            assert_eq!(leaf_models.len() + first_model_idx, target);
+            // the neighbouring rows of the leaves before and after this one
+            let has_prev = target_of(&container.get_key(0)) != leaf_idx;
+            let has_next = target_of(&container.get_key(container.len() - 1)) != leaf_idx;
+            let edges = LeafEdges::of_rows(&container, leaf_idx, has_prev, has_next, data.len());
+            let (own_start, own_end) = (has_prev as usize, container.len() - has_next as usize);
+            let (leaf_model, partial) = build_leaf_or_partial(container, model_type, model_type_partial,
+                                                              max_error, own_start, own_end,
+                                                              &edges, data.len());
+            layers.push(leaf_model, partial);
+            last_leaf = Some(leaf_idx);
+        });
 
-            second_layer_data = Vec::new();
+    // add models at the end with nothing mapped into them
+    for _skipped_idx in (last_leaf.unwrap() + 1)..end_target {
+        layers.push(train_model(model_type, &dummy_md), None);
+    }
+    assert_eq!(layers.leaf_models.len() + first_target, end_target);
+    return layers;
+}
 
-            // include the last item of this leaf in the next leaf
-            // to support lower bound searches.
-            if let Some(v) = last_item {
-                second_layer_data.push(v);
-            }
+// Rebuilds the leaves of a chunk from a checkpoint written by
+// PartialLayers::save. The lower bound corrections of the leaves with a third
+// layer are not in the checkpoint, so they are recomputed from their rows.
+fn restore_partial_3layer_chunk<T: TrainingKey>(data: &RMITrainingData<T>,
+                                                top_model: &Box<dyn Model>,
+                                                model_type: &str,
+                                                model_type_partial: &str,
+                                                start_idx: usize,
+                                                chunk_start: usize, chunk_end: usize,
+                                                end_idx: usize,
+                                                first_model_idx: usize,
+                                                num_models: usize,
+                                                first_partial: usize,
+                                                stage: Stage) -> PartialLayers<T> {
+    assert_eq!(&stage.values[..2], &[chunk_start as u64, chunk_end as u64],
+               "Checkpoint does not match the rows of its batch");
+    let mut layers = PartialLayers::new(first_partial);
+    layers.leaf_models = stage.restore_layer::<T>(0, model_type);
+    layers.partial_models = stage.restore_layer::<T>(1, model_type_partial);
+    let mut third_layer_num = first_partial;
+    for count in stage.values[2..].iter().map(|c| *c as usize) {
+        if count == 0 {
+            layers.partial_idx.push((0, 0));
+        } else {
+            layers.partial_idx.push((third_layer_num, count));
+            third_layer_num += count;
         }
-        second_layer_data.push((x, y));
-        last_target = target;
     }
+    assert_eq!(layers.partial_idx.len(), layers.leaf_models.len());
+    assert_eq!(third_layer_num, layers.third_layer_num());
+
+    // the first call is for the first leaf of the chunk
+    let mut first_leaf: Option<usize> = None;
+    let mut lb_corrs = Vec::new();
+    let leaf_models = &layers.leaf_models;
+    let partial_idx = &layers.partial_idx;
+    for_each_leaf_rows(
+        data, top_model, start_idx, chunk_start, chunk_end, end_idx, first_model_idx, num_models,
+        |leaf_idx, container| {
+            let local_idx = leaf_idx - *first_leaf.get_or_insert(leaf_idx);
+            let (_first, count) = partial_idx[local_idx];
+            if count > 0 {
+                let leaf_model = &leaf_models[local_idx];
+                lb_corrs.push(LowerBoundCorrection::new(
+                    |x| leaf_model.predict_to_int(&x.to_model_input()), count as u64, &container));
+            }
+        });
+    layers.lb_corrs = lb_corrs;
+    return layers;
+}
 
-    // train the last remaining model
-    assert!(! second_layer_data.is_empty());
-    let mut container = RMITrainingData::new(Box::new(second_layer_data));
-    if container.len() > make_partial_threshold {
-        let curr_third_layer_num = (container.len() as f64 / average_partial_model_num as f64).round() as usize;
-        // container.set_offset(container.get(0).1 - (third_layer_num * 100) );
-        let start_y = container.get(0).1;
-        let end_y = container.get(container.len()-1 ).1;
-
-        container.set_offset(container.get(0).1);
-        container.set_scale( (curr_third_layer_num-1) as f64 / (end_y-start_y) as f64);
-        
-        let leaf_model = train_model(model_type, &container);
-        partial_3rd_idx.append(&mut vec![(third_layer_num as usize, curr_third_layer_num as usize)]);
-        
-        container.set_offset(0);
-        container.set_scale(1.0);
-        // build partial 3 layer model with calculated number of models to build
-        let mut curr_partial_3rd_model = build_partial_models_from(&container, &leaf_model, model_type_partial,
-            0, container.len(), 0,
-            curr_third_layer_num as usize, third_layer_num as u64);
-        // Do lowerboundcorrection
-        let lb_corrections = LowerBoundCorrection::new(
-                        |x| leaf_model.predict_to_int(&x.to_model_input()) , curr_third_layer_num as u64, &container
-                    );
-        // set empty models to constant model
-        for idx in 0..(curr_third_layer_num as usize) {
-            assert_eq!(lb_corrections.first_key(idx).is_none(),
-            lb_corrections.last_key(idx).is_none());
-            if lb_corrections.last_key(idx).is_none() {
-                // model is empty!
-                let mut upper_bound = lb_corrections.next_index(idx);
-                // if data is all inside single and first model of partial models, upper bound should be end_y + 1
-                // lowerbound have next set to 0 in this case
-                if lb_corrections.first_non_empty_model() == 0 && lb_corrections.first_non_empty_model() == lb_corrections.last_non_empty_model() {
-                    upper_bound = end_y+1;
-                }
-                // if lb_corrections.last_non_empty_model() < idx as u64 {
-                //     upper_bound = data.len();
-                // }
-                if !curr_partial_3rd_model[idx].set_to_constant_model(upper_bound as u64) {
-                    assert!(false);
+pub(crate) fn build_partial_3layer_models_from<T: TrainingKey>(data: &RMITrainingData<T>,
+                                    top_model: &Box<dyn Model>,
+                                    model_type: &str,
+                                    model_type_partial: &str,
+                                    start_idx: usize, end_idx: usize,
+                                    first_model_idx: usize,
+                                    num_models: usize,
+                                    checkpoints: Option<&Checkpoints>) -> PartialLayers<T> {
+
+    assert!(end_idx > start_idx,
+            "start index was {} but end index was {}",
+            start_idx, end_idx);
+    assert!(end_idx <= data.len());
+    assert!(start_idx <= data.len());
+
+    // with checkpoints, the leaves are trained (and written) in leaf-aligned
+    // batches, which give the same models as a single pass
+    let num_batches = checkpoints.map(|c| c.num_batches(num_models as u64)).unwrap_or(1);
+    let batches = leaf_aligned_ranges(data, start_idx, end_idx,
+                                      first_model_idx as u64, num_models as u64, num_batches,
+                                      |x| top_model.predict_to_int(&x.to_model_input()));
+
+    let mut layers = PartialLayers::new(0);
+    for (batch_idx, &(batch_start, batch_end)) in batches.iter().enumerate() {
+        let stage_name = format!("layer2-{}", batch_idx);
+        let batch = match checkpoints.and_then(|c| c.load(&stage_name)) {
+            Some(stage) => {
+                println!("Restoring batch {} of {} second-level models from a checkpoint",
+                         batch_idx + 1, batches.len());
+                restore_partial_3layer_chunk(data, top_model, model_type, model_type_partial,
+                                             start_idx, batch_start, batch_end, end_idx,
+                                             first_model_idx, num_models,
+                                             layers.third_layer_num(), stage)
+            },
+            None => {
+                let batch = build_partial_3layer_chunk(data, top_model, model_type, model_type_partial,
+                                                       start_idx, batch_start, batch_end, end_idx,
+                                                       first_model_idx, num_models,
+                                                       layers.third_layer_num());
+                if let Some(c) = checkpoints {
+                    batch.save(c, &stage_name, batch_start, batch_end);
                 }
+                batch
             }
-        }
-        // println!("{} First elem y: {} Last elem y: {}, lbcor lastkey: {}",curr_third_layer_num, container.get(0).1,container.get(container.len()-1).1, lb_corrections.last_key(last_model).unwrap().as_uint() );
-        leaf_models.push(leaf_model);
-        partial_3rd_lb_corrs.append(&mut vec![lb_corrections]);
-        partial_3rd_models.append(&mut curr_partial_3rd_model);
-        
-        third_layer_num += curr_third_layer_num;
-        assert!(partial_3rd_models.len() == third_layer_num);
-    }
-    else{
-        let leaf_model = train_model(model_type, &container);
-        partial_3rd_idx.append(&mut vec![(0,0)] );
-        leaf_models.push(leaf_model);
-    }
-    // let leaf_model = train_model(model_type, &container);
-    // leaf_models.push(leaf_model);
-    // partial_3rd_idx.append((0,0));
-    assert!(leaf_models.len() <= num_models);
-    
-    // add models at the end with nothing mapped into them
-    for _skipped_idx in (last_target+1)..(first_model_idx + num_models) as usize {
-        leaf_models.push(train_model(model_type, &dummy_md));
-        partial_3rd_idx.append(&mut vec![(0,0)]);
+        };
+        layers.append(batch);
     }
-    assert_eq!(num_models as usize, leaf_models.len());
-    return (leaf_models , partial_3rd_idx, partial_3rd_lb_corrs, partial_3rd_models, third_layer_num);
+
+    assert_eq!(num_models as usize, layers.leaf_models.len());
+    return layers;
 }
 pub fn train_two_layer<T: TrainingKey>(md_container: &mut RMITrainingData<T>,
                                       layer1_model: &str, layer2_model: &str,
                                       num_leaf_models: u64) -> TrainedRMI {
-    validate(&[String::from(layer1_model), String::from(layer2_model)]);
+    validate::<T>(&[String::from(layer1_model), String::from(layer2_model)]);
 
     let num_rows = md_container.len();
+    let sample_rate = md_container.sample_rate();
 
     println!("Training top-level {} model layer", layer1_model);
     md_container.set_scale(num_leaf_models as f64 / num_rows as f64);
-    let top_model = train_model(layer1_model, &md_container);
+    let top_model = if sample_rate > 1 {
+        println!("Fitting models on every {}th key", sample_rate);
+        train_model(layer1_model, &md_container.sample(sample_rate))
+    } else {
+        train_model(layer1_model, &md_container)
+    };
 
     // Check monotonicity if in debug mode
     #[cfg(debug_assertions)]
@@ -565,53 +1319,24 @@ pub fn train_two_layer<T: TrainingKey>(md_container: &mut RMITrainingData<T>,
           layer2_model, num_leaf_models);
     md_container.set_scale(1.0);
 
-    // find a prediction boundary near the middle
-    let midpoint_model = num_leaf_models / 2;
-    let split_idx = md_container.lower_bound_by(|x| {
-        let model_idx = top_model.predict_to_int(&x.0.to_model_input());
-        let model_target = u64::min(num_leaf_models - 1, model_idx);
-        return model_target.cmp(&midpoint_model);
-    });
-
-    // make sure the split point that we got is valid
-    if split_idx > 0 && split_idx < md_container.len() {
-        let key_at = top_model.predict_to_int(&md_container.get_key(split_idx)
-                                              .to_model_input());
-        let key_pr = top_model.predict_to_int(&md_container.get_key(split_idx - 1)
-                                              .to_model_input());
-        assert!(key_at > key_pr);
-    }
+    // build_models_from trains leaf-aligned chunks of the keys in parallel
+    let leaf_models = build_models_from(&md_container, &top_model, layer2_model,
+                                        0, md_container.len(), 0,
+                                        num_leaf_models as usize, sample_rate);
 
-    let mut leaf_models = if split_idx >= md_container.len() {
-        build_models_from(&md_container, &top_model, layer2_model,
-                          0, md_container.len(), 0,
-                          num_leaf_models as usize)
-    } else {
-        let split_idx_target = u64::min(num_leaf_models - 1,
-                                        top_model.predict_to_int(
-                                            &md_container.get_key(split_idx)
-                                                .to_model_input()))
-            as usize;
-
-        let first_half_models = split_idx_target as usize;
-        let second_half_models = num_leaf_models as usize - split_idx_target as usize;
-
-        let (mut hf1, mut hf2)
-            = rayon::join(|| build_models_from(&md_container, &top_model, layer2_model,
-                                               0, split_idx,
-                                               0,
-                                               first_half_models),
-                          || build_models_from(&md_container, &top_model, layer2_model,
-                                               split_idx + 1, md_container.len(),
-                                               split_idx_target,
-                                               second_half_models));
-
-        let mut leaf_models = Vec::new();
-        leaf_models.append(&mut hf1);
-        leaf_models.append(&mut hf2);
-        leaf_models
-    };
+    return finish_two_layer(md_container, top_model, leaf_models,
+                            format!("{},{}", layer1_model, layer2_model), num_leaf_models);
+}
 
+// Everything after the leaves are trained: the lower bound corrections, the
+// constants of empty leaves, the errors and the exact leaves. Incremental
+// retraining shares this with a full build.
+pub(crate) fn finish_two_layer<T: TrainingKey>(md_container: &RMITrainingData<T>,
+                                               top_model: Box<dyn Model>,
+                                               mut leaf_models: Vec<Box<dyn Model>>,
+                                               models: String,
+                                               num_leaf_models: u64) -> TrainedRMI {
+    let num_rows = md_container.len();
     println!("Computing lower bound stats...");
     let lb_corrections = LowerBoundCorrection::new(
         |x| top_model.predict_to_int(&x.to_model_input()), num_leaf_models, md_container
@@ -648,33 +1373,18 @@ pub fn train_two_layer<T: TrainingKey>(md_container: &mut RMITrainingData<T>,
     println!("Computing last level errors...");
     // evaluate model, compute last level errors
 
-    let mut max_min_gap = vec![[0, u64::MAX] ; num_leaf_models as usize];
-
-    let mut last_layer_max_l1s = vec![(0, 0) ; num_leaf_models as usize];
-    for (x, y) in md_container.iter_model_input() {
-        let leaf_idx = top_model.predict_to_int(&x);
-        let target = u64::min(num_leaf_models - 1, leaf_idx) as usize;
-        
-        let pred = leaf_models[target].predict_to_int(&x);
-
-        if ( (y as u64) > max_min_gap[target as usize][0]){
-            max_min_gap[target as usize][0] = y as u64;
-        }
-        if ( (y as u64) < max_min_gap[target as usize][1]){
-            max_min_gap[target as usize][1] = y as u64;
-        }
-
-        let err = error_between(pred, y as u64, md_container.len() as u64);
-
-        let cur_val = last_layer_max_l1s[target];
-        
-        last_layer_max_l1s[target] = (cur_val.0 + 1, u64::max(err, cur_val.1));
-    }    
+    let leaf_errors = last_level_errors(md_container, top_model.as_ref(), &leaf_models,
+                                        num_leaf_models, default_num_chunks(md_container.len()));
+    let max_min_gap: Vec<[u64; 2]> = leaf_errors.iter().map(|e| [e.max_pos, e.min_pos]).collect();
+    let mut last_layer_max_l1s: Vec<(u64, u64)> = leaf_errors.iter().map(|e| (e.count, e.max_error)).collect();
+    let query_weights = md_container.query_weights();
+    let leaf_weights: Vec<f64> = leaf_errors.iter().map(|e| e.weight).collect();
 
     let mut sum:f64 = 0.0; 
     let mut avg:f64 = 0.0; 
     for i__ in 0..num_leaf_models as usize {
-        sum +=  (max_min_gap[i__][0] - max_min_gap[i__][1]) as f64 ;
+        // empty leaves have min > max, and contribute no gap
+        sum +=  max_min_gap[i__][0].saturating_sub(max_min_gap[i__][1]) as f64 ;
 
         // if (i__ % 10000 == 1){
         //     println!("{}: Gap Value:{} ",i__, max_min_gap[i__][0] - max_min_gap[i__][1]);
@@ -689,37 +1399,27 @@ pub fn train_two_layer<T: TrainingKey>(md_container: &mut RMITrainingData<T>,
     //       includes the first key after the previous leaf (lower error)
     //       (normally, the first key after the previous leaf is the first
     //        key in this leaf, but not in the case where this leaf has no keys)
+    // with upper bound semantics, absent keys are probed instead
+    let upper_bound = md_container.duplicate_semantics() == DuplicateSemantics::UpperBound;
+    let probes = if upper_bound {
+        upper_bound_probes(md_container, |x| top_model.predict_to_int(&x.to_model_input()),
+                           |leaf, x| leaf_models[leaf].predict_to_int(x), num_leaf_models)
+    } else {
+        vec![]
+    };
     let mut large_corrections = 0;
     for leaf_idx in 0..num_leaf_models as usize {
         let curr_err = last_layer_max_l1s[leaf_idx].1;
-
-        let mut upper_flag = false;
-        let mut lower_flag = false;
-        let upper_error = {
-            let (idx_of_next, key_of_next) = lb_corrections.next(leaf_idx);
-            let pred = leaf_models[leaf_idx].predict_to_int(
-                &key_of_next.minus_epsilon().to_model_input()
-            );
-            upper_flag= pred>idx_of_next as u64;
-            error_between(pred, idx_of_next as u64 + 1, md_container.len() as u64)
-        };
-        
-        let lower_error = {
-            let first_key_before = lb_corrections.prev_key(leaf_idx);
-
-            let prev_idx = if leaf_idx == 0 { 0 } else { leaf_idx - 1 };
-            let first_idx = lb_corrections.next_index(prev_idx);
-
-            let pred = leaf_models[leaf_idx].predict_to_int(
-                &first_key_before.plus_epsilon().to_model_input()
-            );
-            lower_flag = pred > first_idx as u64;
-            error_between(pred, first_idx as u64, md_container.len() as u64)
+        let new_err = if upper_bound {
+            let (left, right) = upper_bound_reach(leaf_models[leaf_idx].as_ref(), leaf_idx,
+                                                  &lb_corrections, &probes, num_rows);
+            *[curr_err, left, right].iter().max().unwrap()
+        } else {
+            u64::max(curr_err,
+                     lower_bound_error(leaf_models[leaf_idx].as_ref(), leaf_idx,
+                                       &lb_corrections, md_container.len()))
+                + lb_corrections.longest_run(leaf_idx)
         };
-        
-        
-        let new_err = *(&[curr_err, upper_error, lower_error]).iter().max().unwrap()
-            + lb_corrections.longest_run(leaf_idx);
 
         
 
@@ -736,7 +1436,43 @@ pub fn train_two_layer<T: TrainingKey>(md_container: &mut RMITrainingData<T>,
         trace!("Of {} models, {} needed large lower bound corrections.",
               num_leaf_models, large_corrections);
     }
+
+    // with equal range semantics, the window [pred - left, pred + right] of a
+    // leaf holds both ends of the interval of every key routed to it, and the
+    // symmetric error is the wider of the two sides
+    let mut left_errors = vec![];
+    let mut right_errors = vec![];
+    if md_container.duplicate_semantics() == DuplicateSemantics::EqualRange {
+        for leaf_idx in 0..num_leaf_models as usize {
+            let (left, right) = insertion_reach(leaf_models[leaf_idx].as_ref(), leaf_idx,
+                                                  &lb_corrections, num_rows);
+            left_errors.push(u64::max(leaf_errors[leaf_idx].left, left));
+            right_errors.push(u64::max(leaf_errors[leaf_idx].right, right));
+            last_layer_max_l1s[leaf_idx].1 = u64::max(left_errors[leaf_idx], right_errors[leaf_idx]);
+        }
+    } else if md_container.signed_errors() {
+        // the same bounds as the symmetric error above, one side at a time
+        for leaf_idx in 0..num_leaf_models as usize {
+            let model = leaf_models[leaf_idx].as_ref();
+            let (left, right, run) = if upper_bound {
+                let (left, right) = upper_bound_reach(model, leaf_idx, &lb_corrections, &probes, num_rows);
+                (left, right, 0)
+            } else {
+                let (left, right) = lower_bound_reach(model, leaf_idx, &lb_corrections, num_rows);
+                (left, right, lb_corrections.longest_run(leaf_idx))
+            };
+            left_errors.push(u64::max(leaf_errors[leaf_idx].left, left) + run);
+            right_errors.push(u64::max(leaf_errors[leaf_idx].right, right) + run);
+        }
+    }
                         
+    if let Some(threshold) = md_container.exact_leaf_threshold() {
+        let leaf_models_in = leaf_models;
+        leaf_models = replace_with_exact_leaves(md_container, leaf_models_in, &lb_corrections,
+                                                &mut last_layer_max_l1s, &mut left_errors,
+                                                &mut right_errors, threshold);
+    }
+
     println!("Evaluating two-layer RMI...");
     let mut new_last_layer_max_l1s = vec![(0, 0) ;num_leaf_models as usize];
     let mut idx_ = 0;
@@ -760,10 +1496,21 @@ pub fn train_two_layer<T: TrainingKey>(md_container: &mut RMITrainingData<T>,
         .iter()
         .map(|(n, err)| ((n*err) as f64).powf(2.0) / num_rows as f64).sum::<f64>();
     
-    let model_avg_log2_error: f64 = new_last_layer_max_l1s
-        .iter().map(|(n, err)| (*n as f64)*((2*err + 2) as f64).log2()).sum::<f64>() / num_rows as f64;
+    let model_avg_log2_error: f64 = match query_weights {
+        Some(_) => query_weighted_log2_error(&leaf_weights, &new_last_layer_max_l1s),
+        None => new_last_layer_max_l1s
+            .iter().map(|(n, err)| (*n as f64)*((2*err + 2) as f64).log2()).sum::<f64>() / num_rows as f64
+    };
 
     let model_max_log2_error: f64 = (model_max_error as f64).log2();
+
+    // the number of positions a lookup searches, on average over the keys
+    let model_avg_window: f64 = if left_errors.is_empty() {
+        2.0 * model_avg_error + 1.0
+    } else {
+        new_last_layer_max_l1s.iter().zip(left_errors.iter().zip(right_errors.iter()))
+            .map(|((n, _err), (left, right))| n * (left + right + 1)).sum::<u64>() as f64 / num_rows as f64
+    };
     
 
     let final_errors = last_layer_max_l1s.into_iter()
@@ -780,12 +1527,21 @@ pub fn train_two_layer<T: TrainingKey>(md_container: &mut RMITrainingData<T>,
         model_max_error,
         model_max_error_idx,
         model_max_log2_error,
+        model_avg_window,
         last_layer_max_l1s: final_errors,
         third_layer_max_l1s: vec![],
+        left_errors,
+        right_errors,
         rmi: vec![vec![top_model], leaf_models],
-        models: format!("{},{}", layer1_model, layer2_model),
+        layout: Layout::TwoLayer,
+        models,
         branching_factor: num_leaf_models,
         cache_fix: None,
+        duplicates: md_container.duplicate_semantics(),
+        sample_rate: md_container.sample_rate(),
+        peak_memory: md_container.memory_budget().peak(),
+        error_distribution: None,
+        run_lengths: vec![],
         build_time: 0
     };
 
@@ -795,7 +1551,7 @@ pub fn train_three_layer<T: TrainingKey>(md_container: &mut RMITrainingData<T>,
                                         layer1_model: &str, layer2_model: &str,
                                         layer3_model: &str,
                                         num_leaf_models: u64) -> TrainedRMI {
-    validate(&[String::from(layer1_model), String::from(layer2_model),  String::from(layer3_model)]);
+    validate::<T>(&[String::from(layer1_model), String::from(layer2_model),  String::from(layer3_model)]);
 
     let num_rows = md_container.len();
     
@@ -803,9 +1559,11 @@ pub fn train_three_layer<T: TrainingKey>(md_container: &mut RMITrainingData<T>,
     let third_model_num = (num_leaf_models as f64).sqrt() as u64;
     assert!(num_leaf_models==second_model_num*third_model_num);
 
+    let sample_rate = md_container.sample_rate();
+
     println!("Training top-level {} model layer", layer1_model);
     md_container.set_scale(second_model_num as f64 / num_rows as f64);
-    let top_model = train_model(layer1_model, &md_container);
+    let top_model = train_model_sampled(layer1_model, &md_container, sample_rate);
 
     // Check monotonicity if in debug mode
     #[cfg(debug_assertions)]
@@ -824,63 +1582,11 @@ pub fn train_three_layer<T: TrainingKey>(md_container: &mut RMITrainingData<T>,
     println!("Training second-level {} model layer (num models = {})",
             layer2_model, second_model_num);
     md_container.set_scale( (num_leaf_models) as f64 / num_rows as f64);
-    // md_container.set_scale(1.0);
-    // find a prediction boundary near the middle
-    let midpoint_model = second_model_num / 2;
-    let split_idx = md_container.lower_bound_by(|x| {
-        let model_idx = top_model.predict_to_int(&x.0.to_model_input());
-        let model_target = u64::min(second_model_num - 1, model_idx);
-        return model_target.cmp(&midpoint_model);
-    });
-
-    // make sure the split point that we got is valid
-    if split_idx > 0 && split_idx < md_container.len() {
-        let key_at = top_model.predict_to_int(&md_container.get_key(split_idx)
-                .to_model_input());
-        let key_pr = top_model.predict_to_int(&md_container.get_key(split_idx - 1)
-                .to_model_input());
-        assert!(key_at > key_pr);
-    }
 
-    // let (mut sec_models,mut leaf_models) = build_3layer_models_from(&md_container, &top_model, layer2_model, layer3_model,
-    //                     0, md_container.len(), 0,
-    //                     second_model_num as usize, third_model_num as usize);
-    
-    let first_idx_target = 0;
-    // top_model.predict_to_int(
-    //     &md_container.get_key(0)
-    //         .to_model_input()) as usize;
-    let (mut sec_models) = 
-    if split_idx >= md_container.len() {
-        build_models_from(&md_container, &top_model, layer2_model,
-            0, md_container.len(), first_idx_target,
-            second_model_num as usize)
-    } else {
-        
-        let split_idx_target = u64::min(second_model_num - 1,
-                                        top_model.predict_to_int(
-                                            &md_container.get_key(split_idx)
-                                                .to_model_input()))
-            as usize;
-        let first_half_models = split_idx_target as usize;
-        let second_half_models = second_model_num as usize - split_idx_target as usize;
-        println!("split idx target:{} split_idx:{} first_half:{} first_idx_target:{}", split_idx_target, split_idx, first_half_models, first_idx_target);
-
-        let (mut hf1, mut hf2)
-            = rayon::join(|| build_models_from(&md_container, &top_model, layer2_model,
-                            0, split_idx, first_idx_target,
-                            first_half_models),
-                          || build_models_from(&md_container, &top_model, layer2_model,
-                            split_idx + 1, md_container.len(), split_idx_target,
-                            second_half_models));
-
-        let mut sec_models = Vec::new();
-        sec_models.append(&mut hf1);
-        sec_models.append(&mut hf2);
-        sec_models
-    };
-    
-    
+    // build_models_from trains leaf-aligned chunks of the keys in parallel
+    let mut sec_models = build_models_from(&md_container, &top_model, layer2_model,
+                                           0, md_container.len(), 0,
+                                           second_model_num as usize, sample_rate);
 
     // println!("[2nd layer]Computing lower bound stats...");
     let lb_corrections_top = LowerBoundCorrection::new(
@@ -913,7 +1619,7 @@ pub fn train_three_layer<T: TrainingKey>(md_container: &mut RMITrainingData<T>,
 
     let mut leaf_models = build_3layer_models_from(&md_container, &top_model, &sec_models, layer2_model, layer3_model,
         0, md_container.len(), 0,
-        second_model_num as usize, (num_leaf_models) as usize);
+        second_model_num as usize, (num_leaf_models) as usize, sample_rate);
     
     println!("[3rd layer]Computing lower bound stats...");
     let lb_corrections = LowerBoundCorrection::new(
@@ -956,6 +1662,8 @@ pub fn train_three_layer<T: TrainingKey>(md_container: &mut RMITrainingData<T>,
     let mut max_min_gap = vec![[0, u64::MAX] ; num_leaf_models as usize];
 
     let mut last_layer_max_l1s = vec![(0, 0) ; num_leaf_models as usize];
+    let query_weights = md_container.query_weights();
+    let mut leaf_weights = vec![0.0 ; num_leaf_models as usize];
     for (x, y) in md_container.iter_model_input() {
         let second_idx = top_model.predict_to_int(&x);
         let  pred_sec_layer = u64::min(second_model_num - 1, second_idx) as usize;
@@ -968,6 +1676,9 @@ pub fn train_three_layer<T: TrainingKey>(md_container: &mut RMITrainingData<T>,
         let target = u64::min((pred_sec_layer as u64 +1)*third_model_num - 1, pred_third_layer as u64) as usize;
         let target = u64::max((pred_sec_layer  as u64)*third_model_num, target as u64) as usize;
         let pred = leaf_models[target].predict_to_int(&x);
+        if let Some(weights) = &query_weights {
+            leaf_weights[target] += weights.weight(&x);
+        }
 
         // if ( x.as_int() > max_min_gap[target as usize][0]){
         //     max_min_gap[target as usize][0] = x.as_int();
@@ -994,7 +1705,8 @@ pub fn train_three_layer<T: TrainingKey>(md_container: &mut RMITrainingData<T>,
     let mut sum:f64 = 0.0; 
     let mut avg:f64 = 0.0; 
     for i__ in 0..num_leaf_models as usize {
-        sum +=  (max_min_gap[i__][0] - max_min_gap[i__][1]) as f64 ;
+        // empty leaves have min > max, and contribute no gap
+        sum +=  max_min_gap[i__][0].saturating_sub(max_min_gap[i__][1]) as f64 ;
         // if (i__ % 10000 == 1){
         //     println!("{}: Gap Value:{} ",i__, max_min_gap[i__][0] - max_min_gap[i__][1]);
         // }
@@ -1009,6 +1721,16 @@ pub fn train_three_layer<T: TrainingKey>(md_container: &mut RMITrainingData<T>,
     //       (normally, the first key after the previous leaf is the first
     //        key in this leaf, but not in the case where this leaf has no keys)
     println!("Computing error value...");
+    // with upper bound semantics, absent keys are probed instead
+    let upper_bound = md_container.duplicate_semantics() == DuplicateSemantics::UpperBound;
+    let probes = if upper_bound {
+        upper_bound_probes(md_container, |x| {
+            let pred_sec_layer = u64::min(second_model_num - 1, top_model.predict_to_int(&x.to_model_input())) as usize;
+            return sec_models[pred_sec_layer].predict_to_int(&x.to_model_input());
+        }, |leaf, x| leaf_models[leaf].predict_to_int(x), num_leaf_models)
+    } else {
+        vec![]
+    };
     let mut large_corrections = 0;
     for leaf_idx in 0..num_leaf_models as usize {
 
@@ -1037,8 +1759,14 @@ pub fn train_three_layer<T: TrainingKey>(md_container: &mut RMITrainingData<T>,
         };
 
 
-        let new_err = *(&[curr_err, upper_error, lower_error]).iter().max().unwrap()
-             + lb_corrections.longest_run(leaf_idx);
+        let new_err = if upper_bound {
+            let (left, right) = upper_bound_reach(leaf_models[leaf_idx].as_ref(), leaf_idx,
+                                                  &lb_corrections, &probes, md_container.len());
+            *[curr_err, left, right].iter().max().unwrap()
+        } else {
+            *(&[curr_err, upper_error, lower_error]).iter().max().unwrap()
+                + lb_corrections.longest_run(leaf_idx)
+        };
 
         let num_items_in_leaf = last_layer_max_l1s[leaf_idx].0;
         last_layer_max_l1s[leaf_idx] = (num_items_in_leaf, new_err);
@@ -1068,8 +1796,11 @@ pub fn train_three_layer<T: TrainingKey>(md_container: &mut RMITrainingData<T>,
         .iter()
         .map(|(n, err)| ((n*err) as f64).powf(2.0) / num_rows as f64).sum::<f64>();
 
-    let model_avg_log2_error: f64 = last_layer_max_l1s
-        .iter().map(|(n, err)| (*n as f64)*((2*err + 2) as f64).log2()).sum::<f64>() / num_rows as f64;
+    let model_avg_log2_error: f64 = match query_weights {
+        Some(_) => query_weighted_log2_error(&leaf_weights, &last_layer_max_l1s),
+        None => last_layer_max_l1s
+            .iter().map(|(n, err)| (*n as f64)*((2*err + 2) as f64).log2()).sum::<f64>() / num_rows as f64
+    };
 
     let model_max_log2_error: f64 = (model_max_error as f64).log2();
 
@@ -1085,12 +1816,21 @@ pub fn train_three_layer<T: TrainingKey>(md_container: &mut RMITrainingData<T>,
         model_max_error,
         model_max_error_idx,
         model_max_log2_error,
+        model_avg_window: 2.0 * model_avg_error + 1.0,
         last_layer_max_l1s: final_errors,
         third_layer_max_l1s: vec![],
+        left_errors: vec![],
+        right_errors: vec![],
         rmi: vec![vec![top_model], sec_models, leaf_models],
+        layout: Layout::ThreeLayer { num_second: second_model_num, per_second: third_model_num },
         models: format!("{},{},{}", layer1_model, layer2_model, layer3_model),
         branching_factor: num_leaf_models,
         cache_fix: None,
+        duplicates: md_container.duplicate_semantics(),
+        sample_rate: md_container.sample_rate(),
+        peak_memory: md_container.memory_budget().peak(),
+        error_distribution: None,
+        run_lengths: vec![],
         build_time: 0
     };
 
@@ -1100,7 +1840,7 @@ pub fn train_naive_three_layer<T: TrainingKey>(md_container: &mut RMITrainingDat
     layer1_model: &str, layer2_model: &str,
     layer3_model: &str,
     num_leaf_models: u64) -> TrainedRMI {
-validate(&[String::from(layer1_model), String::from(layer2_model),  String::from(layer3_model)]);
+validate::<T>(&[String::from(layer1_model), String::from(layer2_model),  String::from(layer3_model)]);
 
 let num_rows = md_container.len();
 
@@ -1108,9 +1848,11 @@ let second_model_num = (num_leaf_models as f64).sqrt() as u64;
 let third_model_num = (num_leaf_models as f64).sqrt() as u64;
 assert!(num_leaf_models==second_model_num*third_model_num);
 
+let sample_rate = md_container.sample_rate();
+
 println!("Training top-level {} model layer", layer1_model);
 md_container.set_scale(second_model_num as f64 / num_rows as f64);
-let top_model = train_model(layer1_model, &md_container);
+let top_model = train_model_sampled(layer1_model, &md_container, sample_rate);
 
 // Check monotonicity if in debug mode
 #[cfg(debug_assertions)]
@@ -1129,63 +1871,11 @@ trace!("Top model was monotonic.");
 println!("Training second-level {} model layer (num models = {})",
 layer2_model, second_model_num);
 md_container.set_scale( (num_leaf_models) as f64 / num_rows as f64);
-// md_container.set_scale(1.0);
-// find a prediction boundary near the middle
-let midpoint_model = second_model_num / 2;
-let split_idx = md_container.lower_bound_by(|x| {
-let model_idx = top_model.predict_to_int(&x.0.to_model_input());
-let model_target = u64::min(second_model_num - 1, model_idx);
-return model_target.cmp(&midpoint_model);
-});
-
-// make sure the split point that we got is valid
-if split_idx > 0 && split_idx < md_container.len() {
-let key_at = top_model.predict_to_int(&md_container.get_key(split_idx)
-.to_model_input());
-let key_pr = top_model.predict_to_int(&md_container.get_key(split_idx - 1)
-.to_model_input());
-assert!(key_at > key_pr);
-}
-
-// let (mut sec_models,mut leaf_models) = build_3layer_models_from(&md_container, &top_model, layer2_model, layer3_model,
-//                     0, md_container.len(), 0,
-//                     second_model_num as usize, third_model_num as usize);
-
-let first_idx_target = 0;
-// top_model.predict_to_int(
-//     &md_container.get_key(0)
-//         .to_model_input()) as usize;
-let (mut sec_models) = 
-if split_idx >= md_container.len() {
-build_models_from(&md_container, &top_model, layer2_model,
-0, md_container.len(), first_idx_target,
-second_model_num as usize)
-} else {
-
-let split_idx_target = u64::min(second_model_num - 1,
-    top_model.predict_to_int(
-        &md_container.get_key(split_idx)
-            .to_model_input()))
-as usize;
-let first_half_models = split_idx_target as usize;
-let second_half_models = second_model_num as usize - split_idx_target as usize;
-println!("split idx target:{} split_idx:{} first_half:{} first_idx_target:{}", split_idx_target, split_idx, first_half_models, first_idx_target);
-
-let (mut hf1, mut hf2)
-= rayon::join(|| build_models_from(&md_container, &top_model, layer2_model,
-0, split_idx, first_idx_target,
-first_half_models),
-|| build_models_from(&md_container, &top_model, layer2_model,
-split_idx + 1, md_container.len(), split_idx_target,
-second_half_models));
-
-let mut sec_models = Vec::new();
-sec_models.append(&mut hf1);
-sec_models.append(&mut hf2);
-sec_models
-};
-
 
+// build_models_from trains leaf-aligned chunks of the keys in parallel
+let mut sec_models = build_models_from(&md_container, &top_model, layer2_model,
+                                       0, md_container.len(), 0,
+                                       second_model_num as usize, sample_rate);
 
 println!("[2nd layer]Computing lower bound stats...");
 let lb_corrections_top = LowerBoundCorrection::new(
@@ -1218,7 +1908,8 @@ md_container.set_scale(1.0);
 
 let mut leaf_models = build_naive_3layer_models_from(&md_container, &top_model, &sec_models, layer2_model, layer3_model,
                                                     0, md_container.len(), 0,
-                                                    second_model_num as usize, (num_leaf_models) as usize);
+                                                    second_model_num as usize, (num_leaf_models) as usize,
+                                                    sample_rate);
 
 println!("[3rd layer]Computing lower bound stats...");
 let lb_corrections = LowerBoundCorrection::new(
@@ -1261,6 +1952,8 @@ println!("Computing last level errors...");
 let mut max_min_gap = vec![[0, u64::MAX] ; num_leaf_models as usize];
 
 let mut last_layer_max_l1s = vec![(0, 0) ; num_leaf_models as usize];
+let query_weights = md_container.query_weights();
+let mut leaf_weights = vec![0.0 ; num_leaf_models as usize];
 for (x, y) in md_container.iter_model_input() {
 let second_idx = top_model.predict_to_int(&x);
 let  pred_sec_layer = u64::min(second_model_num - 1, second_idx) as usize;
@@ -1273,6 +1966,9 @@ let target = u64::min(num_leaf_models - 1, pred_third_layer as u64) as usize;
 // let target = u64::min((pred_sec_layer as u64 +1)*third_model_num - 1, pred_third_layer as u64) as usize;
 // let target = u64::max((pred_sec_layer  as u64)*third_model_num, target as u64) as usize;
 let pred = leaf_models[target].predict_to_int(&x);
+if let Some(weights) = &query_weights {
+leaf_weights[target] += weights.weight(&x);
+}
 
 // if ( x.as_int() > max_min_gap[target as usize][0]){
 //     max_min_gap[target as usize][0] = x.as_int();
@@ -1299,7 +1995,8 @@ last_layer_max_l1s[target] = (cur_val.0 + 1, u64::max(err, cur_val.1));
 let mut sum:f64 = 0.0; 
 let mut avg:f64 = 0.0; 
 for i__ in 0..num_leaf_models as usize {
-sum +=  (max_min_gap[i__][0] - max_min_gap[i__][1]) as f64 ;
+// empty leaves have min > max, and contribute no gap
+sum +=  max_min_gap[i__][0].saturating_sub(max_min_gap[i__][1]) as f64 ;
 // if (i__ % 10000 == 1){
 //     println!("{}: Gap Value:{} ",i__, max_min_gap[i__][0] - max_min_gap[i__][1]);
 // }
@@ -1314,6 +2011,16 @@ println!("Average gap: {}",avg);
 //       (normally, the first key after the previous leaf is the first
 //        key in this leaf, but not in the case where this leaf has no keys)
 println!("Computing error value...");
+// with upper bound semantics, absent keys are probed instead
+let upper_bound = md_container.duplicate_semantics() == DuplicateSemantics::UpperBound;
+let probes = if upper_bound {
+upper_bound_probes(md_container, |x| {
+let pred_sec_layer = u64::min(second_model_num - 1, top_model.predict_to_int(&x.to_model_input())) as usize;
+return sec_models[pred_sec_layer].predict_to_int(&x.to_model_input());
+}, |leaf, x| leaf_models[leaf].predict_to_int(x), num_leaf_models)
+} else {
+vec![]
+};
 let mut large_corrections = 0;
 for leaf_idx in 0..num_leaf_models as usize {
 
@@ -1342,8 +2049,14 @@ error_between(pred, first_idx as u64, md_container.len() as u64)
 };
 
 
-let new_err = *(&[curr_err, upper_error, lower_error]).iter().max().unwrap()
-+ lb_corrections.longest_run(leaf_idx);
+let new_err = if upper_bound {
+let (left, right) = upper_bound_reach(leaf_models[leaf_idx].as_ref(), leaf_idx,
+&lb_corrections, &probes, md_container.len());
+*[curr_err, left, right].iter().max().unwrap()
+} else {
+*(&[curr_err, upper_error, lower_error]).iter().max().unwrap()
++ lb_corrections.longest_run(leaf_idx)
+};
 
 let num_items_in_leaf = last_layer_max_l1s[leaf_idx].0;
 last_layer_max_l1s[leaf_idx] = (num_items_in_leaf, new_err);
@@ -1373,8 +2086,11 @@ let model_avg_l2_error: f64 = last_layer_max_l1s
 .iter()
 .map(|(n, err)| ((n*err) as f64).powf(2.0) / num_rows as f64).sum::<f64>();
 
-let model_avg_log2_error: f64 = last_layer_max_l1s
-.iter().map(|(n, err)| (*n as f64)*((2*err + 2) as f64).log2()).sum::<f64>() / num_rows as f64;
+let model_avg_log2_error: f64 = match query_weights {
+Some(_) => query_weighted_log2_error(&leaf_weights, &last_layer_max_l1s),
+None => last_layer_max_l1s
+.iter().map(|(n, err)| (*n as f64)*((2*err + 2) as f64).log2()).sum::<f64>() / num_rows as f64
+};
 
 let model_max_log2_error: f64 = (model_max_error as f64).log2();
 
@@ -1390,12 +2106,21 @@ model_avg_log2_error,
 model_max_error,
 model_max_error_idx,
 model_max_log2_error,
+model_avg_window: 2.0 * model_avg_error + 1.0,
 last_layer_max_l1s: final_errors,
 third_layer_max_l1s: vec![0],
+left_errors: vec![],
+right_errors: vec![],
 rmi: vec![vec![top_model], sec_models, leaf_models],
+layout: Layout::NaiveThreeLayer { num_second: second_model_num },
 models: format!("{},{},{}", layer1_model, layer2_model, layer3_model),
 branching_factor: num_leaf_models,
 cache_fix: None,
+duplicates: md_container.duplicate_semantics(),
+sample_rate: md_container.sample_rate(),
+peak_memory: md_container.memory_budget().peak(),
+error_distribution: None,
+run_lengths: vec![],
 build_time: 0
 };
 
@@ -1406,16 +2131,49 @@ build_time: 0
 pub fn train_partial_three_layer<T: TrainingKey>(md_container: &mut RMITrainingData<T>,
                                         layer1_model: &str, layer2_model: &str,
                                         layer3_model: &str,
-                                        num_leaf_models: u64) -> TrainedRMI {
-    validate(&[String::from(layer1_model), String::from(layer2_model),  String::from(layer3_model)]);
+                                        num_leaf_models: u64,
+                                        checkpoints: Option<&Checkpoints>) -> TrainedRMI {
+    validate::<T>(&[String::from(layer1_model), String::from(layer2_model),  String::from(layer3_model)]);
 
     let num_rows = md_container.len();
     
     let second_model_num = num_leaf_models;
+    // the lower layers decide where to add third layer models from the
+    // keys of each leaf, so only the top model is fit on a sample
+    let sample_rate = md_container.sample_rate();
+
+    // checkpoints have to rebuild the models of every layer from their params
+    if checkpoints.is_some() {
+        for model_type in [layer1_model, layer2_model, layer3_model].iter() {
+            assert!(can_restore::<T>(model_type),
+                    "Models of type {} cannot be restored from checkpoints", model_type);
+        }
+    }
+    if let Some(c) = checkpoints {
+        c.start(&format!("{},{},{}", layer1_model, layer2_model, layer3_model),
+                num_leaf_models, md_container);
+    }
 
-    println!("Training top-level {} model layer", layer1_model);
     md_container.set_scale(second_model_num as f64 / num_rows as f64);
-    let top_model = train_model(layer1_model, &md_container);
+    let top_model = match checkpoints.and_then(|c| c.load("top")) {
+        Some(stage) => {
+            println!("Restoring the top-level {} model from a checkpoint", layer1_model);
+            stage.restore_layer::<T>(0, layer1_model).pop().unwrap()
+        },
+        None => {
+            println!("Training top-level {} model layer", layer1_model);
+            let top_model = if sample_rate > 1 {
+                println!("Fitting the top model on every {}th key", sample_rate);
+                train_model(layer1_model, &md_container.sample(sample_rate))
+            } else {
+                train_model(layer1_model, &md_container)
+            };
+            if let Some(c) = checkpoints {
+                c.save("top", &[], &[std::slice::from_ref(&top_model)]);
+            }
+            top_model
+        }
+    };
 
     // Check monotonicity if in debug mode
     #[cfg(debug_assertions)]
@@ -1436,10 +2194,20 @@ pub fn train_partial_three_layer<T: TrainingKey>(md_container: &mut RMITrainingD
     // md_container.set_scale( (num_leaf_models) as f64 / num_rows as f64);
     md_container.set_scale(1.0);
     let first_idx_target = 0;
-    let (mut sec_models, mut partial_3rd_idx, mut partial_3rd_lb_corrs, mut partial_3rd_models, third_layer_num) = 
-        build_partial_3layer_models_from(&md_container, &top_model, layer2_model,layer3_model,
+    let leaves = checkpoints.and_then(|c| c.load("leaves"));
+    let restored_leaves = leaves.is_some();
+    let mut layers = match leaves {
+        Some(stage) => {
+            println!("Restoring the second- and third-level models from a checkpoint");
+            restore_partial_3layer_chunk(&md_container, &top_model, layer2_model, layer3_model,
+                0, 0, md_container.len(), md_container.len(), first_idx_target,
+                second_model_num as usize, 0, stage)
+        },
+        None => build_partial_3layer_models_from(&md_container, &top_model, layer2_model, layer3_model,
             0, md_container.len(), first_idx_target,
-            second_model_num as usize);
+            second_model_num as usize, checkpoints)
+    };
+    let sec_models = &mut layers.leaf_models;
 
     println!("[2nd layer]Computing lower bound stats...");
     let lb_corrections = LowerBoundCorrection::new(
@@ -1467,113 +2235,121 @@ pub fn train_partial_three_layer<T: TrainingKey>(md_container: &mut RMITrainingD
             negative lookup performance may be poor.");
         assert!(false);
     }
+    if let Some(c) = checkpoints {
+        if !restored_leaves {
+            // the batches are part of the leaves checkpoint
+            layers.save(c, "leaves", 0, md_container.len());
+            c.remove_stages("layer2-");
+        }
+    }
+    let PartialLayers { leaf_models: sec_models, partial_idx: partial_3rd_idx,
+                        lb_corrs: partial_3rd_lb_corrs, partial_models: partial_3rd_models, .. } = layers;
+    let third_layer_num = partial_3rd_models.len();
+
     println!("Computing last level errors...");
     // evaluate model, compute last level errors
 
     // let mut max_min_gap: Vec<(u64, u64)> = vec![[0, u64::MAX] ; num_leaf_models as usize];
 
-    let mut last_layer_max_l1s = vec![(0, 0) ; num_leaf_models as usize];
-    let mut third_layer_max_l1s = vec![(0,0) ; third_layer_num as usize];
-
-    for (x, y) in md_container.iter_model_input() {
-        let leaf_idx = top_model.predict_to_int(&x);
-        let target = u64::min(num_leaf_models - 1, leaf_idx) as usize;
-        
-        let mut pred;
-        if (partial_3rd_idx[target] == (0,0)){
-            pred = sec_models[target].predict_to_int(&x);
-            let err = error_between(pred, y as u64, md_container.len() as u64);
-            let cur_val = last_layer_max_l1s[target];
-            // last_layer_max_l1s[target] = (cur_val.0 + 1, (u64::max(err, cur_val.1)<<32)>>32 );
-            // using 32bit should be enough to express error value
-            // if not cannot build partial 3 layer, increase model number to lower error 
-            // assert!((u64::max(err, cur_val.1)<<32)>>32 == u64::max(err, cur_val.1));
-
-            // cur_val stores minimum error (can be minus value) and maximum error (can be minus also)
-            // first 32bit is for minimum err, next 32bit for maximum error
-            // first bit is used for sign, 1 for minus, 0 for plus
-
-            let mut min_err = (cur_val.1  >> 32)& 0x000000003fffffffu64 ;//left most bit is used for partial model representation
-            let mut min_flag = ((cur_val.1  >> 32)& 0x0000000040000000u64)>>30 ;
-            let mut max_err = (cur_val.1 )& 0x000000007fffffffu64 ;
-            let mut max_flag = ((cur_val.1 )& 0x0000000080000000u64)>>31 ;
-            
-            if pred > y as u64 { // err is minus err
-                if min_err < err || min_flag==0 {
-                    min_err = err;
-                    min_flag = 1;
-                }
-                if max_err > err && max_flag==1 {
-                    max_err = err;
-                    max_flag = 1;
-                }
-            }else{ // pred is smaller than y , plus err
-                if min_err > err && min_flag ==0 {
-                    min_err = err;
-                    min_flag = 0;
-                }
-                if max_err < err || max_flag ==1 {
-                    max_err = err;
-                    max_flag = 0;
-                }
-            }
-            
-            last_layer_max_l1s[target] = (cur_val.0 + 1, min_flag<<62| min_err<<32 | max_flag<<31 | max_err );
-            assert!(last_layer_max_l1s[target].1>>63 == 0); // flag for partial model should be 0
-        }
-        else{
-            //partial_3rd_idx.0 have start of 3rd model list, partial_3rd_idx.1 has number of models 
-            let mut target_third = sec_models[target].predict_to_int(&x) + partial_3rd_idx[target].0 as u64;
-            target_third = u64::min((partial_3rd_idx[target].0 + partial_3rd_idx[target].1 - 1) as u64, target_third );
-            target_third = u64::max(partial_3rd_idx[target].0 as u64, target_third);
-            pred = partial_3rd_models[target_third as usize].predict_to_int(&x);
-            
-            let cur_val = last_layer_max_l1s[target];
-            // put number of cumulative partial models in 32 most significant bits, number of models in 32 least significant bits
-            // first bit is set as 1 if partial model is used
-            assert!(partial_3rd_idx[target].0 as u64 <= 0x000000007fffffffu64);
-            assert!(partial_3rd_idx[target].1 as u64<= 0x00000000ffffffffu64);
-            last_layer_max_l1s[target] = (cur_val.0 + 1, ((partial_3rd_idx[target].0 as u64 | 0x0000000080000000u64)<<32)|partial_3rd_idx[target].1 as u64);
-            
-            let err = error_between(pred, y as u64, md_container.len() as u64);
-            let cur_val = third_layer_max_l1s[target_third as usize];
-            // third_layer_max_l1s[target_third as usize ] = (cur_val.0 +1 , u64::max(err, cur_val.1) );
-            
-            let mut min_err = (cur_val.1  >> 32)& 0x000000003fffffffu64 ;//left most bit is used for partial model representation
-            let mut min_flag = ((cur_val.1  >> 32)& 0x0000000040000000u64)>>30 ;
-            let mut max_err = (cur_val.1 )& 0x000000007fffffffu64 ;
-            let mut max_flag = ((cur_val.1 )& 0x0000000080000000u64)>>31 ;
-            
-            if pred > y as u64{ // err is minus err
-                if min_err < err || min_flag==0 {
-                    min_err = err;
-                    min_flag = 1;
+    // the keys of a leaf (and of its third layer models) all fall into one
+    // leaf-aligned chunk, so the chunks fill disjoint entries
+    let leaf_of = |x: T| top_model.predict_to_int(&x.to_model_input());
+    let chunks = leaf_aligned_chunks(md_container, num_leaf_models, default_num_chunks(md_container.len()), leaf_of);
+    // the query weight of every model that serves lookups, for the average log2 error
+    let query_weights = md_container.query_weights();
+    // partial_3rd_idx.0 has the start of the leaf's 3rd models, partial_3rd_idx.1 the number of models
+    let third_of = |leaf: usize, x: &ModelInput| -> u64 {
+        let (first, count) = partial_3rd_idx[leaf];
+        let target_third = sec_models[leaf].predict_to_int(x) + first as u64;
+        return u64::max(first as u64, u64::min((first + count - 1) as u64, target_third));
+    };
+    let chunk_errors: Vec<(LeafRange<(u64, u64)>, LeafRange<(u64, u64)>, LeafRange<f64>, LeafRange<f64>)> = chunks.par_iter()
+        .map(|&(start, end)| {
+            let mut leaf_errors = LeafRange::new((0, 0));
+            let mut third_errors = LeafRange::new((0, 0));
+            let mut leaf_weights = LeafRange::new(0.0);
+            let mut third_weights = LeafRange::new(0.0);
+            // the row after the chunk is only read as the next key of its last row
+            let mut rows = md_container.iter_range(start, end + 1).peekable();
+            for _ in start..end {
+                let (key, y) = rows.next().unwrap();
+                let x = key.to_model_input();
+                let leaf_idx = top_model.predict_to_int(&x);
+                let target = u64::min(num_leaf_models - 1, leaf_idx) as usize;
+                let weight = query_weights.as_ref().map(|weights| weights.weight(&x)).unwrap_or(0.0);
+
+                if partial_3rd_idx[target] == (0,0) {
+                    *leaf_weights.get_mut(target) += weight;
+                    let pred = sec_models[target].predict_to_int(&x);
+                    let err = error_between(pred, y as u64, md_container.len() as u64);
+                    // using 32bit should be enough to express error value
+                    // if not cannot build partial 3 layer, increase model number to lower error 
+                    let cur_val = leaf_errors.get_mut(target);
+                    *cur_val = (cur_val.0 + 1, track_signed_error(cur_val.1, err, pred > y as u64));
+                    assert!(cur_val.1>>63 == 0); // flag for partial model should be 0
                 }
-                if max_err > err && max_flag==1 {
-                    max_err = err;
-                    max_flag = 1;
+                else{
+                    let target_third = third_of(target, &x);
+                    let pred = partial_3rd_models[target_third as usize].predict_to_int(&x);
+
+                    // put number of cumulative partial models in 32 most significant bits, number of models in 32 least significant bits
+                    // first bit is set as 1 if partial model is used
+                    assert!(partial_3rd_idx[target].0 as u64 <= 0x000000007fffffffu64);
+                    assert!(partial_3rd_idx[target].1 as u64<= 0x00000000ffffffffu64);
+                    let cur_val = leaf_errors.get_mut(target);
+                    *cur_val = (cur_val.0 + 1, ((partial_3rd_idx[target].0 as u64 | 0x0000000080000000u64)<<32)|partial_3rd_idx[target].1 as u64);
+
+                    let err = error_between(pred, y as u64, md_container.len() as u64);
+                    let cur_val = third_errors.get_mut(target_third as usize);
+                    *cur_val = (cur_val.0 + 1, track_signed_error(cur_val.1, err, pred > y as u64));
+                    *third_weights.get_mut(target_third as usize) += weight;
                 }
-            }else{ // pred is smaller than y , plus err
-                if min_err > err && min_flag ==0 {
-                    min_err = err;
-                    min_flag = 0;
+
+                // a key just above the last of a run belongs at the row of
+                // the next key, charge it to the model of the leaf that serves
+                // it (a key that leaves the leaf is a probe of the next leaf)
+                let next = rows.peek().copied();
+                if next.map_or(false, |(next_key, _)| next_key == key) {
+                    continue;
                 }
-                if max_err < err || max_flag ==1 {
-                    max_err = err;
-                    max_flag = 0;
+                let above = match absent_above(key, next.map(|(next_key, _)| next_key)) {
+                    Some(above) => above.to_model_input(),
+                    None => continue,
+                };
+                if u64::min(num_leaf_models - 1, top_model.predict_to_int(&above)) as usize != target {
+                    continue;
                 }
+                let next_y = next.map_or(md_container.len(), |(_, row)| row) as u64;
+                let (model, cur_val) = if partial_3rd_idx[target] == (0,0) {
+                    (sec_models[target].as_ref(), leaf_errors.get_mut(target))
+                } else {
+                    let target_third = third_of(target, &above) as usize;
+                    (partial_3rd_models[target_third].as_ref(), third_errors.get_mut(target_third))
+                };
+                let pred = model.predict_to_int(&above);
+                let err = error_between(pred, next_y, md_container.len() as u64);
+                cur_val.1 = track_signed_error(cur_val.1, err, pred > next_y);
             }
-            third_layer_max_l1s[target_third as usize ] = (cur_val.0 + 1, min_flag<<62| min_err<<32 | max_flag<<31 | max_err );
-        }
+            (leaf_errors, third_errors, leaf_weights, third_weights)
+        })
+        .collect();
 
-        // if ( (y as u64) > max_min_gap[target as usize][0]){
-        //     max_min_gap[target as usize][0] = y as u64;
-        // }
-        // if ( (y as u64) < max_min_gap[target as usize][1]){
-        //     max_min_gap[target as usize][1] = y as u64;
-        // }
-        
-    }    
+    let mut last_layer_max_l1s = vec![(0, 0) ; num_leaf_models as usize];
+    let mut third_layer_max_l1s = vec![(0,0) ; third_layer_num as usize];
+    let mut leaf_weights = vec![0.0 ; num_leaf_models as usize];
+    let mut third_weights = vec![0.0 ; third_layer_num as usize];
+    let take_touched = |all: &mut (u64, u64), v: (u64, u64)| {
+        if v != (0, 0) {
+            assert_eq!(*all, (0, 0), "a leaf was split across chunks");
+            *all = v;
+        }
+    };
+    for (leaf_errors, third_errors, leaf_w, third_w) in chunk_errors {
+        leaf_errors.merge_into(&mut last_layer_max_l1s, take_touched);
+        third_errors.merge_into(&mut third_layer_max_l1s, take_touched);
+        leaf_w.merge_into(&mut leaf_weights, |all, w| *all += w);
+        third_w.merge_into(&mut third_weights, |all, w| *all += w);
+    }
 
     let mut sum:f64 = 0.0; 
     let mut avg:f64 = 0.0; 
@@ -1590,289 +2366,39 @@ pub fn train_partial_three_layer<T: TrainingKey>(md_container: &mut RMITrainingD
     //       includes the first key after the previous leaf (lower error)
     //       (normally, the first key after the previous leaf is the first
     //        key in this leaf, but not in the case where this leaf has no keys)
-    let mut large_corrections = 0;
+    let large_corrections = 0;
     let mut partial_third_num = 0;
     let report_error_threshold = 100000;
+    let report_probes = |leaf_idx: usize, third_idx: Option<usize>, probes: &[(u64, bool); 2]| {
+        if probes[0].0 > report_error_threshold || probes[1].0 > report_error_threshold {
+            println!("[large correction] leaf:{} partial model:{:?} upper_error:{} lower_error:{}",
+                     leaf_idx, third_idx, probes[0].0, probes[1].0);
+        }
+    };
     for leaf_idx in 0..num_leaf_models as usize {
-        if (partial_3rd_idx[leaf_idx] != (0,0)){
-            for third_idx in 0..partial_3rd_idx[leaf_idx].1{
-                let curr_err = third_layer_max_l1s[third_idx+ partial_3rd_idx[leaf_idx].0].1;
-
-                let mut min_err = (curr_err >> 32)& 0x000000003fffffffu64 ;//left most bit is used for partial model representation
-                let mut min_flag = ((curr_err  >> 32)& 0x0000000040000000u64)>>30 ;
-                let mut max_err =curr_err& 0x000000007fffffffu64 ;
-                let mut max_flag = (curr_err& 0x0000000080000000u64)>>31 ;
-
-
-                let mut upper_flag = false;
-                let mut lower_flag = false;
-                let upper_error = {
-                    // if third_idx == partial_3rd_idx[leaf_idx].1-1 {
-                    if third_idx >= partial_3rd_lb_corrs[partial_third_num].last_non_empty_model() as usize {
-                        let (idx_of_next, key_of_next) = lb_corrections.next(leaf_idx);
-                        let pred = partial_3rd_models[third_idx+partial_3rd_idx[leaf_idx].0].predict_to_int(
-                            &key_of_next.minus_epsilon().to_model_input()
-                        );
-                        
-                        upper_flag= pred>idx_of_next as u64;
-                        error_between(pred, idx_of_next as u64 + 1, md_container.len() as u64)
-                    }else{
-                        let (idx_of_next, key_of_next) = partial_3rd_lb_corrs[partial_third_num].next(third_idx);
-                        let pred = partial_3rd_models[third_idx+partial_3rd_idx[leaf_idx].0].predict_to_int(
-                            &key_of_next.minus_epsilon().to_model_input()
-                        );
-                        
-                        upper_flag= pred>idx_of_next as u64;
-                        error_between(pred, idx_of_next as u64 + 1, md_container.len() as u64)
-                    }
-                };
-                let lower_error = {
-                    let mut first_key_before ;
-                    let mut prev_idx ;
-                    let mut first_idx ;
-                    // if empty models 
-                    if third_idx <= partial_3rd_lb_corrs[partial_third_num].first_non_empty_model() as usize {
-                        first_key_before = lb_corrections.prev_key(leaf_idx);
-                        // prev_idx = if third_idx == 0 { 0 } else { third_idx - 1 };
-                        // first_idx = partial_3rd_lb_corrs[partial_third_num].next_index(prev_idx);
-                        prev_idx = if leaf_idx == 0 { 0 } else {  leaf_idx - 1  };
-                        first_idx = lb_corrections.next_index(prev_idx);
-                        if leaf_idx == 0 {
-                            prev_idx = if third_idx == 0 { 0 } else { third_idx - 1 };
-                            first_idx = partial_3rd_lb_corrs[partial_third_num].next_index(prev_idx);
-                        }
-
-                    }else{
-                        first_key_before = partial_3rd_lb_corrs[partial_third_num].prev_key(third_idx);
-                        prev_idx = if third_idx == 0 { 0 } else { third_idx - 1 };
-                        first_idx = partial_3rd_lb_corrs[partial_third_num].next_index(prev_idx);
-                        if partial_3rd_lb_corrs[partial_third_num].first_non_empty_model() == 0 && partial_3rd_lb_corrs[partial_third_num].first_non_empty_model() == partial_3rd_lb_corrs[partial_third_num].last_non_empty_model() {
-                            prev_idx = if leaf_idx == 0 { 0 } else {  leaf_idx - 1  };
-                            first_idx = lb_corrections.next_index(prev_idx);    
-                        }
-                    }
-                    
-                    let pred = partial_3rd_models[third_idx+partial_3rd_idx[leaf_idx].0].predict_to_int(
-                        &first_key_before.plus_epsilon().to_model_input()
-                    );
-                    
-                    lower_flag = pred > first_idx as u64;
-                    error_between(pred, first_idx as u64, md_container.len() as u64)
-                };
-
-                if upper_error > report_error_threshold {
-                    if third_idx >= partial_3rd_lb_corrs[partial_third_num].last_non_empty_model() as usize {
-                        let (idx_of_next, key_of_next) = lb_corrections.next(leaf_idx);
-                        let pred = partial_3rd_models[third_idx+partial_3rd_idx[leaf_idx].0].predict_to_int(
-                            &key_of_next.minus_epsilon().to_model_input()
-                        );
-                        println!("[upper-last]pred: {} idxnext:{} keyofnext:{} upper_error:{}", pred, idx_of_next, &key_of_next.as_uint(), upper_error);
-                        let (idx_of_next_, key_of_next_) = partial_3rd_lb_corrs[partial_third_num].next(third_idx);
-                        println!("[third models upper-last]pred: {} idxnext:{} keyofnext:{}",pred,idx_of_next_, &key_of_next_.as_uint());
-                    }
-                    else{
-                        let (idx_of_next, key_of_next) = partial_3rd_lb_corrs[partial_third_num].next(third_idx);
-                        let pred = partial_3rd_models[third_idx+partial_3rd_idx[leaf_idx].0].predict_to_int(
-                        &key_of_next.minus_epsilon().to_model_input()
-                        );
-                        println!("[upper]pred: {} idxnext:{} keyofnext:{} upper_error:{}", pred, idx_of_next, &key_of_next.as_uint(), upper_error);
-                    }
-                    
-                }
-                if lower_error > report_error_threshold {
-                    let mut first_key_before ;
-                    let mut prev_idx ;
-                    let mut first_idx ;
-
-                    if third_idx <= partial_3rd_lb_corrs[partial_third_num].first_non_empty_model() as usize {
-                        first_key_before = lb_corrections.prev_key(leaf_idx);
-                        // prev_idx = if third_idx == 0 { 0 } else { third_idx - 1 };
-                        // first_idx = partial_3rd_lb_corrs[partial_third_num].next_index(prev_idx);
-                        prev_idx = if leaf_idx == 0 { 0 } else {  leaf_idx - 1  };
-                        first_idx = lb_corrections.next_index(prev_idx);
-                        if leaf_idx == 0 {
-                            prev_idx = if third_idx == 0 { 0 } else { third_idx - 1 };
-                            first_idx = partial_3rd_lb_corrs[partial_third_num].next_index(prev_idx);
-                        }
-                        let pred = partial_3rd_models[third_idx+partial_3rd_idx[leaf_idx].0].predict_to_int(
-                            &first_key_before.plus_epsilon().to_model_input()
-                        );
-                        println!("[lower-last]pred: {} first_idx:{} first_key_before:{} prev_idx:{} lower_error:{}", pred, first_idx, &first_key_before.as_uint(), prev_idx, lower_error);
-                    }else{
-                        first_key_before = partial_3rd_lb_corrs[partial_third_num].prev_key(third_idx);
-                        prev_idx = if third_idx == 0 { 0 } else { third_idx - 1 };
-                        // first_idx is index of first key which goes into next model
-                        first_idx = partial_3rd_lb_corrs[partial_third_num].next_index(prev_idx);
-                        if partial_3rd_lb_corrs[partial_third_num].first_non_empty_model() == 0 && partial_3rd_lb_corrs[partial_third_num].first_non_empty_model() == partial_3rd_lb_corrs[partial_third_num].last_non_empty_model() {
-                            prev_idx = if leaf_idx == 0 { 0 } else {  leaf_idx - 1  };
-                            first_idx = lb_corrections.next_index(prev_idx);    
-                        }
-                        let pred = partial_3rd_models[third_idx+partial_3rd_idx[leaf_idx].0].predict_to_int(
-                            &first_key_before.plus_epsilon().to_model_input()
-                        );
-                        println!("[lower]pred: {} first_idx:{} first_key_before:{} prev_key:{} prev_idx:{} lower_error:{}", pred, first_idx, &first_key_before.as_uint(),partial_3rd_lb_corrs[partial_third_num].prev(third_idx), prev_idx, lower_error);
-                    }
-
-                }
-                
-
-                // let new_err = *(&[curr_err, upper_error, lower_error]).iter().max().unwrap()
-                //     + partial_3rd_lb_corrs[partial_third_num].longest_run(third_idx);
-                
-                if upper_flag { // err is minus err
-                    if min_err < upper_error || min_flag==0 {
-                        min_err = upper_error;
-                        min_flag = 1;
-                    }
-                    if max_err > upper_error && max_flag==1 {
-                        max_err = upper_error;
-                        max_flag = 1;
-                    }
-                }else{ // pred is smaller than y , plus err
-                    if min_err > upper_error && min_flag ==0 {
-                        min_err = upper_error;
-                        min_flag = 0;
-                    }
-                    if max_err < upper_error || max_flag ==1 {
-                        max_err = upper_error;
-                        max_flag = 0;
-                    }
-                }
-
-                if lower_flag { // err is minus err
-                    if min_err < lower_error || min_flag==0 {
-                        min_err = lower_error;
-                        min_flag = 1;
-                    }
-                    if max_err > lower_error && max_flag==1 {
-                        max_err = lower_error;
-                        max_flag = 1;
-                    }
-                }else{ // pred is smaller than y , plus err
-                    if min_err > lower_error && min_flag ==0 {
-                        min_err = lower_error;
-                        min_flag = 0;
-                    }
-                    if max_err < lower_error || max_flag ==1 {
-                        max_err = lower_error;
-                        max_flag = 0;
-                    }
-                }
-                let num_items_in_leaf = third_layer_max_l1s[third_idx+ partial_3rd_idx[leaf_idx].0].0;
-
-                // let new_err = *(&[curr_err, upper_error, lower_error]).iter().max().unwrap();
-                // if new_err > report_error_threshold {
-                //     if third_idx>1 {
-                //         println!("[FYI] Front model elem num:{}", third_layer_max_l1s[third_idx-1+ partial_3rd_idx[leaf_idx].0].0);
-                //     }
-                    
-                //     println!("leaf[{}/{}] partial_model:{}/{} elem num:{}/{} new_err:{} curr err:{} first non empty:{} last non empty:{} partial_third_num:{}\n",
-                //     leaf_idx,num_leaf_models, third_idx, partial_3rd_idx[leaf_idx].1-1, third_layer_max_l1s[third_idx+ partial_3rd_idx[leaf_idx].0].0,last_layer_max_l1s[leaf_idx].0,  new_err, curr_err, partial_3rd_lb_corrs[partial_third_num].first_non_empty_model(),partial_3rd_lb_corrs[partial_third_num].last_non_empty_model() ,partial_third_num);
-                // }
-                // if new_err - curr_err > 512 && num_items_in_leaf > 100 {
-                //     large_corrections += 1;
-                // }
-                // assert!(curr_err<=0x7fffffff);
-                // assert!(new_err<=0xffffffff);
-                // third_layer_max_l1s[third_idx+ partial_3rd_idx[leaf_idx].0] = (num_items_in_leaf, new_err | curr_err<<32 );
-
-                third_layer_max_l1s[third_idx+ partial_3rd_idx[leaf_idx].0] = (num_items_in_leaf, min_flag<<62| min_err<<32 | max_flag<<31 | max_err );
-                // third_layer_max_l1s[third_idx+ partial_3rd_idx[leaf_idx].0] = (num_items_in_leaf, upper_error | lower_error<<32 );
-                
-                
+        let edges = LeafEdges::of(&lb_corrections, leaf_idx);
+        if partial_3rd_idx[leaf_idx] != (0,0) {
+            let partial_lb = &partial_3rd_lb_corrs[partial_third_num];
+            for third_idx in 0..partial_3rd_idx[leaf_idx].1 {
+                let model_idx = third_idx + partial_3rd_idx[leaf_idx].0;
+                let (next, prev) = edges.of_partial(partial_lb, third_idx);
+                let probes = probe_errors(partial_3rd_models[model_idx].as_ref(), next, prev, md_container.len());
+                report_probes(leaf_idx, Some(third_idx), &probes);
+
+                let (num_items, err) = third_layer_max_l1s[model_idx];
+                let err = probes.iter().fold(err, |err, &(e, negative)| track_signed_error(err, e, negative));
+                third_layer_max_l1s[model_idx] = (num_items, err);
             }
             partial_third_num += 1;
-            
             continue;
         }
 
-        let curr_err = last_layer_max_l1s[leaf_idx].1;
-        // println!("model idx:{} Err:{}",leaf_idx, curr_err);
-        let mut min_err = (curr_err >> 32)& 0x000000003fffffffu64 ;//left most bit is used for partial model representation
-        let mut min_flag = ((curr_err  >> 32)& 0x0000000040000000u64)>>30 ;
-        let mut max_err =curr_err& 0x000000007fffffffu64 ;
-        let mut max_flag = (curr_err& 0x0000000080000000u64)>>31 ;
-
-        let mut upper_flag = false;
-        let mut lower_flag = false;
-
-        let upper_error = {
-            let (idx_of_next, key_of_next) = lb_corrections.next(leaf_idx);
-            let pred = sec_models[leaf_idx].predict_to_int(
-                &key_of_next.minus_epsilon().to_model_input()
-            );
-            upper_flag = pred > idx_of_next as u64;
-            error_between(pred, idx_of_next as u64 + 1, md_container.len() as u64)
-        };
-        
-        let lower_error = {
-            let first_key_before = lb_corrections.prev_key(leaf_idx);
-
-            let prev_idx = if leaf_idx == 0 { 0 } else { leaf_idx - 1 };
-            let first_idx = lb_corrections.next_index(prev_idx);
-
-            let pred = sec_models[leaf_idx].predict_to_int(
-                &first_key_before.plus_epsilon().to_model_input()
-            );
-            lower_flag = pred > first_idx as u64;
-            error_between(pred, first_idx as u64, md_container.len() as u64)
-        };
-          
-        
-        // let new_err = *(&[curr_err, upper_error, lower_error]).iter().max().unwrap()
-        //     + lb_corrections.longest_run(leaf_idx);
-        if upper_flag { // err is minus err
-            if min_err < upper_error || min_flag==0 {
-                min_err = upper_error;
-                min_flag = 1;
-            }
-            if max_err > upper_error && max_flag==1 {
-                max_err = upper_error;
-                max_flag = 1;
-            }
-        }else{ // pred is smaller than y , plus err
-            if min_err > upper_error && min_flag ==0 {
-                min_err = upper_error;
-                min_flag = 0;
-            }
-            if max_err < upper_error || max_flag ==1 {
-                max_err = upper_error;
-                max_flag = 0;
-            }
-        }
-
-        if lower_flag { // err is minus err
-            if min_err < lower_error || min_flag==0 {
-                min_err = lower_error;
-                min_flag = 1;
-            }
-            if max_err > lower_error && max_flag==1 {
-                max_err = lower_error;
-                max_flag = 1;
-            }
-        }else{ // pred is smaller than y , plus err
-            if min_err > lower_error && min_flag ==0 {
-                min_err = lower_error;
-                min_flag = 0;
-            }
-            if max_err < lower_error || max_flag ==1 {
-                max_err = lower_error;
-                max_flag = 0;
-            }
-        }
-        let num_items_in_leaf = last_layer_max_l1s[leaf_idx].0;
-        
-        // let new_err = *(&[curr_err, upper_error, lower_error]).iter().max().unwrap();
-        // assert!(curr_err<=0x7fffffff);
-        // assert!(new_err<=0xffffffff);
-        // last_layer_max_l1s[leaf_idx] = (num_items_in_leaf, new_err | curr_err<<32);
-        // if new_err - curr_err > 512 && num_items_in_leaf > 100 {
-        //     large_corrections += 1;
-        // }
-
-        last_layer_max_l1s[leaf_idx] = (num_items_in_leaf,  min_flag<<62| min_err<<32 | max_flag<<31 | max_err);
-
-        
+        let probes = probe_errors(sec_models[leaf_idx].as_ref(), edges.next,
+                                  (edges.prev_key, edges.first_idx), md_container.len());
+        report_probes(leaf_idx, None, &probes);
+        let (num_items, err) = last_layer_max_l1s[leaf_idx];
+        let err = probes.iter().fold(err, |err, &(e, negative)| track_signed_error(err, e, negative));
+        last_layer_max_l1s[leaf_idx] = (num_items, err);
     }
 
 
@@ -1886,6 +2412,34 @@ pub fn train_partial_three_layer<T: TrainingKey>(md_container: &mut RMITrainingD
     // print total leaf-models, partial models, 
     // println!("Total Partial model num: {}, Leaf of partial model num: {}",third_layer_num, partial_3rd_lb_corrs.len());
     println!("[INFO] Number of leaf and partial models: {}, leaf: {}, partial: {}, leaf models that have partial models:{}",(num_leaf_models as usize +third_layer_num as usize  ), num_leaf_models, third_layer_num, partial_3rd_lb_corrs.len() );
+
+    if let Some(max_error) = md_container.max_error() {
+        // the error of a leaf with partial models is the largest of theirs
+        let mut over = 0;
+        for leaf_idx in 0..num_leaf_models as usize {
+            let (first, count) = partial_3rd_idx[leaf_idx];
+            let error = if count > 0 {
+                third_layer_max_l1s[first..first + count].iter()
+                    .map(|(_n, err)| signed_window(*err))
+                    .max()
+                    .unwrap()
+            } else {
+                signed_window(last_layer_max_l1s[leaf_idx].1)
+            };
+            if error <= max_error {
+                continue;
+            }
+            over += 1;
+            let key_range = match (lb_corrections.first_key(leaf_idx), lb_corrections.last_key(leaf_idx)) {
+                (Some(first_key), Some(last_key)) =>
+                    format!("keys {} to {}", first_key.to_key_string(), last_key.to_key_string()),
+                _ => String::from("no keys")
+            };
+            println!("Leaf {} ({}, {} third layer models) has an error of {}, above the maximum of {}",
+                     leaf_idx, key_range, count, error, max_error);
+        }
+        println!("{} leaves exceed the maximum error of {}", over, max_error);
+    }
     
     // let mut new_last_layer_max_l1s = vec![(0, 0) ; (num_leaf_models as usize +third_layer_num as usize -partial_3rd_lb_corrs.len() as usize ) as usize];
 
@@ -1954,8 +2508,18 @@ pub fn train_partial_three_layer<T: TrainingKey>(md_container: &mut RMITrainingD
 
     // let model_avg_log2_error: f64 = new_last_layer_max_l1s
     //     .iter().map(|(n, err)| (*n as f64)*((2*err + 2) as f64).log2()).sum::<f64>() / num_rows as f64;
-    let model_avg_log2_error: f64 = last_layer_max_l1s
-        .iter().map(|(n, err)| (*n as f64)*((( (err& 0x7fffffff) + ((*err >> 32) & 0x3fffffff)) + 2) as f64).log2()).sum::<f64>() / num_rows as f64;
+    // over the models that serve lookups: leaves without partial models (the
+    // others are marked by the top bit) and the partial models
+    let served = || last_layer_max_l1s.iter().zip(leaf_weights.iter())
+        .filter(|((_n, err), _w)| err & 0x8000000000000000u64 == 0)
+        .chain(third_layer_max_l1s.iter().zip(third_weights.iter()));
+    let model_avg_log2_error: f64 = match query_weights {
+        Some(_) => served()
+            .map(|((_n, err), w)| w * ((signed_window(*err) + 2) as f64).log2()).sum::<f64>()
+            / served().map(|(_e, w)| w).sum::<f64>(),
+        None => served()
+            .map(|((n, err), _w)| (*n as f64) * ((signed_window(*err) + 2) as f64).log2()).sum::<f64>() / num_rows as f64
+    };
 
     let model_max_log2_error: f64 = (model_max_error as f64).log2();
 
@@ -1983,12 +2547,21 @@ pub fn train_partial_three_layer<T: TrainingKey>(md_container: &mut RMITrainingD
         model_max_error,
         model_max_error_idx,
         model_max_log2_error,
+        model_avg_window: 2.0 * model_avg_error + 1.0,
         last_layer_max_l1s: final_errors,
         third_layer_max_l1s: final_third_errors,
+        left_errors: vec![],
+        right_errors: vec![],
         rmi: rmi,
+        layout: Layout::PartialThreeLayer,
         models: format!("{},{},{}", layer1_model, layer3_model, layer2_model),
         branching_factor: num_leaf_models,
         cache_fix: None,
+        duplicates: md_container.duplicate_semantics(),
+        sample_rate: md_container.sample_rate(),
+        peak_memory: md_container.memory_budget().peak(),
+        error_distribution: None,
+        run_lengths: vec![],
         build_time: 0
     };
 
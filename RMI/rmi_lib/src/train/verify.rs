@@ -0,0 +1,242 @@
+// < begin copyright >
+// Copyright Ryan Marcus 2020
+//
+// See root directory of this project for license terms.
+//
+// < end copyright >
+
+
+use crate::models::*;
+use crate::train::TrainedRMI;
+use crate::train::parallel::default_num_chunks;
+use rayon::prelude::*;
+
+// only the first violations are kept, the rest are only counted
+const MAX_REPORTED: usize = 1000;
+
+// leaves of a partial three-layer RMI with third-layer models under them
+const PARTIAL_FLAG: u64 = 0x8000000000000000u64;
+
+// A key whose position is outside of the window of the model that a lookup
+// of the key ends in.
+#[derive(Clone, Debug)]
+pub struct Violation {
+    // the last-layer model of the lookup and, in partial three-layer RMIs,
+    // the third-layer model under it
+    pub leaf: usize,
+    pub partial: Option<usize>,
+    pub key: ModelInput,
+    // true for generated keys that are not in the data
+    pub absent: bool,
+    pub predicted: u64,
+    pub position: u64,
+    // the positions [lower, upper] the lookup searches
+    pub lower: u64,
+    pub upper: u64
+}
+
+pub struct Verification {
+    pub keys: usize,
+    pub probes: usize,
+    pub num_violations: usize,
+    // the first violations, in key order
+    pub violations: Vec<Violation>
+}
+
+impl Verification {
+    pub fn passed(&self) -> bool {
+        return self.num_violations == 0;
+    }
+}
+
+// How a lookup walks the layers of a trained RMI, which depends on the
+// function that trained it. Every builder records it in TrainedRMI::layout.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Layout {
+    Single,
+    TwoLayer,
+    // train_multi_layer with more than two layers, which is not verified
+    MultiLayer,
+    // train_three_layer: every second-layer model owns a block of leaves
+    ThreeLayer { num_second: u64, per_second: u64 },
+    // train_naive_three_layer: the second layer indexes all of the leaves
+    NaiveThreeLayer { num_second: u64 },
+    // train_partial_three_layer: the layers are stored as top, third-layer
+    // models, leaves, and the errors are signed windows
+    PartialThreeLayer
+}
+
+// The window a lookup of one key searches.
+pub(super) struct Lookup {
+    pub(super) leaf: usize,
+    pub(super) partial: Option<usize>,
+    pub(super) predicted: u64,
+    pub(super) lower: u64,
+    pub(super) upper: u64
+}
+
+// Unpacks the (min, max) signed error of a partial three-layer model (see
+// track_signed_error) into the positions [pred + min, pred + max].
+fn signed_window(pred: u64, err: u64) -> (u64, u64) {
+    let signed = |magnitude: u64, negative: u64| {
+        if negative == 1 { -(magnitude as i64) } else { magnitude as i64 }
+    };
+    let min = signed((err >> 32) & 0x000000003fffffffu64, (err >> 62) & 1);
+    let max = signed(err & 0x000000007fffffffu64, (err >> 31) & 1);
+    return (i64::max(0, pred as i64 + min) as u64, (pred as i64 + max) as u64);
+}
+
+pub(super) fn lookup(rmi: &TrainedRMI, x: &ModelInput, num_rows: u64) -> Lookup {
+    let top = &rmi.rmi[0][0];
+    let window = |leaf: usize, pred: u64| {
+        let pred = u64::min(pred, num_rows);
+        // equal range and signed windows have a left and a right error
+        let (left, right) = if rmi.left_errors.is_empty() {
+            (rmi.last_layer_max_l1s[leaf], rmi.last_layer_max_l1s[leaf])
+        } else {
+            (rmi.left_errors[leaf], rmi.right_errors[leaf])
+        };
+        return Lookup { leaf, partial: None, predicted: pred,
+                        lower: pred.saturating_sub(left), upper: pred + right };
+    };
+
+    return match rmi.layout {
+        Layout::MultiLayer => panic!("Cannot look up keys in an RMI with {} layers", rmi.rmi.len()),
+        Layout::Single => window(0, top.predict_to_int(x)),
+        Layout::TwoLayer => {
+            let leaf = u64::min(rmi.branching_factor - 1, top.predict_to_int(x)) as usize;
+            window(leaf, rmi.rmi[1][leaf].predict_to_int(x))
+        },
+        Layout::ThreeLayer { num_second, per_second } => {
+            let second = u64::min(num_second - 1, top.predict_to_int(x));
+            let leaf = rmi.rmi[1][second as usize].predict_to_int(x);
+            let leaf = u64::min((second + 1) * per_second - 1, leaf);
+            let leaf = u64::max(second * per_second, leaf) as usize;
+            window(leaf, rmi.rmi[2][leaf].predict_to_int(x))
+        },
+        Layout::NaiveThreeLayer { num_second } => {
+            let second = u64::min(num_second - 1, top.predict_to_int(x));
+            let leaf = rmi.rmi[1][second as usize].predict_to_int(x);
+            let leaf = u64::min(rmi.branching_factor - 1, leaf) as usize;
+            window(leaf, rmi.rmi[2][leaf].predict_to_int(x))
+        },
+        Layout::PartialThreeLayer => {
+            let leaf = u64::min(rmi.branching_factor - 1, top.predict_to_int(x)) as usize;
+            let leaf_pred = rmi.rmi[2][leaf].predict_to_int(x);
+            let err = rmi.last_layer_max_l1s[leaf];
+            if err & PARTIAL_FLAG == 0 {
+                let pred = u64::min(leaf_pred, num_rows);
+                let (lower, upper) = signed_window(pred, err);
+                Lookup { leaf, partial: None, predicted: pred, lower, upper }
+            } else {
+                // the first third-layer model and the number of them
+                let first = (err >> 32) & 0x000000007fffffffu64;
+                let count = err & 0x00000000ffffffffu64;
+                let third = u64::max(first, u64::min(first + count - 1, leaf_pred + first)) as usize;
+                let pred = u64::min(rmi.rmi[1][third].predict_to_int(x), num_rows);
+                let (lower, upper) = signed_window(pred, rmi.third_layer_max_l1s[third]);
+                Lookup { leaf, partial: Some(third), predicted: pred, lower, upper }
+            }
+        }
+    };
+}
+
+// Replays every key of `data` through the trained RMI and checks that the
+// position of the key is within the window of the model the lookup ends in.
+// The gaps around every key are probed too: a key just below it must find
+// its first position, and a key just above it the first position after it,
+// which covers absent keys in and between leaves. With equal range semantics
+// the window must also hold one past the last position of every key. `data`
+// must hold the keys the RMI was trained on.
+pub fn verify<T: TrainingKey>(data: &RMITrainingData<T>, rmi: &TrainedRMI) -> Verification {
+    assert!(rmi.cache_fix.is_none(), "RMIs trained with a cache fix cannot be verified");
+    assert!(rmi.duplicates == data.duplicate_semantics(),
+            "The RMI was trained with {} semantics, but the keys use {}",
+            rmi.duplicates.name(), data.duplicate_semantics().name());
+
+    // positions of deduplicated RMIs are ranks among the distinct keys
+    let unique;
+    let data = if rmi.duplicates == DuplicateSemantics::Dedup {
+        unique = data.dedup_with_runs().0;
+        &unique
+    } else {
+        data
+    };
+    assert_eq!(rmi.num_rmi_rows, data.len(),
+               "The RMI was trained on {} keys, but there are {}", rmi.num_rmi_rows, data.len());
+
+    let num_rows = data.len();
+    let upper_bound = data.duplicate_semantics() == DuplicateSemantics::UpperBound;
+    let equal_range = data.duplicate_semantics() == DuplicateSemantics::EqualRange;
+    let key_less = |a: &T, b: &T| a.to_model_input() < b.to_model_input();
+
+    let num_chunks = default_num_chunks(num_rows);
+    let chunk_size = (num_rows + num_chunks - 1) / usize::max(1, num_chunks);
+    let chunks: Vec<(usize, usize, usize, Vec<Violation>)> = (0..num_chunks).into_par_iter()
+        .map(|chunk| {
+            let (mut keys, mut probes, mut count) = (0, 0, 0);
+            let mut violations = Vec::new();
+            let mut check = |key: &T, position: usize, absent: bool| {
+                let x = key.to_model_input();
+                let found = lookup(rmi, &x, num_rows as u64);
+                let position = position as u64;
+                if position < found.lower || position > found.upper {
+                    count += 1;
+                    if violations.len() < MAX_REPORTED {
+                        violations.push(Violation { leaf: found.leaf, partial: found.partial, key: x,
+                                                    absent, predicted: found.predicted, position,
+                                                    lower: found.lower, upper: found.upper });
+                    }
+                }
+            };
+
+            // every run of duplicates is checked by the chunk of its first row
+            for idx in (chunk * chunk_size)..usize::min(num_rows, (chunk + 1) * chunk_size) {
+                let (key, first_pos) = data.get(idx);
+                if idx > 0 && data.get_key(idx - 1) == key {
+                    continue;
+                }
+                let mut end = idx + 1;
+                while end < num_rows && data.get_key(end) == key {
+                    end += 1;
+                }
+
+                let next = if end < num_rows { Some(data.get(end)) } else { None };
+                let after = next.map_or(num_rows, |(_, pos)| pos);
+
+                let position = if upper_bound { data.get(end - 1).1 } else { first_pos };
+                check(&key, position, false);
+                if equal_range {
+                    check(&key, after, false);
+                }
+                keys += 1;
+
+                if key != T::zero_value() {
+                    let below = key.minus_epsilon();
+                    if key_less(&below, &key) && (idx == 0 || key_less(&data.get_key(idx - 1), &below)) {
+                        check(&below, first_pos, true);
+                        probes += 1;
+                    }
+                }
+                if key != T::max_value() {
+                    let above = key.plus_epsilon();
+                    if key_less(&key, &above) && next.map_or(true, |(k, _)| key_less(&above, &k)) {
+                        check(&above, after, true);
+                        probes += 1;
+                    }
+                }
+            }
+            (keys, probes, count, violations)
+        })
+        .collect();
+
+    let mut res = Verification { keys: 0, probes: 0, num_violations: 0, violations: Vec::new() };
+    for (keys, probes, count, violations) in chunks {
+        res.keys += keys;
+        res.probes += probes;
+        res.num_violations += count;
+        res.violations.extend(violations);
+    }
+    res.violations.truncate(MAX_REPORTED);
+    return res;
+}
@@ -0,0 +1,304 @@
+// < begin copyright > 
+// Copyright Ryan Marcus 2020
+// 
+// See root directory of this project for license terms.
+// 
+// < end copyright > 
+
+use crate::models::*;
+use json::*;
+use log::*;
+use std::cmp::Ordering;
+
+// What to do with input that is not sorted.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InvalidDataPolicy {
+    // refuse to train on unsorted data
+    Abort,
+    // sort the keys (stable), keeping duplicates
+    Sort,
+    // sort the keys and drop duplicates
+    Dedup
+}
+
+impl InvalidDataPolicy {
+    pub fn from_str(s: &str) -> Option<InvalidDataPolicy> {
+        return match s {
+            "abort" => Some(InvalidDataPolicy::Abort),
+            "sort" => Some(InvalidDataPolicy::Sort),
+            "dedup" => Some(InvalidDataPolicy::Dedup),
+            _ => None
+        };
+    }
+}
+
+// Summary of a data set, computed in a single pass after loading.
+// Sortedness fields always describe the input as loaded; the remaining
+// fields describe the data that is actually trained on.
+pub struct DataReport {
+    pub num_keys: usize,
+    pub inversions: usize,
+    pub first_inversion: Option<usize>,
+    pub duplicates: usize,
+    pub longest_run: u64,
+    pub min_key: String,
+    pub max_key: String,
+    pub common_prefix_bits: u32,
+    // key (as a float) at each decile of the data
+    pub cdf: Vec<f64>,
+    pub sorted_keys: bool,
+    pub removed_duplicates: usize
+}
+
+impl DataReport {
+    pub fn is_sorted(&self) -> bool {
+        return self.inversions == 0;
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        return object! {
+            "num_keys" => self.num_keys,
+            "sorted" => self.is_sorted(),
+            "inversions" => self.inversions,
+            "first_inversion" => self.first_inversion,
+            "duplicates" => self.duplicates,
+            "longest_run" => self.longest_run,
+            "min_key" => self.min_key.clone(),
+            "max_key" => self.max_key.clone(),
+            "common_prefix_bits" => self.common_prefix_bits,
+            "cdf_deciles" => self.cdf.clone(),
+            "sorted_keys" => self.sorted_keys,
+            "removed_duplicates" => self.removed_duplicates
+        };
+    }
+
+    pub fn display(&self) {
+        info!("Keys: {} ({} duplicates, longest run {})",
+              self.num_keys, self.duplicates, self.longest_run);
+        if !self.is_sorted() {
+            warn!("Input was not sorted: {} inversions, first at index {}",
+                  self.inversions, self.first_inversion.unwrap());
+        }
+        if self.sorted_keys {
+            info!("Keys were sorted, {} duplicates removed", self.removed_duplicates);
+        }
+        info!("Key range: {} to {} (common prefix: {} bits)",
+              self.min_key, self.max_key, self.common_prefix_bits);
+        info!("CDF deciles: {:?}", self.cdf);
+    }
+}
+
+// The number of leading bits that every key between the two shares, at the
+// full width of the key type (the bit pattern of 64-bit floats; none for
+// 512-bit floats). Byte string keys count the bits of their encoding.
+fn common_prefix_bits<T: TrainingKey>(min: &T, max: &T) -> u32 {
+    return match (min.to_model_input(), max.to_model_input()) {
+        (ModelInput::Int(a), ModelInput::Int(b)) => {
+            let unused = if let KeyType::U32 = T::key_type() { 32 } else { 0 };
+            (a ^ b).leading_zeros() - unused
+        },
+        (ModelInput::Int128(a), ModelInput::Int128(b)) => (a ^ b).leading_zeros(),
+        (ModelInput::UINT512(a), ModelInput::UINT512(b)) => (a ^ b).leading_zeros(),
+        (ModelInput::Float(a), ModelInput::Float(b)) => (a.to_bits() ^ b.to_bits()).leading_zeros(),
+        _ => 0
+    };
+}
+
+fn cmp_keys<T: TrainingKey>(a: &T, b: &T) -> Ordering {
+    return a.to_model_input().partial_cmp(&b.to_model_input())
+        .unwrap_or(Ordering::Equal);
+}
+
+// Computes the report in a single pass over the keys.
+pub fn check_data<T: TrainingKey>(data: &RMITrainingData<T>) -> DataReport {
+    let mut inversions = 0;
+    let mut first_inversion = None;
+    let mut duplicates = 0;
+    let mut longest_run = 0;
+    let mut current_run = 0;
+    let mut min_key: Option<T> = None;
+    let mut max_key: Option<T> = None;
+    let mut last_key: Option<T> = None;
+
+    // iter() repeats the final key once, so only take the keys themselves
+    for (idx, (x, _y)) in data.iter().take(data.len()).enumerate() {
+        if let Some(last) = last_key {
+            match cmp_keys(&x, &last) {
+                Ordering::Less => {
+                    inversions += 1;
+                    first_inversion = first_inversion.or(Some(idx));
+                },
+                _ => {}
+            }
+
+            if x == last {
+                duplicates += 1;
+                current_run += 1;
+            } else {
+                current_run = 1;
+            }
+        } else {
+            current_run = 1;
+        }
+        longest_run = u64::max(longest_run, current_run);
+
+        if min_key.map(|m| cmp_keys(&x, &m) == Ordering::Less).unwrap_or(true) {
+            min_key = Some(x);
+        }
+        if max_key.map(|m| cmp_keys(&x, &m) == Ordering::Greater).unwrap_or(true) {
+            max_key = Some(x);
+        }
+        last_key = Some(x);
+    }
+
+    let num_keys = data.len();
+    let cdf = if num_keys == 0 {
+        vec![]
+    } else {
+        (0..=10).map(|q| data.get_key(q * (num_keys - 1) / 10).as_float()).collect()
+    };
+
+    return DataReport {
+        num_keys,
+        inversions,
+        first_inversion,
+        duplicates,
+        longest_run,
+        min_key: min_key.map(|k| k.to_key_string()).unwrap_or_default(),
+        max_key: max_key.map(|k| k.to_key_string()).unwrap_or_default(),
+        // (keys between the smallest and the largest share their prefix)
+        common_prefix_bits: match (min_key, max_key) {
+            (Some(min), Some(max)) => common_prefix_bits(&min, &max),
+            _ => 0
+        },
+        cdf,
+        sorted_keys: false,
+        removed_duplicates: 0
+    };
+}
+
+// Checks the data and applies the policy. Unsorted data is either rejected or
+// sorted in memory; with Dedup, duplicate keys are always removed. Either way
+// the offsets of the returned data are the positions in the new order.
+pub fn validate_data<T: TrainingKey>(data: RMITrainingData<T>,
+                                     policy: InvalidDataPolicy)
+                                     -> (RMITrainingData<T>, DataReport) {
+    let report = check_data(&data);
+
+    let needs_copy = match policy {
+        InvalidDataPolicy::Abort => {
+            if !report.is_sorted() {
+                panic!("Input keys are not sorted: {} inversions, first at index {}. \
+                        Sort the input, or train with the sort or dedup policy.",
+                       report.inversions, report.first_inversion.unwrap());
+            }
+            false
+        },
+        InvalidDataPolicy::Sort => !report.is_sorted(),
+        InvalidDataPolicy::Dedup => !report.is_sorted() || report.duplicates > 0
+    };
+
+    if !needs_copy {
+        return (data, report);
+    }
+
+    info!("Sorting {} keys in memory", data.len());
+    let mut keys: Vec<T> = data.iter().take(data.len()).map(|(x, _y)| x).collect();
+    keys.sort_by(cmp_keys);
+    if let InvalidDataPolicy::Dedup = policy {
+        keys.dedup();
+    }
+    let removed_duplicates = data.len() - keys.len();
+
+    let sorted: Vec<(T, usize)> = keys.into_iter()
+        .enumerate()
+        .map(|(idx, x)| (x, idx))
+        .collect();
+    let sorted = RMITrainingData::new(Box::new(sorted));
+
+    let mut new_report = check_data(&sorted);
+    new_report.inversions = report.inversions;
+    new_report.first_inversion = report.first_inversion;
+    new_report.sorted_keys = true;
+    new_report.removed_duplicates = removed_duplicates;
+    return (sorted, new_report);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data_of(keys: &[u64]) -> RMITrainingData<u64> {
+        let v: Vec<(u64, usize)> = keys.iter().cloned()
+            .enumerate().map(|(i, k)| (k, i)).collect();
+        return RMITrainingData::new(Box::new(v));
+    }
+
+    #[test]
+    fn test_report_sorted() {
+        let report = check_data(&data_of(&[1, 2, 2, 2, 5, 9, 9, 12, 13, 20, 21]));
+        assert!(report.is_sorted());
+        assert_eq!(report.num_keys, 11);
+        assert_eq!(report.duplicates, 3);
+        assert_eq!(report.longest_run, 3);
+        assert_eq!(report.min_key, "1");
+        assert_eq!(report.max_key, "21");
+        assert_eq!(report.common_prefix_bits, 59);
+        assert_eq!(report.cdf.len(), 11);
+        assert_eq!(report.cdf[0], 1.0);
+        assert_eq!(report.cdf[10], 21.0);
+    }
+
+    #[test]
+    fn test_report_wide_keys() {
+        // keys that only differ in their low 10 bits, and in none of the low 64
+        let base: u128 = 0xABCD << 100;
+        let keys: Vec<(u128, usize)> = (0..1000u128).map(|i| (base + i, i as usize)).collect();
+        let report = check_data(&RMITrainingData::new(Box::new(keys)));
+        assert_eq!(report.common_prefix_bits, 118);
+        assert_eq!(report.min_key, base.to_string());
+        assert_eq!(report.max_key, (base + 999).to_string());
+
+        let keys: Vec<(u128, usize)> = vec![(base, 0), (base + (1 << 90), 1)];
+        assert_eq!(check_data(&RMITrainingData::new(Box::new(keys))).common_prefix_bits, 37);
+
+        let keys: Vec<(u32, usize)> = vec![(0x1000, 0), (0x1fff, 1)];
+        assert_eq!(check_data(&RMITrainingData::new(Box::new(keys))).common_prefix_bits, 20);
+    }
+
+    #[test]
+    fn test_report_byte_strings() {
+        let strings = [&b"chr1:10"[..], b"chr1:2", b"chr1:300000000"];
+        let skip = shared_prefix_len(strings[0], strings[2]);
+        let keys: Vec<(BytesKey, usize)> = strings.iter().enumerate()
+            .map(|(i, s)| (BytesKey::new(s, skip), i))
+            .collect();
+        let report = check_data(&RMITrainingData::new(Box::new(keys)));
+        assert_eq!(report.min_key, "10");
+        assert_eq!(report.max_key, "30000000...");
+    }
+
+    #[test]
+    fn test_sort_and_dedup() {
+        let keys = [5, 3, 3, 8, 1, 8, 8];
+        let (sorted, report) = validate_data(data_of(&keys), InvalidDataPolicy::Sort);
+        assert_eq!(report.inversions, 2);
+        assert_eq!(report.first_inversion, Some(1));
+        assert!(report.sorted_keys);
+        assert_eq!((0..sorted.len()).map(|i| sorted.get_key(i)).collect::<Vec<u64>>(),
+                   vec![1, 3, 3, 5, 8, 8, 8]);
+        assert_eq!(sorted.get(4), (8, 4));
+
+        let (dedup, report) = validate_data(data_of(&keys), InvalidDataPolicy::Dedup);
+        assert_eq!(report.removed_duplicates, 3);
+        assert_eq!(report.duplicates, 0);
+        assert_eq!((0..dedup.len()).map(|i| dedup.get(i)).collect::<Vec<(u64, usize)>>(),
+                   vec![(1, 0), (3, 1), (5, 2), (8, 3)]);
+    }
+
+    #[test]
+    #[should_panic(expected = "not sorted")]
+    fn test_abort_on_unsorted() {
+        validate_data(data_of(&[1, 3, 2]), InvalidDataPolicy::Abort);
+    }
+}
@@ -6,6 +6,7 @@ mod load;
 use load::{ load_data, DataType };
 use rmi_lib::train;
 use rmi_lib::KeyType;
+use rmi_lib::InvalidDataPolicy;
 use rmi_lib::driver_validation;
 use clap::{ App, Arg };
 use log::info;
@@ -57,7 +58,7 @@ fn main() {
     println!("With brancing factor of {}...", branching_factor);
     println!("With {} threads...\n", num_threads);
 
-    let (num_rows, data) = if fp.contains("uint64") {
+    let (_, data) = if fp.contains("uint64") {
         load_data(&fp, DataType::UINT64)
     } else if fp.contains("uint32") {
         key_type = KeyType::U32;
@@ -71,10 +72,27 @@ fn main() {
     } else if fp.contains("f64") {
         key_type = KeyType::F64;
         load_data(&fp, DataType::FLOAT64)
+    } else if fp.contains("f512") {
+        key_type = KeyType::F512;
+        load_data(&fp, DataType::FLOAT512)
+    } else if fp.contains("bytes") {
+        key_type = KeyType::Bytes;
+        if fp.ends_with(".txt") {
+            load_data(&fp, DataType::BYTES_NEWLINE)
+        } else {
+            load_data(&fp, DataType::BYTES_PREFIXED)
+        }
     } else {
-        panic!("Data file must contain uint64, uint32, uint128, uint512, or f64.");
+        panic!("Data file must contain uint64, uint32, uint128, uint512, f64, f512, or bytes.");
     };
 
+    let (_, data, data_report) = data.validate(InvalidDataPolicy::Abort);
+    data_report.display();
+
+    if let Some(prefix) = data.shared_prefix() {
+        println!("Byte string keys share a {}-byte prefix", prefix.len());
+    }
+
     if !Path::new(data_dir).exists() {
         info!("The output directory specified {} does not exist. Creating it.", data_dir);
         std::fs
@@ -84,7 +102,8 @@ fn main() {
 
     let validation_results: ValidationResults = dynamic!(driver_validation, data, models, branching_factor);
     
-    let json_results = validation_results.to_json();
+    let mut json_results = validation_results.to_json();
+    json_results["dataset"] = data_report.to_json();
 
     let timestamp = SystemTime::now()
       .duration_since(SystemTime::UNIX_EPOCH)
@@ -12,7 +12,9 @@ use memmap::MmapOptions;
 // Import necessary modules from the RMI library.
 // RMIライブラリから必要なモジュールをインポートします。
 // 从 RMI 库导入必要的模块。
-use rmi_lib::{RMITrainingData, RMITrainingDataIteratorProvider, KeyType, U512};
+use rmi_lib::{RMITrainingData, RMITrainingDataIteratorProvider, KeyType, U512, F512, BytesKey, shared_prefix_len,
+              validate_data, DataReport, InvalidDataPolicy,
+              DuplicateSemantics, QueryWeights, MemoryBudget};
 // Import byte order handling for reading data in little-endian format.
 // リトルエンディアン形式でデータを読み取るためのバイト順序処理をインポートします。
 // 导入字节顺序处理，用于以小端格式读取数据。
@@ -22,12 +24,13 @@ use byteorder::{LittleEndian, ReadBytesExt};
 // 导入标准库，用于文件操作和转换。
 use std::fs::File;
 use std::convert::TryInto;
+use std::sync::Arc;
 
 // Import the Integer type from the `rug` crate for handling large integers.
 // `rug`クレートから大きな整数を扱うためにInteger型をインポートします。
 // 从 `rug` crate 导入 Integer 类型，用于处理大整数。
 
-// Define an enum for supported data types (e.g., UINT64, UINT512, FLOAT64).
+// Define an enum for supported data types (e.g., UINT64, UINT512, FLOAT64, FLOAT512).
 // サポートされているデータ型を定義する列挙型です（例: UINT64, UINT512, FLOAT64）。
 // 定义一个枚举用于支持的数据类型（例如 UINT64、UINT512、FLOAT64）。
 pub enum DataType {
@@ -35,7 +38,15 @@ pub enum DataType {
     UINT128,
     UINT32,
     UINT512,
-    FLOAT64
+    FLOAT64,
+    FLOAT512,
+    // byte string keys, one per line or length-prefixed (see SliceAdapterBytes)
+    // バイト文字列キー。1行に1つ、または長さ前置形式（SliceAdapterBytesを参照）
+    // 字节串键，每行一个或长度前缀格式（参见 SliceAdapterBytes）
+    #[allow(non_camel_case_types)]
+    BYTES_NEWLINE,
+    #[allow(non_camel_case_types)]
+    BYTES_PREFIXED
 }
 
 // Define a struct to map a memory-mapped file for u64 data.
@@ -204,6 +215,115 @@ impl RMITrainingDataIteratorProvider for SliceAdapterU128 {
     fn len(&self) -> usize { self.length }
 }
 
+// Define a struct and implement the same trait for 512-bit float data.
+// Each key is 68 bytes, in the same layout as the Float512 parameter files.
+// 512ビット浮動小数点データを扱うための構造体を定義し、同じトレイトを実装します。
+// 各キーは68バイトで、Float512パラメータファイルと同じレイアウトです。
+// 为处理 512 位浮点数据定义一个结构体并实现相同的特性。
+// 每个键占 68 字节，布局与 Float512 参数文件相同。
+struct SliceAdapterF512 {
+    data: memmap::Mmap,
+    length: usize
+}
+
+impl RMITrainingDataIteratorProvider for SliceAdapterF512 {
+    type InpType = F512;
+    fn cdf_iter(&self) -> Box<dyn Iterator<Item = (Self::InpType, usize)> + '_> {
+        Box::new((0..self.length).map(move |i| self.get(i).unwrap()))
+    }
+
+    fn get(&self, idx: usize) -> Option<(Self::InpType, usize)> {
+        if idx >= self.length { return None; };
+        let mi = F512::from_le_bytes(&self.data[8 + idx * 68..8 + (idx + 1) * 68]);
+        return Some((mi, idx));
+    }
+
+    fn key_type(&self) -> KeyType {
+        KeyType::F512
+    }
+
+    fn len(&self) -> usize { self.length }
+}
+
+// Define a struct and implement the same trait for variable-length byte strings.
+// Strings are either newline-delimited (no header), or follow the usual 8-byte
+// count header, each prefixed with its length as a little-endian u32.
+// 可変長バイト文字列を扱うための構造体を定義し、同じトレイトを実装します。
+// 文字列は改行区切り（ヘッダなし）か、通常の8バイトの件数ヘッダの後に
+// リトルエンディアンu32の長さを前置した形式です。
+// 为处理可变长度字节串定义一个结构体并实现相同的特性。
+// 字符串要么按换行分隔（无头部），要么在常规的 8 字节计数头之后，
+// 每个字符串前置一个小端 u32 长度。
+struct SliceAdapterBytes {
+    data: memmap::Mmap,
+    ranges: Vec<(usize, usize)>,
+    skip: usize
+}
+
+impl SliceAdapterBytes {
+    fn newline(data: memmap::Mmap) -> SliceAdapterBytes {
+        let mut ranges = Vec::new();
+        let mut start = 0;
+        for (i, b) in data.iter().enumerate() {
+            if *b == b'\n' {
+                ranges.push((start, i));
+                start = i + 1;
+            }
+        }
+        if start < data.len() {
+            ranges.push((start, data.len()));
+        }
+        return SliceAdapterBytes::new(data, ranges);
+    }
+
+    fn length_prefixed(data: memmap::Mmap, num_items: usize) -> SliceAdapterBytes {
+        let mut ranges = Vec::with_capacity(num_items);
+        let mut pos = 8;
+        for _ in 0..num_items {
+            let len = (&data[pos..pos + 4]).read_u32::<LittleEndian>().unwrap() as usize;
+            ranges.push((pos + 4, pos + 4 + len));
+            pos += 4 + len;
+        }
+        return SliceAdapterBytes::new(data, ranges);
+    }
+
+    fn new(data: memmap::Mmap, ranges: Vec<(usize, usize)>) -> SliceAdapterBytes {
+        // the keys are sorted, so the prefix shared by all keys is the
+        // common prefix of the first and last key
+        let skip = match (ranges.first(), ranges.last()) {
+            (Some(&(fs, fe)), Some(&(ls, le))) =>
+                shared_prefix_len(&data[fs..fe], &data[ls..le]),
+            _ => 0
+        };
+        return SliceAdapterBytes { data, ranges, skip };
+    }
+
+    fn shared_prefix(&self) -> Vec<u8> {
+        match self.ranges.first() {
+            Some(&(start, _)) => self.data[start..start + self.skip].to_vec(),
+            None => Vec::new()
+        }
+    }
+}
+
+impl RMITrainingDataIteratorProvider for SliceAdapterBytes {
+    type InpType = BytesKey;
+    fn cdf_iter(&self) -> Box<dyn Iterator<Item = (Self::InpType, usize)> + '_> {
+        Box::new((0..self.ranges.len()).map(move |i| self.get(i).unwrap()))
+    }
+
+    fn get(&self, idx: usize) -> Option<(Self::InpType, usize)> {
+        let (start, end) = *self.ranges.get(idx)?;
+        return Some((BytesKey::new(&self.data[start..end], self.skip), idx));
+    }
+
+    fn key_type(&self) -> KeyType {
+        KeyType::Bytes
+    }
+
+    fn len(&self) -> usize { self.ranges.len() }
+}
+
 // Define an enum to hold different types of memory-mapped RMI training data.
 // 異なるタイプのメモリマップされたRMIトレーニングデータを保持するための列挙型を定義します。
 // 定义一个枚举，用于存储不同类型的内存映射 RMI 训练数据。
@@ -212,7 +332,13 @@ pub enum RMIMMap {
     UINT32(RMITrainingData<u32>),
     UINT512(RMITrainingData<U512>),
     UINT128(RMITrainingData<u128>),
-    FLOAT64(RMITrainingData<f64>)
+    FLOAT64(RMITrainingData<f64>),
+    FLOAT512(RMITrainingData<F512>),
+    // byte string keys, along with the prefix shared by all keys
+    // バイト文字列キーと、すべてのキーが共有するプレフィックス
+    // 字节串键，以及所有键共享的前缀
+    #[allow(clippy::upper_case_acronyms)]
+    BYTES(RMITrainingData<BytesKey>, Vec<u8>)
 }
 
 // Macro for dynamically dispatching the training function based on the data type.
@@ -226,6 +352,8 @@ macro_rules! dynamic {
             load::RMIMMap::UINT128(mut x) => $funcname(&mut x, $($p),*),
             load::RMIMMap::UINT512(mut x) => $funcname(&mut x, $($p),*),
             load::RMIMMap::FLOAT64(mut x) => $funcname(&mut x, $($p),*),
+            load::RMIMMap::FLOAT512(mut x) => $funcname(&mut x, $($p),*),
+            load::RMIMMap::BYTES(mut x, _) => $funcname(&mut x, $($p),*),
         }
     }
 }
@@ -241,15 +369,185 @@ impl RMIMMap {
             RMIMMap::UINT128(x) => RMIMMap::UINT128(x.soft_copy()),
             RMIMMap::UINT512(x) => RMIMMap::UINT512(x.soft_copy()),
             RMIMMap::FLOAT64(x) => RMIMMap::FLOAT64(x.soft_copy()),
+            RMIMMap::FLOAT512(x) => RMIMMap::FLOAT512(x.soft_copy()),
+            RMIMMap::BYTES(x, p) => RMIMMap::BYTES(x.soft_copy(), p.clone()),
         }
     }
 
-    pub fn into_u64(self) -> Option<RMITrainingData<u64>> {
+    // The prefix shared by all byte string keys, which the generated lookup
+    // code needs to encode keys. None for fixed-width keys.
+    // すべてのバイト文字列キーが共有するプレフィックス。生成されたルックアップ
+    // コードがキーのエンコードに使います。固定長キーの場合はNoneです。
+    // 所有字节串键共享的前缀，生成的查找代码用它来编码键。定长键返回 None。
+    pub fn shared_prefix(&self) -> Option<&[u8]> {
         match self {
-            RMIMMap::UINT64(x) => Some(x),
+            RMIMMap::BYTES(_, p) => Some(p),
             _ => None
         }
     }
+
+    // Check key order, duplicates and the key distribution, applying the
+    // policy to unsorted input. Returns the (possibly sorted) data and its size.
+    // キーの順序、重複、分布を確認し、ソートされていない入力にポリシーを適用します。
+    // （ソートされた可能性のある）データとそのサイズを返します。
+    // 检查键的顺序、重复和分布，并对未排序的输入应用策略。
+    // 返回（可能已排序的）数据及其大小。
+    pub fn validate(self, policy: InvalidDataPolicy) -> (usize, RMIMMap, DataReport) {
+        let (data, report) = match self {
+            RMIMMap::UINT64(x) => { let (x, r) = validate_data(x, policy); (RMIMMap::UINT64(x), r) },
+            RMIMMap::UINT32(x) => { let (x, r) = validate_data(x, policy); (RMIMMap::UINT32(x), r) },
+            RMIMMap::UINT128(x) => { let (x, r) = validate_data(x, policy); (RMIMMap::UINT128(x), r) },
+            RMIMMap::UINT512(x) => { let (x, r) = validate_data(x, policy); (RMIMMap::UINT512(x), r) },
+            RMIMMap::FLOAT64(x) => { let (x, r) = validate_data(x, policy); (RMIMMap::FLOAT64(x), r) },
+            RMIMMap::FLOAT512(x) => { let (x, r) = validate_data(x, policy); (RMIMMap::FLOAT512(x), r) },
+            RMIMMap::BYTES(x, p) => {
+                // the keys only hold the bytes after the prefix they all share
+                let (x, mut r) = validate_data(x, policy);
+                if r.num_keys > 0 {
+                    let prefix: String = p.iter().flat_map(|b| std::ascii::escape_default(*b)).map(|c| c as char).collect();
+                    r.min_key = format!("{}{}", prefix, r.min_key);
+                    r.max_key = format!("{}{}", prefix, r.max_key);
+                }
+                (RMIMMap::BYTES(x, p), r)
+            },
+        };
+        return (report.num_keys, data, report);
+    }
+
+    // Choose which position duplicate keys are trained to predict.
+    // 重複キーに対して学習する位置を選択します。
+    // 选择重复键训练时预测的位置。
+    pub fn set_duplicate_semantics(&mut self, duplicates: DuplicateSemantics) {
+        match self {
+            RMIMMap::UINT64(x) => x.set_duplicate_semantics(duplicates),
+            RMIMMap::UINT32(x) => x.set_duplicate_semantics(duplicates),
+            RMIMMap::UINT128(x) => x.set_duplicate_semantics(duplicates),
+            RMIMMap::UINT512(x) => x.set_duplicate_semantics(duplicates),
+            RMIMMap::FLOAT64(x) => x.set_duplicate_semantics(duplicates),
+            RMIMMap::FLOAT512(x) => x.set_duplicate_semantics(duplicates),
+            RMIMMap::BYTES(x, _) => x.set_duplicate_semantics(duplicates),
+        }
+    }
+
+    // Fit models on every k-th key.
+    // k番目ごとのキーでモデルを学習します。
+    // 在每第k个键上拟合模型。
+    pub fn set_sample_rate(&mut self, sample_rate: usize) {
+        match self {
+            RMIMMap::UINT64(x) => x.set_sample_rate(sample_rate),
+            RMIMMap::UINT32(x) => x.set_sample_rate(sample_rate),
+            RMIMMap::UINT128(x) => x.set_sample_rate(sample_rate),
+            RMIMMap::UINT512(x) => x.set_sample_rate(sample_rate),
+            RMIMMap::FLOAT64(x) => x.set_sample_rate(sample_rate),
+            RMIMMap::FLOAT512(x) => x.set_sample_rate(sample_rate),
+            RMIMMap::BYTES(x, _) => x.set_sample_rate(sample_rate),
+        }
+    }
+
+    // Replace leaves whose error exceeds the threshold by an exact search.
+    // 誤差がしきい値を超えるリーフを厳密探索に置き換えます。
+    // 用精确查找替换误差超过阈值的叶子。
+    pub fn set_exact_leaf_threshold(&mut self, threshold: Option<u64>) {
+        match self {
+            RMIMMap::UINT64(x) => x.set_exact_leaf_threshold(threshold),
+            RMIMMap::UINT32(x) => x.set_exact_leaf_threshold(threshold),
+            RMIMMap::UINT128(x) => x.set_exact_leaf_threshold(threshold),
+            RMIMMap::UINT512(x) => x.set_exact_leaf_threshold(threshold),
+            RMIMMap::FLOAT64(x) => x.set_exact_leaf_threshold(threshold),
+            RMIMMap::FLOAT512(x) => x.set_exact_leaf_threshold(threshold),
+            RMIMMap::BYTES(x, _) => x.set_exact_leaf_threshold(threshold),
+        }
+    }
+
+    // Keep separate errors below and above the prediction of each leaf.
+    // 各リーフの予測の下側と上側の誤差を別々に保持します。
+    // 分别保存每个叶子预测值之下和之上的误差。
+    pub fn set_signed_errors(&mut self, signed_errors: bool) {
+        match self {
+            RMIMMap::UINT64(x) => x.set_signed_errors(signed_errors),
+            RMIMMap::UINT32(x) => x.set_signed_errors(signed_errors),
+            RMIMMap::UINT128(x) => x.set_signed_errors(signed_errors),
+            RMIMMap::UINT512(x) => x.set_signed_errors(signed_errors),
+            RMIMMap::FLOAT64(x) => x.set_signed_errors(signed_errors),
+            RMIMMap::FLOAT512(x) => x.set_signed_errors(signed_errors),
+            RMIMMap::BYTES(x, _) => x.set_signed_errors(signed_errors),
+        }
+    }
+
+    // Measure the error of every key after training.
+    // 学習後にすべてのキーの誤差を測定します。
+    // 训练后测量每个键的误差。
+    pub fn set_key_errors(&mut self, key_errors: bool) {
+        match self {
+            RMIMMap::UINT64(x) => x.set_key_errors(key_errors),
+            RMIMMap::UINT32(x) => x.set_key_errors(key_errors),
+            RMIMMap::UINT128(x) => x.set_key_errors(key_errors),
+            RMIMMap::UINT512(x) => x.set_key_errors(key_errors),
+            RMIMMap::FLOAT64(x) => x.set_key_errors(key_errors),
+            RMIMMap::FLOAT512(x) => x.set_key_errors(key_errors),
+            RMIMMap::BYTES(x, _) => x.set_key_errors(key_errors),
+        }
+    }
+
+    // Split leaves whose error exceeds the maximum with a partial third layer.
+    // 誤差が最大値を超えるリーフを部分的な第3層で分割します。
+    // 用部分第三层拆分误差超过最大值的叶子。
+    pub fn set_max_error(&mut self, max_error: Option<u64>) {
+        match self {
+            RMIMMap::UINT64(x) => x.set_max_error(max_error),
+            RMIMMap::UINT32(x) => x.set_max_error(max_error),
+            RMIMMap::UINT128(x) => x.set_max_error(max_error),
+            RMIMMap::UINT512(x) => x.set_max_error(max_error),
+            RMIMMap::FLOAT64(x) => x.set_max_error(max_error),
+            RMIMMap::FLOAT512(x) => x.set_max_error(max_error),
+            RMIMMap::BYTES(x, _) => x.set_max_error(max_error),
+        }
+    }
+
+    // The type of the keys, which decides how query weight files are parsed.
+    // キーの型。クエリ重みファイルの解析方法を決めます。
+    // 键的类型，决定如何解析查询权重文件。
+    pub fn key_type(&self) -> KeyType {
+        match self {
+            RMIMMap::UINT64(_) => KeyType::U64,
+            RMIMMap::UINT32(_) => KeyType::U32,
+            RMIMMap::UINT128(_) => KeyType::U128,
+            RMIMMap::UINT512(_) => KeyType::U512,
+            RMIMMap::FLOAT64(_) => KeyType::F64,
+            RMIMMap::FLOAT512(_) => KeyType::F512,
+            RMIMMap::BYTES(_, _) => KeyType::Bytes,
+        }
+    }
+
+    // Weight the regressions and the average log2 error by query frequency.
+    // 回帰と平均log2誤差をクエリ頻度で重み付けします。
+    // 按查询频率对回归和平均log2误差加权。
+    pub fn set_query_weights(&mut self, query_weights: Option<Arc<QueryWeights>>) {
+        match self {
+            RMIMMap::UINT64(x) => x.set_query_weights(query_weights),
+            RMIMMap::UINT32(x) => x.set_query_weights(query_weights),
+            RMIMMap::UINT128(x) => x.set_query_weights(query_weights),
+            RMIMMap::UINT512(x) => x.set_query_weights(query_weights),
+            RMIMMap::FLOAT64(x) => x.set_query_weights(query_weights),
+            RMIMMap::FLOAT512(x) => x.set_query_weights(query_weights),
+            RMIMMap::BYTES(x, _) => x.set_query_weights(query_weights),
+        }
+    }
+
+    // Track (and optionally limit) the memory of the rows copied for training.
+    // 学習のためにコピーされる行のメモリを追跡（必要なら制限）します。
+    // 跟踪（并可选地限制）为训练复制的行所占的内存。
+    pub fn set_memory_budget(&mut self, memory_budget: Arc<MemoryBudget>) {
+        match self {
+            RMIMMap::UINT64(x) => x.set_memory_budget(memory_budget),
+            RMIMMap::UINT32(x) => x.set_memory_budget(memory_budget),
+            RMIMMap::UINT128(x) => x.set_memory_budget(memory_budget),
+            RMIMMap::UINT512(x) => x.set_memory_budget(memory_budget),
+            RMIMMap::FLOAT64(x) => x.set_memory_budget(memory_budget),
+            RMIMMap::FLOAT512(x) => x.set_memory_budget(memory_budget),
+            RMIMMap::BYTES(x, _) => x.set_memory_budget(memory_budget),
+        }
+    }
 }
 
 // Load data from a file and create the appropriate RMIMMap based on the data type.
@@ -268,6 +566,18 @@ pub fn load_data(filepath: &str,
     // 読み取りのためにファイルをメモリマップします。
     // 为读取映射文件到内存。
     let mmap = unsafe { MmapOptions::new().map(&fd).unwrap() };
+
+    // Newline-delimited string files have no item count header.
+    // 改行区切りの文字列ファイルには件数ヘッダがありません。
+    // 换行分隔的字符串文件没有计数头。
+    if let DataType::BYTES_NEWLINE = dt {
+        let adapter = SliceAdapterBytes::newline(mmap);
+        let prefix = adapter.shared_prefix();
+        let num_items = adapter.len();
+        return (num_items,
+                RMIMMap::BYTES(RMITrainingData::new(Box::new(adapter)), prefix));
+    }
+
     let num_items = (&mmap[0..8]).read_u64::<LittleEndian>().unwrap() as usize;
 
     // Match the data type and create the appropriate RMIMMap variant.
@@ -293,7 +603,17 @@ pub fn load_data(filepath: &str,
         DataType::FLOAT64 =>
             RMIMMap::FLOAT64(RMITrainingData::new(Box::new(
                 SliceAdapterF64 { data: mmap, length: num_items }
-            )))
+            ))),
+        DataType::FLOAT512 =>
+            RMIMMap::FLOAT512(RMITrainingData::new(Box::new(
+                SliceAdapterF512 { data: mmap, length: num_items }
+            ))),
+        DataType::BYTES_PREFIXED => {
+            let adapter = SliceAdapterBytes::length_prefixed(mmap, num_items);
+            let prefix = adapter.shared_prefix();
+            RMIMMap::BYTES(RMITrainingData::new(Box::new(adapter)), prefix)
+        },
+        DataType::BYTES_NEWLINE => unreachable!()
     };
 
     return (num_items, rtd);
@@ -18,12 +18,28 @@ mod load; // Load module definitions with macro imports.
 use load::{load_data, DataType}; // Import functions and types for loading data.
 // データの読み込みに関する関数と型をインポートします。
 // 导入用于加载数据的函数和类型。
-use rmi_lib::{train, train_bounded}; // Import the RMI training functions.
+use rmi_lib::{train, train_with_config, train_bounded, TrainingConfig}; // Import the RMI training functions.
 // RMIトレーニング関数をインポートします。
 // 导入RMI训练函数。
 use rmi_lib::KeyType; // Import the key type for the RMI.
 // RMIのキータイプをインポートします。
 // 导入RMI的键类型。
+use rmi_lib::InvalidDataPolicy; // Import the policy for unsorted input.
+// ソートされていない入力に対するポリシーをインポートします。
+// 导入针对未排序输入的策略。
+use rmi_lib::DuplicateSemantics; // Import the semantics for duplicate keys.
+// 重複キーのセマンティクスをインポートします。
+// 导入重复键的语义。
+use rmi_lib::QueryWeights; // Import the query weights used to weight training.
+// 学習の重み付けに使うクエリ重みをインポートします。
+// 导入用于加权训练的查询权重。
+use rmi_lib::MemoryBudget; // Import the memory budget of training.
+// 学習のメモリ予算をインポートします。
+// 导入训练的内存预算。
+use rmi_lib::train::TrainedRMI; // Import the trained RMI type.
+use rmi_lib::train::Checkpoints; // Import the checkpoints of long training runs.
+// 学習済みRMIの型をインポートします。
+// 导入已训练 RMI 的类型。
 use rmi_lib::optimizer; // Import optimization utilities from the RMI library.
 // RMIライブラリから最適化のユーティリティをインポートします。
 // 从RMI库导入优化工具。
@@ -49,6 +65,9 @@ use std::fs; // Standard filesystem utilities.
 use std::path::Path; // Path utilities for working with file paths.
 // ファイルパスを扱うためのパスユーティリティ。
 // 用于处理文件路径的路径工具。
+use std::sync::Arc; // Shared ownership of the query weights.
+// クエリ重みの共有所有権。
+// 查询权重的共享所有权。
 use rayon::prelude::*; // Import for parallel iteration and task handling.
 // 並列イテレーションとタスク処理用のインポート。
 // 导入并行迭代和任务处理。
@@ -124,6 +143,89 @@ fn main() {
              .short("s")
              .value_name("file")
              .help("dump statistics about the learned model into the specified file"))
+        .arg(Arg::with_name("invalid-input") // What to do with unsorted input.
+    // ソートされていない入力の扱い。
+    // 如何处理未排序的输入。
+             .long("invalid-input")
+             .value_name("policy")
+             .possible_values(&["abort", "sort", "dedup"])
+             .help("check the input order, and what to do with unsorted input: abort (default), sort, or dedup (sort and remove duplicate keys); the input is also checked with --stats-file or --param-grid"))
+        .arg(Arg::with_name("duplicates") // Semantics for duplicate keys.
+    // 重複キーのセマンティクス。
+    // 重复键的语义。
+             .long("duplicates")
+             .value_name("semantics")
+             .possible_values(&["lower", "upper", "dedup", "range"])
+             .help("position predicted for duplicate keys: lower (first, default), upper (last), dedup (distinct keys plus a table of run lengths), or range (first, with left and right errors whose window holds the whole [first, last + 1) interval; two-level specs only)"))
+        .arg(Arg::with_name("sample-rate") // Fit models on a sample of the keys.
+    // キーのサンプルでモデルを学習します。
+    // 在键的样本上拟合模型。
+             .long("sample-rate")
+             .value_name("k")
+             .validator(|k| match k.parse::<usize>() {
+                 Ok(k) if k >= 1 => Ok(()),
+                 _ => Err(String::from("the sample rate must be a positive integer"))
+             })
+             .help("fit models on every k-th key (errors are still computed on all keys), default = 1"))
+        .arg(Arg::with_name("compare-sampling") // Compare a sampled RMI against one trained on all keys.
+    // サンプルで学習したRMIをすべてのキーで学習したRMIと比較します。
+    // 将在样本上训练的RMI与在所有键上训练的RMI进行比较。
+             .long("compare-sampling")
+             .requires("stats-file")
+             .help("with --sample-rate, also train on all keys and record the build time and errors sampling saved in the stats file"))
+        .arg(Arg::with_name("exact-leaves") // Replace leaves with large errors by an exact search.
+    // 誤差の大きいリーフを厳密探索に置き換えます。
+    // 用精确查找替换误差较大的叶子。
+             .long("exact-leaves")
+             .value_name("max error")
+             .help("replace leaves whose error exceeds this bound with a binary search over their keys (written to the EXACT_LEAVES file, searched by the lookup_exact function of the generated exact_leaves.h; two-level specs without --max-error only)"))
+        .arg(Arg::with_name("signed-errors") // Keep separate errors below and above the prediction.
+    // 予測の下側と上側の誤差を別々に保持します。
+    // 分别保存预测值之下和之上的误差。
+             .long("signed-errors")
+             .help("store how far each leaf predicts past its keys and how far short of them as two errors, so that search windows are no wider than needed on either side (two-level specs only; changes the leaf parameter layout)"))
+        .arg(Arg::with_name("key-errors") // Measure the error of every key after training.
+    // 学習後にすべてのキーの誤差を測定します。
+    // 训练后测量每个键的误差。
+             .long("key-errors")
+             .help("after training, look up every key and report the percentiles of their errors (p50 to p99.9) in the output, the stats file, the parameter grid and the optimizer table; an extra pass over the keys for every RMI"))
+        .arg(Arg::with_name("max-error") // Split leaves until they meet a maximum error.
+    // 最大誤差を満たすまでリーフを分割します。
+    // 拆分叶子直到满足最大误差。
+             .long("max-error")
+             .value_name("E")
+             .help("split leaves whose error exceeds E with a partial third layer, doubling its size until every model meets E or has two keys left (two- and three-level specs). Third layer models are not split again, so leaves over very skewed keys or long runs of duplicates can stay above E; each of them is listed with its key range"))
+        .arg(Arg::with_name("query-weights") // Weight keys by how often they are queried.
+    // クエリされる頻度でキーを重み付けします。
+    // 按查询频率对键加权。
+             .long("query-weights")
+             .value_name("file")
+             .help("text file of \"<key> <weight>\" lines: keys from each listed key up to the next weigh that much (default 1) in the linear regressions (linear, linear_fixed, linear_big; other model types ignore them) and in the average log2 error"))
+        .arg(Arg::with_name("max-memory") // Limit the memory of the rows copied for training.
+    // 学習のためにコピーされる行のメモリを制限します。
+    // 限制为训练复制的行所占的内存。
+             .long("max-memory")
+             .value_name("bytes")
+             .help("limit the rows copied for training to this many bytes (K, M, G or T suffixes); the peak is reported in the stats. Under a limit the rows of leaves are read in place rather than copied, and three-level specs without --max-error or --checkpoint train a full three-layer RMI (branching factor must be a square) whose third layer is trained in windows of keys that fit in the limit"))
+        .arg(Arg::with_name("checkpoint") // Write checkpoints of the training stages.
+    // 学習段階のチェックポイントを書き込みます。
+    // 写入训练阶段的检查点。
+             .long("checkpoint")
+             .value_name("dir")
+             .help("write checkpoints of three-level RMIs (and of two-level ones with --max-error) to dir after the top layer, each batch of second-layer models, and the leaves; other specs, and models that cannot be rebuilt from their params, are rejected"))
+        .arg(Arg::with_name("resume") // Resume training from checkpoints.
+    // チェックポイントから学習を再開します。
+    // 从检查点恢复训练。
+             .long("resume")
+             .value_name("dir")
+             .conflicts_with("checkpoint")
+             .help("resume training from the last completed stage checkpointed in dir, which must be of the same input file, models, branching factor and training settings; keeps writing checkpoints to dir"))
+        .arg(Arg::with_name("verify") // Check the error bounds of the trained RMI.
+    // 学習済みRMIの誤差範囲を検査します。
+    // 检查训练好的RMI的误差界。
+             .long("verify")
+             .conflicts_with("bounded")
+             .help("replay every key, and keys just below and above each one, through the trained RMI, and fail without writing it if a position is outside the error window of its leaf"))
         .arg(Arg::with_name("param-grid") // Parameter grid for training.
     // トレーニングのためのパラメータグリッド。
     // 用于训练的参数网格。
@@ -206,7 +308,20 @@ fn main() {
     if matches.value_of("namespace").is_some() && matches.value_of("param-grid").is_some() {
         panic!("Can only specify one of namespace or param-grid");
     }
-    
+
+    // Checkpoints are of a single RMI.
+    // チェックポイントは単一のRMIのものです。
+    // 检查点属于单个RMI。
+    let checkpoint_dir = matches.value_of("resume").or_else(|| matches.value_of("checkpoint"));
+    if checkpoint_dir.is_some() && matches.value_of("param-grid").is_some() {
+        panic!("Can only write checkpoints when training a single RMI, not a param-grid");
+    }
+    if checkpoint_dir.is_some()
+        && (matches.is_present("optimize") || matches.is_present("max-size") || matches.is_present("bounded")) {
+        panic!("Can only write checkpoints of the given models, not with --optimize, --max-size or --bounded");
+    }
+
+
     info!("Reading {}...", fp); // Log the input file being read.
     // 読み込んでいる入力ファイルをログに出力します。
     // 记录正在读取的输入文件。
@@ -234,10 +349,119 @@ fn main() {
     }else if fp.contains("f64") {
         key_type = KeyType::F64;
         load_data(&fp, DataType::FLOAT64)
+    } else if fp.contains("f512") {
+        key_type = KeyType::F512;
+        load_data(&fp, DataType::FLOAT512)
+    } else if fp.contains("bytes") {
+        // Byte string keys: one key per line in .txt files, length-prefixed otherwise.
+        // バイト文字列キー：.txtファイルは1行に1キー、それ以外は長さ前置形式です。
+        // 字节串键：.txt 文件每行一个键，其他文件为长度前缀格式。
+        key_type = KeyType::Bytes;
+        if fp.ends_with(".txt") {
+            load_data(&fp, DataType::BYTES_NEWLINE)
+        } else {
+            load_data(&fp, DataType::BYTES_PREFIXED)
+        }
     } else {
-        panic!("Data file must contain uint64, uint32, uint128, uint512, or f64.");
+        panic!("Data file must contain uint64, uint32, uint128, uint512, f64, f512, or bytes.");
     };
 
+    // Validate the input before training, so that unsorted or corrupted data
+    // is reported up front instead of failing deep inside training. This is a
+    // pass over the keys, so it only runs when a policy or a report is asked for.
+    // ソートされていない、または破損したデータが学習の奥深くで失敗する代わりに
+    // 事前に報告されるよう、学習前に入力を検証します。キーを一巡するため、
+    // ポリシーまたはレポートが指定された場合にのみ実行します。
+    // 在训练前验证输入，使未排序或损坏的数据能提前报告，而不是在训练深处失败。
+    // 这需要遍历一次键，因此仅在指定了策略或需要报告时运行。
+    let (num_rows, mut data, data_report) = if matches.is_present("invalid-input")
+        || matches.is_present("stats-file")
+        || matches.is_present("param-grid") {
+        let policy = matches.value_of("invalid-input")
+            .map(|p| InvalidDataPolicy::from_str(p).unwrap())
+            .unwrap_or(InvalidDataPolicy::Abort);
+        let (num_rows, data, data_report) = data.validate(policy);
+        data_report.display();
+        (num_rows, data, Some(data_report))
+    } else {
+        (num_rows, data, None)
+    };
+
+    // Apply the duplicate key semantics (param-grid configs may override it).
+    // 重複キーのセマンティクスを適用します（param-gridの設定で上書き可能）。
+    // 应用重复键语义（param-grid 配置可以覆盖它）。
+    let duplicates = matches.value_of("duplicates")
+        .map(|d| DuplicateSemantics::from_str(d).unwrap())
+        .unwrap_or(DuplicateSemantics::LowerBound);
+    data.set_duplicate_semantics(duplicates);
+
+    // Fit models on every k-th key (param-grid configs may override it).
+    // k番目ごとのキーでモデルを学習します（param-gridの設定で上書き可能）。
+    // 在每第k个键上拟合模型（param-grid 配置可以覆盖它）。
+    let sample_rate = matches.value_of("sample-rate")
+        .map(|k| k.parse::<usize>().unwrap())
+        .unwrap_or(1);
+    data.set_sample_rate(sample_rate);
+
+    // Replace leaves with large errors by an exact search over their keys.
+    // 誤差の大きいリーフをキーの厳密探索に置き換えます。
+    // 用对其键的精确查找替换误差较大的叶子。
+    if let Some(t) = matches.value_of("exact-leaves") {
+        data.set_exact_leaf_threshold(Some(t.parse::<u64>().expect("Exact leaf threshold must be a positive integer.")));
+    }
+
+    // Keep separate errors below and above the prediction of each leaf.
+    // 各リーフの予測の下側と上側の誤差を別々に保持します。
+    // 分别保存每个叶子预测值之下和之上的误差。
+    data.set_signed_errors(matches.is_present("signed-errors"));
+
+    // Measure the error of every key after training.
+    // 学習後にすべてのキーの誤差を測定します。
+    // 训练后测量每个键的误差。
+    data.set_key_errors(matches.is_present("key-errors"));
+
+    // Split leaves whose error exceeds the maximum error.
+    // 誤差が最大誤差を超えるリーフを分割します。
+    // 拆分误差超过最大误差的叶子。
+    if let Some(e) = matches.value_of("max-error") {
+        data.set_max_error(Some(e.parse::<u64>().expect("Maximum error must be a positive integer.")));
+    }
+
+    // Weight the keys by how often they are queried.
+    // クエリされる頻度でキーを重み付けします。
+    // 按查询频率对键加权。
+    if let Some(path) = matches.value_of("query-weights") {
+        let text = fs::read_to_string(path).expect("Could not read the query weights file.");
+        let weights = QueryWeights::parse(&text, data.key_type())
+            .unwrap_or_else(|e| panic!("Invalid query weights file {}: {}", path, e));
+        println!("Loaded {} query weight ranges", weights.len());
+        data.set_query_weights(Some(Arc::new(weights)));
+    }
+
+    // Track the memory of the rows copied for training, within a limit if given.
+    // 学習のためにコピーされる行のメモリを追跡します（指定があれば制限内で）。
+    // 跟踪为训练复制的行所占的内存（如有指定则在限制内）。
+    let max_memory = matches.value_of("max-memory")
+        .map(|m| MemoryBudget::parse_size(m).expect("Maximum memory must be a positive number of bytes, optionally with a K, M, G or T suffix."));
+    data.set_memory_budget(Arc::new(MemoryBudget::new(max_memory)));
+
+    // Write checkpoints of the training stages, or resume from the ones in the directory.
+    // 学習段階のチェックポイントを書き込むか、ディレクトリ内のチェックポイントから再開します。
+    // 写入训练阶段的检查点，或从目录中的检查点恢复。
+    let mut config = TrainingConfig::default();
+    if let Some(dir) = checkpoint_dir {
+        let input_hash = Checkpoints::hash_file(Path::new(fp)).expect("Could not read the input file.");
+        let checkpoints = Checkpoints::new(Path::new(dir), input_hash, matches.is_present("resume"));
+        config.checkpoints = Some(Arc::new(checkpoints));
+    }
+
+    // Byte string keys are encoded relative to the prefix they all share,
+    // which has to be written out along with the RMI.
+    // バイト文字列キーは共有プレフィックスを基準にエンコードされるため、
+    // RMIと一緒に出力する必要があります。
+    // 字节串键相对于它们共享的前缀进行编码，该前缀需要与 RMI 一起输出。
+    let key_prefix = data.shared_prefix().map(|p| p.to_vec());
+
     if matches.is_present("optimize") {
         // Perform RMI optimization if the `optimize` flag is set.
         // `optimize`フラグが設定されている場合、RMIの最適化を実行します。
@@ -322,8 +546,22 @@ fn main() {
     // 如果有命名空间，提取它。
                     None => None
                 };
+                let dups = match el["duplicates"].as_str() {
+                    Some(s) => DuplicateSemantics::from_str(s)
+                        .unwrap_or_else(|| panic!("Unknown duplicate semantics {}", s)), // Per-config duplicate semantics.
+    // 設定ごとの重複キーのセマンティクス。
+    // 每个配置的重复键语义。
+                    None => duplicates
+                };
+                let rate = match el["sample rate"].as_usize() { // Per-config sample rate.
+                    Some(0) => panic!("The sample rate of a config must be at least 1"),
+                    Some(rate) => rate,
+                    None => sample_rate
+                };
+    // 設定ごとのサンプルレート。
+    // 每个配置的采样率。
 
-                to_test.push((layers, branching, namespace)); // Add the configuration to the list.
+                to_test.push((layers, branching, namespace, dups, rate)); // Add the configuration to the list.
     // 構成をリストに追加します。
     // 将配置添加到列表中。
             }
@@ -341,7 +579,7 @@ fn main() {
     // 自定义进度条的样式。
 
             let train_func =
-                |(models, branch_factor, namespace): &(String, u64, Option<String>)| { // Define the training function.
+                |(models, branch_factor, namespace, dups, rate): &(String, u64, Option<String>, DuplicateSemantics, usize)| { // Define the training function.
     // トレーニング関数を定義します。
     // 定义训练函数。
                     trace!("Training RMI {} with branching factor {}",
@@ -349,9 +587,15 @@ fn main() {
     // 現在のトレーニングタスクをログに出力します。
     // 记录当前的训练任务。
                     
-                    let loc_data = data.soft_copy(); // Create a soft copy of the data.
+                    let mut loc_data = data.soft_copy(); // Create a soft copy of the data.
     // データのソフトコピーを作成します。
     // 创建数据的软拷贝。
+                    loc_data.set_duplicate_semantics(*dups);
+                    loc_data.set_sample_rate(*rate);
+                    // A budget of its own, so that the peak memory is that of this RMI.
+                    // このRMIのピークメモリになるよう、専用の予算を使います。
+                    // 使用独立的预算，使峰值内存为该RMI的峰值。
+                    loc_data.set_memory_budget(Arc::new(MemoryBudget::new(max_memory)));
                     let mut trained_model = dynamic!(train, loc_data, models, *branch_factor); // Train the RMI model.
     // RMIモデルをトレーニングします。
     // 训练RMI模型。
@@ -374,7 +618,13 @@ fn main() {
                         "max error %" => trained_model.model_max_error as f64
                             / num_rows as f64 * 100.0,
                         "max log2 error" => trained_model.model_max_log2_error,
+                        "p50 error" => trained_model.error_distribution.as_ref().map(|d| d.p50),
+                        "p99 error" => trained_model.error_distribution.as_ref().map(|d| d.p99),
+                        "p99.9 error" => trained_model.error_distribution.as_ref().map(|d| d.p999),
                         "size binary search" => size_bs,
+                        "duplicates" => dups.name(),
+                        "sample rate" => *rate,
+                        "build time ns" => trained_model.build_time as u64,
                         "namespace" => namespace.clone()
                     };
 
@@ -393,6 +643,10 @@ fn main() {
                             data_dir,
                             key_type,
                             true).unwrap();
+                        if let Some(prefix) = &key_prefix {
+                            rmi_lib::output_key_encoding(
+                                &nmspc, data_dir, prefix, num_rows as u64, true).unwrap();
+                        }
                         
                     }
                     
@@ -425,7 +679,10 @@ fn main() {
     // 結果をファイルに書き込みます。
     // 将结果写入文件。
             let mut bw = BufWriter::new(f);
-            let json_results = object! { "results" => results };
+            let json_results = object! {
+                "results" => results,
+                "dataset" => data_report.as_ref().map(|r| r.to_json())
+            };
             json_results.write(&mut bw).unwrap();
             
         } else {
@@ -438,6 +695,14 @@ fn main() {
     // 名前空間が指定されている場合、それを使用してモデルをトレーニングします。
     // 如果提供了命名空间，使用它训练模型。
         let namespace = matches.value_of("namespace").unwrap().to_string();
+        // When sampling, a model trained on all keys is the reference for the stats.
+        // サンプリング時は、すべてのキーで学習したモデルを統計の基準にします。
+        // 采样时，以在所有键上训练的模型作为统计的参照。
+        let mut sampling_reference: Option<TrainedRMI> = None;
+        // Keep a handle on the keys to replay them through the trained RMI.
+        // 学習済みRMIでキーを再生するため、キーへのハンドルを保持します。
+        // 保留键的句柄，以便在训练好的RMI上重放它们。
+        let verify_data = if matches.is_present("verify") { Some(data.soft_copy()) } else { None };
         let mut trained_model = match matches.value_of("max-size") {
             None => {
                 // If no max-size is specified, use the default training method.
@@ -453,19 +718,22 @@ fn main() {
                 let trained_model = match matches.value_of("bounded") { // Check if bounded training is required.
     // 制限付きトレーニングが必要かどうかを確認します。
     // 检查是否需要有界训练。
-                    None => dynamic!(train, data, models, branch_factor), // Perform standard training.
+                    None => { // Perform standard training.
     // 標準的なトレーニングを実行します。
     // 执行标准训练。
+                        if sample_rate > 1 && matches.is_present("compare-sampling") {
+                            let mut full_data = data.soft_copy();
+                            full_data.set_sample_rate(1);
+                            sampling_reference = Some(dynamic!(train, full_data, models, branch_factor));
+                        }
+                        dynamic!(train_with_config, data, models, branch_factor, &config)
+                    },
                     Some(s) => {
                         let line_size = s.parse::<usize>()
                             .expect("Line size must be a positive integer."); // Ensure the line size is a positive integer.
     // ラインサイズが正の整数であることを確認します。
     // 确保行大小为正整数。
-                        let d_u64 = data.into_u64()
-                            .expect("Can only construct a bounded RMI on u64 data."); // Ensure the data is of type u64.
-    // データがu64タイプであることを確認します。
-    // 确保数据为u64类型。
-                        train_bounded(&d_u64, models, branch_factor, line_size) // Perform bounded training.
+                        dynamic!(train_bounded, data, models, branch_factor, line_size) // Perform bounded training.
     // 制限付きトレーニングを実行します。
     // 执行有界训练。
                     }
@@ -556,6 +824,91 @@ fn main() {
                 trained_model.model_max_log2_error,
                 trained_model.model_max_error as f64 / num_rows as f64 * 100.0
             );
+            if let Some(dist) = &trained_model.error_distribution {
+                println!(
+                    "Per-key error: mean {}, p50 {}, p90 {}, p99 {}, p99.9 {}", // Print the per-key error percentiles.
+    // キーごとの誤差のパーセンタイルを出力します。
+    // 输出每个键误差的百分位数。
+                    dist.mean, dist.p50, dist.p90, dist.p99, dist.p999
+                );
+            }
+            if !trained_model.left_errors.is_empty() {
+                println!(
+                    "Average search window: {} positions ({} with symmetric errors)", // Print the window sizes.
+    // 探索ウィンドウのサイズを出力します。
+    // 输出搜索窗口的大小。
+                    trained_model.model_avg_window,
+                    2.0 * trained_model.model_avg_error + 1.0
+                );
+            }
+            if let Some(limit) = max_memory {
+                println!("Peak training memory: {} of {} bytes", trained_model.peak_memory, limit);
+                if trained_model.peak_memory > limit {
+                    warn!("Training used more memory than the limit of {} bytes", limit);
+                }
+            }
+        }
+
+        if let Some(verify_data) = verify_data { // Verify the error bounds before writing anything.
+    // 何かを書き込む前に誤差範囲を検証します。
+    // 在写入任何内容之前验证误差界。
+            let res = dynamic!(rmi_lib::verify, verify_data, &trained_model);
+            println!("Verified {} keys and {} absent keys, {} outside of their error window",
+                     res.keys, res.probes, res.num_violations);
+            for v in res.violations.iter() {
+                let model = match v.partial {
+                    Some(third) => format!("leaf {} (partial model {})", v.leaf, third),
+                    None => format!("leaf {}", v.leaf)
+                };
+                println!("  {}: {} key {:?} is at position {}, predicted {} with window [{}, {}]",
+                         model, if v.absent { "absent" } else { "present" }, v.key,
+                         v.position, v.predicted, v.lower, v.upper);
+            }
+            if res.num_violations > res.violations.len() {
+                println!("  ... and {} more", res.num_violations - res.violations.len());
+            }
+            if !res.passed() {
+                panic!("The error bounds of the RMI do not hold for {} keys", res.num_violations);
+            }
+        }
+
+        if let Some(stats_fp) = matches.value_of("stats-file") { // Write the data set and model statistics.
+    // データセットとモデルの統計情報を書き込みます。
+    // 写入数据集和模型的统计信息。
+            let f = File::create(stats_fp).expect("Could not write stats file");
+            let mut bw = BufWriter::new(f);
+            let mut stats = object! {
+                "dataset" => data_report.as_ref().map(|r| r.to_json()),
+                "rmi" => trained_model.to_json()
+            };
+            if let Some(limit) = max_memory { // The memory limit next to the peak.
+    // ピークと並べてメモリ制限を記録します。
+    // 将内存限制与峰值一起记录。
+                stats["memory"] = object! {
+                    "max_memory" => limit,
+                    "peak_memory" => trained_model.peak_memory
+                };
+            }
+            if let Some(reference) = &sampling_reference { // Compare against training on all keys.
+    // すべてのキーで学習した場合と比較します。
+    // 与在所有键上训练的结果进行比较。
+                let saved = 1.0 - trained_model.build_time as f64 / reference.build_time as f64;
+                println!("Sampling every {}th key saved {:.1}% of the build time", sample_rate, saved * 100.0);
+                println!("Max error {} (all keys: {}), average log2 error {} (all keys: {})",
+                         trained_model.model_max_error, reference.model_max_error,
+                         trained_model.model_avg_log2_error, reference.model_avg_log2_error);
+                stats["sampling"] = object! {
+                    "sample_rate" => sample_rate,
+                    "build_time" => trained_model.build_time as u64,
+                    "full_build_time" => reference.build_time as u64,
+                    "build_time_saved" => saved,
+                    "max_error" => trained_model.model_max_error,
+                    "full_max_error" => reference.model_max_error,
+                    "avg_log2_error" => trained_model.model_avg_log2_error,
+                    "full_avg_log2_error" => reference.model_avg_log2_error
+                };
+            }
+            stats.write(&mut bw).unwrap();
         }
         
         if !matches.is_present("no-code") { // If the `no-code` flag is not present, output the code.
@@ -573,6 +926,10 @@ fn main() {
                 data_dir,
                 key_type,
                 !no_errors).unwrap();
+            if let Some(prefix) = &key_prefix {
+                rmi_lib::output_key_encoding(
+                    &namespace, data_dir, prefix, num_rows as u64, !no_errors).unwrap();
+            }
         } else {
             trace!("Skipping code generation due to CLI flag"); // Skip code generation if the flag is set.
     // フラグが設定されている場合、コード生成をスキップします。